@@ -0,0 +1,300 @@
+//! Failures injected into the components while the scripts run, configured
+//! with the `chaos` section.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::Serialize;
+use tokio::{process::Command, sync::oneshot, task::JoinHandle};
+
+use crate::{
+    config::{Chaos, ChaosAction, Component, Config},
+    environment,
+    environment::{command_name, Environment},
+    runtime::ContainerRuntime,
+    state::SharedState,
+    Error,
+};
+
+const DEFAULT_PAUSE: Duration = Duration::from_secs(5);
+
+/// A failure injected into a component, in the `chaos` annotation of the
+/// report.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChaosEvent {
+    /// Time since the scheduler started.
+    pub offset_ms: u64,
+    pub target: String,
+    pub action: &'static str,
+    /// The test running when the failure was injected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The random failures of a chaos config, in the order they happen.
+struct Schedule {
+    rng: StdRng,
+    targets: Vec<Component>,
+    actions: Vec<ChaosAction>,
+    min: Duration,
+    max: Duration,
+}
+
+impl Schedule {
+    fn new(chaos: &Chaos, targets: Vec<Component>, seed: u64) -> Result<Self, Error> {
+        let parse = |duration: &str| {
+            humantime::parse_duration(duration)
+                .map_err(|e| Error::Config(format!("Invalid chaos interval {}: {}", duration, e)))
+        };
+        let (min, max) = (parse(&chaos.interval.min)?, parse(&chaos.interval.max)?);
+        if min > max {
+            return Err(Error::Config(format!(
+                "Chaos interval min {} is above max {}",
+                chaos.interval.min, chaos.interval.max
+            )));
+        }
+        if chaos.actions.is_empty() {
+            return Err(Error::Config("Chaos needs at least one action".to_string()));
+        }
+        Ok(Self {
+            rng: StdRng::seed_from_u64(seed),
+            targets,
+            actions: chaos.actions.clone(),
+            min,
+            max,
+        })
+    }
+
+    /// How long to wait for the next failure, and its target and action.
+    fn next(&mut self) -> (Duration, &Component, ChaosAction) {
+        let wait = self.rng.gen_range(self.min..=self.max);
+        let target = self.targets.choose(&mut self.rng).unwrap();
+        let action = *self.actions.choose(&mut self.rng).unwrap();
+        (wait, target, action)
+    }
+}
+
+/// Injects the failures of a chaos config in the background until stopped.
+pub struct ChaosScheduler {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl ChaosScheduler {
+    /// Start the `chaos` of `cfg`, if it has any.
+    pub fn for_config<E: Environment + 'static>(
+        cfg: &Config,
+        state: Arc<Mutex<SharedState<E>>>,
+    ) -> Result<Option<Self>, Error> {
+        let Some(chaos) = &cfg.chaos else {
+            return Ok(None);
+        };
+        Self::start(
+            chaos,
+            &cfg.components,
+            cfg.container_runtime(),
+            environment::project_dirs()?.data_local_dir(),
+            state,
+        )
+        .map(Some)
+    }
+
+    /// Start injecting failures, recording them in the events of `state`.
+    pub fn start<E: Environment + 'static>(
+        chaos: &Chaos,
+        components: &[Component],
+        runtime: Arc<dyn ContainerRuntime>,
+        data_dir: &Path,
+        state: Arc<Mutex<SharedState<E>>>,
+    ) -> Result<Self, Error> {
+        let targets = chaos
+            .targets
+            .iter()
+            .map(|name| match components.iter().find(|c| &c.name == name) {
+                Some(c) if ["container", "pod", "process"].contains(&c.component_type.as_str()) => {
+                    Ok(c.clone())
+                }
+                Some(c) => Err(Error::Config(format!(
+                    "Chaos target {} is a {}, only containers, pods and processes can fail",
+                    name, c.component_type
+                ))),
+                None => Err(Error::Config(format!("Chaos target {} not found", name))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if targets.is_empty() {
+            return Err(Error::Config("Chaos needs at least one target".to_string()));
+        }
+        let seed = chaos.seed.unwrap_or_else(rand::random);
+        log::info!(
+            "Injecting failures into {}, seed {}",
+            chaos.targets.join(", "),
+            seed
+        );
+        let mut schedule = Schedule::new(chaos, targets, seed)?;
+        let pause_for = match &chaos.pause_for {
+            Some(duration) => humantime::parse_duration(duration).map_err(|e| {
+                Error::Config(format!("Invalid chaos pause_for {}: {}", duration, e))
+            })?,
+            None => DEFAULT_PAUSE,
+        };
+        let injector = Injector {
+            runtime,
+            data_dir: data_dir.to_path_buf(),
+            pause_for,
+        };
+
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let started_at = Instant::now();
+            loop {
+                let (wait, target, action) = schedule.next();
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = &mut stopped => return,
+                }
+                log::warn!("Chaos: {} {}", action.name(), target.name);
+                let offset_ms = started_at.elapsed().as_millis() as u64;
+                let test = {
+                    let state = state.lock();
+                    Some(state.current_test_stack.join(" > ")).filter(|test| !test.is_empty())
+                };
+                let error = match injector.inject(target, action).await {
+                    Ok(()) => None,
+                    Err(e) => {
+                        log::error!("Chaos: failed to {} {}: {}", action.name(), target.name, e);
+                        Some(e.to_string())
+                    }
+                };
+                state.lock().chaos_events.push(ChaosEvent {
+                    offset_ms,
+                    target: target.name.clone(),
+                    action: action.name(),
+                    test,
+                    error,
+                });
+            }
+        });
+        Ok(Self { stop, task })
+    }
+
+    /// Stop injecting failures, once a paused target is resumed.
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        if let Err(e) = self.task.await {
+            log::error!("Chaos scheduler failed: {}", e);
+        }
+    }
+}
+
+/// Runs the commands injecting failures.
+struct Injector {
+    runtime: Arc<dyn ContainerRuntime>,
+    data_dir: PathBuf,
+    pause_for: Duration,
+}
+
+impl Injector {
+    async fn inject(&self, target: &Component, action: ChaosAction) -> Result<(), Error> {
+        if target.component_type == "process" {
+            return self.signal_process(&target.name, action).await;
+        }
+        let pod = target.component_type == "pod";
+        match action {
+            // Pods have no stop timeout
+            ChaosAction::Restart if pod => self.container(pod, &["restart"], target).await,
+            ChaosAction::Restart => self.container(pod, &["restart", "-t", "0"], target).await,
+            ChaosAction::Kill => self.container(pod, &["kill"], target).await,
+            ChaosAction::Pause => {
+                self.container(pod, &["pause"], target).await?;
+                tokio::time::sleep(self.pause_for).await;
+                self.container(pod, &["unpause"], target).await
+            }
+        }
+    }
+
+    async fn container(&self, pod: bool, args: &[&str], target: &Component) -> Result<(), Error> {
+        let mut cmd = self.runtime.command();
+        if pod {
+            cmd.arg("pod");
+        }
+        cmd.args(args).arg(&target.name);
+        run(cmd).await
+    }
+
+    /// Send a signal to the process of a component, by the PID in its file.
+    async fn signal_process(&self, name: &str, action: ChaosAction) -> Result<(), Error> {
+        let pid_file = self.data_dir.join(format!("{}.pid", name));
+        let pid = std::fs::read_to_string(&pid_file)
+            .map_err(|e| Error::io(format!("No PID of process {}", name), e))?;
+        let signal = |signal: &str| {
+            let mut cmd = Command::new("kill");
+            cmd.args([signal, pid.trim()]);
+            cmd
+        };
+        match action {
+            ChaosAction::Restart => run(signal("-TERM")).await,
+            ChaosAction::Kill => run(signal("-KILL")).await,
+            ChaosAction::Pause => {
+                run(signal("-STOP")).await?;
+                tokio::time::sleep(self.pause_for).await;
+                run(signal("-CONT")).await
+            }
+        }
+    }
+}
+
+async fn run(mut cmd: Command) -> Result<(), Error> {
+    let output = cmd
+        .output()
+        .await
+        .map_err(|e| Error::run(command_name(&cmd), None, e))?;
+    if !output.status.success() {
+        return Err(Error::exited(command_name(&cmd), &output));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ChaosInterval;
+
+    #[test]
+    fn test_schedule() {
+        let chaos = Chaos {
+            targets: vec!["api".to_string(), "db".to_string()],
+            interval: ChaosInterval {
+                min: "1s".to_string(),
+                max: "3s".to_string(),
+            },
+            actions: vec![ChaosAction::Restart, ChaosAction::Pause],
+            pause_for: None,
+            seed: None,
+        };
+        let targets: Vec<Component> =
+            serde_yaml::from_str("[{name: api, type: container}, {name: db, type: pod}]").unwrap();
+        let events = |seed| {
+            let mut schedule = Schedule::new(&chaos, targets.clone(), seed).unwrap();
+            (0..20)
+                .map(|_| {
+                    let (wait, target, action) = schedule.next();
+                    (wait, target.name.clone(), action)
+                })
+                .collect::<Vec<_>>()
+        };
+        let first = events(42);
+        assert_eq!(first, events(42));
+        assert_ne!(first, events(43));
+        for (wait, _, action) in &first {
+            assert!(*wait >= Duration::from_secs(1) && *wait <= Duration::from_secs(3));
+            assert_ne!(*action, ChaosAction::Kill);
+        }
+    }
+}
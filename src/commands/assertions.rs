@@ -1,10 +1,16 @@
-use std::sync::Arc;
+use std::{io::IsTerminal, sync::Arc};
 
 use parking_lot::Mutex;
-use rhai::{EvalAltResult, NativeCallContext};
-use similar_asserts::SimpleDiff;
+use rhai::{
+    Array, Dynamic, EvalAltResult, FnPtr, ImmutableString, Map, NativeCallContext, Position,
+};
+use similar::{ChangeTag, TextDiff};
 
-use crate::{state::{Assertion, SharedState}, Environment};
+use super::system::duration_option;
+use crate::{
+    state::{Assertion, SharedState},
+    Environment,
+};
 
 pub fn require<E: Environment>(
     state: Arc<Mutex<SharedState<E>>>,
@@ -27,13 +33,17 @@ pub fn assert<E: Environment>(
     msg: &str,
 ) -> Result<(), Box<EvalAltResult>> {
     let mut state = state.lock();
-    let assertion_name = state.current_test_stack.join(".") + "/" + msg;
+    let mut assertion_name = state.current_test_stack.join(".") + "/";
+    for section in &state.current_sections {
+        assertion_name += &format!("{}/", section);
+    }
     let assertion = Assertion {
-        name: assertion_name,
+        name: assertion_name + msg,
         success,
         message: msg.to_string(),
         file: state.current_file.clone().unwrap_or("unknown".to_string()),
-        line: context.position().line().unwrap_or(0),
+        line: context.call_position().line().unwrap_or(0),
+        sections: state.current_sections.clone(),
     };
     state.push_assertion(assertion);
     if !success {
@@ -42,6 +52,548 @@ pub fn assert<E: Environment>(
     Ok(())
 }
 
-pub fn diff(expected: &str, actual: &str) -> String {
-    SimpleDiff::from_str(expected, actual, "EXPECTED", "ACTUAL").to_string()
+/// How diffs are rendered, from the output config.
+#[derive(Debug, Clone, Copy)]
+pub struct DiffStyle {
+    /// Color removed and added lines and highlight the changed words.
+    pub color: bool,
+    /// Unchanged lines shown around each change.
+    pub context: usize,
+    /// Lines shown before the rest of the diff is cut.
+    pub max_lines: usize,
+}
+
+impl DiffStyle {
+    /// Colored when the progress is printed pretty to a terminal, unless
+    /// `NO_COLOR` is set.
+    pub fn new<E: Environment>(state: &SharedState<E>) -> Self {
+        Self {
+            color: state.pretty()
+                && std::io::stdout().is_terminal()
+                && std::env::var_os("NO_COLOR").is_none(),
+            context: state.output.diff_context.unwrap_or(3),
+            max_lines: state.output.diff_max_lines.unwrap_or(200),
+        }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        match self.color {
+            true => format!("\x1b[{}m{}\x1b[0m", code, text),
+            false => text.to_string(),
+        }
+    }
+
+    /// `lines` cut after `max_lines`, with the number of lines left out.
+    fn truncate(&self, mut lines: Vec<String>) -> String {
+        if lines.len() > self.max_lines {
+            let hidden = lines.len() - self.max_lines;
+            lines.truncate(self.max_lines);
+            lines.push(self.paint("2", &format!("… {} more lines …", hidden)));
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+/// Line diff of two strings with the changed words highlighted, showing only
+/// the lines around the changes. Empty if the strings are equal.
+pub fn diff(expected: &str, actual: &str, style: DiffStyle) -> String {
+    let diff = TextDiff::from_lines(expected, actual);
+    let groups = diff.grouped_ops(style.context);
+    if groups.is_empty() {
+        return String::new();
+    }
+    let unchanged = |count: usize| style.paint("2", &format!("… {} unchanged lines …", count));
+    let mut lines = vec![
+        style.paint("31", "- EXPECTED"),
+        style.paint("32", "+ ACTUAL"),
+    ];
+    let mut line = 0;
+    for group in &groups {
+        let start = group[0].old_range().start;
+        if start > line {
+            lines.push(unchanged(start - line));
+        }
+        for op in group {
+            for change in diff.iter_inline_changes(op) {
+                let (sign, code) = match change.tag() {
+                    ChangeTag::Delete => ("-", "31"),
+                    ChangeTag::Insert => ("+", "32"),
+                    ChangeTag::Equal => (" ", "2"),
+                };
+                let mut text = style.paint(code, sign);
+                for (emphasized, value) in change.iter_strings_lossy() {
+                    let value = value.trim_end_matches(['\n', '\r']);
+                    text += &match emphasized && style.color {
+                        true => style.paint(&format!("{};1;4", code), value),
+                        false => style.paint(code, value),
+                    };
+                }
+                lines.push(text);
+            }
+        }
+        line = group.last().unwrap().old_range().end;
+    }
+    let total = diff.old_slices().len();
+    if total > line {
+        lines.push(unchanged(total - line));
+    }
+    style.truncate(lines)
+}
+
+// Diff maps and arrays field by field, other values as strings
+pub fn diff_values(expected: &Dynamic, actual: &Dynamic, style: DiffStyle) -> String {
+    if !(expected.is_map() && actual.is_map() || expected.is_array() && actual.is_array()) {
+        return diff(&expected.to_string(), &actual.to_string(), style);
+    }
+    let mut differences = vec![];
+    structural_diff("", expected, actual, &mut differences);
+    if differences.is_empty() {
+        return String::new();
+    }
+    let mut lines = vec!["Differences (EXPECTED|ACTUAL):".to_string()];
+    lines.extend(
+        differences
+            .iter()
+            .map(|difference| format!("  {}", difference)),
+    );
+    style.truncate(lines)
+}
+
+// Remove the map fields whose path matches one of the glob patterns, where `*`
+// matches any part of the path, e.g. `*.timestamp` or `items[*].id`
+fn remove_fields(path: &str, value: &Dynamic, patterns: &[regex::Regex]) -> Dynamic {
+    if let Some(map) = value.read_lock::<Map>() {
+        let mut result = Map::new();
+        for (key, v) in map.iter() {
+            let field = if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            if !patterns.iter().any(|p| p.is_match(&field)) {
+                result.insert(key.clone(), remove_fields(&field, v, patterns));
+            }
+        }
+        return result.into();
+    }
+    if let Some(array) = value.read_lock::<Array>() {
+        return array
+            .iter()
+            .enumerate()
+            .map(|(i, v)| remove_fields(&format!("{}[{}]", path, i), v, patterns))
+            .collect::<Array>()
+            .into();
+    }
+    value.clone()
+}
+
+// Regex of a field glob, a leading `*.` also matches the top-level fields
+fn field_pattern(pattern: &str) -> regex::Regex {
+    let (prefix, pattern) = match pattern.strip_prefix("*.") {
+        Some(rest) => ("(.*\\.)?", rest),
+        None => ("", pattern),
+    };
+    let re = regex::escape(pattern).replace("\\*", ".*");
+    regex::Regex::new(&format!("^{}{}$", prefix, re)).unwrap()
+}
+
+pub fn assert_eq_ignoring<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    expected: Dynamic,
+    actual: Dynamic,
+    ignore: Array,
+    msg: Option<&str>,
+) -> Result<(), Box<EvalAltResult>> {
+    let ignore = ignore
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .collect::<Vec<_>>();
+    let patterns = ignore
+        .iter()
+        .map(|pattern| field_pattern(pattern))
+        .collect::<Vec<_>>();
+    let expected = remove_fields("", &expected, &patterns);
+    let actual = remove_fields("", &actual, &patterns);
+
+    let mut differences = vec![];
+    structural_diff("", &expected, &actual, &mut differences);
+    let msg = match msg {
+        Some(msg) => msg.to_string(),
+        None => format!("Expected values to be equal ignoring {}", ignore.join(", ")),
+    };
+    if differences.is_empty() {
+        assert(state, context, true, &msg)
+    } else {
+        let style = DiffStyle::new(&state.lock());
+        let diff = diff_values(&expected, &actual, style);
+        assert(state, context, false, &format!("{}\n{}", msg, diff))
+    }
+}
+
+// Numbers are used as they are, duration strings like "1.5s" in seconds
+fn number_or_duration(name: &str, value: &Dynamic) -> Result<f64, Box<EvalAltResult>> {
+    if let Ok(value) = value.as_float() {
+        return Ok(value);
+    }
+    if let Ok(value) = value.as_int() {
+        return Ok(value as f64);
+    }
+    if let Some(value) = value.read_lock::<ImmutableString>() {
+        if let Ok(duration) = humantime::parse_duration(value.as_str()) {
+            return Ok(duration.as_secs_f64());
+        }
+    }
+    let msg = format!("{} must be a number or a duration, got {}", name, value);
+    Err(Box::new(EvalAltResult::ErrorRuntime(
+        msg.into(),
+        Position::NONE,
+    )))
+}
+
+pub fn assert_close<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    expected: Dynamic,
+    actual: Dynamic,
+    tolerance: Map,
+    msg: Option<&str>,
+) -> Result<(), Box<EvalAltResult>> {
+    let expected_value = number_or_duration("expected", &expected)?;
+    let actual_value = number_or_duration("actual", &actual)?;
+    let abs = match tolerance.get("abs") {
+        Some(abs) => Some(number_or_duration("abs", abs)?),
+        None => None,
+    };
+    let rel = match tolerance.get("rel") {
+        Some(rel) => Some(number_or_duration("rel", rel)?),
+        None => None,
+    };
+
+    let delta = (actual_value - expected_value).abs();
+    let allowed = abs
+        .unwrap_or(0.0)
+        .max(rel.unwrap_or(0.0) * expected_value.abs());
+    let success = delta <= allowed;
+
+    let msg = match msg {
+        Some(msg) => msg.to_string(),
+        None => format!("Expected {} to be close to {}", actual, expected),
+    };
+    let msg = if success {
+        msg
+    } else {
+        format!("{} (delta {}, allowed {})", msg, delta, allowed)
+    };
+    assert(state, context, success, &msg)
+}
+
+pub fn assert_exit_code<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    job: Map,
+    expected: i64,
+    msg: Option<&str>,
+) -> Result<(), Box<EvalAltResult>> {
+    let exit_code = job
+        .get("exit_code")
+        .and_then(|code| code.as_int().ok())
+        .ok_or_else(|| {
+            Box::new(EvalAltResult::ErrorRuntime(
+                "Expected a job result with an exit_code".into(),
+                Position::NONE,
+            ))
+        })?;
+    let success = exit_code == expected;
+    let msg = match msg {
+        Some(msg) => msg.to_string(),
+        None => format!("Expected exit code {}", expected),
+    };
+    let msg = if success {
+        msg
+    } else {
+        let stderr = job
+            .get("stderr")
+            .map(|stderr| stderr.to_string())
+            .unwrap_or_default();
+        format!("{}, got {}: {}", msg, exit_code, stderr.trim())
+    };
+    assert(state, context, success, &msg)
+}
+
+/// Assert that a response of the http functions, requested with
+/// `full_response`, arrived within `limit`, an int in ms or a duration string.
+pub fn assert_latency_under<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    response: Map,
+    limit: Dynamic,
+    msg: Option<&str>,
+) -> Result<(), Box<EvalAltResult>> {
+    let elapsed_ms = response
+        .get("elapsed_ms")
+        .and_then(|elapsed| elapsed.as_float().ok())
+        .ok_or_else(|| {
+            Box::new(EvalAltResult::ErrorRuntime(
+                "Expected a response with elapsed_ms, requested with full_response: true".into(),
+                Position::NONE,
+            ))
+        })?;
+    let mut options = Map::new();
+    options.insert("limit".into(), limit);
+    let limit = duration_option(&options, "limit", std::time::Duration::ZERO)?;
+    let limit_ms = limit.as_secs_f64() * 1000.0;
+    let success = elapsed_ms < limit_ms;
+
+    let msg = match msg {
+        Some(msg) => msg.to_string(),
+        None => format!(
+            "Expected a response within {}",
+            humantime::format_duration(limit)
+        ),
+    };
+    let msg = if success {
+        msg
+    } else {
+        format!("{}, took {:.1}ms", msg, elapsed_ms)
+    };
+    assert(state, context, success, &msg)
+}
+
+fn structural_diff(path: &str, expected: &Dynamic, actual: &Dynamic, out: &mut Vec<String>) {
+    let name = if path.is_empty() { "<root>" } else { path };
+    if let (Some(expected), Some(actual)) = (expected.read_lock::<Map>(), actual.read_lock::<Map>())
+    {
+        diff_maps(path, &expected, &actual, out);
+    } else if let (Some(expected), Some(actual)) =
+        (expected.read_lock::<Array>(), actual.read_lock::<Array>())
+    {
+        for i in 0..expected.len().max(actual.len()) {
+            let index = format!("{}[{}]", path, i);
+            match (expected.get(i), actual.get(i)) {
+                (Some(e), Some(a)) => structural_diff(&index, e, a, out),
+                (Some(e), None) => out.push(format!("{}: missing, expected {:?}", index, e)),
+                (None, Some(a)) => out.push(format!("{}: unexpected {:?}", index, a)),
+                (None, None) => unreachable!(),
+            }
+        }
+    } else if expected.type_name() != actual.type_name() {
+        out.push(format!(
+            "{}: expected {} {:?}, got {} {:?}",
+            name,
+            expected.type_name(),
+            expected,
+            actual.type_name(),
+            actual
+        ));
+    } else if expected.to_string() != actual.to_string() {
+        out.push(format!(
+            "{}: expected {:?}, got {:?}",
+            name, expected, actual
+        ));
+    }
+}
+
+fn diff_maps(path: &str, expected: &Map, actual: &Map, out: &mut Vec<String>) {
+    let field = |key: &str| {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", path, key)
+        }
+    };
+    for (key, e) in expected {
+        match actual.get(key) {
+            Some(a) => structural_diff(&field(key), e, a, out),
+            None => out.push(format!("{}: missing, expected {:?}", field(key), e)),
+        }
+    }
+    for (key, a) in actual {
+        if !expected.contains_key(key) {
+            out.push(format!("{}: unexpected {:?}", field(key), a));
+        }
+    }
+}
+
+// Extract the message a script would see in a catch block, without the
+// "in call to function" wrapping added by rhai for nested calls.
+fn error_message(err: &EvalAltResult) -> String {
+    match err.unwrap_inner() {
+        EvalAltResult::ErrorRuntime(value, _) if value.is_string() => value.to_string(),
+        inner => inner.to_string(),
+    }
+}
+
+pub fn catch_error(context: NativeCallContext, cb: FnPtr) -> Result<Dynamic, Box<EvalAltResult>> {
+    match cb.call_within_context::<Dynamic>(&context, ()) {
+        Ok(_) => Ok(Dynamic::UNIT),
+        Err(err) => Ok(error_message(&err).into()),
+    }
+}
+
+pub fn assert_throws<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    cb: FnPtr,
+    pattern: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let (success, msg) = match cb.call_within_context::<Dynamic>(&context, ()) {
+        Ok(_) => (
+            false,
+            format!(
+                "Expected an error matching '{}' but none was thrown",
+                pattern
+            ),
+        ),
+        Err(err) => {
+            let message = error_message(&err);
+            let matches = match regex::Regex::new(pattern) {
+                Ok(re) => re.is_match(&message),
+                Err(_) => message.contains(pattern),
+            };
+            if matches {
+                (true, format!("Expected an error matching '{}'", pattern))
+            } else {
+                (
+                    false,
+                    format!(
+                        "Expected an error matching '{}', got '{}'",
+                        pattern, message
+                    ),
+                )
+            }
+        }
+    };
+    assert(state, context, success, &msg)
+}
+
+// Retry `cb` until none of its assertions fail, only the assertions of the
+// last attempt are kept
+pub fn eventually<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    options: Map,
+    cb: FnPtr,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let timeout = duration_option(&options, "timeout", std::time::Duration::from_secs(30))?;
+    let interval = duration_option(&options, "interval", std::time::Duration::from_secs(1))?;
+
+    let start = std::time::Instant::now();
+    loop {
+        let (test_id, mark, was_failed) = {
+            let state = state.lock();
+            let test_id = state.get_current_test_id();
+            let mark = state.assertions.get(&test_id).map_or(0, |a| a.len());
+            (test_id, mark, state.current_test_failed)
+        };
+
+        let result = cb.call_within_context::<Dynamic>(&context, ());
+
+        let mut state = state.lock();
+        let failed = result.is_err()
+            || state
+                .assertions
+                .get(&test_id)
+                .is_some_and(|a| a.iter().skip(mark).any(|a| !a.success));
+        if !failed || start.elapsed() + interval > timeout {
+            return result;
+        }
+
+        // Forget the failed attempt and try again
+        if let Some(assertions) = state.assertions.get_mut(&test_id) {
+            assertions.truncate(mark);
+        }
+        state.current_test_failed = was_failed;
+        drop(state);
+        std::thread::sleep(interval);
+    }
+}
+
+/// The failed assertions recorded so far, by file and line, as
+/// `#{test, name, message, file, line, sections}`.
+pub fn failed_assertions<E: Environment>(state: Arc<Mutex<SharedState<E>>>) -> Array {
+    let state = state.lock();
+    let mut failed = state
+        .assertions
+        .iter()
+        .flat_map(|(test_id, assertions)| assertions.iter().map(move |a| (test_id, a)))
+        .filter(|(_, assertion)| !assertion.success)
+        .collect::<Vec<_>>();
+    failed.sort_by(|(_, a), (_, b)| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    failed
+        .into_iter()
+        .map(|(test_id, assertion)| {
+            let mut map = Map::new();
+            map.insert("test".into(), test_id.to_string().into());
+            map.insert("name".into(), assertion.name.clone().into());
+            map.insert("message".into(), assertion.message.clone().into());
+            map.insert("file".into(), assertion.file.clone().into());
+            map.insert("line".into(), (assertion.line as i64).into());
+            let sections = assertion.sections.iter().cloned().map(Dynamic::from);
+            map.insert("sections".into(), sections.collect::<Array>().into());
+            map.into()
+        })
+        .collect()
+}
+
+/// The number of assertions recorded so far, passed or failed.
+pub fn assertion_count<E: Environment>(state: Arc<Mutex<SharedState<E>>>) -> i64 {
+    state
+        .lock()
+        .assertions
+        .values()
+        .map(Vec::len)
+        .sum::<usize>() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_pattern() {
+        let pattern = field_pattern("*.timestamp");
+        assert!(pattern.is_match("timestamp"));
+        assert!(pattern.is_match("items[0].timestamp"));
+        assert!(!pattern.is_match("last_timestamp"));
+        let pattern = field_pattern("items[*].id");
+        assert!(pattern.is_match("items[3].id"));
+        assert!(!pattern.is_match("id"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let style = DiffStyle {
+            color: false,
+            context: 1,
+            max_lines: 200,
+        };
+        let expected = (0..500)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let actual = expected.replace("line 250\n", "line two fifty\n");
+        assert_eq!(
+            diff(&expected, &actual, style),
+            "- EXPECTED\n+ ACTUAL\n… 249 unchanged lines …\n line 249\n-line 250\n\
+             +line two fifty\n line 251\n… 248 unchanged lines …\n"
+        );
+        assert_eq!(diff(&expected, &expected, style), "");
+
+        let style = DiffStyle {
+            max_lines: 3,
+            ..style
+        };
+        let actual = expected.replace("line", "row");
+        let cut = diff(&expected, &actual, style);
+        assert_eq!(cut, "- EXPECTED\n+ ACTUAL\n-line 0\n… 999 more lines …\n");
+
+        let style = DiffStyle {
+            color: true,
+            max_lines: 200,
+            ..style
+        };
+        let colored = diff("a b c\n", "a x c\n", style);
+        assert!(colored.contains("\x1b[31;1;4mb\x1b[0m"));
+        assert!(colored.contains("\x1b[32;1;4mx\x1b[0m"));
+    }
 }
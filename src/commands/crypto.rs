@@ -0,0 +1,242 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit},
+    Aes128Gcm, Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use rhai::{EvalAltResult, Map, INT};
+use rsa::{
+    pkcs1v15,
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding},
+    signature::SignatureEncoding,
+    Oaep, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::Sha256;
+
+use crate::rhai::invalid;
+
+// AES-GCM nonces are 96 bits and prepended to the ciphertext
+const NONCE_LEN: usize = 12;
+
+fn decode(name: &str, value: &str) -> Result<Vec<u8>, Box<EvalAltResult>> {
+    STANDARD
+        .decode(value.trim())
+        .map_err(|e| invalid(format!("{} must be base64: {}", name, e)))
+}
+
+fn key_pair(private_key: String, public_key: String) -> Map {
+    let mut map = Map::new();
+    map.insert("private_key".into(), private_key.into());
+    map.insert("public_key".into(), public_key.into());
+    map
+}
+
+/// Random base64 AES key of 128 or 256 bits.
+pub fn aes_keygen(bits: INT) -> Result<String, Box<EvalAltResult>> {
+    let mut rng = rand::thread_rng();
+    let key = match bits {
+        128 => Aes128Gcm::generate_key(&mut rng).to_vec(),
+        256 => Aes256Gcm::generate_key(&mut rng).to_vec(),
+        _ => {
+            return Err(invalid(format!(
+                "AES keys have 128 or 256 bits, not {}",
+                bits
+            )))
+        }
+    };
+    Ok(STANDARD.encode(key))
+}
+
+/// Base64 of a random nonce followed by the ciphertext of `plaintext`.
+pub fn encrypt_aes_gcm(key: &str, plaintext: &str) -> Result<String, Box<EvalAltResult>> {
+    let key = decode("key", key)?;
+    let mut rng = rand::thread_rng();
+    let (nonce, ciphertext) = match key.len() {
+        16 => {
+            let nonce = Aes128Gcm::generate_nonce(&mut rng);
+            let cipher = Aes128Gcm::new_from_slice(&key).expect("checked length");
+            (nonce, cipher.encrypt(&nonce, plaintext.as_bytes()))
+        }
+        32 => {
+            let nonce = Aes256Gcm::generate_nonce(&mut rng);
+            let cipher = Aes256Gcm::new_from_slice(&key).expect("checked length");
+            (nonce, cipher.encrypt(&nonce, plaintext.as_bytes()))
+        }
+        len => {
+            return Err(invalid(format!(
+                "AES key must have 16 or 32 bytes, got {}",
+                len
+            )))
+        }
+    };
+    let ciphertext = ciphertext.map_err(|e| invalid(format!("Failed to encrypt: {}", e)))?;
+    Ok(STANDARD.encode([nonce.as_slice(), &ciphertext].concat()))
+}
+
+pub fn decrypt_aes_gcm(key: &str, ciphertext: &str) -> Result<String, Box<EvalAltResult>> {
+    let key = decode("key", key)?;
+    let data = decode("ciphertext", ciphertext)?;
+    if data.len() < NONCE_LEN {
+        return Err(invalid("Ciphertext is too short".to_string()));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::from_slice(nonce);
+    let plaintext = match key.len() {
+        16 => Aes128Gcm::new_from_slice(&key)
+            .expect("checked length")
+            .decrypt(nonce, ciphertext),
+        32 => Aes256Gcm::new_from_slice(&key)
+            .expect("checked length")
+            .decrypt(nonce, ciphertext),
+        len => {
+            return Err(invalid(format!(
+                "AES key must have 16 or 32 bytes, got {}",
+                len
+            )))
+        }
+    }
+    .map_err(|_| invalid("Failed to decrypt: wrong key or modified ciphertext".to_string()))?;
+    String::from_utf8(plaintext).map_err(|e| invalid(format!("Decrypted text is not UTF-8: {}", e)))
+}
+
+/// `#{private_key, public_key}` with the raw 32 byte keys in base64.
+pub fn ed25519_keygen() -> Map {
+    let key = SigningKey::generate(&mut rand::thread_rng());
+    key_pair(
+        STANDARD.encode(key.to_bytes()),
+        STANDARD.encode(key.verifying_key().to_bytes()),
+    )
+}
+
+pub fn ed25519_sign(private_key: &str, message: &str) -> Result<String, Box<EvalAltResult>> {
+    let key: [u8; 32] = decode("private_key", private_key)?
+        .try_into()
+        .map_err(|_| invalid("Ed25519 private key must have 32 bytes".to_string()))?;
+    let signature = SigningKey::from_bytes(&key).sign(message.as_bytes());
+    Ok(STANDARD.encode(signature.to_bytes()))
+}
+
+pub fn ed25519_verify(
+    public_key: &str,
+    message: &str,
+    signature: &str,
+) -> Result<bool, Box<EvalAltResult>> {
+    let key: [u8; 32] = decode("public_key", public_key)?
+        .try_into()
+        .map_err(|_| invalid("Ed25519 public key must have 32 bytes".to_string()))?;
+    let key = VerifyingKey::from_bytes(&key)
+        .map_err(|e| invalid(format!("Invalid Ed25519 public key: {}", e)))?;
+    let Ok(signature) = <[u8; 64]>::try_from(decode("signature", signature)?) else {
+        return Ok(false);
+    };
+    Ok(key
+        .verify(
+            message.as_bytes(),
+            &ed25519_dalek::Signature::from_bytes(&signature),
+        )
+        .is_ok())
+}
+
+/// `#{private_key, public_key}` as PKCS#8 and SPKI PEM.
+pub fn rsa_keygen(bits: INT) -> Result<Map, Box<EvalAltResult>> {
+    if !(1024..=8192).contains(&bits) {
+        return Err(invalid(format!(
+            "RSA keys have 1024 to 8192 bits, not {}",
+            bits
+        )));
+    }
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), bits as usize)
+        .map_err(|e| invalid(format!("Failed to generate RSA key: {}", e)))?;
+    let private_key = key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| invalid(format!("Failed to encode RSA key: {}", e)))?;
+    let public_key = key
+        .to_public_key()
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| invalid(format!("Failed to encode RSA key: {}", e)))?;
+    Ok(key_pair(private_key.to_string(), public_key))
+}
+
+fn rsa_private_key(pem: &str) -> Result<RsaPrivateKey, Box<EvalAltResult>> {
+    RsaPrivateKey::from_pkcs8_pem(pem)
+        .map_err(|e| invalid(format!("Invalid RSA private key: {}", e)))
+}
+
+fn rsa_public_key(pem: &str) -> Result<RsaPublicKey, Box<EvalAltResult>> {
+    RsaPublicKey::from_public_key_pem(pem)
+        .map_err(|e| invalid(format!("Invalid RSA public key: {}", e)))
+}
+
+/// Base64 of `plaintext` encrypted with RSA-OAEP and SHA-256.
+pub fn rsa_encrypt(public_key: &str, plaintext: &str) -> Result<String, Box<EvalAltResult>> {
+    let ciphertext = rsa_public_key(public_key)?
+        .encrypt(
+            &mut rand::thread_rng(),
+            Oaep::new::<Sha256>(),
+            plaintext.as_bytes(),
+        )
+        .map_err(|e| invalid(format!("Failed to encrypt: {}", e)))?;
+    Ok(STANDARD.encode(ciphertext))
+}
+
+pub fn rsa_decrypt(private_key: &str, ciphertext: &str) -> Result<String, Box<EvalAltResult>> {
+    let plaintext = rsa_private_key(private_key)?
+        .decrypt(Oaep::new::<Sha256>(), &decode("ciphertext", ciphertext)?)
+        .map_err(|e| invalid(format!("Failed to decrypt: {}", e)))?;
+    String::from_utf8(plaintext).map_err(|e| invalid(format!("Decrypted text is not UTF-8: {}", e)))
+}
+
+/// Base64 RSASSA-PKCS1-v1_5 signature of `message` with SHA-256.
+pub fn rsa_sign(private_key: &str, message: &str) -> Result<String, Box<EvalAltResult>> {
+    let key = pkcs1v15::SigningKey::<Sha256>::new(rsa_private_key(private_key)?);
+    let signature = key
+        .try_sign(message.as_bytes())
+        .map_err(|e| invalid(format!("Failed to sign: {}", e)))?;
+    Ok(STANDARD.encode(signature.to_bytes()))
+}
+
+pub fn rsa_verify(
+    public_key: &str,
+    message: &str,
+    signature: &str,
+) -> Result<bool, Box<EvalAltResult>> {
+    let key = pkcs1v15::VerifyingKey::<Sha256>::new(rsa_public_key(public_key)?);
+    let Ok(signature) = pkcs1v15::Signature::try_from(decode("signature", signature)?.as_slice())
+    else {
+        return Ok(false);
+    };
+    Ok(key.verify(message.as_bytes(), &signature).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trips() {
+        let key = aes_keygen(256).unwrap();
+        let ciphertext = encrypt_aes_gcm(&key, "secret").unwrap();
+        assert_eq!(decrypt_aes_gcm(&key, &ciphertext).unwrap(), "secret");
+        assert!(decrypt_aes_gcm(&aes_keygen(256).unwrap(), &ciphertext).is_err());
+
+        let keys = ed25519_keygen();
+        let (private_key, public_key) = (
+            keys["private_key"].to_string(),
+            keys["public_key"].to_string(),
+        );
+        let signature = ed25519_sign(&private_key, "message").unwrap();
+        assert!(ed25519_verify(&public_key, "message", &signature).unwrap());
+        assert!(!ed25519_verify(&public_key, "other", &signature).unwrap());
+
+        let keys = rsa_keygen(1024).unwrap();
+        let (private_key, public_key) = (
+            keys["private_key"].to_string(),
+            keys["public_key"].to_string(),
+        );
+        let ciphertext = rsa_encrypt(&public_key, "secret").unwrap();
+        assert_eq!(rsa_decrypt(&private_key, &ciphertext).unwrap(), "secret");
+        let signature = rsa_sign(&private_key, "message").unwrap();
+        assert!(rsa_verify(&public_key, "message", &signature).unwrap());
+        assert!(!rsa_verify(&public_key, "other", &signature).unwrap());
+    }
+}
@@ -1,4 +1,4 @@
-use rhai::{Dynamic, EvalAltResult, Position};
+use rhai::{Blob, Dynamic, EvalAltResult, Map, Position};
 
 pub fn parse_json(json: &str) -> Result<Dynamic, Box<EvalAltResult>> {
     serde_json::from_str(json).map_err(|e| {
@@ -48,3 +48,130 @@ pub fn to_toml(value: &Dynamic) -> Result<String, Box<EvalAltResult>> {
         Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
     })
 }
+
+pub fn parse_cbor(cbor: &[u8]) -> Result<Dynamic, Box<EvalAltResult>> {
+    let value: ciborium::Value = ciborium::from_reader(cbor).map_err(|e| {
+        let msg = format!("Failed to parse CBOR: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    Ok(cbor_to_dynamic(value))
+}
+
+// Rhai can't deserialize CBOR nulls directly, so convert the values by hand.
+// Tags are dropped and non-string map keys become strings.
+fn cbor_to_dynamic(value: ciborium::Value) -> Dynamic {
+    use ciborium::Value;
+    match value {
+        Value::Integer(i) => i64::try_from(i128::from(i))
+            .map(Dynamic::from)
+            .unwrap_or_else(|_| Dynamic::from(i128::from(i) as f64)),
+        Value::Bytes(bytes) => Dynamic::from_blob(bytes),
+        Value::Float(f) => f.into(),
+        Value::Text(s) => s.into(),
+        Value::Bool(b) => b.into(),
+        Value::Null => Dynamic::UNIT,
+        Value::Tag(_, value) => cbor_to_dynamic(*value),
+        Value::Array(values) => values
+            .into_iter()
+            .map(cbor_to_dynamic)
+            .collect::<rhai::Array>()
+            .into(),
+        Value::Map(entries) => entries
+            .into_iter()
+            .map(|(key, value)| {
+                let key = match key {
+                    Value::Text(s) => s,
+                    key => cbor_to_dynamic(key).to_string(),
+                };
+                (key.into(), cbor_to_dynamic(value))
+            })
+            .collect::<Map>()
+            .into(),
+        _ => Dynamic::UNIT,
+    }
+}
+
+pub fn to_cbor(value: &Dynamic) -> Result<Blob, Box<EvalAltResult>> {
+    let mut cbor = Blob::new();
+    ciborium::into_writer(value, &mut cbor).map_err(|e| {
+        let msg = format!("Failed to convert to CBOR: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    Ok(cbor)
+}
+
+pub fn parse_msgpack(msgpack: &[u8]) -> Result<Dynamic, Box<EvalAltResult>> {
+    rmp_serde::from_slice(msgpack).map_err(|e| {
+        let msg = format!("Failed to parse MessagePack: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+pub fn to_msgpack(value: &Dynamic) -> Result<Blob, Box<EvalAltResult>> {
+    rmp_serde::to_vec(value).map_err(|e| {
+        let msg = format!("Failed to convert to MessagePack: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+pub fn load_data(path: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+    let parse = match std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+    {
+        Some("json") => parse_json,
+        Some("yaml") | Some("yml") => parse_yaml,
+        Some("toml") => parse_toml,
+        _ => {
+            let msg = format!("Unsupported data file extension: {}", path);
+            return Err(Box::new(EvalAltResult::ErrorRuntime(
+                msg.into(),
+                Position::NONE,
+            )));
+        }
+    };
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        let msg = format!("Failed to read data file {}: {}", path, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    parse(&content)
+}
+
+pub fn parse_dotenv(path: &str) -> Result<Map, Box<EvalAltResult>> {
+    let iter = dotenvy::from_path_iter(path).map_err(|e| {
+        let msg = format!("Failed to read environment file {}: {}", path, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let mut map = Map::new();
+    for item in iter {
+        let (key, value) = item.map_err(|e| {
+            let msg = format!("Failed to parse environment file {}: {}", path, e);
+            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+        })?;
+        map.insert(key.into(), value.into());
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_binary_round_trips() {
+        let mut value =
+            parse_json(r#"{"name": "a", "count": 2, "ratio": 0.5, "tags": [true, null]}"#).unwrap();
+        value
+            .write_lock::<Map>()
+            .unwrap()
+            .insert("raw".into(), Dynamic::from_blob(vec![1, 2, 3]));
+
+        let decoded = parse_cbor(&to_cbor(&value).unwrap()).unwrap();
+        assert_eq!(decoded.to_string(), value.to_string());
+        let decoded = parse_msgpack(&to_msgpack(&value).unwrap()).unwrap();
+        assert_eq!(decoded.to_string(), value.to_string());
+
+        assert!(parse_cbor(&[0xff]).is_err());
+        assert!(parse_msgpack(&[0xc1]).is_err());
+    }
+}
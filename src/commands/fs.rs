@@ -3,7 +3,11 @@ use std::sync::Arc;
 use parking_lot::Mutex;
 use rhai::{Array, Dynamic, EvalAltResult, Position};
 
-use crate::{state::SharedState, Environment};
+use crate::{
+    remote::{shell_quote, Remote},
+    state::SharedState,
+    Environment,
+};
 
 pub fn read_file(path: &str) -> Result<String, Box<EvalAltResult>> {
     std::fs::read_to_string(path).map_err(|e| {
@@ -33,6 +37,66 @@ pub fn mkdir(path: &str) -> Result<(), Box<EvalAltResult>> {
     })
 }
 
+fn remote_error(e: crate::Error) -> Box<EvalAltResult> {
+    Box::new(EvalAltResult::ErrorRuntime(
+        e.to_string().into(),
+        Position::NONE,
+    ))
+}
+
+/// Run `command` with the quoted `path` on the remote host.
+pub fn remote_run(
+    remote: &Remote,
+    command: &str,
+    path: &str,
+) -> Result<Vec<u8>, Box<EvalAltResult>> {
+    remote
+        .run_checked(&format!("{} {}", command, shell_quote(path)), None, None)
+        .map_err(remote_error)
+}
+
+pub fn remote_read_file(remote: &Remote, path: &str) -> Result<String, Box<EvalAltResult>> {
+    let content = remote_run(remote, "cat", path)?;
+    String::from_utf8(content).map_err(|e| {
+        let msg = format!("Failed to read file: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+pub fn remote_write_file(
+    remote: &Remote,
+    path: &str,
+    content: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    remote
+        .run_checked(
+            &format!("cat > {}", shell_quote(path)),
+            Some(content.as_bytes()),
+            None,
+        )
+        .map(|_| ())
+        .map_err(remote_error)
+}
+
+pub fn remote_file_exists(remote: &Remote, path: &str) -> Result<bool, Box<EvalAltResult>> {
+    let output = remote
+        .run(&format!("test -e {}", shell_quote(path)), None, None)
+        .map_err(remote_error)?;
+    // ssh exits with 255 when it fails itself
+    if output.status.code() == Some(255) {
+        let msg = format!(
+            "Failed to reach {}: {}",
+            remote.host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            msg.into(),
+            Position::NONE,
+        )));
+    }
+    Ok(output.status.success())
+}
+
 pub fn ls(path: &str) -> Result<Array, Box<EvalAltResult>> {
     let metadata = std::fs::metadata(path).map_err(|e| {
         let msg = format!("Failed to get metadata: {}", e);
@@ -41,7 +105,7 @@ pub fn ls(path: &str) -> Result<Array, Box<EvalAltResult>> {
 
     if metadata.is_file() {
         return Ok(vec![Dynamic::from(
-            path.split('/').last().unwrap_or(path).to_string(),
+            path.rsplit('/').next().unwrap_or(path).to_string(),
         )]);
     }
 
@@ -62,16 +126,97 @@ pub fn file_exists(path: &str) -> bool {
     std::fs::metadata(path).is_ok()
 }
 
+// The directory relative paths are resolved against
+pub fn workdir() -> Result<String, Box<EvalAltResult>> {
+    std::env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .map_err(|e| {
+            let msg = format!("Failed to get working directory: {}", e);
+            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+        })
+}
+
+// Create a temporary directory, removed at the end of the current test or,
+// outside of tests and when `global` is set, at the end of the run
 pub fn temp_dir<E: Environment>(
     state: Arc<Mutex<SharedState<E>>>,
     prefix: &str,
+    global: bool,
 ) -> Result<String, Box<EvalAltResult>> {
     let temp_dir = tempdir::TempDir::new(prefix).map_err(|e| {
         let msg = format!("Failed to create temporary directory: {}", e);
         Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
     })?;
     let path = temp_dir.path().to_string_lossy().to_string();
-    state.lock().temp_dirs.push(temp_dir);
+    let mut state = state.lock();
+    if state.in_test && !global {
+        state.test_temp_dirs.push(temp_dir);
+    } else {
+        state.temp_dirs.push(temp_dir);
+    }
+    Ok(path)
+}
+
+// Path of the artifact `name` in the directory of the current test, recorded
+// in the report once a file was written to it
+pub fn artifact_path<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    name: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let relative = std::path::Path::new(name)
+        .components()
+        .all(|part| matches!(part, std::path::Component::Normal(_)));
+    if !relative {
+        let msg = format!(
+            "Invalid artifact name {}, expected a path inside the artifacts directory",
+            name
+        );
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            msg.into(),
+            Position::NONE,
+        )));
+    }
+    let mut state = state.lock();
+    let mut dir = state.artifacts_dir.clone();
+    for part in &state.current_test_stack {
+        dir.push(
+            part.chars()
+                .map(|c| {
+                    if c.is_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>(),
+        );
+    }
+    let path = dir.join(name);
+    std::fs::create_dir_all(path.parent().unwrap_or(&dir)).map_err(|e| {
+        let msg = format!("Failed to create artifacts directory: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let path = path.to_string_lossy().to_string();
+    state.push_annotation("artifacts", path.clone().into());
+    Ok(path)
+}
+
+// Save an artifact from an existing file, or from the given content otherwise
+pub fn save_artifact<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    name: &str,
+    content_or_path: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let path = artifact_path(state, name)?;
+    let result = if std::path::Path::new(content_or_path).is_file() {
+        std::fs::copy(content_or_path, &path).map(|_| ())
+    } else {
+        std::fs::write(&path, content_or_path)
+    };
+    result.map_err(|e| {
+        let msg = format!("Failed to save artifact {}: {}", name, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
     Ok(path)
 }
 
@@ -145,6 +290,32 @@ pub fn absolute_path(path: &str) -> Result<String, Box<EvalAltResult>> {
         .map(|p| p.to_string_lossy().to_string())
 }
 
+// Resolve a path relative to the directory of the currently running script
+pub fn asset_path<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    path: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    let path = std::path::Path::new(path);
+    if path.is_absolute() {
+        return Ok(path.to_string_lossy().to_string());
+    }
+    let base = state
+        .lock()
+        .current_file
+        .as_ref()
+        .and_then(|file| std::path::Path::new(file).parent().map(|p| p.to_path_buf()))
+        .unwrap_or_default();
+    Ok(base.join(path).to_string_lossy().to_string())
+}
+
+// Read a file relative to the directory of the currently running script
+pub fn read_asset<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    path: &str,
+) -> Result<String, Box<EvalAltResult>> {
+    read_file(&asset_path(state, path)?)
+}
+
 // Helper function for recursive directory copying
 fn copy_dir_all(src: &str, dst: &str) -> std::io::Result<()> {
     std::fs::create_dir_all(dst)?;
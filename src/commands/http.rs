@@ -1,6 +1,11 @@
+use std::time::{Duration, Instant};
 
 use reqwest::header::HeaderMap;
-use rhai::{Dynamic, EvalAltResult, Position};
+use rhai::{Dynamic, EvalAltResult, Map, Position};
+
+use super::system::duration_option;
+use crate::config::{HttpRetry, RetryError, RetryOn};
+use crate::rhai::invalid;
 
 fn get_url_and_headers(options: &Dynamic) -> Result<(String, HeaderMap), Box<EvalAltResult>> {
     let mut url = options
@@ -67,63 +72,441 @@ fn get_url_and_headers(options: &Dynamic) -> Result<(String, HeaderMap), Box<Eva
     Ok((url, header_map))
 }
 
-pub async fn http_get(options: Dynamic) -> Result<String, Box<EvalAltResult>> {
+/// Retry policy of one request, from its options and the global default.
+struct RetryPolicy {
+    retries: u64,
+    retry_on: Vec<RetryOn>,
+    backoff: Duration,
+    backoff_factor: f64,
+}
+
+fn retry_policy(options: &Dynamic, default: &HttpRetry) -> Result<RetryPolicy, Box<EvalAltResult>> {
+    let options = options.as_map_ref()?;
+
+    let retries = match options.get("retries") {
+        Some(retries) => retries
+            .as_int()
+            .ok()
+            .and_then(|retries| u64::try_from(retries).ok())
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Option retries must be a positive integer, got {}",
+                    retries
+                ))
+            })?,
+        None => default.retries.unwrap_or(0),
+    };
+
+    let retry_on = match options.get("retry_on") {
+        Some(retry_on) => retry_on
+            .as_array_ref()
+            .map_err(|_| invalid("Option retry_on must be an array".to_string()))?
+            .iter()
+            .map(|value| {
+                if let Some(status) = value.as_int().ok().and_then(|s| u16::try_from(s).ok()) {
+                    Ok(RetryOn::Status(status))
+                } else if value.to_string() == "connect" {
+                    Ok(RetryOn::Error(RetryError::Connect))
+                } else {
+                    Err(invalid(format!(
+                        "Option retry_on must list status codes or \"connect\", got {}",
+                        value
+                    )))
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => default.retry_on.clone().unwrap_or_else(|| {
+            vec![
+                RetryOn::Status(502),
+                RetryOn::Status(503),
+                RetryOn::Status(504),
+                RetryOn::Error(RetryError::Connect),
+            ]
+        }),
+    };
+
+    let default_backoff = match &default.backoff {
+        Some(backoff) => humantime::parse_duration(backoff)
+            .map_err(|e| invalid(format!("Invalid global http_retry backoff: {}", e)))?,
+        None => Duration::from_millis(100),
+    };
+    let backoff = duration_option(&options, "backoff", default_backoff)?;
+
+    let backoff_factor = match options.get("backoff_factor") {
+        Some(factor) => factor
+            .as_float()
+            .or_else(|_| factor.as_int().map(|factor| factor as f64))
+            .map_err(|_| {
+                invalid(format!(
+                    "Option backoff_factor must be a number, got {}",
+                    factor
+                ))
+            })?,
+        None => default.backoff_factor.unwrap_or(2.0),
+    };
+    if !backoff_factor.is_finite() || backoff_factor < 0.0 {
+        return Err(invalid(format!(
+            "backoff_factor must not be negative, got {}",
+            backoff_factor
+        )));
+    }
+
+    Ok(RetryPolicy {
+        retries,
+        retry_on,
+        backoff,
+        backoff_factor,
+    })
+}
+
+/// How long a request took, from sending its last attempt until the body was
+/// read, recorded for the latency percentiles of the report.
+pub struct Latency {
+    /// Method and URL without the query, e.g. `GET http://localhost/users`.
+    pub endpoint: String,
+    pub elapsed: Duration,
+}
+
+impl Latency {
+    fn new(method: &str, url: &str, elapsed: Duration) -> Self {
+        let url = url.split_once('?').map_or(url, |(url, _)| url);
+        Self {
+            endpoint: format!("{} {}", method, url),
+            elapsed,
+        }
+    }
+}
+
+/// Send the request built by `request`, retrying as `policy` allows, and
+/// return the response with the number of attempts and when the last one
+/// started.
+async fn send(
+    policy: &RetryPolicy,
+    request: impl Fn() -> reqwest::RequestBuilder,
+    error: &str,
+) -> Result<(reqwest::Response, i64, Instant), Box<EvalAltResult>> {
+    let mut delay = policy.backoff;
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        let result = request().send().await;
+        let retry = attempt <= policy.retries
+            && match &result {
+                Ok(response) => policy
+                    .retry_on
+                    .contains(&RetryOn::Status(response.status().as_u16())),
+                Err(e) => {
+                    e.is_connect()
+                        && policy
+                            .retry_on
+                            .contains(&RetryOn::Error(RetryError::Connect))
+                }
+            };
+        if !retry {
+            let response = result.map_err(|e| {
+                let msg = if attempt > 1 {
+                    format!("{} after {} attempts: {}", error, attempt, e)
+                } else {
+                    format!("{}: {}", error, e)
+                };
+                Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+            })?;
+            return Ok((response, attempt as i64, started));
+        }
+        log::debug!("Retrying request in {:?}, attempt {}", delay, attempt + 1);
+        tokio::time::sleep(delay).await;
+        delay = delay.mul_f64(policy.backoff_factor);
+        attempt += 1;
+    }
+}
+
+fn full_response(options: &Dynamic) -> Result<bool, Box<EvalAltResult>> {
+    Ok(options
+        .as_map_ref()?
+        .get("full_response")
+        .and_then(|full| full.as_bool().ok())
+        .unwrap_or(false))
+}
+
+/// The body of `response`, or with the `full_response` option a map with
+/// `status`, `headers`, `body`, `attempts` and `elapsed_ms`, and the time
+/// until the body was read.
+async fn response_value(
+    options: &Dynamic,
+    response: reqwest::Response,
+    attempts: i64,
+    started: Instant,
+) -> Result<(Dynamic, Duration), Box<EvalAltResult>> {
+    let full = full_response(options)?;
+    let status = response.status().as_u16() as i64;
+    let headers: Map = response
+        .headers()
+        .iter()
+        .map(|(key, value)| {
+            (
+                key.as_str().into(),
+                String::from_utf8_lossy(value.as_bytes())
+                    .into_owned()
+                    .into(),
+            )
+        })
+        .collect();
+    let body = response.text().await.map_err(|e| {
+        let msg = format!("Failed to parse response body: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let elapsed = started.elapsed();
+    if !full {
+        return Ok((body.into(), elapsed));
+    }
+    let mut map = Map::new();
+    map.insert("status".into(), status.into());
+    map.insert("headers".into(), headers.into());
+    map.insert("body".into(), body.into());
+    map.insert("attempts".into(), attempts.into());
+    map.insert("elapsed_ms".into(), (elapsed.as_secs_f64() * 1000.0).into());
+    Ok((map.into(), elapsed))
+}
+
+pub async fn http_get(
+    options: Dynamic,
+    retry: HttpRetry,
+) -> Result<(Dynamic, Latency), Box<EvalAltResult>> {
     let (url, headers) = get_url_and_headers(&options)?;
+    let policy = retry_policy(&options, &retry)?;
     let client = reqwest::Client::new();
-    client
-        .get(url)
-        .headers(headers)
-        .send()
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to get URL: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })?
-        .text()
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to parse response body: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })
+    let (response, attempts, started) = send(
+        &policy,
+        || client.get(&url).headers(headers.clone()),
+        "Failed to get URL",
+    )
+    .await?;
+    let (value, elapsed) = response_value(&options, response, attempts, started).await?;
+    Ok((value, Latency::new("GET", &url, elapsed)))
 }
 
-pub async fn http_post(options: Dynamic) -> Result<String, Box<EvalAltResult>> {
+pub async fn http_post(
+    options: Dynamic,
+    retry: HttpRetry,
+) -> Result<(Dynamic, Latency), Box<EvalAltResult>> {
     let (url, headers) = get_url_and_headers(&options)?;
+    let policy = retry_policy(&options, &retry)?;
     let body = options
         .as_map_ref()?
         .get("body")
         .map(|body| body.to_owned().to_string())
         .unwrap_or_default();
     let client = reqwest::Client::new();
-    client
-        .post(url)
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to post to URL: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })?
-        .text()
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to parse response body: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })
+    let (response, attempts, started) = send(
+        &policy,
+        || {
+            client
+                .post(&url)
+                .headers(headers.clone())
+                .body(body.clone())
+        },
+        "Failed to post to URL",
+    )
+    .await?;
+    let (value, elapsed) = response_value(&options, response, attempts, started).await?;
+    Ok((value, Latency::new("POST", &url, elapsed)))
+}
+
+pub async fn http_head(
+    options: Dynamic,
+    retry: HttpRetry,
+) -> Result<(Dynamic, Latency), Box<EvalAltResult>> {
+    let (url, headers) = get_url_and_headers(&options)?;
+    let policy = retry_policy(&options, &retry)?;
+    let client = reqwest::Client::new();
+    let (response, attempts, started) = send(
+        &policy,
+        || client.head(&url).headers(headers.clone()),
+        "Failed to head URL",
+    )
+    .await?;
+    let (value, elapsed) = if full_response(&options)? {
+        response_value(&options, response, attempts, started).await?
+    } else {
+        (Dynamic::UNIT, started.elapsed())
+    };
+    Ok((value, Latency::new("HEAD", &url, elapsed)))
+}
+
+/// An open server-sent events response.
+pub struct SseStream {
+    response: reqwest::Response,
+    parser: SseParser,
+}
+
+/// Received bytes not yet parsed and the fields of the pending event.
+#[derive(Default)]
+struct SseParser {
+    buffer: Vec<u8>,
+    event: Option<String>,
+    data: Vec<String>,
+    id: Option<String>,
+    retry: Option<i64>,
+}
+
+impl SseParser {
+    /// Parse the complete lines in the buffer until an event is dispatched.
+    fn parse_event(&mut self) -> Option<Map> {
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            // \r\n is a single line ending, but may be split across chunks
+            if self.buffer[end] == b'\r' && end + 1 == self.buffer.len() {
+                return None;
+            }
+            let line = String::from_utf8_lossy(&self.buffer[..end]).into_owned();
+            let skip = if self.buffer[end..].starts_with(b"\r\n") {
+                2
+            } else {
+                1
+            };
+            self.buffer.drain(..end + skip);
+            if let Some(event) = self.parse_line(&line) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    fn parse_line(&mut self, line: &str) -> Option<Map> {
+        if line.is_empty() {
+            let event = self.event.take();
+            let data = std::mem::take(&mut self.data);
+            let retry = self.retry.take();
+            if data.is_empty() {
+                return None;
+            }
+            let mut map = Map::new();
+            map.insert("event".into(), event.unwrap_or("message".into()).into());
+            map.insert("data".into(), data.join("\n").into());
+            map.insert(
+                "id".into(),
+                self.id.clone().map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+            );
+            map.insert(
+                "retry".into(),
+                retry.map(Dynamic::from).unwrap_or(Dynamic::UNIT),
+            );
+            return Some(map);
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => self.event = Some(value.to_string()),
+            "data" => self.data.push(value.to_string()),
+            "id" => self.id = Some(value.to_string()),
+            "retry" => self.retry = value.parse().ok(),
+            _ => {}
+        }
+        None
+    }
 }
 
-pub async fn http_head(options: Dynamic) -> Result<(), Box<EvalAltResult>> {
+pub async fn sse_connect(options: Dynamic) -> Result<SseStream, Box<EvalAltResult>> {
     let (url, headers) = get_url_and_headers(&options)?;
     let client = reqwest::Client::new();
-    client
-        .head(url)
+    let response = client
+        .get(url)
         .headers(headers)
+        .header(reqwest::header::ACCEPT, "text/event-stream")
         .send()
         .await
+        .and_then(|response| response.error_for_status())
         .map_err(|e| {
-            let msg = format!("Failed to head URL: {}", e);
+            let msg = format!("Failed to connect to event stream: {}", e);
             Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })
-        .map(|_| ())
+        })?;
+    Ok(SseStream {
+        response,
+        parser: SseParser::default(),
+    })
+}
+
+/// The next event of `stream` as a map with `event`, `data`, `id` and
+/// `retry`, or `()` if none arrives within `timeout`.
+pub async fn sse_next(
+    stream: &mut SseStream,
+    timeout: std::time::Duration,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let next = async {
+        loop {
+            if let Some(event) = stream.parser.parse_event() {
+                return Ok(event);
+            }
+            match stream.response.chunk().await {
+                Ok(Some(chunk)) => stream.parser.buffer.extend_from_slice(&chunk),
+                Ok(None) => {
+                    return Err(Box::new(EvalAltResult::ErrorRuntime(
+                        "Event stream closed".into(),
+                        Position::NONE,
+                    )))
+                }
+                Err(e) => {
+                    let msg = format!("Failed to read event stream: {}", e);
+                    return Err(Box::new(EvalAltResult::ErrorRuntime(
+                        msg.into(),
+                        Position::NONE,
+                    )));
+                }
+            }
+        }
+    };
+    match tokio::time::timeout(timeout, next).await {
+        Ok(event) => event.map(Dynamic::from_map),
+        Err(_) => Ok(Dynamic::UNIT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy() {
+        let default = HttpRetry {
+            retries: Some(2),
+            backoff: Some("1s".to_string()),
+            ..Default::default()
+        };
+        let mut options = Map::new();
+        options.insert(
+            "retry_on".into(),
+            vec![Dynamic::from(502_i64), "connect".into()].into(),
+        );
+        options.insert("backoff".into(), Dynamic::from(10_i64));
+        let policy = retry_policy(&options.clone().into(), &default).unwrap();
+        assert_eq!(policy.retries, 2);
+        assert_eq!(policy.backoff, Duration::from_millis(10));
+        assert_eq!(
+            policy.retry_on,
+            vec![RetryOn::Status(502), RetryOn::Error(RetryError::Connect)]
+        );
+        options.insert("retry_on".into(), vec![Dynamic::from("sometimes")].into());
+        assert!(retry_policy(&options.into(), &default).is_err());
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser {
+            buffer: b": comment\r\nevent: update\r\ndata: a\r\ndata:b\r\nid: 7\r\n\r".to_vec(),
+            ..Default::default()
+        };
+        assert!(parser.parse_event().is_none());
+        parser.buffer.extend_from_slice(b"\ndata: c\n\n");
+        let event = parser.parse_event().unwrap();
+        assert_eq!(event["event"].to_string(), "update");
+        assert_eq!(event["data"].to_string(), "a\nb");
+        assert_eq!(event["id"].to_string(), "7");
+        let event = parser.parse_event().unwrap();
+        assert_eq!(event["event"].to_string(), "message");
+        assert_eq!(event["data"].to_string(), "c");
+        assert_eq!(event["id"].to_string(), "7");
+        assert!(parser.parse_event().is_none());
+    }
 }
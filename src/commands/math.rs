@@ -1,4 +1,8 @@
-use rand::Rng;
+use rand::{seq::SliceRandom, Rng};
+use rand_distr::{Distribution, Exp, Normal};
+use rhai::{Array, Dynamic, EvalAltResult, FLOAT};
+
+use crate::rhai::invalid;
 
 pub fn random_string(length: usize) -> String {
     let mut rng = rand::thread_rng();
@@ -8,7 +12,82 @@ pub fn random_string(length: usize) -> String {
         .collect()
 }
 
-pub fn random_int(min: i64, max: i64) -> i64 {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(min..max)
+/// Random integer between `min` and `max`, both included.
+pub fn random_int(min: i64, max: i64) -> Result<i64, Box<EvalAltResult>> {
+    if min > max {
+        return Err(invalid(format!(
+            "random_int: min {} is greater than max {}",
+            min, max
+        )));
+    }
+    Ok(rand::thread_rng().gen_range(min..=max))
+}
+
+/// Random float between `min` (included) and `max` (excluded).
+pub fn random_float(min: FLOAT, max: FLOAT) -> Result<FLOAT, Box<EvalAltResult>> {
+    if !min.is_finite() || !max.is_finite() || min > max {
+        return Err(invalid(format!(
+            "random_float: invalid range {} to {}",
+            min, max
+        )));
+    }
+    if min == max {
+        return Ok(min);
+    }
+    Ok(rand::thread_rng().gen_range(min..max))
+}
+
+pub fn random_choice(array: &Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    array
+        .choose(&mut rand::thread_rng())
+        .cloned()
+        .ok_or_else(|| invalid("random_choice: the array is empty".to_string()))
+}
+
+/// A copy of `array` in random order.
+pub fn shuffle(mut array: Array) -> Array {
+    array.shuffle(&mut rand::thread_rng());
+    array
+}
+
+pub fn random_normal(mean: FLOAT, std_dev: FLOAT) -> Result<FLOAT, Box<EvalAltResult>> {
+    if std_dev < 0.0 {
+        return Err(invalid(format!(
+            "random_normal: std_dev {} must not be negative",
+            std_dev
+        )));
+    }
+    let normal =
+        Normal::new(mean, std_dev).map_err(|e| invalid(format!("random_normal: {}", e)))?;
+    Ok(normal.sample(&mut rand::thread_rng()))
+}
+
+/// Random value of the exponential distribution with the given `rate`, e.g.
+/// the time between requests arriving at `rate` per second.
+pub fn random_exponential(rate: FLOAT) -> Result<FLOAT, Box<EvalAltResult>> {
+    if rate <= 0.0 {
+        return Err(invalid(format!(
+            "random_exponential: rate {} must be positive",
+            rate
+        )));
+    }
+    let exp = Exp::new(rate).map_err(|e| invalid(format!("random_exponential: {}", e)))?;
+    Ok(exp.sample(&mut rand::thread_rng()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_ranges() {
+        assert_eq!(random_int(3, 3).unwrap(), 3);
+        assert!((0..100).all(|_| (1..=2).contains(&random_int(1, 2).unwrap())));
+        assert!(random_int(2, 1).is_err());
+        assert_eq!(random_float(1.5, 1.5).unwrap(), 1.5);
+        assert!(random_float(2.0, 1.0).is_err());
+        assert!(random_choice(&Array::new()).is_err());
+        assert!(random_normal(0.0, -1.0).is_err());
+        assert!(random_exponential(0.0).is_err());
+    }
 }
@@ -1,20 +1,28 @@
 use std::sync::Arc;
 
 use parking_lot::Mutex;
-use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, NativeCallContext, Position};
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FnPtr, Map, NativeCallContext, Position};
 
 use crate::environment::Environment;
 use crate::state::SharedState;
 
 mod assertions;
-mod structure_helpers;
-mod system;
-mod kv;
+mod crypto;
 mod encoding;
 mod fs;
 mod http;
+mod kv;
 mod math;
+mod proto;
 mod spawn;
+mod stats;
+mod structure_helpers;
+mod system;
+mod units;
+
+pub use encoding::load_data;
+pub use http::{Latency, SseStream};
+pub(crate) use stats::percentile;
 
 pub fn register_commands<E: Environment + Clone + 'static>(
     engine: &mut Engine,
@@ -25,9 +33,13 @@ pub fn register_commands<E: Environment + Clone + 'static>(
     register_system(engine, state.clone());
     register_kv(engine, state.clone());
     register_encoding(engine);
+    register_crypto(engine);
     register_fs(engine, state.clone());
-    register_http(engine);
+    register_http(engine, state.clone());
+    register_proto(engine, state.clone());
     register_math(engine);
+    register_stats(engine);
+    register_units(engine);
     register_spawn(engine, state.clone());
 }
 
@@ -35,39 +47,84 @@ fn register_structure_helpers<E: Environment + Clone + 'static>(
     engine: &mut Engine,
     state: Arc<Mutex<SharedState<E>>>,
 ) {
-    let state_clone = state.clone();
-    engine.register_fn(
-        "describe",
-        move |context: NativeCallContext, msg: &str, cb: FnPtr| -> Result<(), Box<EvalAltResult>> {
-            structure_helpers::describe::<E>(state_clone.clone(), context, msg, cb, "Testing")
-        },
-    );
+    // describe and its alias task, with and without an options map
+    for (name, prefix) in [("describe", "Testing"), ("task", "Task:")] {
+        let state_clone = state.clone();
+        engine.register_fn(
+            name,
+            move |context: NativeCallContext,
+                  msg: &str,
+                  cb: FnPtr|
+                  -> Result<(), Box<EvalAltResult>> {
+                structure_helpers::describe::<E>(
+                    state_clone.clone(),
+                    context,
+                    msg,
+                    vec![],
+                    cb,
+                    prefix,
+                )
+            },
+        );
 
-    // alias describe as task
-    let state_clone = state.clone();
-    engine.register_fn(
-        "task",
-        move |context: NativeCallContext, msg: &str, cb: FnPtr| -> Result<(), Box<EvalAltResult>> {
-            structure_helpers::describe::<E>(state_clone.clone(), context, msg, cb, "Task:")
-        },
-    );
+        let state_clone = state.clone();
+        engine.register_fn(
+            name,
+            move |context: NativeCallContext,
+                  msg: &str,
+                  options: Map,
+                  cb: FnPtr|
+                  -> Result<(), Box<EvalAltResult>> {
+                let tags = structure_helpers::parse_tags(&options)?;
+                structure_helpers::describe::<E>(
+                    state_clone.clone(),
+                    context,
+                    msg,
+                    tags,
+                    cb,
+                    prefix,
+                )
+            },
+        );
+    }
 
     let state_clone = state.clone();
     engine.register_fn(
-        "it",
-        move |context: NativeCallContext, msg: &str, cb: FnPtr| -> Result<(), Box<EvalAltResult>> {
-            structure_helpers::it::<E>(state_clone.clone(), context, msg, cb, "It")
+        "section",
+        move |context: NativeCallContext,
+              name: &str,
+              cb: FnPtr|
+              -> Result<Dynamic, Box<EvalAltResult>> {
+            structure_helpers::section::<E>(state_clone.clone(), context, name, cb)
         },
     );
 
-    // alias it as step
-    let state_clone = state.clone();
-    engine.register_fn(
-        "step",
-        move |context: NativeCallContext, msg: &str, cb: FnPtr| -> Result<(), Box<EvalAltResult>> {
-            structure_helpers::it::<E>(state_clone.clone(), context, msg, cb, "Step:")
-        },
-    );
+    // it and its alias step, with and without an options map
+    for (name, prefix) in [("it", "It"), ("step", "Step:")] {
+        let state_clone = state.clone();
+        engine.register_fn(
+            name,
+            move |context: NativeCallContext,
+                  msg: &str,
+                  cb: FnPtr|
+                  -> Result<(), Box<EvalAltResult>> {
+                structure_helpers::it::<E>(state_clone.clone(), context, msg, vec![], cb, prefix)
+            },
+        );
+
+        let state_clone = state.clone();
+        engine.register_fn(
+            name,
+            move |context: NativeCallContext,
+                  msg: &str,
+                  options: Map,
+                  cb: FnPtr|
+                  -> Result<(), Box<EvalAltResult>> {
+                let tags = structure_helpers::parse_tags(&options)?;
+                structure_helpers::it::<E>(state_clone.clone(), context, msg, tags, cb, prefix)
+            },
+        );
+    }
 }
 
 fn register_assertions<E: Environment + Clone + 'static>(
@@ -96,8 +153,200 @@ fn register_assertions<E: Environment + Clone + 'static>(
         },
     );
 
+    let state_clone = state.clone();
     engine.register_fn("diff", move |expected: &str, actual: &str| -> String {
-        assertions::diff(expected, actual)
+        let style = assertions::DiffStyle::new(&state_clone.lock());
+        assertions::diff(expected, actual, style)
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "diff",
+        move |expected: Dynamic, actual: Dynamic| -> String {
+            let style = assertions::DiffStyle::new(&state_clone.lock());
+            assertions::diff_values(&expected, &actual, style)
+        },
+    );
+
+    engine.register_fn(
+        "catch_error",
+        |context: NativeCallContext, cb: FnPtr| -> Result<Dynamic, Box<EvalAltResult>> {
+            assertions::catch_error(context, cb)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_throws",
+        move |context: NativeCallContext,
+              cb: FnPtr,
+              pattern: &str|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_throws::<E>(state_clone.clone(), context, cb, pattern)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_eq_ignoring",
+        move |context: NativeCallContext,
+              expected: Dynamic,
+              actual: Dynamic,
+              ignore: Array|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_eq_ignoring::<E>(
+                state_clone.clone(),
+                context,
+                expected,
+                actual,
+                ignore,
+                None,
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_eq_ignoring",
+        move |context: NativeCallContext,
+              expected: Dynamic,
+              actual: Dynamic,
+              ignore: Array,
+              msg: &str|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_eq_ignoring::<E>(
+                state_clone.clone(),
+                context,
+                expected,
+                actual,
+                ignore,
+                Some(msg),
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_exit_code",
+        move |context: NativeCallContext,
+              job: Map,
+              expected: i64|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_exit_code::<E>(state_clone.clone(), context, job, expected, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_exit_code",
+        move |context: NativeCallContext,
+              job: Map,
+              expected: i64,
+              msg: &str|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_exit_code::<E>(
+                state_clone.clone(),
+                context,
+                job,
+                expected,
+                Some(msg),
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_latency_under",
+        move |context: NativeCallContext,
+              response: Map,
+              limit: Dynamic|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_latency_under::<E>(
+                state_clone.clone(),
+                context,
+                response,
+                limit,
+                None,
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_latency_under",
+        move |context: NativeCallContext,
+              response: Map,
+              limit: Dynamic,
+              msg: &str|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_latency_under::<E>(
+                state_clone.clone(),
+                context,
+                response,
+                limit,
+                Some(msg),
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_close",
+        move |context: NativeCallContext,
+              expected: Dynamic,
+              actual: Dynamic,
+              tolerance: Map|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_close::<E>(
+                state_clone.clone(),
+                context,
+                expected,
+                actual,
+                tolerance,
+                None,
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "assert_close",
+        move |context: NativeCallContext,
+              expected: Dynamic,
+              actual: Dynamic,
+              tolerance: Map,
+              msg: &str|
+              -> Result<(), Box<EvalAltResult>> {
+            assertions::assert_close::<E>(
+                state_clone.clone(),
+                context,
+                expected,
+                actual,
+                tolerance,
+                Some(msg),
+            )
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "eventually",
+        move |context: NativeCallContext,
+              options: Map,
+              cb: FnPtr|
+              -> Result<Dynamic, Box<EvalAltResult>> {
+            assertions::eventually::<E>(state_clone.clone(), context, options, cb)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn("failed_assertions", move || -> Array {
+        assertions::failed_assertions::<E>(state_clone.clone())
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn("assertion_count", move || -> i64 {
+        assertions::assertion_count::<E>(state_clone.clone())
     });
 }
 
@@ -113,21 +362,28 @@ fn register_system<E: Environment + Clone + 'static>(
         },
     );
 
+    let state_clone = state.clone();
     engine.register_fn(
         "exec",
-        move |command: &str| -> Result<String, Box<EvalAltResult>> { system::exec(command) },
+        move |command: &str| -> Result<String, Box<EvalAltResult>> {
+            system::exec_on::<E>(state_clone.clone(), command, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "exec",
+        move |command: &str, options: Map| -> Result<String, Box<EvalAltResult>> {
+            let timeout = system::timeout_option(&options)?;
+            system::exec_on::<E>(state_clone.clone(), command, timeout)
+        },
     );
 
     let state_clone = state.clone();
     engine.register_fn(
         "start_component",
         move |component: &str| -> Result<(), Box<EvalAltResult>> {
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(system::start_component::<E>(
-                    state_clone.clone(),
-                    component,
-                ))
-            })
+            system::start_component::<E>(state_clone.clone(), component)
         },
     );
 
@@ -135,12 +391,198 @@ fn register_system<E: Environment + Clone + 'static>(
     engine.register_fn(
         "stop_component",
         move |component: &str| -> Result<(), Box<EvalAltResult>> {
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(system::stop_component::<E>(
-                    state_clone.clone(),
-                    component,
-                ))
-            })
+            system::stop_component::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "restart_component",
+        move |context: NativeCallContext, component: &str| -> Result<(), Box<EvalAltResult>> {
+            system::restart_component::<E>(context, state_clone.clone(), component, Map::new())
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "restart_component",
+        move |context: NativeCallContext,
+              component: &str,
+              condition: FnPtr|
+              -> Result<(), Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("condition".into(), Dynamic::from(condition));
+            system::restart_component::<E>(context, state_clone.clone(), component, options)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "restart_component",
+        move |context: NativeCallContext,
+              component: &str,
+              options: Map|
+              -> Result<(), Box<EvalAltResult>> {
+            system::restart_component::<E>(context, state_clone.clone(), component, options)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "create_component",
+        move |component: Map| -> Result<(), Box<EvalAltResult>> {
+            system::create_component::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "destroy_component",
+        move |component: &str| -> Result<(), Box<EvalAltResult>> {
+            system::destroy_component::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "run_job",
+        move |component: &str| -> Result<Map, Box<EvalAltResult>> {
+            system::run_job::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn("pause_component", move |component: &str| {
+        system::pause_component::<E>(state_clone.clone(), component)
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn("resume_component", move |component: &str| {
+        system::resume_component::<E>(state_clone.clone(), component)
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "restart_count",
+        move |component: &str| -> Result<i64, Box<EvalAltResult>> {
+            system::restart_count::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "component_status",
+        move |component: &str| -> Result<Map, Box<EvalAltResult>> {
+            system::component_status::<E>(state_clone.clone(), component)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "component_wait_exit",
+        move |component: &str| -> Result<Map, Box<EvalAltResult>> {
+            let timeout = std::time::Duration::from_secs(30);
+            system::component_wait_exit::<E>(state_clone.clone(), component, timeout)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "component_wait_exit",
+        move |component: &str, timeout: Dynamic| -> Result<Map, Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("timeout".into(), timeout);
+            let timeout = system::duration_option(&options, "timeout", std::time::Duration::ZERO)?;
+            system::component_wait_exit::<E>(state_clone.clone(), component, timeout)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "container_exec",
+        move |component: &str, command: Dynamic| -> Result<String, Box<EvalAltResult>> {
+            system::container_exec_stdout::<E>(state_clone.clone(), component, command, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "container_exec",
+        move |component: &str,
+              command: Dynamic,
+              options: Map|
+              -> Result<String, Box<EvalAltResult>> {
+            let timeout = system::timeout_option(&options)?;
+            system::container_exec_stdout::<E>(state_clone.clone(), component, command, timeout)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "container_exec_result",
+        move |component: &str, command: Dynamic| -> Result<Map, Box<EvalAltResult>> {
+            system::container_exec::<E>(state_clone.clone(), component, command, None)
+                .map(|result| system::job_map(&result))
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "container_exec_result",
+        move |component: &str, command: Dynamic, options: Map| -> Result<Map, Box<EvalAltResult>> {
+            let timeout = system::timeout_option(&options)?;
+            system::container_exec::<E>(state_clone.clone(), component, command, timeout)
+                .map(|result| system::job_map(&result))
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn("container_cp", move |src: &str, dst: &str| {
+        system::container_cp::<E>(state_clone.clone(), src, dst)
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "port_forward",
+        move |component: &str, port: i64| -> Result<Map, Box<EvalAltResult>> {
+            system::port_forward::<E>(state_clone.clone(), component, port)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn("port_forward_close", move |handle: i64| {
+        state_clone.lock().port_forwards.remove(&handle);
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "component_logs",
+        move |component: &str| -> Result<String, Box<EvalAltResult>> {
+            system::component_logs::<E>(state_clone.clone(), component, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "component_logs",
+        move |component: &str, since: Dynamic| -> Result<String, Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("since".into(), since);
+            let since = system::duration_option(&options, "since", Default::default())?;
+            system::component_logs::<E>(state_clone.clone(), component, Some(since))
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn("job_result", move |component: &str| -> Dynamic {
+        system::job_result::<E>(state_clone.clone(), component)
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "reseed",
+        move |context: NativeCallContext, component: &str| -> Result<(), Box<EvalAltResult>> {
+            system::reseed::<E>(context, state_clone.clone(), component)
         },
     );
 
@@ -159,37 +601,75 @@ fn register_system<E: Environment + Clone + 'static>(
         |duration: &str| -> Result<(), Box<EvalAltResult>> { system::sleep_str(duration) },
     );
 
+    let state_clone = state.clone();
     engine.register_fn(
         "wait_until",
-        |context: NativeCallContext,
-         condition: FnPtr,
-         timeout: i64|
-         -> Result<(), Box<EvalAltResult>> {
-            system::wait_until(context, condition, timeout)
+        move |context: NativeCallContext,
+              condition: FnPtr,
+              timeout: i64|
+              -> Result<Dynamic, Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("timeout".into(), timeout.into());
+            system::wait_until(context, state_clone.clone(), condition, options)
         },
     );
 
+    let state_clone = state.clone();
     engine.register_fn(
         "wait_until",
-        |context: NativeCallContext,
-         condition: FnPtr,
-         timeout: &str|
-         -> Result<(), Box<EvalAltResult>> {
-            let duration = humantime::parse_duration(timeout).map_err(|e| {
-                let msg = format!("Invalid duration: {}", e);
-                Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-            })?;
-            system::wait_until(context, condition, duration.as_millis() as i64)
+        move |context: NativeCallContext,
+              condition: FnPtr,
+              timeout: &str|
+              -> Result<Dynamic, Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("timeout".into(), timeout.into());
+            system::wait_until(context, state_clone.clone(), condition, options)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "wait_until",
+        move |context: NativeCallContext,
+              condition: FnPtr,
+              options: Map|
+              -> Result<Dynamic, Box<EvalAltResult>> {
+            system::wait_until(context, state_clone.clone(), condition, options)
         },
     );
 
+    let state_clone = state.clone();
+    engine.register_fn("run_id", move || -> String {
+        system::run_id::<E>(state_clone.clone())
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn("target", move || -> Result<Map, Box<EvalAltResult>> {
+        system::target::<E>(state_clone.clone(), None)
+    });
+
     let state_clone = state.clone();
     engine.register_fn(
-        "data_dir",
-        move || -> Result<String, Box<EvalAltResult>> {
-            system::data_dir::<E>(state_clone.clone())
+        "target",
+        move |name: &str| -> Result<Map, Box<EvalAltResult>> {
+            system::target::<E>(state_clone.clone(), Some(name))
         },
     );
+
+    let state_clone = state.clone();
+    engine.register_fn("target_name", move || -> Dynamic {
+        system::target_name::<E>(state_clone.clone())
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn("test_stats", move || -> Map {
+        system::test_stats::<E>(state_clone.clone())
+    });
+
+    let state_clone = state.clone();
+    engine.register_fn("data_dir", move || -> Result<String, Box<EvalAltResult>> {
+        system::data_dir::<E>(state_clone.clone())
+    });
 }
 
 fn register_kv<E: Environment + Clone + 'static>(
@@ -250,6 +730,54 @@ fn register_encoding(engine: &mut Engine) {
         "to_toml",
         |value: Dynamic| -> Result<String, Box<EvalAltResult>> { encoding::to_toml(&value) },
     );
+
+    engine.register_fn(
+        "parse_cbor",
+        |cbor: Blob| -> Result<Dynamic, Box<EvalAltResult>> { encoding::parse_cbor(&cbor) },
+    );
+
+    engine.register_fn(
+        "to_cbor",
+        |value: Dynamic| -> Result<Blob, Box<EvalAltResult>> { encoding::to_cbor(&value) },
+    );
+
+    engine.register_fn(
+        "parse_msgpack",
+        |msgpack: Blob| -> Result<Dynamic, Box<EvalAltResult>> {
+            encoding::parse_msgpack(&msgpack)
+        },
+    );
+
+    engine.register_fn(
+        "to_msgpack",
+        |value: Dynamic| -> Result<Blob, Box<EvalAltResult>> { encoding::to_msgpack(&value) },
+    );
+
+    engine.register_fn(
+        "load_data",
+        |path: &str| -> Result<Dynamic, Box<EvalAltResult>> { encoding::load_data(path) },
+    );
+
+    engine.register_fn(
+        "parse_dotenv",
+        |path: &str| -> Result<Map, Box<EvalAltResult>> { encoding::parse_dotenv(path) },
+    );
+}
+
+fn register_crypto(engine: &mut Engine) {
+    engine.register_fn("aes_keygen", || crypto::aes_keygen(256));
+    engine.register_fn("aes_keygen", crypto::aes_keygen);
+    engine.register_fn("encrypt_aes_gcm", crypto::encrypt_aes_gcm);
+    engine.register_fn("decrypt_aes_gcm", crypto::decrypt_aes_gcm);
+    engine.register_fn("ed25519_keygen", crypto::ed25519_keygen);
+    engine.register_fn("ed25519_sign", crypto::ed25519_sign);
+    engine.register_fn("ed25519_verify", crypto::ed25519_verify);
+    engine.register_fn("rsa_keygen", || crypto::rsa_keygen(2048));
+    engine.register_fn("rsa_keygen", crypto::rsa_keygen);
+    engine.register_fn("rsa_encrypt", crypto::rsa_encrypt);
+    engine.register_fn("rsa_decrypt", crypto::rsa_decrypt);
+    engine.register_fn("rsa_sign", crypto::rsa_sign);
+    engine.register_fn("rsa_verify", crypto::rsa_verify);
 }
 
 fn register_fs<E: Environment + Clone + 'static>(
@@ -260,97 +788,271 @@ fn register_fs<E: Environment + Clone + 'static>(
     engine.register_fn(
         "temp_dir",
         move |prefix: &str| -> Result<String, Box<EvalAltResult>> {
-            fs::temp_dir(state_clone.clone(), prefix)
+            fs::temp_dir(state_clone.clone(), prefix, false)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "temp_dir_global",
+        move |prefix: &str| -> Result<String, Box<EvalAltResult>> {
+            fs::temp_dir(state_clone.clone(), prefix, true)
         },
     );
 
+    // The file commands that run on the remote host with `remote.files`
+    let remote_files = {
+        let state = state.clone();
+        move || state.lock().remote.clone().filter(|remote| remote.files)
+    };
+
+    let remote = remote_files.clone();
     engine.register_fn(
         "write_file",
-        |path: &str, content: &str| -> Result<(), Box<EvalAltResult>> {
-            fs::write_file(path, content)
+        move |path: &str, content: &str| -> Result<(), Box<EvalAltResult>> {
+            match remote() {
+                Some(remote) => fs::remote_write_file(&remote, path, content),
+                None => fs::write_file(path, content),
+            }
         },
     );
 
+    let remote = remote_files.clone();
     engine.register_fn(
         "read_file",
-        |path: &str| -> Result<String, Box<EvalAltResult>> { fs::read_file(path) },
+        move |path: &str| -> Result<String, Box<EvalAltResult>> {
+            match remote() {
+                Some(remote) => fs::remote_read_file(&remote, path),
+                None => fs::read_file(path),
+            }
+        },
     );
 
-    engine.register_fn("mkdir", |path: &str| -> Result<(), Box<EvalAltResult>> {
-        fs::mkdir(path)
-    });
+    let remote = remote_files.clone();
+    engine.register_fn(
+        "mkdir",
+        move |path: &str| -> Result<(), Box<EvalAltResult>> {
+            match remote() {
+                Some(remote) => fs::remote_run(&remote, "mkdir -p", path).map(|_| ()),
+                None => fs::mkdir(path),
+            }
+        },
+    );
 
-    engine.register_fn("remove", |path: &str| -> Result<(), Box<EvalAltResult>> {
-        fs::remove(path)
-    });
+    let remote = remote_files.clone();
+    engine.register_fn(
+        "remove",
+        move |path: &str| -> Result<(), Box<EvalAltResult>> {
+            match remote() {
+                Some(remote) => fs::remote_run(&remote, "rm -rf", path).map(|_| ()),
+                None => fs::remove(path),
+            }
+        },
+    );
 
     engine.register_fn("ls", |path: &str| -> Result<Array, Box<EvalAltResult>> {
         fs::ls(path)
     });
 
-    engine.register_fn("file_exists", |path: &str| -> bool {
-        fs::file_exists(path)
-    });
+    let remote = remote_files;
+    engine.register_fn(
+        "file_exists",
+        move |path: &str| -> Result<bool, Box<EvalAltResult>> {
+            match remote() {
+                Some(remote) => fs::remote_file_exists(&remote, path),
+                None => Ok(fs::file_exists(path)),
+            }
+        },
+    );
 
-    engine.register_fn("stat", |path: &str| -> Result<Dynamic, Box<EvalAltResult>> {
-        fs::stat(path)
-    });
+    engine.register_fn(
+        "stat",
+        |path: &str| -> Result<Dynamic, Box<EvalAltResult>> { fs::stat(path) },
+    );
 
     engine.register_fn(
         "copy",
-        |src: &str, dst: &str| -> Result<(), Box<EvalAltResult>> {
-            fs::copy(src, dst)
-        },
+        |src: &str, dst: &str| -> Result<(), Box<EvalAltResult>> { fs::copy(src, dst) },
     );
 
     engine.register_fn(
         "rename",
-        |src: &str, dst: &str| -> Result<(), Box<EvalAltResult>> {
-            fs::rename(src, dst)
+        |src: &str, dst: &str| -> Result<(), Box<EvalAltResult>> { fs::rename(src, dst) },
+    );
+
+    engine.register_fn("is_dir", |path: &str| -> bool { fs::is_dir(path) });
+
+    engine.register_fn("is_file", |path: &str| -> bool { fs::is_file(path) });
+
+    engine.register_fn(
+        "absolute_path",
+        |path: &str| -> Result<String, Box<EvalAltResult>> { fs::absolute_path(path) },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "asset_path",
+        move |path: &str| -> Result<String, Box<EvalAltResult>> {
+            fs::asset_path(state_clone.clone(), path)
         },
     );
 
-    engine.register_fn("is_dir", |path: &str| -> bool {
-        fs::is_dir(path)
-    });
+    let state_clone = state.clone();
+    engine.register_fn(
+        "read_asset",
+        move |path: &str| -> Result<String, Box<EvalAltResult>> {
+            fs::read_asset(state_clone.clone(), path)
+        },
+    );
 
-    engine.register_fn("is_file", |path: &str| -> bool {
-        fs::is_file(path)
+    engine.register_fn("workdir", || -> Result<String, Box<EvalAltResult>> {
+        fs::workdir()
     });
 
+    let state_clone = state.clone();
     engine.register_fn(
-        "absolute_path",
-        |path: &str| -> Result<String, Box<EvalAltResult>> {
-            fs::absolute_path(path)
+        "artifact_path",
+        move |name: &str| -> Result<String, Box<EvalAltResult>> {
+            fs::artifact_path(state_clone.clone(), name)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "save_artifact",
+        move |name: &str, content_or_path: &str| -> Result<String, Box<EvalAltResult>> {
+            fs::save_artifact(state_clone.clone(), name, content_or_path)
         },
     );
 }
 
-fn register_http(engine: &mut Engine) {
+fn register_http<E: Environment + Clone + 'static>(
+    engine: &mut Engine,
+    state: Arc<Mutex<SharedState<E>>>,
+) {
+    let state_clone = state.clone();
     engine.register_fn(
         "http_get",
-        |options: Dynamic| -> Result<String, Box<EvalAltResult>> {
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(http::http_get(options))
-            })
+        move |options: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+            let retry = state_clone.lock().http_retry.clone();
+            let (value, latency) = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(http::http_get(options, retry))
+            })?;
+            state_clone.lock().record_latency(latency);
+            Ok(value)
         },
     );
 
+    let state_clone = state.clone();
     engine.register_fn(
         "http_post",
-        |options: Dynamic| -> Result<String, Box<EvalAltResult>> {
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(http::http_post(options))
-            })
+        move |options: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+            let retry = state_clone.lock().http_retry.clone();
+            let (value, latency) = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(http::http_post(options, retry))
+            })?;
+            state_clone.lock().record_latency(latency);
+            Ok(value)
         },
     );
 
+    let state_clone = state.clone();
     engine.register_fn(
         "http_head",
-        |options: Dynamic| -> Result<(), Box<EvalAltResult>> {
-            tokio::task::block_in_place(|| {
-                tokio::runtime::Handle::current().block_on(http::http_head(options))
+        move |options: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+            let retry = state_clone.lock().http_retry.clone();
+            let (value, latency) = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(http::http_head(options, retry))
+            })?;
+            state_clone.lock().record_latency(latency);
+            Ok(value)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "sse_connect",
+        move |options: Dynamic| -> Result<i64, Box<EvalAltResult>> {
+            let stream = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(http::sse_connect(options))
+            })?;
+            let mut state = state_clone.lock();
+            let handle = state.next_sse_handle;
+            state.next_sse_handle += 1;
+            state.sse_streams.insert(handle, stream);
+            Ok(handle)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "sse_next",
+        move |handle: i64, timeout: Dynamic| -> Result<Dynamic, Box<EvalAltResult>> {
+            let mut options = Map::new();
+            options.insert("timeout".into(), timeout);
+            let timeout = system::duration_option(&options, "timeout", std::time::Duration::ZERO)?;
+            // The stream is taken out of the state while waiting, so that
+            // other functions don't block on the state lock.
+            let mut stream = state_clone
+                .lock()
+                .sse_streams
+                .remove(&handle)
+                .ok_or_else(|| {
+                    let msg = format!("No such event stream: {}", handle);
+                    Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+                })?;
+            let event = tokio::task::block_in_place(|| {
+                tokio::runtime::Handle::current().block_on(http::sse_next(&mut stream, timeout))
+            });
+            state_clone.lock().sse_streams.insert(handle, stream);
+            event
+        },
+    );
+
+    engine.register_fn("sse_close", move |handle: i64| {
+        state.lock().sse_streams.remove(&handle);
+    });
+}
+
+fn register_proto<E: Environment + Clone + 'static>(
+    engine: &mut Engine,
+    state: Arc<Mutex<SharedState<E>>>,
+) {
+    let state_clone = state.clone();
+    engine.register_fn(
+        "proto_load",
+        move |path: &str| -> Result<i64, Box<EvalAltResult>> {
+            let pool = proto::proto_load(path)?;
+            let mut state = state_clone.lock();
+            let handle = state.next_proto_handle;
+            state.next_proto_handle += 1;
+            state.proto_pools.insert(handle, pool);
+            Ok(handle)
+        },
+    );
+
+    let pool = move |handle: i64| {
+        state
+            .lock()
+            .proto_pools
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| {
+                let msg = format!("No such protobuf descriptor: {}", handle);
+                Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
             })
+    };
+    let pool_clone = pool.clone();
+    engine.register_fn(
+        "proto_encode",
+        move |handle: i64, name: &str, value: Dynamic| -> Result<Blob, Box<EvalAltResult>> {
+            proto::proto_encode(&pool_clone(handle)?, name, &value)
+        },
+    );
+    engine.register_fn(
+        "proto_decode",
+        move |handle: i64, name: &str, bytes: Blob| -> Result<Dynamic, Box<EvalAltResult>> {
+            proto::proto_decode(&pool(handle)?, name, &bytes)
         },
     );
 }
@@ -360,9 +1062,34 @@ fn register_math(engine: &mut Engine) {
         math::random_string(length as usize)
     });
 
-    engine.register_fn("random_int", |min: i64, max: i64| -> i64 {
-        math::random_int(min, max)
+    engine.register_fn("random_int", math::random_int);
+    engine.register_fn("random_float", || math::random_float(0.0, 1.0));
+    engine.register_fn("random_float", math::random_float);
+    engine.register_fn("random_choice", |array: Array| math::random_choice(&array));
+    engine.register_fn("shuffle", math::shuffle);
+    engine.register_fn("random_normal", math::random_normal);
+    engine.register_fn("random_exponential", math::random_exponential);
+}
+
+fn register_stats(engine: &mut Engine) {
+    engine.register_fn("mean", |array: Array| stats::mean(&array));
+    engine.register_fn("median", |array: Array| stats::median(&array));
+    engine.register_fn("percentile", |array: Array, p: f64| {
+        stats::percentile(&array, p)
+    });
+    engine.register_fn("percentile", |array: Array, p: i64| {
+        stats::percentile(&array, p as f64)
     });
+    engine.register_fn("stddev", |array: Array| stats::stddev(&array));
+    engine.register_fn("min", |array: Array| stats::min(&array));
+    engine.register_fn("max", |array: Array| stats::max(&array));
+}
+
+fn register_units(engine: &mut Engine) {
+    engine.register_fn("parse_duration", units::parse_duration);
+    engine.register_fn("format_duration", units::format_duration);
+    engine.register_fn("parse_size", units::parse_size);
+    engine.register_fn("format_size", units::format_size);
 }
 
 fn register_spawn<E: Environment + Clone + 'static>(
@@ -381,13 +1108,34 @@ fn register_spawn<E: Environment + Clone + 'static>(
     engine.register_fn(
         "wait_for_tasks",
         move |ids: Array| -> Result<Array, Box<EvalAltResult>> {
-            spawn::wait_for_tasks(
-                state_clone.clone(),
-                ids.iter()
-                    .map(|id| id.as_int().unwrap())
-                    .collect::<Vec<i64>>()
-                    .as_slice(),
-            )
+            spawn::wait_for_tasks(state_clone.clone(), &spawn::task_ids(&ids)?, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "wait_for_tasks",
+        move |ids: Array, timeout: Dynamic| -> Result<Array, Box<EvalAltResult>> {
+            let timeout = spawn::task_timeout(timeout)?;
+            spawn::wait_for_tasks(state_clone.clone(), &spawn::task_ids(&ids)?, Some(timeout))
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "wait_for_tasks_result",
+        move |ids: Array| -> Result<Array, Box<EvalAltResult>> {
+            spawn::wait_for_tasks_result(state_clone.clone(), &spawn::task_ids(&ids)?, None)
+        },
+    );
+
+    let state_clone = state.clone();
+    engine.register_fn(
+        "wait_for_tasks_result",
+        move |ids: Array, timeout: Dynamic| -> Result<Array, Box<EvalAltResult>> {
+            let timeout = spawn::task_timeout(timeout)?;
+            let ids = spawn::task_ids(&ids)?;
+            spawn::wait_for_tasks_result(state_clone.clone(), &ids, Some(timeout))
         },
     );
 
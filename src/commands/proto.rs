@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use prost_reflect::{
+    prost::Message, DescriptorPool, DeserializeOptions, DynamicMessage, MessageDescriptor,
+    SerializeOptions,
+};
+use rhai::{Blob, Dynamic, EvalAltResult};
+
+use crate::rhai::invalid;
+
+/// Load the messages of a `.proto` file, compiled with `protoc` including
+/// its imports, or of a serialized `FileDescriptorSet` (`protoc -o`).
+pub fn proto_load(path: &str) -> Result<DescriptorPool, Box<EvalAltResult>> {
+    let bytes = if path.ends_with(".proto") {
+        compile_proto(path)?
+    } else {
+        std::fs::read(path)
+            .map_err(|e| invalid(format!("Failed to read descriptor set {}: {}", path, e)))?
+    };
+    DescriptorPool::decode(bytes.as_slice())
+        .map_err(|e| invalid(format!("Invalid descriptor set {}: {}", path, e)))
+}
+
+fn compile_proto(path: &str) -> Result<Vec<u8>, Box<EvalAltResult>> {
+    let dir = tempdir::TempDir::new("sam-proto")
+        .map_err(|e| invalid(format!("Failed to create temporary directory: {}", e)))?;
+    let out = dir.path().join("descriptor.pb");
+    let include = Path::new(path)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let output = std::process::Command::new("protoc")
+        .arg("--include_imports")
+        .arg("-I")
+        .arg(include)
+        .arg("-o")
+        .arg(&out)
+        .arg(path)
+        .output()
+        .map_err(|e| invalid(format!("Failed to run protoc, is it installed? {}", e)))?;
+    if !output.status.success() {
+        return Err(invalid(format!(
+            "protoc failed for {}: {}",
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    std::fs::read(&out).map_err(|e| invalid(format!("Failed to read descriptor set: {}", e)))
+}
+
+fn message(pool: &DescriptorPool, name: &str) -> Result<MessageDescriptor, Box<EvalAltResult>> {
+    pool.get_message_by_name(name)
+        .ok_or_else(|| invalid(format!("Unknown protobuf message {}", name)))
+}
+
+/// Encode a map in the protobuf JSON mapping, with field names as in the
+/// `.proto` file or in lowerCamelCase.
+pub fn proto_encode(
+    pool: &DescriptorPool,
+    name: &str,
+    value: &Dynamic,
+) -> Result<Blob, Box<EvalAltResult>> {
+    let desc = message(pool, name)?;
+    let json = serde_json::to_value(value)
+        .map_err(|e| invalid(format!("Failed to convert to {}: {}", name, e)))?;
+    let message = DynamicMessage::deserialize_with_options(
+        desc,
+        json,
+        &DeserializeOptions::new().deny_unknown_fields(true),
+    )
+    .map_err(|e| invalid(format!("Failed to convert to {}: {}", name, e)))?;
+    Ok(message.encode_to_vec())
+}
+
+/// Decode `bytes` to a map with the field names of the `.proto` file. Fields
+/// with default values are included, 64 bit integers are numbers, enums are
+/// their names and bytes base64.
+pub fn proto_decode(
+    pool: &DescriptorPool,
+    name: &str,
+    bytes: &[u8],
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let desc = message(pool, name)?;
+    let message = DynamicMessage::decode(desc, bytes)
+        .map_err(|e| invalid(format!("Failed to decode {}: {}", name, e)))?;
+    let options = SerializeOptions::new()
+        .stringify_64_bit_integers(false)
+        .use_proto_field_name(true)
+        .skip_default_fields(false);
+    let json = message
+        .serialize_with_options(serde_json::value::Serializer, &options)
+        .map_err(|e| invalid(format!("Failed to decode {}: {}", name, e)))?;
+    rhai::serde::to_dynamic(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use prost_reflect::prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+    };
+
+    use super::*;
+
+    fn field(name: &str, number: i32, typ: Type, label: Label) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            r#type: Some(typ as i32),
+            label: Some(label as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let set = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some("event.proto".to_string()),
+                package: Some("test".to_string()),
+                syntax: Some("proto3".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Event".to_string()),
+                    field: vec![
+                        field("id", 1, Type::Int64, Label::Optional),
+                        field("name", 2, Type::String, Label::Optional),
+                        field("tags", 3, Type::String, Label::Repeated),
+                    ],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        };
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("event.pb");
+        std::fs::write(&path, set.encode_to_vec()).unwrap();
+        let pool = proto_load(path.to_str().unwrap()).unwrap();
+
+        let value: Dynamic =
+            rhai::serde::to_dynamic(serde_json::json!({"id": 7, "name": "start", "tags": ["a"]}))
+                .unwrap();
+        let bytes = proto_encode(&pool, "test.Event", &value).unwrap();
+        let decoded = proto_decode(&pool, "test.Event", &bytes).unwrap();
+        assert_eq!(decoded.to_string(), value.to_string());
+
+        let unknown = rhai::serde::to_dynamic(serde_json::json!({"other": 1})).unwrap();
+        assert!(proto_encode(&pool, "test.Event", &unknown).is_err());
+        assert!(proto_encode(&pool, "test.Missing", &value).is_err());
+        assert!(proto_decode(&pool, "test.Event", &[0xff]).is_err());
+    }
+}
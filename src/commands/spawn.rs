@@ -1,9 +1,10 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use parking_lot::Mutex;
-use rhai::{Array, Dynamic, EvalAltResult, FnPtr, NativeCallContext, Position};
+use rhai::{Array, Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext, Position};
 use tokio::task::JoinHandle;
 
+use super::system::duration_option;
 use crate::{state::SharedState, Environment};
 
 pub fn spawn_task<E: Environment + Clone + 'static>(
@@ -11,22 +12,65 @@ pub fn spawn_task<E: Environment + Clone + 'static>(
     _context: NativeCallContext,
     cb: FnPtr,
 ) -> Result<i64, Box<EvalAltResult>> {
-    let (file, mut env, module_dirs) = {
+    let (
+        file,
+        ast,
+        mut env,
+        module_dirs,
+        modules,
+        run_id,
+        stdout,
+        http_retry,
+        exec_timeout,
+        log_throttle,
+        remote,
+        targets,
+        target,
+        pool,
+    ) = {
         let state = state.lock();
         (
             state.current_file.clone().unwrap_or_default(),
+            state
+                .current_file
+                .as_ref()
+                .and_then(|file| state.script_asts.get(file))
+                .cloned()
+                .unwrap_or_default(),
             state.env.clone(),
             state.module_dirs.clone(),
+            state.component_modules.clone(),
+            state.run_id.clone(),
+            state.stdout.clone(),
+            state.http_retry.clone(),
+            state.exec_timeout,
+            state.log_throttle.clone(),
+            state.remote.clone(),
+            state.targets.clone(),
+            state.target.clone(),
+            state.engine_pool.clone(),
         )
     };
     env.stop_on_drop(false);
     log::debug!("Spawning task in file: {}", file);
-    let mut engine = crate::Engine::new(env, &module_dirs);
-    log::debug!("fresh engine created");
-    let out: JoinHandle<Result<Dynamic, Box<EvalAltResult>>> = tokio::task::spawn(async move {
-        log::debug!("running task in file: {}", file);
-        engine.run_fn_ptr(cb, &file)
-    });
+    let mut engine = pool.take(env, &module_dirs);
+    engine.set_run_id(run_id);
+    engine.set_component_modules(modules);
+    engine.set_stdout(stdout);
+    engine.set_http_retry(http_retry);
+    engine.set_exec_timeout(exec_timeout);
+    engine.set_log_throttle(log_throttle);
+    engine.set_remote(remote);
+    engine.set_targets(targets, target);
+    log::debug!("engine ready");
+    // Scripts block their thread, keep them off the workers driving the timers
+    let out: JoinHandle<Result<Dynamic, Box<EvalAltResult>>> =
+        tokio::task::spawn_blocking(move || {
+            log::debug!("running task in file: {}", file);
+            let result = engine.run_fn_ptr(cb, &file, ast);
+            pool.put(engine);
+            result
+        });
     log::debug!("task spawned");
     let id = {
         log::debug!("inserting task into state");
@@ -63,13 +107,120 @@ pub fn wait_for_task<E: Environment + Clone + 'static>(
     Ok(result)
 }
 
-pub fn wait_for_tasks<E: Environment + Clone + 'static>(
+/// Wait for all `ids` to finish, or until `timeout` passed, and return the
+/// outcome of each as `#{id, status, value, error}` with the status `ok`,
+/// `failed` or `timeout`. Tasks still running at the timeout are left to finish
+/// in the background.
+pub fn wait_for_tasks_result<E: Environment + Clone + 'static>(
     state: Arc<Mutex<SharedState<E>>>,
     ids: &[i64],
+    timeout: Option<Duration>,
 ) -> Result<Array, Box<EvalAltResult>> {
-    let mut results = Array::new();
+    // A task listed twice is waited for once
+    let mut unique: Vec<i64> = vec![];
     for id in ids {
-        results.push(wait_for_task(state.clone(), *id)?);
+        if !unique.contains(id) {
+            unique.push(*id);
+        }
     }
+    let handles = {
+        let mut state = state.lock();
+        if let Some(id) = unique
+            .iter()
+            .find(|id| !state.spawn_handles.contains_key(id))
+        {
+            let msg = format!("No such task: {}", id);
+            return Err(Box::new(EvalAltResult::ErrorRuntime(
+                msg.into(),
+                Position::NONE,
+            )));
+        }
+        unique
+            .iter()
+            .filter_map(|id| Some((*id, state.spawn_handles.remove(id)?)))
+            .collect::<Vec<_>>()
+    };
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    let results = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(async {
+            let mut results = Array::new();
+            for (id, handle) in handles {
+                let joined = match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, handle).await,
+                    None => Ok(handle.await),
+                };
+                let mut result = Map::new();
+                result.insert("id".into(), id.into());
+                let (status, value, error) = match joined {
+                    Ok(Ok(Ok(value))) => ("ok", value, Dynamic::UNIT),
+                    Ok(Ok(Err(e))) => ("failed", Dynamic::UNIT, e.to_string().into()),
+                    Ok(Err(e)) => (
+                        "failed",
+                        Dynamic::UNIT,
+                        format!("Task failed: {}", e).into(),
+                    ),
+                    Err(_) => ("timeout", Dynamic::UNIT, "Timed out".into()),
+                };
+                result.insert("status".into(), status.into());
+                result.insert("value".into(), value);
+                result.insert("error".into(), error);
+                results.push(result.into());
+            }
+            results
+        })
+    });
     Ok(results)
 }
+
+/// Wait for all `ids` and return their values, failing with the errors of
+/// all failed tasks once every task finished or `timeout` passed.
+pub fn wait_for_tasks<E: Environment + Clone + 'static>(
+    state: Arc<Mutex<SharedState<E>>>,
+    ids: &[i64],
+    timeout: Option<Duration>,
+) -> Result<Array, Box<EvalAltResult>> {
+    let results = wait_for_tasks_result(state, ids, timeout)?;
+    let mut values = Array::new();
+    let mut failures = vec![];
+    for result in results {
+        let mut result = result.cast::<Map>();
+        let status = result["status"].to_string();
+        if status == "ok" {
+            values.push(result.remove("value").unwrap());
+        } else {
+            failures.push(format!("task {}: {}", result["id"], result["error"]));
+        }
+    }
+    if !failures.is_empty() {
+        let msg = format!(
+            "{} of {} tasks failed:\n{}",
+            failures.len(),
+            ids.len(),
+            failures.join("\n")
+        );
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            msg.into(),
+            Position::NONE,
+        )));
+    }
+    Ok(values)
+}
+
+/// The task ids of a script array.
+pub fn task_ids(ids: &Array) -> Result<Vec<i64>, Box<EvalAltResult>> {
+    ids.iter()
+        .map(|id| {
+            id.as_int().map_err(|typ| {
+                let msg = format!("Expected task ids, got {}", typ);
+                Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+            })
+        })
+        .collect()
+}
+
+/// The overall timeout of waiting for tasks, in ms or as a duration string.
+pub fn task_timeout(timeout: Dynamic) -> Result<Duration, Box<EvalAltResult>> {
+    let mut options = Map::new();
+    options.insert("timeout".into(), timeout);
+    duration_option(&options, "timeout", Duration::ZERO)
+}
@@ -0,0 +1,103 @@
+use rhai::{Array, Dynamic, EvalAltResult, FLOAT};
+
+use crate::rhai::invalid;
+
+fn numbers(name: &str, array: &Array) -> Result<Vec<FLOAT>, Box<EvalAltResult>> {
+    if array.is_empty() {
+        return Err(invalid(format!("{}: the array is empty", name)));
+    }
+    array
+        .iter()
+        .map(|value| {
+            if let Ok(value) = value.as_float() {
+                Ok(value)
+            } else if let Ok(value) = value.as_int() {
+                Ok(value as FLOAT)
+            } else {
+                Err(invalid(format!("{}: {} is not a number", name, value)))
+            }
+        })
+        .collect()
+}
+
+fn sorted(mut values: Vec<FLOAT>) -> Vec<FLOAT> {
+    values.sort_by(|a, b| a.total_cmp(b));
+    values
+}
+
+pub fn mean(array: &Array) -> Result<FLOAT, Box<EvalAltResult>> {
+    let values = numbers("mean", array)?;
+    Ok(values.iter().sum::<FLOAT>() / values.len() as FLOAT)
+}
+
+pub fn median(array: &Array) -> Result<FLOAT, Box<EvalAltResult>> {
+    percentile(array, 50.0)
+}
+
+/// The `p`th percentile (0 to 100) of `array`, interpolating linearly between
+/// the two closest values.
+pub fn percentile(array: &Array, p: FLOAT) -> Result<FLOAT, Box<EvalAltResult>> {
+    if !(0.0..=100.0).contains(&p) {
+        return Err(invalid(format!(
+            "percentile: p {} must be between 0 and 100",
+            p
+        )));
+    }
+    let values = sorted(numbers("percentile", array)?);
+    let rank = p / 100.0 * (values.len() - 1) as FLOAT;
+    let (low, high) = (rank.floor() as usize, rank.ceil() as usize);
+    Ok(values[low] + (values[high] - values[low]) * (rank - low as FLOAT))
+}
+
+/// Population standard deviation of `array`.
+pub fn stddev(array: &Array) -> Result<FLOAT, Box<EvalAltResult>> {
+    let values = numbers("stddev", array)?;
+    let mean = values.iter().sum::<FLOAT>() / values.len() as FLOAT;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<FLOAT>() / values.len() as FLOAT;
+    Ok(variance.sqrt())
+}
+
+/// Smallest element of `array`, keeping its type.
+pub fn min(array: &Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    extreme("min", array, |a, b| a < b)
+}
+
+/// Largest element of `array`, keeping its type.
+pub fn max(array: &Array) -> Result<Dynamic, Box<EvalAltResult>> {
+    extreme("max", array, |a, b| a > b)
+}
+
+fn extreme(
+    name: &str,
+    array: &Array,
+    better: fn(FLOAT, FLOAT) -> bool,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let values = numbers(name, array)?;
+    let mut best = 0;
+    for (i, value) in values.iter().enumerate() {
+        if better(*value, values[best]) {
+            best = i;
+        }
+    }
+    Ok(array[best].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let array: Array = [4_i64, 1, 3, 2].into_iter().map(Dynamic::from).collect();
+        assert_eq!(mean(&array).unwrap(), 2.5);
+        assert_eq!(median(&array).unwrap(), 2.5);
+        assert_eq!(percentile(&array, 0.0).unwrap(), 1.0);
+        assert_eq!(percentile(&array, 100.0).unwrap(), 4.0);
+        assert!((stddev(&array).unwrap() - 1.25_f64.sqrt()).abs() < 1e-9);
+        assert_eq!(min(&array).unwrap().as_int().unwrap(), 1);
+        assert_eq!(max(&array).unwrap().as_int().unwrap(), 4);
+        assert!(mean(&Array::new()).is_err());
+        assert!(percentile(&array, 101.0).is_err());
+        assert!(mean(&vec![Dynamic::from("a")]).is_err());
+    }
+}
@@ -1,82 +1,109 @@
-use std::{io::Write, sync::Arc};
+use std::sync::Arc;
 
 use parking_lot::Mutex;
-use rhai::{EvalAltResult, FnPtr, NativeCallContext};
+use rhai::{Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext, Position};
 
-use crate::{state::SharedState, Environment};
+use crate::{
+    state::{SharedState, SkipReason, SkippedTest, TestInfo, TestProgress, TestStatus},
+    Environment,
+};
 
-pub fn print_indented(msg: &str, indention_level: usize, silent: bool) {
-    if silent {
-        return;
-    }
-    let prefix = format!(" \x1b[32mTEST\x1b[0m{}", "  ".repeat(indention_level));
-    if msg.contains('\n') {
-        for line in msg.lines() {
-            println!("{}{}", prefix, line);
-        }
-    } else {
-        print!("{}{}", prefix, msg);
-    }
+// Read the tags from the options map passed to describe/it
+pub fn parse_tags(options: &Map) -> Result<Vec<String>, Box<EvalAltResult>> {
+    let Some(tags) = options.get("tags") else {
+        return Ok(vec![]);
+    };
+    let tags = tags.as_array_ref().map_err(|_| {
+        Box::new(EvalAltResult::ErrorRuntime(
+            "'tags' must be an array of strings".into(),
+            Position::NONE,
+        ))
+    })?;
+    Ok(tags.iter().map(|tag| tag.to_string()).collect())
 }
 
 pub fn describe<E: Environment>(
     state: Arc<Mutex<SharedState<E>>>,
     context: NativeCallContext,
     msg: &str,
+    tags: Vec<String>,
     cb: FnPtr,
     print_prefix: &str,
 ) -> Result<(), Box<EvalAltResult>> {
-    let indention_level = {
+    let (indention_level, output) = {
         let mut state = state.lock();
+        state.current_tags_stack.push(tags);
         let (test_count, error_count) = (state.test_count, state.error_count);
         state.nested_test_counts.push((test_count, error_count));
         state.test_count = 0;
         state.error_count = 0;
         state.indention_level += 1;
         state.current_test_stack.push(msg.to_string());
-        state.indention_level
+        (state.indention_level, state.output.clone())
     };
 
-    print_indented(
+    state.lock().print_indented(
         &format!("{} \x1b[3m{}\x1b[0m ...\n", print_prefix, msg),
         indention_level - 1,
-        state.lock().silent,
     );
 
     let start = std::time::Instant::now();
-    match cb.call_within_context::<()>(&context, ()) {
+    match cb.call_within_context::<Dynamic>(&context, ()) {
         Ok(_) => {
             let mut state = state.lock();
             let duration = start.elapsed();
+            let status = match (state.test_count, state.error_count) {
+                (0, _) => TestStatus::Skipped,
+                (_, 0) => TestStatus::Passed,
+                _ => TestStatus::Failed,
+            };
+            state.write_event(
+                "describe",
+                &[
+                    ("status", status_name(status).into()),
+                    ("tests", state.test_count.into()),
+                    ("failed", state.error_count.into()),
+                    ("duration_ms", (duration.as_millis() as u64).into()),
+                ],
+            );
             if state.error_count == 0 && state.test_count > 0 {
-                print_indented(
-                    &format!("{} \x1b[3m{}\x1b[0m \x1b[32msucceeded\x1b[0m! ✅ ({} tests passed) ({})\n", print_prefix, msg, state.test_count, humantime::format_duration(duration)),
+                state.print_indented(
+                    &format!(
+                        "{} \x1b[3m{}\x1b[0m \x1b[32m{}\x1b[0m! {} ({} tests passed) ({})\n",
+                        print_prefix,
+                        msg,
+                        output.succeeded_word(),
+                        output.passed_marker(),
+                        state.test_count,
+                        humantime::format_duration(duration)
+                    ),
                     indention_level - 1,
-                    state.silent,
                 );
             } else if state.test_count == 0 {
-                print_indented(
+                state.print_indented(
                     &format!(
-                        "{} \x1b[3m{}\x1b[0m \x1b[33mskipped\x1b[0m! ⏭️ (no tests) ({})\n",
+                        "{} \x1b[3m{}\x1b[0m \x1b[33m{}\x1b[0m! {} (no tests) ({})\n",
                         print_prefix,
                         msg,
+                        output.skipped_word(),
+                        output.skipped_marker(),
                         humantime::format_duration(duration)
                     ),
                     indention_level - 1,
-                    state.silent,
                 );
             } else {
-                print_indented(
+                state.print_indented(
                     &format!(
-                        "{} \x1b[3m{}\x1b[0m \x1b[31mfailed\x1b[0m! 😭 ({} tests failed out of {}) ({})\n",
+                        "{} \x1b[3m{}\x1b[0m \x1b[31m{}\x1b[0m! {} ({} tests failed out of {}) ({})\n",
                         print_prefix,
                         msg,
+                        output.failed_word(),
+                        output.failed_marker(),
                         state.error_count,
                         state.test_count,
                         humantime::format_duration(duration)
                     ),
                     indention_level - 1,
-                    state.silent,
                 );
             }
             if let Some((parent_tests, parent_errors)) = state.nested_test_counts.pop() {
@@ -86,17 +113,26 @@ pub fn describe<E: Environment>(
         }
         Err(e) => {
             let duration = start.elapsed();
+            state.lock().write_event(
+                "describe",
+                &[
+                    ("status", "failed".into()),
+                    ("error", e.to_string().replace("\n", " ").into()),
+                    ("duration_ms", (duration.as_millis() as u64).into()),
+                ],
+            );
             let error = e.to_string().replace("\n", "\n ");
-            print_indented(
+            state.lock().print_indented(
                 &format!(
-                    "{} \x1b[3m{}\x1b[0m \x1b[31mfailed\x1b[0m! 😭: {} ({})\n",
+                    "{} \x1b[3m{}\x1b[0m \x1b[31m{}\x1b[0m! {}: {} ({})\n",
                     print_prefix,
                     msg,
+                    output.failed_word(),
+                    output.failed_marker(),
                     error,
                     humantime::format_duration(duration)
                 ),
                 indention_level - 1,
-                state.lock().silent,
             );
             let mut state = state.lock();
             state.nested_test_counts.pop(); // Clean up the stack on error
@@ -106,6 +142,7 @@ pub fn describe<E: Environment>(
         let mut state = state.lock();
         state.indention_level -= 1;
         state.current_test_stack.pop();
+        state.current_tags_stack.pop();
     }
     Ok(())
 }
@@ -114,43 +151,143 @@ pub fn it<E: Environment>(
     state: Arc<Mutex<SharedState<E>>>,
     context: NativeCallContext,
     msg: &str,
+    tags: Vec<String>,
     cb: FnPtr,
     print_prefix: &str,
 ) -> Result<(), Box<EvalAltResult>> {
-    let indention_level = {
+    let (indention_level, was_in_test, temp_dir_mark, progress_index) = {
         let mut state = state.lock();
         state.current_test_stack.push(msg.to_string());
-        if should_skip(&state) {
-            print_indented(
-                &format!("{} \x1b[3m{}\x1b[0m ⏭️\n", print_prefix, msg),
+        if state.list_only && skip_reason(&state).is_none() {
+            let mut all_tags: Vec<String> = state.current_tags_stack.concat();
+            for tag in tags {
+                if !all_tags.contains(&tag) {
+                    all_tags.push(tag);
+                }
+            }
+            let test = TestInfo {
+                id: state.current_test_stack.join("."),
+                environment: String::new(),
+                path: state.current_test_stack.clone(),
+                file: state.current_file.clone().unwrap_or_default(),
+                line: context.call_position().line().unwrap_or(0),
+                tags: all_tags,
+            };
+            state.discovered_tests.push(test);
+            state.current_test_stack.pop();
+            return Ok(());
+        }
+        if let Some(reason) = skip_reason(&state) {
+            state.print_indented(
+                &format!(
+                    "{} \x1b[3m{}\x1b[0m {}\n",
+                    print_prefix,
+                    msg,
+                    state.output.skipped_marker()
+                ),
                 state.indention_level,
-                state.silent,
             );
+            state.write_event("test", &[("status", "skipped".into())]);
+            state.tests_skipped += 1;
+            let test = state.current_test_stack.join(".");
+            state.skipped_tests.push(SkippedTest { test, reason });
+            let path = state.current_test_stack.clone();
+            state
+                .progress
+                .push(TestProgress::new(path, TestStatus::Skipped));
             state.current_test_stack.pop();
             return Ok(());
         }
         state.test_count += 1;
-        state.indention_level
+        state.tests_run += 1;
+        let was_in_test = std::mem::replace(&mut state.in_test, true);
+        let path = state.current_test_stack.clone();
+        state
+            .progress
+            .push(TestProgress::new(path, TestStatus::Running));
+        (
+            state.indention_level,
+            was_in_test,
+            state.test_temp_dirs.len(),
+            state.progress.len() - 1,
+        )
     };
-    print_indented(
+    state.lock().print_indented(
         &format!("{} \x1b[3m{}\x1b[0m...", print_prefix, msg),
         indention_level,
-        state.lock().silent,
     );
-    std::io::stdout().flush().unwrap();
 
     let start = std::time::Instant::now();
-    let result = cb.call_within_context::<()>(&context, ());
+    let result = cb.call_within_context::<Dynamic>(&context, ());
     let duration = start.elapsed();
     let mut state = state.lock();
 
+    // Remove the temporary directories created by this test
+    state.in_test = was_in_test;
+    let keep_temp_dirs =
+        state.keep_failed_artifacts && (result.is_err() || state.current_test_failed);
+    for temp_dir in state.test_temp_dirs.split_off(temp_dir_mark) {
+        if keep_temp_dirs {
+            let path = temp_dir.into_path();
+            log::info!(
+                "Keeping temporary directory {} of failed test",
+                path.display()
+            );
+        }
+    }
+
+    let status = if result.is_err() || state.current_test_failed {
+        TestStatus::Failed
+    } else {
+        TestStatus::Passed
+    };
+    state.progress[progress_index].finish(status, duration);
+
+    let error = result.as_ref().err().map(|e| {
+        let mut error = e.to_string().replace("\n", " ").replace("  ", " ");
+        if let Some(sections) = state.failed_sections.take() {
+            error += &format!(" (in section {})", sections.join(" > "));
+        }
+        error
+    });
+    let failed_assertions = state
+        .assertions
+        .get(&state.get_current_test_id())
+        .unwrap_or(&vec![])
+        .iter()
+        .filter(|a| !a.success)
+        .map(|a| a.label().into())
+        .collect::<Vec<serde_json::Value>>();
+    let mut fields = vec![
+        ("status", status_name(status).into()),
+        ("duration_ms", (duration.as_millis() as u64).into()),
+    ];
+    if let Some(error) = &error {
+        fields.push(("error", error.as_str().into()));
+    }
+    if !failed_assertions.is_empty() {
+        fields.push(("failed_assertions", failed_assertions.into()));
+    }
+    state.write_event("test", &fields);
+
     match result {
         Ok(_) => {
-            if !state.current_test_failed && !state.silent {
-                println!("✅ ({})", humantime::format_duration(duration));
-            } else if !state.silent {
-                println!("😭 ({})", humantime::format_duration(duration));
+            if state.current_test_failed {
+                state.tests_failed += 1;
                 state.error_count += 1;
+            }
+            if !state.current_test_failed && state.pretty() {
+                state.stdout.writeln(format!(
+                    "{} ({})",
+                    state.output.passed_marker(),
+                    humantime::format_duration(duration)
+                ));
+            } else if state.pretty() {
+                state.stdout.writeln(format!(
+                    "{} ({})",
+                    state.output.failed_marker(),
+                    humantime::format_duration(duration)
+                ));
                 for assertion in state
                     .assertions
                     .get(&state.get_current_test_id())
@@ -158,21 +295,24 @@ pub fn it<E: Environment>(
                     .iter()
                     .filter(|a| !a.success)
                 {
-                    print_indented(
+                    state.print_indented(
                         &format!(
                             "\x1b[3m{}\x1b[0m \x1b[31m(failed)\x1b[0m\n",
-                            assertion.message
+                            assertion.label()
                         ),
                         state.indention_level + 1,
-                        state.silent,
                     );
                 }
             }
         }
         Err(e) => {
-            let error = e.to_string().replace("\n", " ").replace("  ", " ");
-            if !state.silent {
-                println!("😭: {} ({})", error, humantime::format_duration(duration));
+            if state.pretty() {
+                state.stdout.writeln(format!(
+                    "{}: {} ({})",
+                    state.output.failed_marker(),
+                    error.unwrap_or_default(),
+                    humantime::format_duration(duration)
+                ));
             }
             for assertion in state
                 .assertions
@@ -181,16 +321,16 @@ pub fn it<E: Environment>(
                 .iter()
                 .filter(|a| !a.success)
             {
-                print_indented(
+                state.print_indented(
                     &format!(
                         " - \x1b[3m{}\x1b[0m \x1b[31m(failed)\x1b[0m\n",
-                        assertion.message
+                        assertion.label()
                     ),
                     state.indention_level,
-                    state.silent,
                 );
             }
             state.error_count += 1;
+            state.tests_failed += 1;
             if state.fail_fast {
                 return Err(e);
             }
@@ -198,13 +338,55 @@ pub fn it<E: Environment>(
     };
     state.current_test_stack.pop();
     state.current_test_failed = false;
+    state.failed_sections = None;
     Ok(())
 }
 
-pub fn should_skip<E: Environment>(state: &SharedState<E>) -> bool {
+/// Run `cb` as a named section of the running test. The assertions made in
+/// it are labelled with the section, like an error thrown in it.
+pub fn section<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    context: NativeCallContext,
+    name: &str,
+    cb: FnPtr,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    state.lock().current_sections.push(name.to_string());
+    let result = cb.call_within_context::<Dynamic>(&context, ());
+    let mut state = state.lock();
+    if result.is_err() && state.failed_sections.is_none() {
+        state.failed_sections = Some(state.current_sections.clone());
+    }
+    state.current_sections.pop();
+    result
+}
+
+fn status_name(status: TestStatus) -> &'static str {
+    match status {
+        TestStatus::Running => "running",
+        TestStatus::Passed => "passed",
+        TestStatus::Failed => "failed",
+        TestStatus::Skipped => "skipped",
+    }
+}
+
+/// Why the current test is skipped, `None` if it runs.
+pub fn skip_reason<E: Environment>(state: &SharedState<E>) -> Option<SkipReason> {
     log::debug!("Checking if we should skip");
     let test_path = state.current_test_stack.join(".");
     log::debug!("Test path: {}", test_path);
+    if !state.exact_tests.is_empty() && !state.exact_tests.contains(&test_path) {
+        log::debug!("Test path is not in the exact test list");
+        return Some(SkipReason::NotExact);
+    }
+    if let Some(paths) = &state.affected_paths {
+        let affected = paths
+            .iter()
+            .any(|path| test_path == *path || test_path.starts_with(&format!("{}.", path)));
+        if !affected {
+            log::debug!("Test path does not touch the selected components");
+            return Some(SkipReason::NotAffected);
+        }
+    }
     // If there's a skip expression and it matches, we should skip
     if let Some(skip) = &state.skip_expression {
         log::debug!("Skip expression: {}", skip);
@@ -212,14 +394,14 @@ pub fn should_skip<E: Environment>(state: &SharedState<E>) -> bool {
             Ok(re) => {
                 if re.is_match(&test_path) {
                     log::debug!("Skip expression matches test path");
-                    return true;
+                    return Some(SkipReason::Skip(skip.clone()));
                 }
             }
             Err(_) => {
                 log::debug!("Invalid skip expression: {}", skip);
                 if test_path.contains(skip) {
                     log::debug!("Skip expression matches test path anyway");
-                    return true;
+                    return Some(SkipReason::Skip(skip.clone()));
                 }
             }
         }
@@ -228,7 +410,7 @@ pub fn should_skip<E: Environment>(state: &SharedState<E>) -> bool {
     // If there's a filter expression, skip if it doesn't match
     if let Some(filter) = &state.filter_expression {
         log::debug!("Filter expression: {}", filter);
-        match regex::Regex::new(filter) {
+        let skipped = match regex::Regex::new(filter) {
             Ok(re) => {
                 log::debug!(
                     "Filter expression matches test path: {}",
@@ -240,14 +422,15 @@ pub fn should_skip<E: Environment>(state: &SharedState<E>) -> bool {
                 log::debug!("Invalid filter expression: {}", filter);
                 if test_path.contains(filter) {
                     log::debug!("Filter expression matches test path anyway");
-                    return true;
+                    return Some(SkipReason::Filter(filter.clone()));
                 }
                 false
             }
-        }
+        };
+        skipped.then(|| SkipReason::Filter(filter.clone()))
     } else {
         // No filter or skip expressions, don't skip
         log::debug!("No filter or skip expressions, don't skip");
-        false
+        None
     }
-}
\ No newline at end of file
+}
@@ -1,23 +1,26 @@
-use std::{env, process::Command, sync::Arc};
+use std::{env, sync::Arc, time::Duration};
 
 use parking_lot::Mutex;
-use rhai::{EvalAltResult, FnPtr, NativeCallContext, Position};
+use rhai::{Array, Dynamic, EvalAltResult, FnPtr, Map, NativeCallContext, Position};
 
-use crate::{state::SharedState, Environment};
+use crate::{
+    config::Component, environment::JobResult, remote::Remote, state::SharedState, Environment,
+};
 
 const DEFAULT_SHELL: &str = "sh";
 
-pub fn exec(command: &str) -> Result<String, Box<EvalAltResult>> {
+/// Run `command` with the shell, killed with its children after `timeout`.
+pub fn exec(command: &str, timeout: Option<Duration>) -> Result<String, Box<EvalAltResult>> {
     let shell = env::var("SHELL").unwrap_or_else(|_| DEFAULT_SHELL.to_string());
-    let output = Command::new(shell)
-        .arg("-c")
-        .arg(command)
-        .envs(std::env::vars())
-        .output()
-        .map_err(|e| {
-            let msg = format!("Failed to execute command: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })?;
+    let mut cmd = tokio::process::Command::new(shell);
+    cmd.arg("-c").arg(command).envs(std::env::vars());
+    let output = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(crate::timeout::output(&mut cmd, timeout))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to execute command: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
     if !output.status.success() {
         let error = String::from_utf8_lossy(&output.stderr);
         let msg = format!("Command failed with {}: {}", output.status, error);
@@ -33,26 +36,85 @@ pub fn exec(command: &str) -> Result<String, Box<EvalAltResult>> {
     Ok(resp)
 }
 
+/// Run `command` on the remote host if it runs `exec`, or locally, killed
+/// after `timeout` or the default exec timeout.
+pub fn exec_on<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    command: &str,
+    timeout: Option<Duration>,
+) -> Result<String, Box<EvalAltResult>> {
+    let (remote, default_timeout) = {
+        let state = state.lock();
+        (
+            state.remote.clone().filter(|remote| remote.exec),
+            state.exec_timeout,
+        )
+    };
+    match remote {
+        Some(remote) => remote_exec(&remote, command, timeout.or(default_timeout)),
+        None => exec(command, timeout.or(default_timeout)),
+    }
+}
+
+/// The `timeout` of command options, if set.
+pub fn timeout_option(options: &Map) -> Result<Option<Duration>, Box<EvalAltResult>> {
+    match options.contains_key("timeout") {
+        true => duration_option(options, "timeout", Duration::ZERO).map(Some),
+        false => Ok(None),
+    }
+}
+
+/// Run `command` with the shell of the remote host, killing `ssh` after
+/// `timeout`.
+pub fn remote_exec(
+    remote: &Remote,
+    command: &str,
+    timeout: Option<Duration>,
+) -> Result<String, Box<EvalAltResult>> {
+    let output = remote.run_checked(command, None, timeout).map_err(|e| {
+        Box::new(EvalAltResult::ErrorRuntime(
+            e.to_string().into(),
+            Position::NONE,
+        ))
+    })?;
+    String::from_utf8(output).map_err(|e| {
+        let msg = format!("Failed to convert output to string: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
 pub fn log<E: Environment>(
     context: NativeCallContext,
     state: Arc<Mutex<SharedState<E>>>,
     msg: &str,
 ) -> Result<(), Box<EvalAltResult>> {
-    if log::log_enabled!(log::Level::Info) {
-        println!();
-    }
-    let file = state
-        .lock()
-        .current_file
-        .clone()
-        .unwrap_or("unknown".to_string());
-    let file = file.split('/').last().unwrap_or("unknown").to_string();
-    log::info!(
+    let (file, throttle) = {
+        let state = state.lock();
+        // Finish the line of the running test before logging to stderr
+        if log::log_enabled!(log::Level::Info) && !state.output.structured() {
+            state.stdout.writeln("");
+        }
+        state.stdout.flush();
+        (
+            state.current_file.clone().unwrap_or("unknown".to_string()),
+            state.log_throttle.clone(),
+        )
+    };
+    let file = file.rsplit('/').next().unwrap_or("unknown").to_string();
+    let line = format!(
         "{}:{}: {}",
         file,
-        context.position().line().unwrap_or(0),
+        context.call_position().line().unwrap_or(0),
         msg
     );
+    match throttle {
+        Some(throttle) => {
+            for line in throttle.lock().lines(&line) {
+                log::info!("{}", line);
+            }
+        }
+        None => log::info!("{}", line),
+    }
     Ok(())
 }
 
@@ -68,33 +130,71 @@ pub fn get_env(key: &str) -> Result<String, Box<EvalAltResult>> {
     })
 }
 
-pub fn wait_until(
+/// Read a duration option given either as a humantime string or as milliseconds.
+pub fn duration_option(
+    options: &Map,
+    key: &str,
+    default: std::time::Duration,
+) -> Result<std::time::Duration, Box<EvalAltResult>> {
+    let Some(value) = options.get(key) else {
+        return Ok(default);
+    };
+    if let Ok(millis) = value.as_int() {
+        return Ok(std::time::Duration::from_millis(millis.max(0) as u64));
+    }
+    let value = value.clone().into_string().map_err(|_| {
+        let msg = format!("Option {} must be a duration string or milliseconds", key);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    humantime::parse_duration(&value).map_err(|e| {
+        let msg = format!("Invalid duration for option {}: {}", key, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Call `condition` every `interval` until it returns something other than
+/// `false` or `()`, and return that value.
+pub fn wait_until<E: Environment>(
     context: NativeCallContext,
+    state: Arc<Mutex<SharedState<E>>>,
     condition: FnPtr,
-    timeout: i64,
-) -> Result<(), Box<EvalAltResult>> {
+    options: Map,
+) -> Result<Dynamic, Box<EvalAltResult>> {
+    let timeout = duration_option(&options, "timeout", std::time::Duration::from_secs(30))?;
+    let interval = duration_option(&options, "interval", std::time::Duration::from_millis(100))?;
+    let message = options
+        .get("message")
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| "condition".to_string());
+
     let start = std::time::Instant::now();
-    loop {
-        match condition.call_within_context::<bool>(&context, ()) {
-            // Condition evaluated to true -> stop waiting and return
-            Ok(true) => return Ok(()),
+    let result = loop {
+        match condition.call_within_context::<Dynamic>(&context, ()) {
             // Condition evaluated to false -> check timeout and wait if possible
-            Ok(false) => {
-                if start.elapsed().as_millis() > timeout as u128 {
-                    let msg = "Timeout waiting for condition".to_string();
-                    return Err(Box::new(EvalAltResult::ErrorRuntime(
+            Ok(value) if value.is_unit() || value.as_bool() == Ok(false) => {
+                if start.elapsed() > timeout {
+                    let msg = format!(
+                        "Timeout waiting for {} after {}",
+                        message,
+                        humantime::format_duration(timeout)
+                    );
+                    break Err(Box::new(EvalAltResult::ErrorRuntime(
                         msg.into(),
                         Position::NONE,
                     )));
                 }
-                std::thread::sleep(std::time::Duration::from_millis(100));
+                std::thread::sleep(interval);
             }
+            // Condition is ready -> stop waiting and return its value
+            Ok(value) => break Ok(value),
             // Some error occurred inside condition call -> return it immediately
-            Err(err) => {
-                return Err(err);
-            }
+            Err(err) => break Err(err),
         }
-    }
+    };
+    state
+        .lock()
+        .add_duration_annotation("wait_time_ms", start.elapsed().as_millis() as u64);
+    result
 }
 
 pub fn sleep_str(duration: &str) -> Result<(), Box<EvalAltResult>> {
@@ -107,34 +207,457 @@ pub fn sleep_str(duration: &str) -> Result<(), Box<EvalAltResult>> {
     Ok(())
 }
 
-pub async fn start_component<E: Environment + Clone>(
+/// A clone of the environment sharing its components, to manage them without
+/// holding the state lock while the runtime commands run.
+fn env_handle<E: Environment + Clone>(state: &SharedState<E>) -> E {
+    let mut env = state.env.clone();
+    env.stop_on_drop(false);
+    env
+}
+
+pub fn start_component<E: Environment + Clone>(
     state: Arc<Mutex<SharedState<E>>>,
     component: &str,
 ) -> Result<(), Box<EvalAltResult>> {
-    state
-        .lock()
-        .env
-        .start_component(component)
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to start component: {}", e);
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.start_component(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to start component: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+pub fn stop_component<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.stop_component(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to stop component: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Restart a component, then wait for the `condition` of `options` if set,
+/// like `wait_until` with the same options.
+pub fn restart_component<E: Environment + Clone>(
+    context: NativeCallContext,
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    mut options: Map,
+) -> Result<(), Box<EvalAltResult>> {
+    let condition = match options.remove("condition") {
+        Some(condition) => Some(condition.try_cast::<FnPtr>().ok_or_else(|| {
+            let msg = "Option condition must be a function".to_string();
             Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })
+        })?),
+        None => None,
+    };
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.restart_component(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to restart component: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let Some(condition) = condition else {
+        return Ok(());
+    };
+    options
+        .entry("message".into())
+        .or_insert_with(|| format!("{} to be ready", component).into());
+    wait_until(context, state, condition, options).map(|_| ())
+}
+
+pub fn create_component<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: Map,
+) -> Result<(), Box<EvalAltResult>> {
+    let component: Component = rhai::serde::from_dynamic(&component.into()).map_err(|e| {
+        let msg = format!("Invalid component definition: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let failed = |e: crate::Error| {
+        let msg = format!("Failed to create component: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    };
+    let name = component.name.clone();
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", name.clone().into());
+        state.env.create_component(component).map_err(failed)?;
+        env_handle(&state)
+    };
+    let result = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.start_component(&name))
+    });
+    if let Err(e) = result {
+        let _ = state.lock().env.destroy_component(&name);
+        return Err(failed(e));
+    }
+    Ok(())
+}
+
+pub fn destroy_component<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let failed = |e: crate::Error| {
+        let msg = format!("Failed to destroy component: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    };
+    // The clone still knows the component to stop
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        let env = env_handle(&state);
+        state.env.destroy_component(component).map_err(failed)?;
+        env
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.stop_component(component))
+    })
+    .map_err(failed)
+}
+
+pub fn job_map(result: &JobResult) -> Map {
+    let mut map = Map::new();
+    map.insert("exit_code".into(), result.exit_code.into());
+    map.insert("success".into(), result.success().into());
+    map.insert("stdout".into(), result.stdout.clone().into());
+    map.insert("stderr".into(), result.stderr.clone().into());
+    map.insert(
+        "duration_ms".into(),
+        (result.duration.as_millis() as i64).into(),
+    );
+    map
+}
+
+pub fn run_job<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<Map, Box<EvalAltResult>> {
+    let mut env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.run_job(component))
+    })
+    .map(|result| job_map(&result))
+    .map_err(|e| {
+        let msg = format!("Failed to run job: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Wait for a container or process component to exit on its own, with the
+/// same result as `run_job`.
+pub fn component_wait_exit<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    timeout: std::time::Duration,
+) -> Result<Map, Box<EvalAltResult>> {
+    let env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.wait_exit(component, timeout))
+    })
+    .map(|result| job_map(&result))
+    .map_err(|e| {
+        let msg = format!("Failed to wait for {} to exit: {}", component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Run a command in the container of a component, a string with `sh -c` or
+/// an array of arguments.
+pub fn container_exec<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    command: Dynamic,
+    timeout: Option<Duration>,
+) -> Result<JobResult, Box<EvalAltResult>> {
+    let command = if command.is_array() {
+        command
+            .cast::<Array>()
+            .into_iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+    } else if command.is_string() {
+        vec!["sh".to_string(), "-c".to_string(), command.to_string()]
+    } else {
+        let msg = format!(
+            "Expected a string or an array command, got {}",
+            command.type_name()
+        );
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            msg.into(),
+            Position::NONE,
+        )));
+    };
+    let (env, timeout) = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        (env_handle(&state), timeout.or(state.exec_timeout))
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.container_exec(component, &command, timeout))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to exec in {}: {}", component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Stdout of a command in the container of a component, failing if it exits
+/// with an error.
+pub fn container_exec_stdout<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    command: Dynamic,
+    timeout: Option<Duration>,
+) -> Result<String, Box<EvalAltResult>> {
+    let result = container_exec(state, component, command, timeout)?;
+    if !result.success() {
+        let msg = format!(
+            "Command in {} failed with exit code {}: {}",
+            component,
+            result.exit_code,
+            result.stderr.trim()
+        );
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            msg.into(),
+            Position::NONE,
+        )));
+    }
+    Ok(result.stdout)
 }
 
-pub async fn stop_component<E: Environment + Clone>(
+/// Copy files between the host and a container, `component:/path` on the
+/// container side.
+pub fn container_cp<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    src: &str,
+    dst: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let env = env_handle(&state.lock());
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.container_cp(src, dst))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to copy {} to {}: {}", src, dst, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Pause a component to simulate a hung service, until `resume_component`
+/// or the end of the script.
+pub fn pause_component<E: Environment + Clone>(
     state: Arc<Mutex<SharedState<E>>>,
     component: &str,
 ) -> Result<(), Box<EvalAltResult>> {
+    let env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.pause_component(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to pause {}: {}", component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    state.lock().paused_components.push(component.to_string());
+    Ok(())
+}
+
+pub fn resume_component<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let env = env_handle(&state.lock());
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.resume_component(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to resume {}: {}", component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
     state
         .lock()
-        .env
-        .stop_component(component)
-        .await
-        .map_err(|e| {
-            let msg = format!("Failed to stop component: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })
+        .paused_components
+        .retain(|paused| paused != component);
+    Ok(())
+}
+
+/// How often the restart policy of a component restarted it.
+pub fn restart_count<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<i64, Box<EvalAltResult>> {
+    let env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.restart_count(component))
+    })
+    .map(|count| count as i64)
+    .map_err(|e| {
+        let msg = format!("Failed to get restart count: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+/// Whether a component runs, as `#{running, exit_code, uptime_ms, restarts}`
+/// with `exit_code` and `uptime_ms` unit when not known.
+pub fn component_status<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<Map, Box<EvalAltResult>> {
+    let env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    let status = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.component_status(component))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to get the status of {}: {}", component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let mut map = Map::new();
+    map.insert("running".into(), status.running.into());
+    map.insert(
+        "exit_code".into(),
+        status.exit_code.map_or(Dynamic::UNIT, Dynamic::from),
+    );
+    map.insert(
+        "uptime_ms".into(),
+        status.uptime.map_or(Dynamic::UNIT, |uptime| {
+            Dynamic::from(uptime.as_millis() as i64)
+        }),
+    );
+    map.insert("restarts".into(), (status.restarts as i64).into());
+    Ok(map)
+}
+
+/// Forward a local port to `port` of a component, as `#{port, handle}`. The
+/// port stays open until `port_forward_close(handle)` or the end of the
+/// script.
+pub fn port_forward<E: Environment + Clone>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    port: i64,
+) -> Result<Map, Box<EvalAltResult>> {
+    let port = u16::try_from(port).map_err(|_| {
+        let msg = format!("Invalid port: {}", port);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let env = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        env_handle(&state)
+    };
+    let forward = tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(env.port_forward(component, port))
+    })
+    .map_err(|e| {
+        let msg = format!("Failed to forward port {} of {}: {}", port, component, e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })?;
+    let mut state = state.lock();
+    let handle = state.next_port_forward_handle;
+    state.next_port_forward_handle += 1;
+    let mut result = Map::new();
+    result.insert("port".into(), (forward.port as i64).into());
+    result.insert("handle".into(), handle.into());
+    state.port_forwards.insert(handle, forward);
+    Ok(result)
+}
+
+/// Captured output of a container, pod, process or job, the lines written in
+/// the last `since` if set.
+pub fn component_logs<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+    since: Option<std::time::Duration>,
+) -> Result<String, Box<EvalAltResult>> {
+    let mut state = state.lock();
+    state.push_annotation("components", component.into());
+    state.env.component_logs(component, since).map_err(|e| {
+        let msg = format!("Failed to get logs: {}", e);
+        Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+    })
+}
+
+pub fn job_result<E: Environment>(state: Arc<Mutex<SharedState<E>>>, component: &str) -> Dynamic {
+    let mut state = state.lock();
+    state.push_annotation("components", component.into());
+    match state.env.job_result(component) {
+        Some(result) => job_map(&result).into(),
+        None => Dynamic::UNIT,
+    }
+}
+
+pub fn reseed<E: Environment + Clone>(
+    context: NativeCallContext,
+    state: Arc<Mutex<SharedState<E>>>,
+    component: &str,
+) -> Result<(), Box<EvalAltResult>> {
+    let seeds = {
+        let mut state = state.lock();
+        state.push_annotation("components", component.into());
+        state
+            .seeds
+            .iter()
+            .find(|seeds| seeds.component.name == component)
+            .cloned()
+            .ok_or_else(|| {
+                let msg = format!("No seeds configured for component {}", component);
+                Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+            })?
+    };
+    let engine = context.engine();
+    tokio::task::block_in_place(|| {
+        tokio::runtime::Handle::current().block_on(crate::seed::run_seeds(&seeds, &mut |script| {
+            engine
+                .run_file(script.into())
+                .map_err(|e| crate::Error::Other(e.to_string()))
+        }))
+    })
+    .map_err(|e| {
+        Box::new(EvalAltResult::ErrorRuntime(
+            e.to_string().into(),
+            Position::NONE,
+        ))
+    })
 }
 
 pub fn data_dir<E: Environment>(
@@ -154,3 +677,53 @@ pub fn data_dir<E: Environment>(
         })
         .map(|str_| str_.to_string())
 }
+
+pub fn run_id<E: Environment>(state: Arc<Mutex<SharedState<E>>>) -> String {
+    state.lock().run_id.clone()
+}
+
+/// The target `name` of the config, or the selected one without a name.
+pub fn target<E: Environment>(
+    state: Arc<Mutex<SharedState<E>>>,
+    name: Option<&str>,
+) -> Result<Map, Box<EvalAltResult>> {
+    let state = state.lock();
+    let Some(name) = name.or(state.target.as_deref()) else {
+        return Err(Box::new(EvalAltResult::ErrorRuntime(
+            "No target selected, pass --target or set global.target".into(),
+            Position::NONE,
+        )));
+    };
+    match state.targets.get(name) {
+        Some(target) => Ok(target.to_map(name)),
+        None => Err(Box::new(EvalAltResult::ErrorRuntime(
+            format!("Target {} not found in config", name).into(),
+            Position::NONE,
+        ))),
+    }
+}
+
+/// The name of the selected target, `()` if none is.
+pub fn target_name<E: Environment>(state: Arc<Mutex<SharedState<E>>>) -> Dynamic {
+    match &state.lock().target {
+        Some(name) => name.clone().into(),
+        None => Dynamic::UNIT,
+    }
+}
+
+pub fn test_stats<E: Environment>(state: Arc<Mutex<SharedState<E>>>) -> Map {
+    let state = state.lock();
+    let mut stats = Map::new();
+    stats.insert("tests".into(), Dynamic::from(state.tests_run as i64));
+    stats.insert(
+        "passed".into(),
+        Dynamic::from((state.tests_run - state.tests_failed) as i64),
+    );
+    stats.insert("failed".into(), Dynamic::from(state.tests_failed as i64));
+    stats.insert("skipped".into(), Dynamic::from(state.tests_skipped as i64));
+    stats.insert(
+        "elapsed_ms".into(),
+        Dynamic::from(state.started_at.elapsed().as_millis() as i64),
+    );
+    stats
+}
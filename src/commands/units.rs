@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use rhai::{EvalAltResult, INT};
+
+use crate::rhai::invalid;
+
+const SIZE_UNITS: &[(&str, u64)] = &[
+    ("B", 1),
+    ("KB", 1000),
+    ("MB", 1000_u64.pow(2)),
+    ("GB", 1000_u64.pow(3)),
+    ("TB", 1000_u64.pow(4)),
+    ("KiB", 1 << 10),
+    ("MiB", 1 << 20),
+    ("GiB", 1 << 30),
+    ("TiB", 1 << 40),
+];
+
+/// Milliseconds in a humantime duration like "1h 30m" or "250ms".
+pub fn parse_duration(duration: &str) -> Result<INT, Box<EvalAltResult>> {
+    let duration = humantime::parse_duration(duration)
+        .map_err(|e| invalid(format!("Invalid duration '{}': {}", duration, e)))?;
+    INT::try_from(duration.as_millis())
+        .map_err(|_| invalid(format!("Duration {:?} is too long", duration)))
+}
+
+pub fn format_duration(millis: INT) -> Result<String, Box<EvalAltResult>> {
+    let millis = u64::try_from(millis)
+        .map_err(|_| invalid(format!("Duration {}ms must not be negative", millis)))?;
+    Ok(humantime::format_duration(Duration::from_millis(millis)).to_string())
+}
+
+/// Bytes in a size like "2GiB", "1.5 MB" or "512". KB, MB, ... are powers of
+/// 1000, KiB, MiB, ... powers of 1024.
+pub fn parse_size(size: &str) -> Result<INT, Box<EvalAltResult>> {
+    let trimmed = size.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| invalid(format!("Invalid size '{}': expected a number", size)))?;
+    let unit = unit.trim();
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        SIZE_UNITS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|(_, multiplier)| *multiplier)
+            .ok_or_else(|| invalid(format!("Invalid size '{}': unknown unit '{}'", size, unit)))?
+    };
+    let bytes = (number * multiplier as f64).round();
+    if bytes > INT::MAX as f64 {
+        return Err(invalid(format!("Size '{}' is too large", size)));
+    }
+    Ok(bytes as INT)
+}
+
+/// `bytes` in the largest binary unit it fills, e.g. "1.5GiB".
+pub fn format_size(bytes: INT) -> Result<String, Box<EvalAltResult>> {
+    let bytes = u64::try_from(bytes)
+        .map_err(|_| invalid(format!("Size {} must not be negative", bytes)))?;
+    let (unit, multiplier) = SIZE_UNITS
+        .iter()
+        .rev()
+        .find(|(name, multiplier)| name.ends_with("iB") && bytes >= *multiplier)
+        .unwrap_or(&SIZE_UNITS[0]);
+    if *multiplier == 1 {
+        return Ok(format!("{}B", bytes));
+    }
+    let value = format!("{:.2}", bytes as f64 / *multiplier as f64);
+    let value = value.trim_end_matches('0').trim_end_matches('.');
+    Ok(format!("{}{}", value, unit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_units() {
+        assert_eq!(parse_duration("1h 30m").unwrap(), 5_400_000);
+        assert_eq!(format_duration(5_400_000).unwrap(), "1h 30m");
+        assert!(format_duration(-1).is_err());
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1.5 kb").unwrap(), 1500);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert!(parse_size("3 parsecs").is_err());
+        assert_eq!(format_size(512).unwrap(), "512B");
+        assert_eq!(format_size(3 << 29).unwrap(), "1.5GiB");
+        assert_eq!(format_size(1 << 20).unwrap(), "1MiB");
+    }
+}
@@ -0,0 +1,466 @@
+//! Components from the services of a docker-compose file.
+
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    config::{
+        Build, Component, ComponentHooks, Dependency, DependencyCondition, Healthcheck, Port,
+        Resources, RestartPolicy, Security, Volume,
+    },
+    Error,
+};
+
+#[derive(Debug, Deserialize)]
+struct ComposeFile {
+    #[serde(default)]
+    services: BTreeMap<String, Service>,
+}
+
+/// The parts of a compose service that map to a component, other keys are
+/// ignored.
+#[derive(Debug, Deserialize)]
+struct Service {
+    image: Option<String>,
+    build: Option<ServiceBuild>,
+    #[serde(default)]
+    ports: Vec<ServicePort>,
+    environment: Option<Environment>,
+    depends_on: Option<DependsOn>,
+    command: Option<StringOrList>,
+    entrypoint: Option<StringOrList>,
+    #[serde(default)]
+    volumes: Vec<ServiceVolume>,
+    network_mode: Option<String>,
+    healthcheck: Option<ServiceHealthcheck>,
+    restart: Option<String>,
+    cpus: Option<serde_yaml::Value>,
+    mem_limit: Option<serde_yaml::Value>,
+    pids_limit: Option<i64>,
+    user: Option<String>,
+    #[serde(default)]
+    privileged: bool,
+    #[serde(default)]
+    cap_add: Vec<String>,
+    #[serde(default)]
+    cap_drop: Vec<String>,
+    #[serde(default)]
+    security_opt: Vec<String>,
+    #[serde(default)]
+    devices: Vec<String>,
+    #[serde(default)]
+    profiles: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ServiceHealthcheck {
+    test: Option<StringOrList>,
+    interval: Option<String>,
+    timeout: Option<String>,
+    retries: Option<u32>,
+    #[serde(default)]
+    disable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ServiceBuild {
+    Context(String),
+    Long {
+        context: Option<String>,
+        dockerfile: Option<String>,
+        args: Option<Environment>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ServicePort {
+    Short(serde_yaml::Value),
+    Long {
+        target: u16,
+        published: Option<serde_yaml::Value>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Environment {
+    List(Vec<String>),
+    Map(BTreeMap<String, serde_yaml::Value>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DependsOn {
+    List(Vec<String>),
+    Map(BTreeMap<String, DependsOnCondition>),
+}
+
+#[derive(Debug, Deserialize)]
+struct DependsOnCondition {
+    condition: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ServiceVolume {
+    Short(String),
+    Long {
+        source: Option<String>,
+        target: String,
+    },
+}
+
+/// Container components for the services of the compose file at `path`,
+/// started by default like `docker compose up` does. Relative host paths of
+/// volumes resolve against the directory of the compose file.
+pub fn load_components(path: &str) -> Result<Vec<Component>, Error> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read compose file {}: {}", path, e)))?;
+    let compose: ComposeFile = serde_yaml::from_str(&content)
+        .map_err(|e| Error::Config(format!("Invalid compose file {}: {}", path, e)))?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+    compose
+        .services
+        .into_iter()
+        .map(|(name, service)| {
+            service_component(&name, service, dir)
+                .map_err(|e| Error::Config(format!("Service {} in {}: {}", name, path, e)))
+        })
+        .collect()
+}
+
+fn service_component(name: &str, service: Service, dir: &Path) -> Result<Component, String> {
+    let build = service.build.map(|build| {
+        let (context, containerfile, args) = match build {
+            ServiceBuild::Context(context) => (context, None, None),
+            ServiceBuild::Long {
+                context,
+                dockerfile,
+                args,
+            } => (context.unwrap_or(".".to_string()), dockerfile, args),
+        };
+        let args = match args {
+            None => BTreeMap::new(),
+            Some(Environment::List(list)) => list
+                .iter()
+                .map(|arg| match arg.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (arg.clone(), std::env::var(arg).unwrap_or_default()),
+                })
+                .collect(),
+            Some(Environment::Map(map)) => map
+                .into_iter()
+                .map(|(key, value)| (key, scalar(&value)))
+                .collect(),
+        };
+        Build {
+            context: dir.join(context).to_string_lossy().to_string(),
+            containerfile,
+            args,
+        }
+    });
+    if service.image.is_none() && build.is_none() {
+        return Err("image is missing".to_string());
+    }
+
+    let ports = service
+        .ports
+        .iter()
+        .map(parse_port)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let environment = match service.environment {
+        None => vec![],
+        Some(Environment::List(list)) => list,
+        Some(Environment::Map(map)) => map
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, scalar(&value)))
+            .collect(),
+    };
+
+    let dependencies = match service.depends_on {
+        None => vec![],
+        Some(DependsOn::List(list)) => list.into_iter().map(Dependency::Name).collect(),
+        Some(DependsOn::Map(map)) => map
+            .into_iter()
+            .map(|(component, dep)| match dep.condition.as_deref() {
+                Some("service_healthy") => Dependency::Condition {
+                    component,
+                    condition: DependencyCondition::Healthy,
+                },
+                _ => Dependency::Name(component),
+            })
+            .collect(),
+    };
+
+    let healthcheck = service
+        .healthcheck
+        .filter(|healthcheck| !healthcheck.disable)
+        .and_then(|healthcheck| {
+            let command = match healthcheck.test? {
+                StringOrList::String(s) => vec!["sh".to_string(), "-c".to_string(), s],
+                StringOrList::List(list) => match list.first().map(|s| s.as_str()) {
+                    Some("CMD") => list[1..].to_vec(),
+                    Some("CMD-SHELL") => {
+                        vec!["sh".to_string(), "-c".to_string(), list[1..].join(" ")]
+                    }
+                    _ => return None,
+                },
+            };
+            // Compose gives up after `retries` failed checks
+            let interval = healthcheck.interval.unwrap_or("30s".to_string());
+            let timeout = healthcheck.retries.and_then(|retries| {
+                let interval = humantime::parse_duration(&interval).ok()?;
+                Some(humantime::format_duration(interval * retries).to_string())
+            });
+            Some(Healthcheck {
+                command,
+                http: None,
+                tcp: None,
+                interval: Some(interval),
+                timeout: timeout.or(healthcheck.timeout),
+            })
+        });
+
+    let command = service.command.map(|command| match command {
+        StringOrList::String(s) => s.split_whitespace().map(|s| s.to_string()).collect(),
+        StringOrList::List(list) => list,
+    });
+
+    // `--entrypoint` takes a JSON array for entrypoints with arguments
+    let entrypoint = match service.entrypoint {
+        None => None,
+        Some(StringOrList::String(s)) => Some(s),
+        Some(StringOrList::List(list)) if list.len() == 1 => list.into_iter().next(),
+        Some(StringOrList::List(list)) => {
+            Some(serde_json::to_string(&list).map_err(|e| e.to_string())?)
+        }
+    };
+
+    let mut volumes = vec![];
+    for volume in service.volumes {
+        let (source, target) = match volume {
+            ServiceVolume::Short(s) => {
+                let mut parts = s.split(':');
+                match (parts.next(), parts.next()) {
+                    (Some(source), Some(target)) => (source.to_string(), target.to_string()),
+                    _ => {
+                        log::warn!("Skipping anonymous volume {} of service {}", s, name);
+                        continue;
+                    }
+                }
+            }
+            ServiceVolume::Long {
+                source: Some(source),
+                target,
+            } => (source, target),
+            ServiceVolume::Long { target, .. } => {
+                log::warn!("Skipping anonymous volume {} of service {}", target, name);
+                continue;
+            }
+        };
+        if !source.starts_with('.') && !source.starts_with('/') && !source.starts_with('~') {
+            volumes.push(Volume {
+                name: Some(source),
+                container: target,
+                ..Default::default()
+            });
+            continue;
+        }
+        let host = if source.starts_with('.') {
+            dir.join(&source).to_string_lossy().to_string()
+        } else {
+            source
+        };
+        volumes.push(Volume {
+            host: Some(host),
+            container: target,
+            ..Default::default()
+        });
+    }
+
+    let restart = match service.restart.as_deref() {
+        None | Some("no") => RestartPolicy::Never,
+        Some("always") | Some("unless-stopped") => RestartPolicy::Always,
+        Some(policy) if policy.starts_with("on-failure") => RestartPolicy::OnFailure,
+        Some(policy) => return Err(format!("unknown restart policy {}", policy)),
+    };
+
+    let cpus = match service.cpus.as_ref().map(scalar) {
+        Some(cpus) => Some(cpus.parse().map_err(|_| format!("invalid cpus {}", cpus))?),
+        None => None,
+    };
+    let resources = Resources {
+        cpus,
+        memory: service.mem_limit.as_ref().map(scalar),
+        pids_limit: service.pids_limit,
+    };
+    let security = Security {
+        user: service.user,
+        privileged: service.privileged.then_some(true),
+        cap_add: service.cap_add,
+        cap_drop: service.cap_drop,
+        security_opt: service.security_opt,
+        devices: service.devices,
+    };
+
+    Ok(Component {
+        name: name.to_string(),
+        component_type: "container".to_string(),
+        extends: None,
+        dependencies,
+        start_by_default: true,
+        profiles: service.profiles,
+        ports,
+        containers: vec![],
+        network: service.network_mode,
+        networks: vec![],
+        image: service.image,
+        build,
+        command,
+        entrypoint,
+        environment,
+        workdir: None,
+        shell: false,
+        volumes,
+        secrets: vec![],
+        healthcheck,
+        restart,
+        resources,
+        security,
+        module: None,
+        hooks: ComponentHooks::default(),
+        template: None,
+        params: BTreeMap::new(),
+    })
+}
+
+/// Ports as `8080:80`, `127.0.0.1:8080:80/tcp`, `80` or `{target, published}`.
+/// A port without a host port gets a random one.
+fn parse_port(port: &ServicePort) -> Result<Port, String> {
+    let (host, container) = match port {
+        ServicePort::Short(value) => {
+            let spec = scalar(value);
+            let spec = spec.split('/').next().unwrap_or_default();
+            let parts = spec.rsplitn(3, ':').collect::<Vec<_>>();
+            match parts.as_slice() {
+                [container] => ("0".to_string(), container.to_string()),
+                [container, host, ..] => (host.to_string(), container.to_string()),
+                [] => unreachable!("rsplitn yields at least one part"),
+            }
+        }
+        ServicePort::Long { target, published } => (
+            published.as_ref().map(scalar).unwrap_or("0".to_string()),
+            target.to_string(),
+        ),
+    };
+    let parse = |s: &str| {
+        s.parse::<u16>()
+            .map_err(|_| format!("unsupported port {}, port ranges are not supported", s))
+    };
+    Ok(Port {
+        host: if host.is_empty() { 0 } else { parse(&host)? },
+        container: parse(&container)?,
+    })
+}
+
+fn scalar(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Null => String::new(),
+        value => serde_yaml::to_string(value)
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_components() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("docker-compose.yaml");
+        std::fs::write(
+            &path,
+            r#"
+services:
+  db:
+    image: postgres:16
+    environment:
+      POSTGRES_PASSWORD: postgres
+      POSTGRES_PORT: 5432
+    volumes:
+      - ./data:/var/lib/postgresql/data
+      - cache:/cache
+  api:
+    build:
+      context: ./api
+      args:
+        - VERSION=1.2
+    ports:
+      - "8080:80"
+      - "127.0.0.1:9090:9090/tcp"
+      - "3000"
+    environment:
+      - DATABASE_URL=postgres://db:5432/app
+    depends_on:
+      db:
+        condition: service_healthy
+    command: serve --verbose
+    healthcheck:
+      test: ["CMD", "curl", "-f", "http://localhost/health"]
+      interval: 2s
+      retries: 5
+"#,
+        )
+        .unwrap();
+
+        let components = load_components(path.to_str().unwrap()).unwrap();
+        let api = &components[0];
+        assert_eq!(api.name, "api");
+        let build = api.build.as_ref().unwrap();
+        assert_eq!(build.context, dir.path().join("./api").to_string_lossy());
+        assert_eq!(build.args["VERSION"], "1.2");
+        assert_eq!(
+            api.ports,
+            [(8080, 80), (9090, 9090), (0, 3000)].map(|(host, container)| Port { host, container })
+        );
+        assert_eq!(
+            api.dependencies,
+            [Dependency::Condition {
+                component: "db".to_string(),
+                condition: DependencyCondition::Healthy
+            }]
+        );
+        assert_eq!(api.command.as_ref().unwrap(), &["serve", "--verbose"]);
+        let healthcheck = api.healthcheck.as_ref().unwrap();
+        assert_eq!(
+            healthcheck.command,
+            ["curl", "-f", "http://localhost/health"]
+        );
+        assert_eq!(healthcheck.timeout.as_deref(), Some("10s"));
+
+        let db = &components[1];
+        assert_eq!(
+            db.environment,
+            ["POSTGRES_PASSWORD=postgres", "POSTGRES_PORT=5432"]
+        );
+        assert_eq!(db.volumes.len(), 2);
+        let data = dir.path().join("./data").to_string_lossy().to_string();
+        assert_eq!(db.volumes[0].host, Some(data));
+        assert_eq!(db.volumes[1].args(true).unwrap(), ["-v", "cache:/cache"]);
+    }
+}
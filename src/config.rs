@@ -1,20 +1,510 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
 use clap::ArgMatches;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::Error;
+use crate::{
+    commands, compose,
+    provenance::ReportSigning,
+    remote::Remote,
+    runtime::{ContainerRuntime, PodmanConnection, Runtime},
+    seed::{ComponentSeeds, Seed},
+    Error,
+};
+
+pub const DEFAULT_ARTIFACTS_DIR: &str = "sam-artifacts";
 
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 #[schemars(title = "SAM configuration file")]
 pub struct Config {
     pub name: String,
-    pub base: Option<String>,
+    /// Configs this one is built on, a path or a list merged from left to
+    /// right, this config last. Components with the same name are merged
+    /// field by field.
+    #[serde(default, deserialize_with = "base_files")]
+    #[schemars(with = "BaseFiles")]
+    pub base: Vec<String>,
+    /// CLI running the containers and pods, `podman` by default. Pods need
+    /// podman.
+    pub runtime: Option<Runtime>,
+    /// docker-compose file whose services are added as container components.
+    /// Components of the config with the same name replace them.
+    pub compose: Option<String>,
+    /// Run the components on another host over SSH.
+    pub remote: Option<Remote>,
+    /// Manage the containers through another podman service, e.g. a podman
+    /// machine or a rootful socket.
+    pub podman: Option<PodmanConnection>,
+    /// Components instantiated with `template` by the components of the same
+    /// file, with `{{name}}` and `{{params.<key>}}` replaced.
+    #[serde(default)]
+    pub templates: BTreeMap<String, serde_json::Value>,
     #[serde(default)]
     pub components: Vec<Component>,
+    /// Directory whose `*.yaml` files each define a component or a list of
+    /// them, added to `components` in the order of the file names.
+    pub components_dir: Option<String>,
+    /// Fail to load the config, and the files of `components_dir`, when they
+    /// have keys the config format does not have, e.g. a misspelled
+    /// `enviroment`, instead of ignoring them.
+    #[serde(default)]
+    pub strict: bool,
+    /// Components of the base configs left out of this one, along with their
+    /// seeds.
+    #[serde(default)]
+    pub remove_components: Vec<String>,
     #[serde(default)]
     pub reset: Vec<String>,
     #[serde(default)]
     pub global: Global,
+    /// Arbitrary values exposed to scripts as the `data` constant.
+    #[serde(default)]
+    pub data: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    pub report_upload: Option<ReportUpload>,
+    /// Attach where the report was produced from and sign it.
+    pub report_signing: Option<ReportSigning>,
+    /// Test data loaded into components once they are running, by component
+    /// name.
+    #[serde(default)]
+    pub seeds: HashMap<String, Vec<Seed>>,
+    /// Failures injected into components while the scripts run.
+    pub chaos: Option<Chaos>,
+    /// Deployments the scripts can run against, by name, read with
+    /// `target()` and selected with `--target` or `global.target`.
+    #[serde(default)]
+    pub targets: BTreeMap<String, Target>,
+    /// Disk space reclaimed by `sam reset`.
+    #[serde(default)]
+    pub cleanup: Cleanup,
+}
+
+/// What `sam reset` removes, so that long-lived runners do not slowly fill
+/// their disks with images, volumes and build cache.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Cleanup {
+    /// Remove the named volumes created for the components, on by default.
+    #[serde(default = "Cleanup::default_volumes")]
+    pub volumes: bool,
+    /// Prune the dangling images older than this, e.g. `7d`.
+    pub images_older_than: Option<String>,
+    /// Shrink the build cache to this size, e.g. `10GB`. Podman has no size
+    /// limit and removes its build cache entirely.
+    pub build_cache: Option<String>,
+}
+
+impl Cleanup {
+    fn default_volumes() -> bool {
+        true
+    }
+
+    pub fn images_older_than(&self) -> Result<Option<Duration>, Error> {
+        self.images_older_than
+            .as_deref()
+            .map(|age| {
+                humantime::parse_duration(age).map_err(|e| {
+                    Error::Config(format!("Invalid cleanup images_older_than {}: {}", age, e))
+                })
+            })
+            .transpose()
+    }
+}
+
+impl Default for Cleanup {
+    fn default() -> Self {
+        Self {
+            volumes: Self::default_volumes(),
+            images_older_than: None,
+            build_cache: None,
+        }
+    }
+}
+
+/// A deployment the scripts run against. `${VAR}` in the values is replaced
+/// by the environment variable.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Target {
+    pub base_url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    /// Extra request headers, e.g. an API key.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// The deployment runs elsewhere: when it is selected the components,
+    /// their seeds and chaos are left out and only the scripts run.
+    #[serde(default)]
+    pub external: bool,
+}
+
+impl Target {
+    /// The target as a Rhai map, with the environment variables expanded.
+    pub fn to_map(&self, name: &str) -> ::rhai::Map {
+        let expand = |value: &Option<String>| match value {
+            Some(value) => expand_env_vars(value).into(),
+            None => ::rhai::Dynamic::UNIT,
+        };
+        let headers: ::rhai::Map = self
+            .headers
+            .iter()
+            .map(|(key, value)| (key.into(), expand_env_vars(value).into()))
+            .collect();
+        let mut map = ::rhai::Map::new();
+        map.insert("name".into(), name.into());
+        map.insert("base_url".into(), expand_env_vars(&self.base_url).into());
+        map.insert("username".into(), expand(&self.username));
+        map.insert("password".into(), expand(&self.password));
+        map.insert("token".into(), expand(&self.token));
+        map.insert("headers".into(), headers.into());
+        map.insert("external".into(), self.external.into());
+        map
+    }
+}
+
+/// Where to POST the test report after the run.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ReportUpload {
+    pub url: String,
+    /// Extra request headers, `${VAR}` is replaced by the environment variable.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// Where the artifacts of failed runs are POSTed as a gzipped tarball,
+    /// not uploaded if unset.
+    pub artifacts_url: Option<String>,
+    /// How long each upload may take, e.g. `30s`, 1 minute by default.
+    pub timeout: Option<String>,
+}
+
+impl ReportUpload {
+    pub fn timeout(&self) -> Result<Duration, Error> {
+        match &self.timeout {
+            Some(timeout) => parse_timeout("report_upload", timeout),
+            None => Ok(Duration::from_secs(60)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+/// `path` with symlinks and `..` resolved, as it is if it does not exist.
+fn canonical_path(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+/// Replace `${VAR}` with the value of the environment variable `VAR`, or an
+/// empty string when it is not set.
+pub fn expand_env_vars(value: &str) -> String {
+    let re = regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    re.replace_all(value, |caps: &regex::Captures| {
+        std::env::var(&caps[1]).unwrap_or_default()
+    })
+    .into_owned()
+}
+
+/// Load the variables of a `.env` file into the process environment. Variables
+/// that are already set are not overridden. A missing file is only an error
+/// when `required` is set. Returns whether the file was loaded.
+pub fn load_dotenv(path: &str, required: bool) -> Result<bool, Error> {
+    match dotenvy::from_path(path) {
+        Ok(()) => Ok(true),
+        Err(dotenvy::Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+            Ok(false)
+        }
+        Err(e) => Err(Error::Config(format!(
+            "Failed to load environment file {}: {}",
+            path, e
+        ))),
+    }
+}
+
+/// The non-empty documents of a YAML file, with their `<<` merge keys
+/// applied. Anchors are only visible in the document defining them.
+pub fn yaml_documents(yaml: &str) -> Result<Vec<serde_yaml::Value>, serde_yaml::Error> {
+    let mut documents = vec![];
+    for document in serde_yaml::Deserializer::from_str(yaml) {
+        let mut value = serde_yaml::Value::deserialize(document)?;
+        if value.is_null() {
+            continue;
+        }
+        value.apply_merge()?;
+        documents.push(value);
+    }
+    Ok(documents)
+}
+
+/// The documents of a config as one: the components of later documents are
+/// added to those of the first, their other keys replace those of the first.
+fn merge_documents(
+    documents: Vec<serde_yaml::Value>,
+) -> Result<serde_yaml::Value, serde_yaml::Error> {
+    let mut documents = documents.into_iter();
+    let Some(mut first) = documents.next() else {
+        return Ok(serde_yaml::Value::Null);
+    };
+    // Not a mapping, left for the deserializer to report
+    let Some(merged) = first.as_mapping_mut() else {
+        return Ok(first);
+    };
+    for (i, document) in documents.enumerate() {
+        let serde_yaml::Value::Mapping(document) = document else {
+            return Err(serde::de::Error::custom(format!(
+                "document {} of the config is not a mapping",
+                i + 2
+            )));
+        };
+        for (key, value) in document {
+            match (merged.get_mut(&key), value) {
+                (
+                    Some(serde_yaml::Value::Sequence(components)),
+                    serde_yaml::Value::Sequence(more),
+                ) if key.as_str() == Some("components") => components.extend(more),
+                (_, value) => {
+                    merged.insert(key, value);
+                }
+            }
+        }
+    }
+    Ok(first)
+}
+
+/// The `*.yaml` and `*.yml` files of `dir`, sorted.
+fn yaml_files(dir: &str) -> Result<Vec<String>, Error> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| Error::Config(format!("Failed to read components_dir {}: {}", dir, e)))?;
+    let mut files = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == "yaml" || extension == "yml")
+        })
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// The components of the files of `dir` with the file defining them. A
+/// document of a file is a component, a list of them or a mapping with
+/// `components`, which may use the `templates` of the config.
+fn component_files(
+    dir: &str,
+    templates: &BTreeMap<String, serde_json::Value>,
+    strict: bool,
+) -> Result<Vec<(String, Component)>, Error> {
+    let templates = serde_yaml::to_value(templates).map_err(|e| Error::Config(e.to_string()))?;
+    let mut components = vec![];
+    for file in yaml_files(dir)? {
+        let error = |e: String| Error::Config(e).context(format!("Failed to load {}", file));
+        let yaml = std::fs::read_to_string(&file).map_err(|e| error(e.to_string()))?;
+        let mut entries = vec![];
+        for document in yaml_documents(&yaml).map_err(|e| error(e.to_string()))? {
+            match document {
+                serde_yaml::Value::Sequence(items) => entries.extend(items),
+                document => match document.get("components") {
+                    Some(serde_yaml::Value::Sequence(items)) => entries.extend(items.clone()),
+                    _ => entries.push(document),
+                },
+            }
+        }
+        let mut value = serde_yaml::Mapping::new();
+        value.insert("components".into(), entries.into());
+        let mut value = serde_yaml::Value::Mapping(value);
+        if strict {
+            let unknown = crate::lint::unknown_keys(std::slice::from_ref(&value))?;
+            if !unknown.is_empty() {
+                return Err(error(unknown.join("; ")));
+            }
+        }
+        value["templates"] = templates.clone();
+        expand_templates(&mut value).map_err(|e| error(e.to_string()))?;
+        let file_components: Vec<Component> = serde_yaml::from_value(value["components"].clone())
+            .map_err(|e| error(e.to_string()))?;
+        components.extend(file_components.into_iter().map(|c| (file.clone(), c)));
+    }
+    Ok(components)
+}
+
+/// Replace the components with a `template` by the entry of `templates`, with
+/// the placeholders replaced and the fields of the component added.
+fn expand_templates(cfg: &mut serde_yaml::Value) -> Result<(), serde_yaml::Error> {
+    let Some(templates) = cfg.get("templates").cloned() else {
+        return Ok(());
+    };
+    let Some(components) = cfg
+        .get_mut("components")
+        .and_then(serde_yaml::Value::as_sequence_mut)
+    else {
+        return Ok(());
+    };
+    for component in components {
+        let Some(template_name) = component.get("template").and_then(|t| t.as_str()) else {
+            continue;
+        };
+        let name = component
+            .get("name")
+            .and_then(|n| n.as_str())
+            .unwrap_or_default();
+        let error = |msg: String| {
+            serde::de::Error::custom(format!(
+                "Component {} of template {}: {}",
+                name, template_name, msg
+            ))
+        };
+        let Some(serde_yaml::Value::Mapping(template)) = templates.get(template_name) else {
+            return Err(error(
+                "the template is not a mapping in templates".to_string(),
+            ));
+        };
+        let empty = serde_yaml::Mapping::new();
+        let params = match component.get("params") {
+            Some(serde_yaml::Value::Mapping(params)) => params,
+            None => &empty,
+            Some(_) => return Err(error("params is not a mapping".to_string())),
+        };
+        let mut expanded = serde_yaml::Value::Mapping(template.clone());
+        replace_params(&mut expanded, name, params).map_err(error)?;
+        let serde_yaml::Value::Mapping(mut expanded) = expanded else {
+            unreachable!("the template is a mapping");
+        };
+        for (key, value) in component.as_mapping().into_iter().flatten() {
+            expanded.insert(key.clone(), value.clone());
+        }
+        *component = serde_yaml::Value::Mapping(expanded);
+    }
+    Ok(())
+}
+
+/// Replace `{{name}}` and `{{params.<key>}}` in the strings of `value`. A
+/// string that is only a placeholder becomes the value of the parameter, e.g.
+/// a number for a port.
+fn replace_params(
+    value: &mut serde_yaml::Value,
+    name: &str,
+    params: &serde_yaml::Mapping,
+) -> Result<(), String> {
+    let re = regex::Regex::new(r"\{\{\s*(name|params\.([A-Za-z0-9_-]+))\s*\}\}").unwrap();
+    let param = |caps: &regex::Captures| match caps.get(2) {
+        None => Ok(serde_yaml::Value::from(name)),
+        Some(key) => params
+            .get(key.as_str())
+            .cloned()
+            .ok_or_else(|| format!("{} is not set in params", &caps[0])),
+    };
+    match value {
+        serde_yaml::Value::String(text) => {
+            if let Some(caps) = re.captures(text).filter(|caps| caps[0].len() == text.len()) {
+                *value = param(&caps)?;
+                return Ok(());
+            }
+            let mut result = Ok(());
+            let replaced = re.replace_all(text, |caps: &regex::Captures| {
+                match param(caps).and_then(|value| match value {
+                    serde_yaml::Value::String(s) => Ok(s),
+                    serde_yaml::Value::Number(n) => Ok(n.to_string()),
+                    serde_yaml::Value::Bool(b) => Ok(b.to_string()),
+                    _ => Err(format!("{} is not a string, number or bool", &caps[0])),
+                }) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        result = Err(e);
+                        String::new()
+                    }
+                }
+            });
+            *text = replaced.into_owned();
+            result
+        }
+        serde_yaml::Value::Sequence(values) => values
+            .iter_mut()
+            .try_for_each(|value| replace_params(value, name, params)),
+        serde_yaml::Value::Mapping(values) => values
+            .values_mut()
+            .try_for_each(|value| replace_params(value, name, params)),
+        _ => Ok(()),
+    }
+}
+
+/// Random restarts, pauses and kills of components during the run, for basic
+/// resilience testing.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct Chaos {
+    /// Container, pod and process components failures are injected into.
+    pub targets: Vec<String>,
+    pub interval: ChaosInterval,
+    /// Failures to pick from, all of them by default.
+    #[serde(default = "ChaosAction::all")]
+    pub actions: Vec<ChaosAction>,
+    /// How long a paused target stays paused, `5s` by default.
+    pub pause_for: Option<String>,
+    /// Seed of the random choices, to inject the same failures again. A
+    /// random seed is logged otherwise.
+    pub seed: Option<u64>,
+}
+
+/// Time between two failures, uniformly distributed between `min` and
+/// `max`, like `10s` and `1m`.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ChaosInterval {
+    pub min: String,
+    pub max: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChaosAction {
+    /// Restart the containers, or stop the process with SIGTERM so that its
+    /// restart policy starts it again.
+    Restart,
+    /// Freeze the target for `pause_for`, with SIGSTOP for processes.
+    Pause,
+    /// Kill the target with SIGKILL.
+    Kill,
+}
+
+impl ChaosAction {
+    fn all() -> Vec<Self> {
+        vec![Self::Restart, Self::Pause, Self::Kill]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Restart => "restart",
+            Self::Pause => "pause",
+            Self::Kill => "kill",
+        }
+    }
+}
+
+/// Shell commands or `.rhai` files executed around the test run.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct Hooks {
+    /// Executed after the environment started, before the first script.
+    #[serde(default)]
+    pub pre_run: Vec<String>,
+    /// Executed after all scripts ran, whether they passed or not.
+    #[serde(default)]
+    pub post_run: Vec<String>,
+    /// Executed after post_run when a script or assertion failed.
+    #[serde(default)]
+    pub post_failure: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
@@ -29,37 +519,484 @@ pub struct Global {
     pub skip: Option<String>,
     #[serde(default)]
     pub no_fail_fast: bool,
+    /// Script run once before all other scripts, e.g. to store shared
+    /// credentials with `set`. The tests are not run if it fails.
+    pub setup_script: Option<String>,
+    /// Script run once after all other scripts, even if they failed.
+    pub teardown_script: Option<String>,
+    /// Order of the scripts in script directories, `config` when `order` is
+    /// set and `lex` otherwise.
+    pub script_order: Option<ScriptOrder>,
+    /// Scripts of a script directory to run first, by file name or path.
+    #[serde(default)]
+    pub order: Vec<String>,
+    /// Keep running the remaining scripts after one fails to compile or
+    /// stops with an error.
+    #[serde(default)]
+    pub continue_on_error: bool,
+    /// Keep the per-test temporary directories of failed tests.
+    #[serde(default)]
+    pub keep_failed_artifacts: bool,
+    /// Directory that relative paths are resolved against, relative to the
+    /// directory of the config file. Defaults to the current directory.
+    pub workdir: Option<String>,
+    /// Directory for files saved with `save_artifact`, kept after the run.
+    /// Defaults to [`DEFAULT_ARTIFACTS_DIR`].
+    pub artifacts_dir: Option<String>,
     #[serde(default)]
     pub reset_once: bool,
     #[serde(default)]
     pub force: bool,
     #[serde(default)]
     pub module_dirs: Vec<String>,
+    /// JSON, YAML or TOML files merged into the `data` constant, in order.
+    #[serde(default)]
+    pub data_files: Vec<String>,
+    /// Dotenv files loaded before the components start, relative to the
+    /// workdir. Variables already set, e.g. in the shell or `.env`, are kept.
+    #[serde(default)]
+    pub env_files: Vec<String>,
+    #[serde(default)]
+    pub output: Output,
+    /// Default retry policy of the http commands, overridden by the options
+    /// of each request.
+    #[serde(default)]
+    pub http_retry: HttpRetry,
+    /// Deduplication and rate limiting of script `log` output and captured
+    /// component logs, off by default.
+    pub log_limits: Option<LogLimits>,
+    /// Rhai engine options applied to every script.
+    #[serde(default)]
+    pub engine: EngineOptions,
+    /// Rhai engine options for individual scripts or script directories,
+    /// overriding `engine`.
+    #[serde(default)]
+    pub script_options: HashMap<PathBuf, EngineOptions>,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// Name of the entry of `targets` the scripts run against.
+    pub target: Option<String>,
+    /// Profiles whose components start by default, besides the components
+    /// without profiles.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    /// Print the commands starting the environment instead of running them,
+    /// set with `--dry-run`.
+    #[serde(skip)]
+    pub dry_run: bool,
+}
+
+/// How long external commands may run before they are killed, e.g. `30s`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct Timeouts {
+    /// Commands of `exec` and `container_exec`, unlimited by default.
+    pub exec: Option<String>,
+    /// Container runtime commands managing the components, e.g. `run` or
+    /// `rm`, 10 minutes by default.
+    pub runtime: Option<String>,
+}
+
+impl Timeouts {
+    pub fn exec(&self) -> Result<Option<Duration>, Error> {
+        self.exec
+            .as_deref()
+            .map(|timeout| parse_timeout("exec", timeout))
+            .transpose()
+    }
+
+    pub fn runtime(&self) -> Result<Duration, Error> {
+        match &self.runtime {
+            Some(timeout) => parse_timeout("runtime", timeout),
+            None => Ok(Duration::from_secs(600)),
+        }
+    }
+}
+
+fn parse_timeout(name: &str, timeout: &str) -> Result<Duration, Error> {
+    humantime::parse_duration(timeout)
+        .map_err(|e| Error::Config(format!("Invalid {} timeout {}: {}", name, timeout, e)))
+}
+
+/// How test results are printed to the console.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct Output {
+    /// `ascii` replaces the emoji status markers with plain text.
+    pub style: Option<OutputStyle>,
+    /// `logfmt` or `json` prints the test progress as one line per test and
+    /// describe block.
+    pub format: Option<LogFormat>,
+    /// Words printed for succeeded, failed and skipped describe blocks.
+    pub succeeded: Option<String>,
+    pub failed: Option<String>,
+    pub skipped: Option<String>,
+    /// Lines of a diff shown before the rest is cut, 200 by default.
+    pub diff_max_lines: Option<usize>,
+    /// Unchanged lines shown around each change of a diff, 3 by default.
+    pub diff_context: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+pub struct LogLimits {
+    /// Replace repeats of the previous line with `last message repeated N
+    /// times`.
+    #[serde(default)]
+    pub dedup: bool,
+    /// Lines kept per second, the others are dropped and counted.
+    pub max_per_second: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct HttpRetry {
+    /// Attempts after the first one, 0 by default.
+    pub retries: Option<u64>,
+    /// Status codes and errors that are retried, by default 502, 503, 504
+    /// and `connect`.
+    pub retry_on: Option<Vec<RetryOn>>,
+    /// Delay before the first retry, 100ms by default.
+    pub backoff: Option<String>,
+    /// Factor the delay grows by after each retry, 2 by default.
+    pub backoff_factor: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(untagged)]
+pub enum RetryOn {
+    Status(u16),
+    Error(RetryError),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryError {
+    /// The connection could not be established.
+    Connect,
+}
+
+/// Order in which the scripts of a script directory run.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptOrder {
+    /// By file name, with numeric prefixes like `10-` compared as numbers.
+    Lex,
+    /// Shuffled on every run, to find scripts that depend on each other.
+    Random,
+    /// The scripts listed in `order` first, the rest by file name.
+    Config,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStyle {
+    #[default]
+    Unicode,
+    Ascii,
+}
+
+/// How logs and test progress are printed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Indented, colored lines.
+    #[default]
+    Pretty,
+    Logfmt,
+    /// One JSON object per line.
+    Json,
+}
+
+impl LogFormat {
+    /// The format named by `--log-format`, pretty for unknown names.
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "logfmt" => Self::Logfmt,
+            "json" => Self::Json,
+            _ => Self::Pretty,
+        }
+    }
+}
+
+impl Output {
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            style: other.style.or(self.style),
+            format: other.format.or(self.format),
+            succeeded: other.succeeded.clone().or(self.succeeded.clone()),
+            failed: other.failed.clone().or(self.failed.clone()),
+            skipped: other.skipped.clone().or(self.skipped.clone()),
+            diff_max_lines: other.diff_max_lines.or(self.diff_max_lines),
+            diff_context: other.diff_context.or(self.diff_context),
+        }
+    }
+
+    /// Whether progress is printed as logfmt or JSON lines.
+    pub fn structured(&self) -> bool {
+        self.format
+            .is_some_and(|format| format != LogFormat::Pretty)
+    }
+
+    fn is_ascii(&self) -> bool {
+        self.style == Some(OutputStyle::Ascii)
+    }
+
+    pub fn passed_marker(&self) -> &str {
+        if self.is_ascii() {
+            "[ok]"
+        } else {
+            "✅"
+        }
+    }
+
+    pub fn failed_marker(&self) -> &str {
+        if self.is_ascii() {
+            "[FAIL]"
+        } else {
+            "😭"
+        }
+    }
+
+    pub fn skipped_marker(&self) -> &str {
+        if self.is_ascii() {
+            "[skip]"
+        } else {
+            "⏭️"
+        }
+    }
+
+    pub fn succeeded_word(&self) -> &str {
+        self.succeeded.as_deref().unwrap_or("succeeded")
+    }
+
+    pub fn failed_word(&self) -> &str {
+        self.failed.as_deref().unwrap_or("failed")
+    }
+
+    pub fn skipped_word(&self) -> &str {
+        self.skipped.as_deref().unwrap_or("skipped")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct EngineOptions {
+    pub optimization_level: Option<OptimizationLevel>,
+    pub max_call_levels: Option<usize>,
+    pub max_expr_depth: Option<usize>,
+    pub strict_variables: Option<bool>,
+}
+
+/// Rhai's `full` level is left out on purpose: it evaluates registered
+/// commands at compile time, which breaks anything with side effects.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizationLevel {
+    None,
+    Simple,
+}
+
+impl EngineOptions {
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            optimization_level: other.optimization_level.or(self.optimization_level),
+            max_call_levels: other.max_call_levels.or(self.max_call_levels),
+            max_expr_depth: other.max_expr_depth.or(self.max_expr_depth),
+            strict_variables: other.strict_variables.or(self.strict_variables),
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             name: "test".to_string(),
-            base: None,
+            base: vec![],
+            runtime: None,
+            compose: None,
+            remote: None,
+            podman: None,
+            templates: BTreeMap::new(),
             components: vec![],
+            components_dir: None,
+            strict: false,
+            remove_components: vec![],
             reset: vec![],
             global: Global::default(),
+            data: serde_json::Map::new(),
+            hooks: Hooks::default(),
+            report_upload: None,
+            report_signing: None,
+            seeds: HashMap::new(),
+            chaos: None,
+            targets: BTreeMap::new(),
+            cleanup: Cleanup::default(),
         }
     }
 }
 
 impl Config {
     pub fn load(path: &str) -> Result<Self, Error> {
-        let cfg = std::fs::read_to_string(path).map_err(|e| Error::Config(e.to_string()))?;
-        let mut cfg = Self::from_yaml(&cfg).map_err(|e| Error::Config(e.to_string()))?;
-        if let Some(base) = &cfg.base {
-            let base_cfg = Self::load(base)?;
+        let mut cfg = Self::load_layer(path, &mut vec![], &mut vec![])?;
+        cfg.resolve_extends()
+            .map_err(|e| e.context(format!("Failed to load config {}", path)))?;
+        if let Some(component) = cfg.components.iter().find(|c| c.component_type.is_empty()) {
+            return Err(Error::Config(format!(
+                "Component {} has no type, set one of container, pod, process or job",
+                component.name
+            ))
+            .context(format!("Failed to load config {}", path)));
+        }
+        Ok(cfg)
+    }
+
+    /// The config at `path` merged into its bases, which may still have
+    /// components without a type when it only overrides a later base.
+    /// `chain` holds the configs including this one, by canonical path, and
+    /// `loaded` all the configs loaded so far, which are not merged again when
+    /// several configs share a base.
+    fn load_layer(
+        path: &str,
+        chain: &mut Vec<(PathBuf, String)>,
+        loaded: &mut Vec<PathBuf>,
+    ) -> Result<Self, Error> {
+        let load_error =
+            |e: String| Error::Config(e).context(format!("Failed to load config {}", path));
+        let yaml = std::fs::read_to_string(path).map_err(|e| load_error(e.to_string()))?;
+        let mut cfg = Self::from_yaml(&yaml).map_err(|e| load_error(e.to_string()))?;
+        if cfg.strict {
+            let documents = yaml_documents(&yaml).map_err(|e| load_error(e.to_string()))?;
+            let unknown = crate::lint::unknown_keys(&documents)?;
+            if !unknown.is_empty() {
+                return Err(load_error(unknown.join("; ")));
+            }
+        }
+        if let Some(dir) = &cfg.components_dir {
+            for (file, component) in component_files(dir, &cfg.templates, cfg.strict)? {
+                if cfg.get_component(&component.name).is_some() {
+                    return Err(load_error(format!(
+                        "Component {} of {} is already defined",
+                        component.name, file
+                    )));
+                }
+                cfg.components.push(component);
+            }
+        }
+        // Like the paths of a compose file, secret files are relative to the
+        // config defining them
+        let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+        for secret in cfg.components.iter_mut().flat_map(|c| c.secrets.iter_mut()) {
+            secret.resolve_source(dir);
+        }
+        if let Some(compose) = &cfg.compose {
+            let services = compose::load_components(compose)?;
+            let services = services
+                .into_iter()
+                .filter(|service| cfg.get_component(&service.name).is_none())
+                .collect::<Vec<_>>();
+            cfg.components.splice(0..0, services);
+        }
+        chain.push((canonical_path(path), path.to_string()));
+        loaded.push(canonical_path(path));
+        let mut base_cfg: Option<Self> = None;
+        for base in &cfg.base {
+            let canonical = canonical_path(base);
+            if chain.iter().any(|(included, _)| *included == canonical) {
+                let names = chain
+                    .iter()
+                    .map(|(_, name)| name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(Error::Config(format!(
+                    "Base configs include each other: {} -> {}",
+                    names.join(" -> "),
+                    base
+                )));
+            }
+            if loaded.contains(&canonical) {
+                log::debug!("Base config {} of {} is already loaded", base, path);
+                continue;
+            }
+            let layer = Self::load_layer(base, chain, loaded)?;
+            base_cfg = Some(match base_cfg {
+                Some(base_cfg) => base_cfg.merge(&layer)?,
+                None => layer,
+            });
+        }
+        chain.pop();
+        if let Some(base_cfg) = base_cfg {
             cfg = base_cfg.merge(&cfg)?;
         }
         Ok(cfg)
     }
 
+    /// Merge the components with `extends` into the components they extend,
+    /// which may extend others in turn.
+    fn resolve_extends(&mut self) -> Result<(), Error> {
+        let mut resolved = vec![];
+        for component in &self.components {
+            let mut chain = vec![component];
+            while let Some(parent) = &chain.last().unwrap().extends {
+                let parent = self.get_component(parent).ok_or_else(|| {
+                    Error::Config(format!(
+                        "Component {} extends unknown component {}",
+                        chain.last().unwrap().name,
+                        parent
+                    ))
+                })?;
+                if chain.iter().any(|c| c.name == parent.name) {
+                    let names = chain.iter().map(|c| c.name.as_str()).collect::<Vec<_>>();
+                    return Err(Error::Config(format!(
+                        "Components extend each other: {} -> {}",
+                        names.join(" -> "),
+                        parent.name
+                    )));
+                }
+                chain.push(parent);
+            }
+            let mut chain = chain.into_iter().rev();
+            let first = chain.next().unwrap().clone();
+            let mut merged = chain.fold(first, |merged, component| merged.merge(component));
+            merged.name = component.name.clone();
+            merged.start_by_default = component.start_by_default;
+            resolved.push(merged);
+        }
+        self.components = resolved;
+        Ok(())
+    }
+
+    pub fn container_runtime(&self) -> Arc<dyn ContainerRuntime> {
+        self.runtime
+            .unwrap_or_default()
+            .container_runtime(self.remote.as_ref(), self.podman.as_ref())
+    }
+
+    /// The config file at `path` and the base files it is built on.
+    pub fn source_files(path: &str) -> Result<Vec<String>, Error> {
+        let mut files = vec![];
+        Self::add_source_files(path, &mut files, &mut vec![])?;
+        Ok(files)
+    }
+
+    // The bases already listed are skipped, which also stops at cycles
+    fn add_source_files(
+        path: &str,
+        files: &mut Vec<String>,
+        listed: &mut Vec<PathBuf>,
+    ) -> Result<(), Error> {
+        let canonical = canonical_path(path);
+        if listed.contains(&canonical) {
+            return Ok(());
+        }
+        listed.push(canonical);
+        let cfg = std::fs::read_to_string(path).map_err(|e| Error::Config(e.to_string()))?;
+        let cfg = Self::from_yaml(&cfg).map_err(|e| Error::Config(e.to_string()))?;
+        files.push(path.to_string());
+        if let Some(dir) = &cfg.components_dir {
+            files.extend(yaml_files(dir)?);
+        }
+        files.extend(cfg.compose.clone());
+        for base in &cfg.base {
+            Self::add_source_files(base, files, listed)?;
+        }
+        Ok(())
+    }
+
     pub fn merge(&self, other: &Self) -> Result<Self, Error> {
         let mut result = self.clone();
         for component in &other.components {
@@ -68,11 +1005,81 @@ impl Config {
                 .iter()
                 .position(|c| c.name == component.name)
             {
-                result.components[pos] = component.clone();
+                result.components[pos] = result.components[pos].merge(component);
             } else {
                 result.components.push(component.clone());
             }
         }
+        for name in &other.remove_components {
+            let Some(pos) = result.components.iter().position(|c| &c.name == name) else {
+                return Err(Error::Config(format!(
+                    "Cannot remove component {}, it is not in the base config",
+                    name
+                )));
+            };
+            result.components.remove(pos);
+            result.seeds.remove(name);
+        }
+        for component in &result.components {
+            if let Some(dependency) = component.dependencies.iter().find(|dependency| {
+                other
+                    .remove_components
+                    .iter()
+                    .any(|n| n == dependency.name())
+            }) {
+                return Err(Error::Config(format!(
+                    "Component {} depends on the removed component {}",
+                    component.name,
+                    dependency.name()
+                )));
+            }
+        }
+
+        for (key, value) in &other.data {
+            result.data.insert(key.clone(), value.clone());
+        }
+
+        if !other.hooks.pre_run.is_empty() {
+            result.hooks.pre_run = other.hooks.pre_run.clone();
+        }
+        if !other.hooks.post_run.is_empty() {
+            result.hooks.post_run = other.hooks.post_run.clone();
+        }
+        if !other.hooks.post_failure.is_empty() {
+            result.hooks.post_failure = other.hooks.post_failure.clone();
+        }
+
+        result.strict |= other.strict;
+        result.runtime = other.runtime.or(self.runtime);
+        if other.compose.is_some() {
+            result.compose = other.compose.clone();
+        }
+        if other.remote.is_some() {
+            result.remote = other.remote.clone();
+        }
+        if other.podman.is_some() {
+            result.podman = other.podman.clone();
+        }
+
+        if other.report_upload.is_some() {
+            result.report_upload = other.report_upload.clone();
+        }
+        if other.report_signing.is_some() {
+            result.report_signing = other.report_signing.clone();
+        }
+
+        for (component, seeds) in &other.seeds {
+            result.seeds.insert(component.clone(), seeds.clone());
+        }
+        if other.chaos.is_some() {
+            result.chaos = other.chaos.clone();
+        }
+        for (name, target) in &other.targets {
+            result.targets.insert(name.clone(), target.clone());
+        }
+        if other.cleanup != Cleanup::default() {
+            result.cleanup = other.cleanup.clone();
+        }
 
         // Merge global settings
         if !other.global.scripts.is_empty() {
@@ -81,6 +1088,35 @@ impl Config {
         if !other.global.module_dirs.is_empty() {
             result.global.module_dirs = other.global.module_dirs.clone();
         }
+        result.global.output = result.global.output.merge(&other.global.output);
+        result.global.engine = result.global.engine.merge(&other.global.engine);
+        for (script, options) in &other.global.script_options {
+            result
+                .global
+                .script_options
+                .insert(script.clone(), options.clone());
+        }
+        if other.global.log_limits.is_some() {
+            result.global.log_limits = other.global.log_limits.clone();
+        }
+        if !other.global.data_files.is_empty() {
+            result.global.data_files = other.global.data_files.clone();
+        }
+        if !other.global.env_files.is_empty() {
+            result.global.env_files = other.global.env_files.clone();
+        }
+        if other.global.workdir.is_some() {
+            result.global.workdir = other.global.workdir.clone();
+        }
+        if other.global.timeouts.exec.is_some() {
+            result.global.timeouts.exec = other.global.timeouts.exec.clone();
+        }
+        if other.global.timeouts.runtime.is_some() {
+            result.global.timeouts.runtime = other.global.timeouts.runtime.clone();
+        }
+        if other.global.artifacts_dir.is_some() {
+            result.global.artifacts_dir = other.global.artifacts_dir.clone();
+        }
         if other.global.delay.is_some() {
             result.global.delay = other.global.delay.clone();
         }
@@ -93,15 +1129,40 @@ impl Config {
         if other.global.skip.is_some() {
             result.global.skip = other.global.skip.clone();
         }
+        if other.global.target.is_some() {
+            result.global.target = other.global.target.clone();
+        }
+        if !other.global.profiles.is_empty() {
+            result.global.profiles = other.global.profiles.clone();
+        }
         result.global.reset_once |= other.global.reset_once;
         result.global.force |= other.global.force;
         result.global.keep_running |= other.global.keep_running;
+        result.global.keep_failed_artifacts |= other.global.keep_failed_artifacts;
+        result.global.continue_on_error |= other.global.continue_on_error;
+        if other.global.setup_script.is_some() {
+            result.global.setup_script = other.global.setup_script.clone();
+        }
+        if other.global.teardown_script.is_some() {
+            result.global.teardown_script = other.global.teardown_script.clone();
+        }
+        if other.global.script_order.is_some() {
+            result.global.script_order = other.global.script_order;
+        }
+        if !other.global.order.is_empty() {
+            result.global.order = other.global.order.clone();
+        }
 
         Ok(result)
     }
 
+    /// Parse a config file, which may be split in several `---` documents
+    /// and use `<<` merge keys of anchors.
     pub fn from_yaml(yaml: &str) -> Result<Self, serde_yaml::Error> {
-        serde_yaml::from_str(yaml)
+        let documents = yaml_documents(yaml)?;
+        let mut cfg = merge_documents(documents)?;
+        expand_templates(&mut cfg)?;
+        serde_yaml::from_value(cfg)
     }
 
     pub fn read_flags(&mut self, args: &ArgMatches) -> Result<(), Error> {
@@ -113,6 +1174,27 @@ impl Config {
             }
         }
 
+        if let Some(workdir) = args.get_one::<String>("workdir") {
+            let workdir = std::path::absolute(workdir).map_err(|e| Error::Config(e.to_string()))?;
+            log::debug!("Setting workdir from command line: {}", workdir.display());
+            self.global.workdir = Some(workdir.to_string_lossy().into_owned());
+        }
+
+        if args.get_flag("ascii") {
+            log::debug!("Setting output style from command line: ascii");
+            self.global.output.style = Some(OutputStyle::Ascii);
+        }
+
+        if let Some(format) = args.get_one::<String>("log-format") {
+            log::debug!("Setting log format from command line: {}", format);
+            self.global.output.format = Some(LogFormat::parse(format));
+        }
+
+        if let Some(artifacts_dir) = args.get_one::<String>("artifacts-dir") {
+            log::debug!("Setting artifacts_dir from command line: {}", artifacts_dir);
+            self.global.artifacts_dir = Some(artifacts_dir.to_string());
+        }
+
         if let Some(delay) = args.get_one::<String>("delay") {
             log::debug!("Setting delay from command line: {}", delay);
             self.global.delay = Some(delay.to_string());
@@ -139,11 +1221,36 @@ impl Config {
             self.global.module_dirs = dirs;
         }
 
+        if let Some(data_files) = args.get_many::<String>("data-file") {
+            let files: Vec<String> = data_files.map(|s| s.to_string()).collect();
+            log::debug!("Setting data files from command line: {:?}", files);
+            self.global.data_files = files;
+        }
+
         if args.get_flag("no-fail-fast") {
             log::debug!("Setting no_fail_fast from command line: true");
             self.global.no_fail_fast = true;
         }
 
+        if let Some(order) = args.get_one::<String>("script-order") {
+            log::debug!("Setting script_order from command line: {}", order);
+            self.global.script_order = Some(match order.as_str() {
+                "random" => ScriptOrder::Random,
+                "config" => ScriptOrder::Config,
+                _ => ScriptOrder::Lex,
+            });
+        }
+
+        if args.get_flag("continue-on-error") {
+            log::debug!("Setting continue_on_error from command line: true");
+            self.global.continue_on_error = true;
+        }
+
+        if args.get_flag("keep-failed-artifacts") {
+            log::debug!("Setting keep_failed_artifacts from command line: true");
+            self.global.keep_failed_artifacts = true;
+        }
+
         if args.get_flag("keep-running") {
             log::debug!("Setting keep_running from command line: true");
             self.global.keep_running = true;
@@ -159,61 +1266,1737 @@ impl Config {
             self.global.force = true;
         }
 
+        if args.get_flag("dry-run") {
+            log::debug!("Setting dry_run from command line: true");
+            self.global.dry_run = true;
+        }
+
+        if let Some(target) = args.get_one::<String>("target") {
+            log::debug!("Setting target from command line: {}", target);
+            self.global.target = Some(target.clone());
+        }
+
+        if let Some(profiles) = args.get_many::<String>("profile") {
+            let profiles: Vec<String> = profiles.cloned().collect();
+            log::debug!("Setting profiles from command line: {:?}", profiles);
+            self.global.profiles = profiles;
+        }
+
+        for assignment in args.get_many::<String>("set").into_iter().flatten() {
+            log::debug!("Setting {} from command line", assignment);
+            self.set(assignment)?;
+        }
+        self.check_profiles()?;
+        self.select_target()
+    }
+
+    /// Apply a `--set path=value` override. The path is made of the keys of
+    /// the config separated by `.`, list entries are selected by name or
+    /// index, and `KEY` of an environment sets the variable. The value is
+    /// YAML, e.g. `3` or `[a, b]`.
+    pub fn set(&mut self, assignment: &str) -> Result<(), Error> {
+        let invalid = |e: String| Error::Config(format!("Invalid --set {}: {}", assignment, e));
+        let Some((path, value)) = assignment.split_once('=') else {
+            return Err(invalid("expected path=value".to_string()));
+        };
+        let value: serde_json::Value =
+            serde_yaml::from_str(value).map_err(|e| invalid(e.to_string()))?;
+        let keys = path.split('.').collect::<Vec<_>>();
+        let mut root = serde_json::to_value(&*self).map_err(|e| invalid(e.to_string()))?;
+        set_value(&mut root, &keys, value).map_err(invalid)?;
+        let mut cfg: Self = serde_json::from_value(root).map_err(|e| invalid(e.to_string()))?;
+        // Fields serde skips, or ignores when they are unknown
+        cfg.global.dry_run = self.global.dry_run;
+        let root = serde_json::to_value(&cfg).map_err(|e| invalid(e.to_string()))?;
+        if find_value(&root, &keys).is_none() {
+            return Err(invalid(format!("the config has no field {}", path)));
+        }
+        *self = cfg;
+        Ok(())
+    }
+
+    /// Check that each selected profile is one of a component.
+    pub fn check_profiles(&self) -> Result<(), Error> {
+        let known = self
+            .components
+            .iter()
+            .flat_map(|c| &c.profiles)
+            .collect::<std::collections::BTreeSet<_>>();
+        match self.global.profiles.iter().find(|p| !known.contains(p)) {
+            Some(profile) => Err(Error::Config(format!(
+                "Profile {} is not used by any component, available: {}",
+                profile,
+                known.into_iter().cloned().collect::<Vec<_>>().join(", ")
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the selected target exists, and leave the components out
+    /// when it is an external deployment.
+    pub fn select_target(&mut self) -> Result<(), Error> {
+        let Some(name) = &self.global.target else {
+            return Ok(());
+        };
+        let target = self.targets.get(name).ok_or_else(|| {
+            let names = self.targets.keys().cloned().collect::<Vec<_>>();
+            Error::Config(format!(
+                "Target {} not found in config, available: {}",
+                name,
+                names.join(", ")
+            ))
+        })?;
+        if target.external {
+            log::info!("Running against {}, not starting the components", name);
+            self.components.clear();
+            self.seeds.clear();
+            self.chaos = None;
+        }
         Ok(())
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Component {
     pub name: String,
-    #[serde(rename = "type")]
+    /// `container`, `pod`, `process` or `job`, may be left out to merge the
+    /// other fields into the component of the same name of a base config.
+    #[serde(rename = "type", default)]
     pub component_type: String,
-    #[serde(default)]
-    pub dependencies: Vec<String>,
+    /// Component this one is a variant of, its fields replacing those of the
+    /// other like in an overlay, e.g. only a different image tag. It is only
+    /// started by default with its own `start_by_default`.
+    pub extends: Option<String>,
+    /// Components started before this one, by name or as
+    /// `{component: db, condition: healthy}` to also wait for their
+    /// healthcheck to pass.
+    #[serde(default, alias = "depends_on")]
+    pub dependencies: Vec<Dependency>,
     #[serde(default)]
     pub start_by_default: bool,
+    /// Only start the component by default when one of these profiles is
+    /// selected, or a component started by default depends on it.
+    #[serde(default)]
+    pub profiles: Vec<String>,
     #[serde(default)]
     pub ports: Vec<Port>,
     #[serde(default)]
     pub containers: Vec<Container>,
     pub network: Option<String>,
+    /// Named networks the containers or the pod join, created if missing.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    /// Image of a `container` or `job` component, also the tag of the image
+    /// built with `build`.
     pub image: Option<String>,
+    /// Build the image from local sources each time the component starts.
+    pub build: Option<Build>,
     pub command: Option<Vec<String>>,
     pub entrypoint: Option<String>,
-    #[serde(default)]
+    /// `KEY=value` entries, or a mapping of the variables to their values.
+    #[serde(default, deserialize_with = "environment_entries")]
+    #[schemars(with = "EnvironmentEntries")]
     pub environment: Vec<String>,
+    /// Working directory of a process, or of a job without image.
+    pub workdir: Option<String>,
+    /// Run the command of a process, or of a job without image, with
+    /// `sh -c`, its elements joined by spaces.
+    #[serde(default)]
+    pub shell: bool,
     #[serde(default)]
     pub volumes: Vec<Volume>,
+    /// Podman secrets created before the containers start and mounted in
+    /// all of them.
+    #[serde(default)]
+    pub secrets: Vec<Secret>,
+    pub healthcheck: Option<Healthcheck>,
+    /// Restart the containers or the process when they exit during the run.
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    /// Limits of the container of a `container` or `job` component.
+    #[serde(flatten)]
+    pub resources: Resources,
+    /// User, privileges and devices of the container of a `container` or
+    /// `job` component.
+    #[serde(flatten)]
+    pub security: Security,
+    /// Rhai file whose functions the scripts call as `<name>::function()`,
+    /// with `-` in the name replaced by `_`.
+    pub module: Option<String>,
+    #[serde(flatten)]
+    pub hooks: ComponentHooks,
+    /// Entry of `templates` the component was instantiated from, its own
+    /// fields replacing those of the template.
+    pub template: Option<String>,
+    /// Values of the `{{params.<key>}}` placeholders of the template.
+    #[serde(default)]
+    pub params: BTreeMap<String, serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+/// A podman secret, mounted at `/run/secrets/<name>` in the containers.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Secret {
+    pub name: String,
+    /// File with the value, relative to the config file, or `env:VAR` for
+    /// the environment variable `VAR`.
+    pub source: String,
+}
+
+impl Secret {
+    /// Resolve a relative file `source` against `dir`.
+    fn resolve_source(&mut self, dir: &Path) {
+        if !self.source.starts_with("env:") && Path::new(&self.source).is_relative() {
+            self.source = dir.join(&self.source).to_string_lossy().into_owned();
+        }
+    }
+
+    /// Arguments of `secret create`, replacing an existing secret.
+    pub fn create_args(&self) -> Vec<String> {
+        let mut args = vec![
+            "secret".to_string(),
+            "create".to_string(),
+            "--replace".to_string(),
+        ];
+        match self.source.strip_prefix("env:") {
+            Some(var) => args.extend(["--env".to_string(), self.name.clone(), var.to_string()]),
+            None => args.extend([self.name.clone(), self.source.clone()]),
+        }
+        args
+    }
+}
+
+/// Shell commands run on the host around the start and stop of a component,
+/// with `SAM_COMPONENT` set to its name and the placeholders of
+/// `environment` replaced.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+pub struct ComponentHooks {
+    /// Run before the component starts, a failure fails the start.
+    #[serde(default)]
+    pub pre_start: Vec<String>,
+    /// Run once the component started, and passed its healthcheck if it has
+    /// one. A failure fails the start.
+    #[serde(default)]
+    pub post_start: Vec<String>,
+    /// Run before the component stops. Failures of the stop hooks are logged,
+    /// the component is stopped anyway.
+    #[serde(default)]
+    pub pre_stop: Vec<String>,
+    /// Run once the component stopped.
+    #[serde(default)]
+    pub post_stop: Vec<String>,
+}
+
+impl ComponentHooks {
+    pub fn merge(&self, other: &Self) -> Self {
+        let pick = |own: &Vec<String>, other: &Vec<String>| match other.is_empty() {
+            true => own.clone(),
+            false => other.clone(),
+        };
+        Self {
+            pre_start: pick(&self.pre_start, &other.pre_start),
+            post_start: pick(&self.post_start, &other.post_start),
+            pre_stop: pick(&self.pre_stop, &other.pre_stop),
+            post_stop: pick(&self.post_stop, &other.post_stop),
+        }
+    }
+}
+
+/// Image built with `podman build` before the container runs. Unchanged
+/// layers are reused from the build cache of the runtime.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+pub struct Build {
+    /// Directory with the sources of the image.
+    pub context: String,
+    /// Containerfile relative to the context, `Containerfile` or `Dockerfile`
+    /// by default.
+    pub containerfile: Option<String>,
+    /// Values of the `ARG`s of the containerfile.
+    #[serde(default)]
+    pub args: BTreeMap<String, String>,
+}
+
+impl Build {
+    /// Arguments of `build`, tagging the image as `tag`.
+    pub fn args(&self, tag: &str) -> Vec<String> {
+        let mut args = vec!["build".to_string(), "-t".to_string(), tag.to_string()];
+        if let Some(containerfile) = &self.containerfile {
+            let path = std::path::Path::new(&self.context).join(containerfile);
+            args.extend(["-f".to_string(), path.to_string_lossy().to_string()]);
+        }
+        for (key, value) in &self.args {
+            args.extend(["--build-arg".to_string(), format!("{}={}", key, value)]);
+        }
+        args.push(self.context.clone());
+        args
+    }
+}
+
+/// Resource limits of a container, as the options of `podman run`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+pub struct Resources {
+    /// Number of CPUs, e.g. `0.5`.
+    pub cpus: Option<f64>,
+    /// Memory with a unit, e.g. `256m` or `1g`.
+    pub memory: Option<String>,
+    /// Maximum number of processes, `-1` for no limit.
+    pub pids_limit: Option<i64>,
+}
+
+impl Resources {
+    pub fn merge(&self, other: &Self) -> Self {
+        Self {
+            cpus: other.cpus.or(self.cpus),
+            memory: other.memory.clone().or(self.memory.clone()),
+            pids_limit: other.pids_limit.or(self.pids_limit),
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        if let Some(memory) = &self.memory {
+            args.push(format!("--memory={}", memory));
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            args.push(format!("--pids-limit={}", pids_limit));
+        }
+        args
+    }
+}
+
+/// User, privileges and host devices of a container, as the options of
+/// `podman run`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
+pub struct Security {
+    /// User the command runs as, e.g. `postgres` or `1000:1000`.
+    pub user: Option<String>,
+    /// All capabilities and access to the devices of the host, off unless
+    /// set. An overlay can turn it off again with `false`.
+    pub privileged: Option<bool>,
+    /// Capabilities added to the default ones, e.g. `NET_ADMIN`.
+    #[serde(default)]
+    pub cap_add: Vec<String>,
+    #[serde(default)]
+    pub cap_drop: Vec<String>,
+    /// e.g. `label=disable` or `seccomp=unconfined`.
+    #[serde(default)]
+    pub security_opt: Vec<String>,
+    /// Host devices passed through, e.g. `/dev/kvm` or
+    /// `/dev/dri:/dev/dri:rw`.
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl Security {
+    pub fn merge(&self, other: &Self) -> Self {
+        let pick = |own: &Vec<String>, other: &Vec<String>| match other.is_empty() {
+            true => own.clone(),
+            false => other.clone(),
+        };
+        Self {
+            user: other.user.clone().or(self.user.clone()),
+            privileged: other.privileged.or(self.privileged),
+            cap_add: pick(&self.cap_add, &other.cap_add),
+            cap_drop: pick(&self.cap_drop, &other.cap_drop),
+            security_opt: pick(&self.security_opt, &other.security_opt),
+            devices: pick(&self.devices, &other.devices),
+        }
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(user) = &self.user {
+            args.push(format!("--user={}", user));
+        }
+        if self.privileged == Some(true) {
+            args.push("--privileged".to_string());
+        }
+        for cap in &self.cap_add {
+            args.push(format!("--cap-add={}", cap));
+        }
+        for cap in &self.cap_drop {
+            args.push(format!("--cap-drop={}", cap));
+        }
+        for opt in &self.security_opt {
+            args.push(format!("--security-opt={}", opt));
+        }
+        for device in &self.devices {
+            args.push(format!("--device={}", device));
+        }
+        args
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    Always,
+    /// Only after a non-zero exit code.
+    OnFailure,
+}
+
+impl RestartPolicy {
+    /// Whether a process that exited with `success` is restarted.
+    pub fn restarts(self, success: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Always => true,
+            Self::OnFailure => !success,
+        }
+    }
+
+    /// Value of the `--restart` option of the container runtimes.
+    pub fn container_arg(self) -> &'static str {
+        match self {
+            Self::Never => "no",
+            Self::Always => "always",
+            Self::OnFailure => "on-failure",
+        }
+    }
+}
+
+impl Component {
+    /// The component with the fields set in `other` replacing its own, for
+    /// `extends` and for components of base configs alike. Lists are merged
+    /// by key instead: environment variables by name, ports by container
+    /// port, volumes by container path and the containers of a pod by name.
+    pub fn merge(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        if !other.component_type.is_empty() {
+            result.component_type = other.component_type.clone();
+        }
+        if other.extends.is_some() {
+            result.extends = other.extends.clone();
+        }
+        if !other.dependencies.is_empty() {
+            result.dependencies = other.dependencies.clone();
+        }
+        result.start_by_default |= other.start_by_default;
+        if !other.profiles.is_empty() {
+            result.profiles = other.profiles.clone();
+        }
+        for port in &other.ports {
+            match result
+                .ports
+                .iter_mut()
+                .find(|p| p.container == port.container)
+            {
+                Some(existing) => *existing = port.clone(),
+                None => result.ports.push(port.clone()),
+            }
+        }
+        for container in &other.containers {
+            match result
+                .containers
+                .iter_mut()
+                .find(|c| c.name == container.name)
+            {
+                Some(existing) => *existing = container.clone(),
+                None => result.containers.push(container.clone()),
+            }
+        }
+        if other.network.is_some() {
+            result.network = other.network.clone();
+        }
+        if !other.networks.is_empty() {
+            result.networks = other.networks.clone();
+        }
+        if other.image.is_some() {
+            result.image = other.image.clone();
+        }
+        if other.build.is_some() {
+            result.build = other.build.clone();
+        }
+        if other.command.is_some() {
+            result.command = other.command.clone();
+        }
+        if other.entrypoint.is_some() {
+            result.entrypoint = other.entrypoint.clone();
+        }
+        result.environment = merge_environment(&self.environment, &other.environment);
+        if other.workdir.is_some() {
+            result.workdir = other.workdir.clone();
+        }
+        result.shell |= other.shell;
+        for volume in &other.volumes {
+            match result
+                .volumes
+                .iter_mut()
+                .find(|v| v.container == volume.container)
+            {
+                Some(existing) => *existing = volume.clone(),
+                None => result.volumes.push(volume.clone()),
+            }
+        }
+        if !other.secrets.is_empty() {
+            result.secrets = other.secrets.clone();
+        }
+        if other.healthcheck.is_some() {
+            result.healthcheck = other.healthcheck.clone();
+        }
+        if other.restart != RestartPolicy::default() {
+            result.restart = other.restart;
+        }
+        result.resources = self.resources.merge(&other.resources);
+        result.security = self.security.merge(&other.security);
+        if other.module.is_some() {
+            result.module = other.module.clone();
+        }
+        result.hooks = self.hooks.merge(&other.hooks);
+        if other.template.is_some() {
+            result.template = other.template.clone();
+        }
+        result.params.extend(other.params.clone());
+        result
+    }
+
+    /// The image the container runs, `sam/<name>` for built images without
+    /// an `image`.
+    pub fn image(&self) -> Option<String> {
+        match (&self.image, &self.build) {
+            (Some(image), _) => Some(image.clone()),
+            (None, Some(_)) => Some(format!("sam/{}", self.name.to_lowercase())),
+            (None, None) => None,
+        }
+    }
+
+    pub fn depends_on(&self, component_name: &str) -> bool {
+        self.dependencies
+            .iter()
+            .any(|dep| dep.name() == component_name)
+    }
+
+    /// The command run as a process, with `${VAR}` replaced and wrapped in
+    /// `sh -c` with `shell`.
+    pub fn process_command(&self) -> Vec<String> {
+        let command = self
+            .command
+            .iter()
+            .flatten()
+            .map(|arg| expand_env_vars(arg));
+        if self.shell {
+            let line = command.collect::<Vec<_>>().join(" ");
+            vec!["sh".to_string(), "-c".to_string(), line]
+        } else {
+            command.collect()
+        }
+    }
+
+    /// The working directory of the process, with `${VAR}` replaced.
+    pub fn process_workdir(&self) -> Option<String> {
+        self.workdir.as_deref().map(expand_env_vars)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+#[serde(untagged)]
+pub enum Dependency {
+    Name(String),
+    /// `{component: db, condition: healthy}`, also written with `name`.
+    Condition {
+        #[serde(alias = "name")]
+        component: String,
+        #[serde(default)]
+        condition: DependencyCondition,
+    },
+}
+
+impl Dependency {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Name(name) => name,
+            Self::Condition { component, .. } => component,
+        }
+    }
+
+    pub fn condition(&self) -> DependencyCondition {
+        match self {
+            Self::Name(_) => DependencyCondition::Started,
+            Self::Condition { condition, .. } => *condition,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyCondition {
+    /// The dependency was started, also `service_started` like in compose.
+    #[default]
+    #[serde(alias = "service_started")]
+    Started,
+    /// The healthcheck of the dependency passed, also `service_healthy`.
+    #[serde(alias = "service_healthy")]
+    Healthy,
+}
+
+/// How to tell that a component is ready. Set one of `command`, `http` or
+/// `tcp`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Healthcheck {
+    /// Command exiting with 0 once healthy, run in the container (the first
+    /// container of a pod) or locally for processes.
+    #[serde(default)]
+    pub command: Vec<String>,
+    /// URL answering with a success status once healthy.
+    pub http: Option<String>,
+    /// Host port accepting TCP connections once healthy, on the remote host
+    /// if there is one.
+    pub tcp: Option<u16>,
+    /// Time between checks, 1s by default.
+    pub interval: Option<String>,
+    /// How long to wait for the component to become healthy, 60s by default.
+    pub timeout: Option<String>,
+}
+
+/// Environment variables as `KEY=value` entries, or as a mapping.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum EnvironmentEntries {
+    List(Vec<String>),
+    Map(BTreeMap<String, serde_json::Value>),
+}
+
+/// Set the field at `keys` of a serialized config to `value`, adding entries
+/// to maps and environments.
+fn set_value(
+    root: &mut serde_json::Value,
+    keys: &[&str],
+    value: serde_json::Value,
+) -> Result<(), String> {
+    let Some((last, parents)) = keys.split_last() else {
+        return Err("empty path".to_string());
+    };
+    let mut current = root;
+    for (i, key) in parents.iter().enumerate() {
+        current = child(current, key)
+            .ok_or_else(|| format!("the config has no field {}", keys[..=i].join(".")))?;
+    }
+    match current {
+        serde_json::Value::Object(map) => {
+            map.insert(last.to_string(), value);
+        }
+        serde_json::Value::Array(items) if child_index(items, last).is_some() => {
+            let i = child_index(items, last).unwrap();
+            items[i] = value;
+        }
+        // An environment, as `KEY=value` entries
+        serde_json::Value::Array(items) if items.iter().all(|item| item.is_string()) => {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Null => String::new(),
+                value => value.to_string(),
+            };
+            let entry = serde_json::Value::from(format!("{}={}", last, value));
+            let prefix = format!("{}=", last);
+            match items
+                .iter()
+                .position(|item| item.as_str().unwrap().starts_with(&prefix))
+            {
+                Some(i) => items[i] = entry,
+                None => items.push(entry),
+            }
+        }
+        _ => return Err(format!("the config has no field {}", keys.join("."))),
+    }
+    Ok(())
+}
+
+fn find_value<'a>(root: &'a serde_json::Value, keys: &[&str]) -> Option<&'a serde_json::Value> {
+    keys.iter().try_fold(root, |current, key| match current {
+        serde_json::Value::Object(map) => map.get(*key),
+        serde_json::Value::Array(items) => child_index(items, key)
+            .map(|i| &items[i])
+            // A variable of an environment
+            .or_else(|| {
+                let prefix = format!("{}=", key);
+                items
+                    .iter()
+                    .find(|item| item.as_str().is_some_and(|s| s.starts_with(&prefix)))
+            }),
+        _ => None,
+    })
+}
+
+fn child<'a>(value: &'a mut serde_json::Value, key: &str) -> Option<&'a mut serde_json::Value> {
+    match value {
+        serde_json::Value::Object(map) => map.get_mut(key),
+        serde_json::Value::Array(items) => {
+            let i = child_index(items, key)?;
+            items.get_mut(i)
+        }
+        _ => None,
+    }
+}
+
+/// The entry of a list named `key`, e.g. a component, or at index `key`.
+fn child_index(items: &[serde_json::Value], key: &str) -> Option<usize> {
+    items
+        .iter()
+        .position(|item| item.get("name").and_then(|name| name.as_str()) == Some(key))
+        .or_else(|| key.parse().ok().filter(|i| *i < items.len()))
+}
+
+/// `own` with the variables of `other` replacing those of the same name and
+/// added after them.
+fn merge_environment(own: &[String], other: &[String]) -> Vec<String> {
+    let key = |entry: &str| entry.split('=').next().unwrap_or_default().to_string();
+    let mut result = own.to_vec();
+    for entry in other {
+        match result.iter_mut().find(|own| key(own) == key(entry)) {
+            Some(own) => own.clone_from(entry),
+            None => result.push(entry.clone()),
+        }
+    }
+    result
+}
+
+/// A config file, or a list of them.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum BaseFiles {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn base_files<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match BaseFiles::deserialize(deserializer)? {
+        BaseFiles::One(path) => vec![path],
+        BaseFiles::Many(paths) => paths,
+    })
+}
+
+fn environment_entries<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(match EnvironmentEntries::deserialize(deserializer)? {
+        EnvironmentEntries::List(list) => list,
+        EnvironmentEntries::Map(map) => map
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(value) => format!("{}={}", key, value),
+                serde_json::Value::Null => format!("{}=", key),
+                value => format!("{}={}", key, value),
+            })
+            .collect(),
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Port {
     pub host: u16,
     pub container: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
 pub struct Container {
     pub name: String,
     pub image: String,
     #[serde(default)]
     pub command: Vec<String>,
     pub entrypoint: Option<String>,
-    #[serde(default)]
+    #[serde(default, deserialize_with = "environment_entries")]
+    #[schemars(with = "EnvironmentEntries")]
     pub environment: Vec<String>,
     #[serde(default)]
     pub volumes: Vec<Volume>,
     pub network: Option<String>,
+    /// Named networks the container joins, created if missing.
+    #[serde(default)]
+    pub networks: Vec<String>,
+    #[serde(flatten)]
+    pub resources: Resources,
+    #[serde(flatten)]
+    pub security: Security,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+/// A mount of a container: a host path, a named volume or a tmpfs.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, JsonSchema)]
 pub struct Volume {
-    pub host: String,
+    /// Host file or directory bind mounted into the container.
+    pub host: Option<String>,
+    /// Volume created by SAM if missing, kept between runs and removed by
+    /// `sam reset`.
+    pub name: Option<String>,
+    /// Empty in-memory filesystem, gone once the container stops.
+    #[serde(default)]
+    pub tmpfs: bool,
+    /// Size limit of a tmpfs, e.g. `64m`.
+    pub size: Option<String>,
     pub container: String,
 }
 
+impl Volume {
+    /// Arguments of `run` mounting the volume, bind mounts relabeled for
+    /// SELinux if `relabel`.
+    pub fn args(&self, relabel: bool) -> Result<Vec<String>, Error> {
+        let mount = match (&self.host, &self.name, self.tmpfs) {
+            (Some(host), None, false) if relabel => format!("{}:{}:z", host, self.container),
+            (Some(host), None, false) => format!("{}:{}", host, self.container),
+            (None, Some(name), false) => format!("{}:{}", name, self.container),
+            (None, None, true) => {
+                let mount = match &self.size {
+                    Some(size) => format!("{}:size={}", self.container, size),
+                    None => self.container.clone(),
+                };
+                return Ok(vec!["--tmpfs".to_string(), mount]);
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "Volume {} needs exactly one of host, name and tmpfs",
+                    self.container
+                )))
+            }
+        };
+        if self.size.is_some() {
+            return Err(Error::Config(format!(
+                "Volume {} has a size, only a tmpfs can have one",
+                self.container
+            )));
+        }
+        Ok(vec!["-v".to_string(), mount])
+    }
+}
+
 impl Config {
+    pub fn script_order(&self) -> ScriptOrder {
+        match self.global.script_order {
+            Some(order) => order,
+            None if self.global.order.is_empty() => ScriptOrder::Lex,
+            None => ScriptOrder::Config,
+        }
+    }
+
+    /// The configured working directory, resolved against the directory of
+    /// the config file at `config_path`.
+    pub fn workdir(&self, config_path: &str) -> Option<PathBuf> {
+        let workdir = PathBuf::from(self.global.workdir.as_ref()?);
+        if workdir.is_absolute() {
+            return Some(workdir);
+        }
+        let config_dir = std::path::Path::new(config_path)
+            .parent()
+            .unwrap_or(std::path::Path::new(""));
+        Some(config_dir.join(workdir))
+    }
+
+    /// The seeds of all components, in the order the components are defined.
+    pub fn component_seeds(&self) -> Result<Vec<ComponentSeeds>, Error> {
+        if let Some(name) = self
+            .seeds
+            .keys()
+            .find(|name| self.get_component(name).is_none())
+        {
+            return Err(Error::Config(format!(
+                "Seeds for unknown component {}",
+                name
+            )));
+        }
+        Ok(self
+            .components
+            .iter()
+            .filter_map(|component| {
+                let seeds = self.seeds.get(&component.name)?;
+                Some(ComponentSeeds {
+                    component: component.clone(),
+                    seeds: seeds.clone(),
+                    runtime: self.container_runtime(),
+                    remote: self.remote.clone(),
+                })
+            })
+            .collect())
+    }
+
+    /// Directories to resolve Rhai modules from, the directory of the first
+    /// script if none are configured.
+    pub fn module_dirs(&self) -> Result<Vec<String>, Error> {
+        let mut module_dirs = self.global.module_dirs.clone();
+        if module_dirs.is_empty() {
+            log::debug!("No module directories specified, using script directory");
+            let first_script = self
+                .global
+                .scripts
+                .first()
+                .ok_or(Error::Config("No scripts found in config".to_string()))?;
+            let path = PathBuf::from(first_script);
+            if path.is_file() {
+                log::debug!("Using parent directory of script file: {}", first_script);
+                module_dirs.push(
+                    path.parent()
+                        .ok_or(Error::Other(format!(
+                            "No parent directory found for script {}",
+                            first_script
+                        )))?
+                        .to_string_lossy()
+                        .into_owned(),
+                );
+            } else if path.is_dir() {
+                log::debug!("Using script directory directly: {}", first_script);
+                module_dirs.push(path.to_string_lossy().into_owned());
+            } else {
+                return Err(Error::Other(format!(
+                    "No script or directory found at {}",
+                    first_script
+                )));
+            }
+        }
+        Ok(module_dirs)
+    }
+
+    /// Load `global.env_files` into the environment of sam, seen by the
+    /// components, hooks, `${VAR}` and `get_env`.
+    pub fn load_env_files(&self) -> Result<(), Error> {
+        for file in &self.global.env_files {
+            log::debug!("Loading environment file {}", file);
+            load_dotenv(file, true)?;
+        }
+        Ok(())
+    }
+
+    /// The `data` section merged with the configured data files, exposed to
+    /// scripts as the `data` constant.
+    pub fn script_data(&self) -> Result<::rhai::Map, Error> {
+        let mut data: ::rhai::Map = ::rhai::serde::to_dynamic(&self.data)
+            .map_err(|e| Error::Config(format!("Invalid data section: {}", e)))?
+            .cast();
+        for file in &self.global.data_files {
+            log::debug!("Loading data file {}", file);
+            let values = commands::load_data(file).map_err(|e| Error::Config(e.to_string()))?;
+            let values = values.try_cast::<::rhai::Map>().ok_or_else(|| {
+                Error::Config(format!("Data file {} does not contain a map", file))
+            })?;
+            data.extend(values);
+        }
+        Ok(data)
+    }
+
+    /// Namespaces and paths of the `module` files of the components.
+    pub fn component_modules(&self) -> Vec<(String, String)> {
+        self.components
+            .iter()
+            .filter_map(|component| {
+                let module = component.module.clone()?;
+                Some((component.name.replace('-', "_"), module))
+            })
+            .collect()
+    }
+
     pub fn get_component(&self, name: &str) -> Option<&Component> {
         self.components.iter().find(|c| c.name == name)
     }
+
+    /// The components started with the environment: those marked
+    /// `start_by_default` without profiles or in a selected profile, and the
+    /// components marked `start_by_default` they depend on.
+    pub fn default_components(&self) -> Vec<&Component> {
+        let selected = |c: &Component| {
+            c.profiles.is_empty() || c.profiles.iter().any(|p| self.global.profiles.contains(p))
+        };
+        let mut names = self
+            .components
+            .iter()
+            .filter(|c| c.start_by_default && selected(c))
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        let mut i = 0;
+        while i < names.len() {
+            for dependency in &self.get_component(names[i]).unwrap().dependencies {
+                let name = dependency.name();
+                let starts = self.get_component(name).is_some_and(|c| c.start_by_default);
+                if starts && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            i += 1;
+        }
+        self.components
+            .iter()
+            .filter(|c| names.contains(&c.name.as_str()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("SAM_TEST_EXPAND", "secret");
+        assert_eq!(
+            expand_env_vars("Bearer ${SAM_TEST_EXPAND}"),
+            "Bearer secret"
+        );
+        assert_eq!(expand_env_vars("${SAM_TEST_UNSET_VARIABLE}x"), "x");
+        assert_eq!(expand_env_vars("$NOT_BRACED"), "$NOT_BRACED");
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let cfg = Config::from_yaml(
+            r#"
+name: deps
+components:
+  - name: db
+    type: container
+    healthcheck:
+      command: [pg_isready]
+    post_start: [./migrate.sh]
+    pre_stop: [./flush.sh]
+  - name: api
+    type: container
+    build:
+      context: api
+      containerfile: Containerfile.test
+      args:
+        VERSION: "1.2"
+    restart: on-failure
+    networks: [frontend, backend]
+    cpus: 0.5
+    memory: 256m
+    user: "1000:1000"
+    cap_add: [NET_ADMIN]
+    security_opt: [label=disable]
+    devices: [/dev/kvm]
+    depends_on:
+      - cache
+      - component: db
+        condition: healthy
+      - name: queue
+        condition: service_healthy
+    volumes:
+      - name: api-data
+        container: /data
+      - tmpfs: true
+        size: 64m
+        container: /tmp
+      - host: ./api
+        name: api-src
+        container: /src
+    secrets:
+      - name: api_key
+        source: env:API_KEY
+      - name: tls_cert
+        source: certs/api.pem
+"#,
+        )
+        .unwrap();
+        let api = cfg.get_component("api").unwrap();
+        assert!(api.depends_on("cache") && api.depends_on("db"));
+        assert_eq!(
+            api.dependencies[0].condition(),
+            DependencyCondition::Started
+        );
+        assert_eq!(
+            api.dependencies[1].condition(),
+            DependencyCondition::Healthy
+        );
+        assert_eq!(api.dependencies[2].name(), "queue");
+        assert_eq!(
+            api.dependencies[2].condition(),
+            DependencyCondition::Healthy
+        );
+        assert_eq!(api.restart, RestartPolicy::OnFailure);
+        assert!(api.restart.restarts(false) && !api.restart.restarts(true));
+        let db = cfg.get_component("db").unwrap();
+        assert_eq!(db.restart, RestartPolicy::Never);
+        assert_eq!(db.hooks.post_start, ["./migrate.sh"]);
+        assert_eq!(db.hooks.pre_stop, ["./flush.sh"]);
+        assert!(api.hooks.pre_start.is_empty());
+        assert_eq!(api.resources.args(), ["--cpus=0.5", "--memory=256m"]);
+        assert_eq!(
+            api.security.args(),
+            [
+                "--user=1000:1000",
+                "--cap-add=NET_ADMIN",
+                "--security-opt=label=disable",
+                "--device=/dev/kvm"
+            ]
+        );
+        assert!(db.security.args().is_empty());
+        assert_eq!(api.networks, ["frontend", "backend"]);
+        assert_eq!(api.image().as_deref(), Some("sam/api"));
+        assert_eq!(api.volumes[0].args(true).unwrap(), ["-v", "api-data:/data"]);
+        assert_eq!(
+            api.volumes[1].args(true).unwrap(),
+            ["--tmpfs", "/tmp:size=64m"]
+        );
+        assert!(api.volumes[2].args(true).is_err());
+        let secrets = api
+            .secrets
+            .iter()
+            .map(Secret::create_args)
+            .collect::<Vec<_>>();
+        assert_eq!(secrets[0][3..], ["--env", "api_key", "API_KEY"]);
+        assert_eq!(secrets[1][3..], ["tls_cert", "certs/api.pem"]);
+        assert_eq!(
+            api.build.as_ref().unwrap().args("sam/api"),
+            [
+                "build",
+                "-t",
+                "sam/api",
+                "-f",
+                "api/Containerfile.test",
+                "--build-arg",
+                "VERSION=1.2",
+                "api"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_component_seeds() {
+        let cfg = Config::from_yaml(
+            r#"
+name: seeds
+components:
+  - name: web
+    type: process
+  - name: db
+    type: container
+seeds:
+  db:
+    - sql: seeds/users.sql
+      exec: [psql, -U, postgres]
+    - script: seeds/db.rhai
+"#,
+        )
+        .unwrap();
+        let seeds = cfg.component_seeds().unwrap();
+        assert_eq!(seeds.len(), 1);
+        assert_eq!(seeds[0].component.name, "db");
+        assert_eq!(seeds[0].seeds[0].exec, ["psql", "-U", "postgres"]);
+
+        let mut cfg = cfg;
+        cfg.seeds.insert("cache".to_string(), vec![]);
+        assert!(cfg.component_seeds().is_err());
+    }
+
+    #[test]
+    fn test_targets() {
+        let mut cfg = Config::from_yaml(
+            r#"
+name: targets
+components:
+  - name: api
+    type: process
+targets:
+  local:
+    base_url: http://localhost:8080
+  staging:
+    base_url: https://staging.example.com
+    token: ${SAM_TEST_TARGET_TOKEN}
+    external: true
+"#,
+        )
+        .unwrap();
+        cfg.select_target().unwrap();
+        assert_eq!(cfg.components.len(), 1);
+
+        cfg.global.target = Some("local".to_string());
+        cfg.select_target().unwrap();
+        assert_eq!(cfg.components.len(), 1);
+
+        std::env::set_var("SAM_TEST_TARGET_TOKEN", "secret");
+        let staging = cfg.targets["staging"].to_map("staging");
+        assert_eq!(staging["token"].to_string(), "secret");
+        assert!(staging["username"].is_unit());
+        cfg.global.target = Some("staging".to_string());
+        cfg.select_target().unwrap();
+        assert!(cfg.components.is_empty());
+
+        cfg.global.target = Some("prod".to_string());
+        let error = cfg.select_target().unwrap_err().to_string();
+        assert!(error.ends_with("Target prod not found in config, available: local, staging"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let mut cfg = Config::from_yaml(
+            r#"
+name: profiles
+components:
+  - name: db
+    type: container
+    start_by_default: true
+  - name: api
+    type: process
+    start_by_default: true
+    depends_on: [db]
+  - name: queue
+    type: container
+    start_by_default: true
+    profiles: [workers]
+  - name: worker
+    type: process
+    start_by_default: true
+    profiles: [workers]
+    depends_on: [queue]
+  - name: web
+    type: process
+    start_by_default: true
+    profiles: [frontend]
+    depends_on: [api]
+  - name: search
+    type: container
+    profiles: [frontend]
+"#,
+        )
+        .unwrap();
+        let names = |cfg: &Config| {
+            cfg.default_components()
+                .iter()
+                .map(|c| c.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&cfg), ["db", "api"]);
+
+        cfg.global.profiles = vec!["frontend".to_string()];
+        cfg.check_profiles().unwrap();
+        assert_eq!(names(&cfg), ["db", "api", "web"]);
+
+        // Components of other profiles start when a started component needs them
+        cfg.components[2].profiles = vec!["queues".to_string()];
+        cfg.global.profiles = vec!["workers".to_string()];
+        assert_eq!(names(&cfg), ["db", "api", "queue", "worker"]);
+
+        cfg.global.profiles = vec!["worker".to_string()];
+        let error = cfg.check_profiles().unwrap_err().to_string();
+        assert!(error.ends_with(
+            "Profile worker is not used by any component, available: frontend, queues, workers"
+        ));
+    }
+
+    #[test]
+    fn test_templates() {
+        let cfg = Config::from_yaml(
+            r#"
+name: templates
+templates:
+  worker:
+    type: container
+    image: my/worker
+    environment:
+      - WORKER_ID={{name}}
+      - QUEUE={{ params.queue }}
+      - API={{port.api.8080}}
+    ports:
+      - host: "{{params.port}}"
+        container: 9000
+components:
+  - name: worker-high
+    template: worker
+    params: {queue: high, port: 9001}
+  - name: worker-low
+    template: worker
+    params: {queue: low, port: 9002}
+    image: my/worker:canary
+"#,
+        )
+        .unwrap();
+        let high = cfg.get_component("worker-high").unwrap();
+        assert_eq!(high.component_type, "container");
+        assert_eq!(
+            high.environment,
+            [
+                "WORKER_ID=worker-high",
+                "QUEUE=high",
+                "API={{port.api.8080}}"
+            ]
+        );
+        assert_eq!(high.ports[0].host, 9001);
+        let low = cfg.get_component("worker-low").unwrap();
+        assert_eq!(low.image.as_deref(), Some("my/worker:canary"));
+        assert_eq!(low.ports[0].host, 9002);
+
+        let error = Config::from_yaml(
+            "name: t\ntemplates: {w: {type: process, command: ['{{params.x}}']}}\n\
+             components: [{name: a, template: w}]",
+        )
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("{{params.x}} is not set in params"));
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let cfg = Config::from_yaml("name: default").unwrap();
+        assert!(cfg.cleanup.volumes);
+        assert_eq!(cfg.cleanup.images_older_than().unwrap(), None);
+
+        let cfg = Config::from_yaml(
+            r#"
+name: cleanup
+cleanup:
+  volumes: false
+  images_older_than: 7d
+  build_cache: 10GB
+"#,
+        )
+        .unwrap();
+        assert!(!cfg.cleanup.volumes);
+        assert_eq!(
+            cfg.cleanup.images_older_than().unwrap(),
+            Some(Duration::from_secs(7 * 24 * 3600))
+        );
+        let merged = Config::from_yaml("name: base")
+            .unwrap()
+            .merge(&cfg)
+            .unwrap();
+        assert_eq!(merged.cleanup.build_cache.as_deref(), Some("10GB"));
+    }
+
+    #[test]
+    fn test_security_merge() {
+        let base: Security = serde_yaml::from_str("user: postgres\nprivileged: true").unwrap();
+        let overlay: Security = serde_yaml::from_str("privileged: false").unwrap();
+        assert_eq!(base.merge(&overlay).args(), ["--user=postgres"]);
+        assert_eq!(
+            base.merge(&Security::default()).args(),
+            ["--user=postgres", "--privileged"]
+        );
+    }
+
+    #[test]
+    fn test_base_chain() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let write = |name: &str, yaml: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, yaml).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let common = write(
+            "common.yaml",
+            r#"
+name: common
+components:
+  - name: api
+    type: container
+    image: api:1
+    start_by_default: true
+    environment: [LOG=info, PORT=8080]
+    memory: 1g
+    ports:
+      - host: 8080
+        container: 8080
+      - host: 9090
+        container: 9090
+    volumes:
+      - name: api-data
+        container: /data
+"#,
+        );
+        let ci = write(
+            "ci.yaml",
+            r#"
+name: ci
+components:
+  - name: api
+    image: api:2
+    environment:
+      LOG: debug
+    ports:
+      - host: 18080
+        container: 8080
+    volumes:
+      - tmpfs: true
+        container: /data
+      - host: ./fixtures
+        container: /fixtures
+"#,
+        );
+        let local = write(
+            "local.yaml",
+            &format!(
+                "name: local\nbase: [{}, {}]\ncomponents:\n  - name: api\n    cpus: 0.5\n",
+                common, ci
+            ),
+        );
+
+        let cfg = Config::load(&local).unwrap();
+        let api = cfg.get_component("api").unwrap();
+        assert_eq!(api.component_type, "container");
+        assert_eq!(api.image.as_deref(), Some("api:2"));
+        assert!(api.start_by_default);
+        assert_eq!(api.environment, ["LOG=debug", "PORT=8080"]);
+        assert_eq!(api.resources.memory.as_deref(), Some("1g"));
+        assert_eq!(api.resources.cpus, Some(0.5));
+        // Ports and volumes of the base are replaced by container port and
+        // path, the others are kept
+        let ports = api
+            .ports
+            .iter()
+            .map(|p| (p.host, p.container))
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [(18080, 8080), (9090, 9090)]);
+        let volumes = api
+            .volumes
+            .iter()
+            .map(|v| (v.container.as_str(), v.tmpfs))
+            .collect::<Vec<_>>();
+        assert_eq!(volumes, [("/data", true), ("/fixtures", false)]);
+        assert_eq!(
+            Config::source_files(&local).unwrap(),
+            [local, common, ci.clone()]
+        );
+
+        let error = Config::load(&ci).unwrap_err().to_string();
+        assert!(
+            error.ends_with("Component api has no type, set one of container, pod, process or job")
+        );
+    }
+
+    #[test]
+    fn test_base_cycle() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let a = dir.path().join("a.yaml").to_string_lossy().into_owned();
+        let b = dir.path().join("b.yaml").to_string_lossy().into_owned();
+        std::fs::write(&a, format!("name: a\nbase: {}\n", b)).unwrap();
+        std::fs::write(&b, format!("name: b\nbase: {}\n", a)).unwrap();
+
+        let error = Config::load(&a).unwrap_err().to_string();
+        assert!(error.ends_with(&format!(
+            "Base configs include each other: {} -> {} -> {}",
+            a, b, a
+        )));
+        assert_eq!(Config::source_files(&a).unwrap(), [a.clone(), b.clone()]);
+
+        let c = dir.path().join("c.yaml").to_string_lossy().into_owned();
+        std::fs::write(&c, "name: c\n").unwrap();
+        std::fs::write(&a, format!("name: a\nbase: [{}, {}]\n", c, b)).unwrap();
+        let error = Config::load(&a).unwrap_err().to_string();
+        assert!(error.ends_with(&format!(
+            "Base configs include each other: {} -> {} -> {}",
+            a, b, a
+        )));
+    }
+
+    #[test]
+    fn test_base_diamond() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let write = |name: &str, yaml: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, yaml).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+        let common = write(
+            "common.yaml",
+            r#"
+name: common
+components:
+  - name: api
+    type: container
+    image: api:1
+  - name: worker
+    type: container
+    image: worker:1
+"#,
+        );
+        let ci = write(
+            "ci.yaml",
+            &format!(
+                "name: ci\nbase: {}\nremove_components: [worker]\n\
+                 components:\n  - name: api\n    image: api:2\n",
+                common
+            ),
+        );
+
+        // common is merged once, before ci, whichever order the bases are in
+        for bases in [[&common, &ci], [&ci, &common]] {
+            let local = write(
+                "local.yaml",
+                &format!("name: local\nbase: [{}, {}]\n", bases[0], bases[1]),
+            );
+            let cfg = Config::load(&local).unwrap();
+            let names = cfg
+                .components
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(names, ["api"]);
+            assert_eq!(cfg.components[0].image.as_deref(), Some("api:2"));
+            assert_eq!(Config::source_files(&local).unwrap().len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_secret_source_relative_to_config() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("sam.yaml");
+        std::fs::write(
+            &path,
+            r#"
+name: secrets
+components:
+  - name: api
+    type: container
+    image: api:1
+    secrets:
+      - name: api_key
+        source: env:API_KEY
+      - name: tls_cert
+        source: certs/api.pem
+      - name: ca
+        source: /etc/ssl/ca.pem
+"#,
+        )
+        .unwrap();
+        let cfg = Config::load(&path.to_string_lossy()).unwrap();
+        let sources = cfg.components[0]
+            .secrets
+            .iter()
+            .map(|secret| secret.source.clone())
+            .collect::<Vec<_>>();
+        let cert = dir.path().join("certs/api.pem");
+        assert_eq!(
+            sources,
+            ["env:API_KEY", cert.to_str().unwrap(), "/etc/ssl/ca.pem"]
+        );
+    }
+
+    #[test]
+    fn test_components_dir() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let components = dir.path().join("components");
+        std::fs::create_dir(&components).unwrap();
+        std::fs::write(
+            components.join("b-workers.yaml"),
+            "- name: worker\n  type: process\n  command: [./worker]\n",
+        )
+        .unwrap();
+        std::fs::write(
+            components.join("a-api.yaml"),
+            "name: api\ntemplate: service\nparams:\n  tag: '2'\n",
+        )
+        .unwrap();
+        std::fs::write(components.join("notes.txt"), "not a component").unwrap();
+        let path = dir.path().join("sam.yaml");
+        let yaml = format!(
+            r#"
+name: split
+components_dir: {}
+templates:
+  service:
+    type: container
+    image: "{{{{name}}}}:{{{{params.tag}}}}"
+components:
+  - name: db
+    type: container
+    image: postgres
+"#,
+            components.display()
+        );
+        std::fs::write(&path, &yaml).unwrap();
+        let path = path.to_string_lossy().into_owned();
+
+        let cfg = Config::load(&path).unwrap();
+        let names = cfg.components.iter().map(|c| &c.name).collect::<Vec<_>>();
+        assert_eq!(names, ["db", "api", "worker"]);
+        assert_eq!(
+            cfg.get_component("api").unwrap().image.as_deref(),
+            Some("api:2")
+        );
+        assert_eq!(Config::source_files(&path).unwrap().len(), 3);
+
+        std::fs::write(components.join("c-db.yaml"), "name: db\ntype: process\n").unwrap();
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error.contains("Component db of "), "{}", error);
+        assert!(error.ends_with("c-db.yaml is already defined"));
+    }
+
+    #[test]
+    fn test_strict() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("sam.yaml");
+        let yaml = r#"
+name: strict
+components:
+  - name: api
+    type: process
+    command: [./api]
+    enviroment: [LOG=debug]
+"#;
+        std::fs::write(&path, yaml).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        assert!(Config::load(&path).is_ok());
+
+        std::fs::write(&path, format!("strict: true\n{}", yaml)).unwrap();
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error
+            .ends_with("unknown key `components[api].enviroment`, did you mean `environment`?"));
+
+        let components = dir.path().join("components");
+        std::fs::create_dir(&components).unwrap();
+        std::fs::write(
+            components.join("db.yaml"),
+            "name: db\ntype: process\nimag: x\n",
+        )
+        .unwrap();
+        let yaml = format!(
+            "name: strict\nstrict: true\ncomponents_dir: {}\n",
+            components.display()
+        );
+        std::fs::write(&path, yaml).unwrap();
+        let error = Config::load(&path).unwrap_err().to_string();
+        assert!(error.ends_with("unknown key `components[db].imag`, did you mean `image`?"));
+    }
+
+    #[test]
+    fn test_extends() {
+        let mut cfg = Config::from_yaml(
+            r#"
+name: variants
+components:
+  - name: api
+    type: container
+    image: api:1
+    start_by_default: true
+    environment: [LOG=info, PORT=8080]
+    ports:
+      - host: 8080
+        container: 8080
+      - host: 9090
+        container: 9090
+    volumes:
+      - name: data
+        container: /data
+  - name: api-debug
+    extends: api
+    environment:
+      LOG: debug
+    ports:
+      - host: 18080
+        container: 8080
+    volumes:
+      - host: ./fixtures
+        container: /fixtures
+  - name: api-debug-2
+    extends: api-debug
+    image: api:2
+"#,
+        )
+        .unwrap();
+        cfg.resolve_extends().unwrap();
+        let api = cfg.get_component("api").unwrap();
+        let variant = cfg.get_component("api-debug-2").unwrap();
+        assert_eq!(variant.component_type, "container");
+        assert_eq!(variant.image.as_deref(), Some("api:2"));
+        assert!(!variant.start_by_default);
+        assert_eq!(variant.environment, ["LOG=debug", "PORT=8080"]);
+        let ports = variant
+            .ports
+            .iter()
+            .map(|p| (p.host, p.container))
+            .collect::<Vec<_>>();
+        assert_eq!(ports, [(18080, 8080), (9090, 9090)]);
+        let volumes = variant
+            .volumes
+            .iter()
+            .map(|v| &v.container)
+            .collect::<Vec<_>>();
+        assert_eq!(volumes, ["/data", "/fixtures"]);
+        assert_eq!(api.image.as_deref(), Some("api:1"));
+        assert_eq!(api.environment, ["LOG=info", "PORT=8080"]);
+
+        let mut cfg = Config::from_yaml(
+            "name: cycle\ncomponents:\n  - {name: a, extends: b}\n  - {name: b, extends: a}\n",
+        )
+        .unwrap();
+        let error = cfg.resolve_extends().unwrap_err().to_string();
+        assert!(error.ends_with("Components extend each other: a -> b -> a"));
+        let mut cfg =
+            Config::from_yaml("name: unknown\ncomponents:\n  - {name: a, extends: b}\n").unwrap();
+        let error = cfg.resolve_extends().unwrap_err().to_string();
+        assert!(error.ends_with("Component a extends unknown component b"));
+    }
+
+    #[test]
+    fn test_remove_components() {
+        let base = Config::from_yaml(
+            r#"
+name: ci
+components:
+  - name: db
+    type: container
+    image: postgres
+  - name: api
+    type: container
+    image: api
+    dependencies: [db]
+  - name: exporter
+    type: container
+    image: exporter
+    dependencies: [api]
+seeds:
+  exporter: []
+"#,
+        )
+        .unwrap();
+        let local = Config::from_yaml("name: local\nremove_components: [exporter]").unwrap();
+        let merged = base.merge(&local).unwrap();
+        let names = merged
+            .components
+            .iter()
+            .map(|c| &c.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["db", "api"]);
+        assert!(merged.seeds.is_empty());
+
+        let local = Config::from_yaml("name: local\nremove_components: [db]").unwrap();
+        let error = base.merge(&local).unwrap_err().to_string();
+        assert!(error.ends_with("Component api depends on the removed component db"));
+        let local = Config::from_yaml("name: local\nremove_components: [cache]").unwrap();
+        let error = base.merge(&local).unwrap_err().to_string();
+        assert!(error.ends_with("Cannot remove component cache, it is not in the base config"));
+    }
+
+    #[test]
+    fn test_set() {
+        let mut cfg = Config::from_yaml(
+            r#"
+name: set
+components:
+  - name: caddy
+    type: container
+    image: caddy:2.7
+    environment: [LOG=info]
+"#,
+        )
+        .unwrap();
+        cfg.global.dry_run = true;
+        cfg.set("components.caddy.image=caddy:2.8").unwrap();
+        cfg.set("components.caddy.environment.LOG=debug").unwrap();
+        cfg.set("components.caddy.environment.PORT=80").unwrap();
+        cfg.set("components.0.memory=1g").unwrap();
+        cfg.set("global.repeat=3").unwrap();
+        cfg.set("global.scripts=[a.rhai, b.rhai]").unwrap();
+        cfg.set("data.user=admin").unwrap();
+
+        let caddy = cfg.get_component("caddy").unwrap();
+        assert_eq!(caddy.image.as_deref(), Some("caddy:2.8"));
+        assert_eq!(caddy.environment, ["LOG=debug", "PORT=80"]);
+        assert_eq!(caddy.resources.memory.as_deref(), Some("1g"));
+        assert_eq!(cfg.global.repeat, Some(3));
+        assert_eq!(cfg.global.scripts, ["a.rhai", "b.rhai"]);
+        assert_eq!(cfg.data["user"], "admin");
+        assert!(cfg.global.dry_run);
+
+        let error = cfg.set("global.repat=3").unwrap_err().to_string();
+        assert!(
+            error.ends_with("Invalid --set global.repat=3: the config has no field global.repat")
+        );
+        let error = cfg.set("components.web.image=web").unwrap_err().to_string();
+        assert!(error.ends_with("the config has no field components.web"));
+        let error = cfg.set("global.repeat=many").unwrap_err().to_string();
+        assert!(error.contains("invalid type"));
+        assert!(cfg.set("global.repeat").is_err());
+    }
 }
@@ -1,20 +1,148 @@
-use std::{collections::HashSet, path::Path, process::Stdio};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    process::{Output, Stdio},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use directories::ProjectDirs;
-use tokio::process::Command;
-
-use crate::{config::Config, Error};
-
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt},
+    process::Command,
+};
+
+use crate::{
+    config::{
+        self, Component, Config, DependencyCondition, Healthcheck, LogLimits, RestartPolicy, Volume,
+    },
+    remote::{shell_quote, Remote},
+    runtime::ContainerRuntime,
+    throttle::LogThrottle,
+    timeout, Error,
+};
+
+/// Delay before a process is restarted by its restart policy.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// Time an SSH tunnel of `port_forward` has to accept connections.
+const PORT_FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Lines of captured output written within this time share the time of the
+/// first of them, which keeps the line times of chatty processes small.
+pub const LINE_TIME_RESOLUTION: Duration = Duration::from_millis(100);
+
+/// `{{component.<name>.ip}}` and `{{port.<name>.<port>}}` placeholders of
+/// environment variables and hooks.
+pub static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\{\{\s*(component|port)\.([A-Za-z0-9_-]+)\.([A-Za-z0-9_]+)\s*\}\}").unwrap()
+});
+
+// The futures are awaited on the thread running the scripts, so they need
+// no Send bound
+#[allow(async_fn_in_trait)]
 pub trait Environment: Send + Sync {
     async fn start(&mut self) -> Result<(), Error>;
     async fn stop(&mut self) -> Result<(), Error>;
     async fn start_component(&mut self, component_name: &str) -> Result<(), Error>;
     async fn stop_component(&mut self, component_name: &str) -> Result<(), Error>;
+    /// Register an ad-hoc component, started like the components of the
+    /// config with `start_component`.
+    fn create_component(&mut self, component: Component) -> Result<(), Error>;
+    /// Unregister a component created with `create_component`, to be stopped
+    /// with `stop_component` by a clone made before.
+    fn destroy_component(&mut self, component_name: &str) -> Result<(), Error>;
+    /// Run a `job` component to completion, also if it ran before. A non-zero
+    /// exit code is part of the result, not an error.
+    async fn run_job(&mut self, component_name: &str) -> Result<JobResult, Error>;
+    /// Result of the last run of a `job` component.
+    fn job_result(&self, component_name: &str) -> Option<JobResult>;
+    /// Apply the components of a changed config to the running environment,
+    /// restarting only the components that were added, removed or changed.
+    async fn reload(&mut self, cfg: &Config) -> Result<(), Error>;
     fn stop_on_drop(&mut self, stop_on_drop: bool);
     fn data_dir(&self) -> &Path;
+    fn is_running(&self, component_name: &str) -> bool;
+    /// How often the containers or the process of a component were restarted
+    /// by its restart policy.
+    async fn restart_count(&self, component_name: &str) -> Result<u64, Error>;
+    /// Whether a component is still running, its exit code once it exited on
+    /// its own and how long it has been up.
+    async fn component_status(&self, component_name: &str) -> Result<ComponentStatus, Error>;
+    /// Captured stdout and stderr of a component, only the lines written in
+    /// the last `since` if set.
+    fn component_logs(
+        &self,
+        component_name: &str,
+        since: Option<Duration>,
+    ) -> Result<String, Error>;
+    /// Local port connected to `port` of a running component, through a
+    /// tunnel if the component runs on a remote host.
+    async fn port_forward(&self, component_name: &str, port: u16) -> Result<PortForward, Error>;
+    /// Wait up to `timeout` for the container or process of a component to
+    /// exit on its own, with its exit code and output.
+    async fn wait_exit(&self, component_name: &str, timeout: Duration) -> Result<JobResult, Error>;
+    /// Run `command` in the container of a component, the first container of
+    /// a pod, or a container of a pod by its name, killed after `timeout`.
+    async fn container_exec(
+        &self,
+        name: &str,
+        command: &[String],
+        timeout: Option<Duration>,
+    ) -> Result<JobResult, Error>;
+    /// Copy files between the host and a container, one of `src` and `dst`
+    /// being `name:/path` with a name `container_exec` accepts.
+    async fn container_cp(&self, src: &str, dst: &str) -> Result<(), Error>;
+    /// Freeze the containers or the process of a running component, without
+    /// stopping them, until `resume_component`.
+    async fn pause_component(&self, component_name: &str) -> Result<(), Error>;
+    async fn resume_component(&self, component_name: &str) -> Result<(), Error>;
+    /// Stop and start a component again, waiting for its healthcheck to pass
+    /// if it has one.
+    async fn restart_component(&mut self, component_name: &str) -> Result<(), Error>;
+}
+
+/// Local port forwarded to a port of a component, open until dropped.
+#[derive(Debug)]
+pub struct PortForward {
+    pub port: u16,
+    /// SSH process of the tunnel, killed on drop.
+    _tunnel: Option<tokio::process::Child>,
+}
+
+/// Outcome of a `job` component that ran to completion.
+#[derive(Debug, Clone, Default)]
+pub struct JobResult {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
 }
 
+impl JobResult {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0
+    }
+}
+
+/// State of a component as seen by `component_status`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ComponentStatus {
+    pub running: bool,
+    /// Exit code of the process or container, if it exited on its own.
+    pub exit_code: Option<i64>,
+    /// Time since the process or container last (re)started, while running.
+    pub uptime: Option<Duration>,
+    pub restarts: u64,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
 pub struct MockEnvironment {}
+
+#[cfg(test)]
 impl Environment for MockEnvironment {
     async fn start(&mut self) -> Result<(), Error> {
         Ok(())
@@ -28,24 +156,503 @@ impl Environment for MockEnvironment {
     async fn stop_component(&mut self, _component_name: &str) -> Result<(), Error> {
         Ok(())
     }
+    fn create_component(&mut self, _component: Component) -> Result<(), Error> {
+        Ok(())
+    }
+    fn destroy_component(&mut self, _component_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn run_job(&mut self, _component_name: &str) -> Result<JobResult, Error> {
+        Ok(JobResult::default())
+    }
+    fn job_result(&self, _component_name: &str) -> Option<JobResult> {
+        None
+    }
+    async fn reload(&mut self, _cfg: &Config) -> Result<(), Error> {
+        Ok(())
+    }
     fn stop_on_drop(&mut self, _stop_on_drop: bool) {}
     fn data_dir(&self) -> &Path {
         unreachable!()
     }
+    fn is_running(&self, _component_name: &str) -> bool {
+        false
+    }
+    async fn restart_count(&self, _component_name: &str) -> Result<u64, Error> {
+        Ok(0)
+    }
+    async fn component_status(&self, _component_name: &str) -> Result<ComponentStatus, Error> {
+        Ok(ComponentStatus::default())
+    }
+    fn component_logs(
+        &self,
+        _component_name: &str,
+        _since: Option<Duration>,
+    ) -> Result<String, Error> {
+        Ok(String::new())
+    }
+    async fn port_forward(&self, _component_name: &str, port: u16) -> Result<PortForward, Error> {
+        Ok(PortForward {
+            port,
+            _tunnel: None,
+        })
+    }
+    async fn wait_exit(
+        &self,
+        _component_name: &str,
+        _timeout: Duration,
+    ) -> Result<JobResult, Error> {
+        Ok(JobResult::default())
+    }
+    async fn container_exec(
+        &self,
+        _name: &str,
+        _command: &[String],
+        _timeout: Option<Duration>,
+    ) -> Result<JobResult, Error> {
+        Ok(JobResult::default())
+    }
+    async fn container_cp(&self, _src: &str, _dst: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn pause_component(&self, _component_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn resume_component(&self, _component_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn restart_component(&mut self, _component_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct ConfigurableEnvironment {
     cfg: Config,
-    is_running: HashSet<String>,
+    runtime: Arc<dyn ContainerRuntime>,
+    run_id: String,
+    /// Shared with the clones, which start and stop components without the
+    /// script state locked.
+    components: Arc<parking_lot::Mutex<Components>>,
     stop_on_drop: bool,
     dirs: ProjectDirs,
+    /// Shared with the tasks restarting processes.
+    restarts: Arc<parking_lot::Mutex<Restarts>>,
+    capture: Capture,
+    /// How long container runtime commands may run.
+    runtime_timeout: Duration,
+    /// Print the commands starting the components instead of running them.
+    dry_run: bool,
+}
+
+/// When the lines of the captured output files were written, as the time and
+/// offset of the first line of every `LINE_TIME_RESOLUTION` by file.
+type LogTimes = Arc<parking_lot::Mutex<HashMap<PathBuf, Vec<(SystemTime, u64)>>>>;
+
+/// How the output of processes and containers is written to files.
+#[derive(Clone, Default)]
+struct Capture {
+    times: LogTimes,
+    /// Deduplication and rate limiting of each output file.
+    limits: Option<LogLimits>,
+}
+
+#[derive(Default)]
+struct Components {
+    is_running: HashSet<String>,
+    created: HashSet<String>,
+    job_results: HashMap<String, JobResult>,
+}
+
+#[derive(Default)]
+struct Restarts {
+    /// Restarts of the processes, by component.
+    counts: HashMap<String, u64>,
+    /// Processes being stopped, that must not be restarted.
+    stopping: HashSet<String>,
+    /// Exit codes of the processes that exited on their own.
+    exit_codes: HashMap<String, i64>,
+    /// When the processes were last started or restarted.
+    started_at: HashMap<String, Instant>,
+    /// Times the processes were started by `start_component`. A monitor of
+    /// an earlier start leaves the process alone.
+    generations: HashMap<String, u64>,
+}
+
+impl Restarts {
+    /// Whether the monitor of the `generation` start of `name` still looks
+    /// after its process.
+    fn monitors(&self, name: &str, generation: u64) -> bool {
+        !self.stopping.contains(name) && self.generations.get(name) == Some(&generation)
+    }
+}
+
+/// Spawn a process of a component, writing its PID and output to the data
+/// directory. The output is appended after a restart.
+fn spawn_process(
+    cmd: Command,
+    component_name: &str,
+    data_dir: &Path,
+    restarted: bool,
+    capture: &Capture,
+) -> Result<tokio::process::Child, Error> {
+    let child = spawn_captured(cmd, component_name, data_dir, restarted, capture)?;
+
+    // Write PID to file
+    if let Some(pid) = child.id() {
+        let pid_file_path = data_dir.join(format!("{}.pid", component_name));
+        std::fs::write(&pid_file_path, pid.to_string())
+            .map_err(|e| Error::io(format!("Failed to write {}", pid_file_path.display()), e))?;
+    }
+    Ok(child)
+}
+
+/// Spawn `cmd` with its stdout and stderr copied to `<name>.stdout` and
+/// `<name>.stderr` in the data directory.
+fn spawn_captured(
+    mut cmd: Command,
+    name: &str,
+    data_dir: &Path,
+    append: bool,
+    capture: &Capture,
+) -> Result<tokio::process::Child, Error> {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| Error::run(command_name(&cmd), None, e))?;
+
+    if let Some(stdout) = child.stdout.take() {
+        let path = data_dir.join(format!("{}.stdout", name));
+        tokio::spawn(copy_output(stdout, path, append, capture.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let path = data_dir.join(format!("{}.stderr", name));
+        tokio::spawn(copy_output(stderr, path, append, capture.clone()));
+    }
+    Ok(child)
+}
+
+/// Copy `output` to the file at `path` line by line, recording when each
+/// line was written. Repeated lines and lines over the rate limit are
+/// replaced by their counts if there are log limits.
+async fn copy_output(
+    output: impl AsyncRead + Unpin,
+    path: PathBuf,
+    append: bool,
+    capture: Capture,
+) {
+    let times = capture.times;
+    let mut throttle = capture.limits.map(LogThrottle::new);
+    let mut options = tokio::fs::OpenOptions::new();
+    options.create(true).write(true);
+    if append {
+        options.append(true);
+    } else {
+        options.truncate(true);
+    }
+    let mut file = match options.open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to open {}: {}", path.display(), e);
+            return;
+        }
+    };
+    // When each line was written, for `sam logs --since` in another process
+    let mut times_file = match options.open(times_path(&path)).await {
+        Ok(file) => Some(file),
+        Err(e) => {
+            log::warn!("Failed to open the line times of {}: {}", path.display(), e);
+            None
+        }
+    };
+    let mut offset = match append {
+        true => file.metadata().await.map_or(0, |metadata| metadata.len()),
+        false => {
+            times.lock().remove(&path);
+            0
+        }
+    };
+
+    let mut chunk_started_at: Option<SystemTime> = None;
+
+    let mut reader = tokio::io::BufReader::new(output);
+    let mut line = vec![];
+    loop {
+        line.clear();
+        let read = match reader.read_until(b'\n', &mut line).await {
+            Ok(read) => read,
+            Err(e) => {
+                log::warn!("Failed to read output for {}: {}", path.display(), e);
+                0
+            }
+        };
+        let lines = match (read, throttle.as_mut()) {
+            (0, None) => return,
+            (_, None) => vec![std::mem::take(&mut line)],
+            (0, Some(throttle)) => throttle_lines(throttle.flush()),
+            (_, Some(throttle)) => {
+                let text = String::from_utf8_lossy(&line);
+                throttle_lines(throttle.lines(text.trim_end_matches(['\r', '\n'])))
+            }
+        };
+        for line in lines {
+            let now = SystemTime::now();
+            let new_chunk = chunk_started_at.is_none_or(|started_at| {
+                now.duration_since(started_at)
+                    .map_or(true, |elapsed| elapsed >= LINE_TIME_RESOLUTION)
+            });
+            if new_chunk {
+                chunk_started_at = Some(now);
+                times
+                    .lock()
+                    .entry(path.clone())
+                    .or_default()
+                    .push((now, offset));
+                let millis = now
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis();
+                let entry = format!("{} {}\n", millis, offset);
+                let failed = match &mut times_file {
+                    Some(times_file) => times_file.write_all(entry.as_bytes()).await.is_err(),
+                    None => false,
+                };
+                if failed {
+                    log::warn!("Failed to write the line times of {}", path.display());
+                    times_file = None;
+                }
+            }
+            // Flushed so that the logs can be read right away
+            let written = match file.write_all(&line).await {
+                Ok(()) => file.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                log::error!("Failed to write {}: {}", path.display(), e);
+                return;
+            }
+            offset += line.len() as u64;
+        }
+        if read == 0 {
+            return;
+        }
+    }
+}
+
+/// File next to a captured output file with the time lines were written, as
+/// `<unix millis> <offset>` lines for every `LINE_TIME_RESOLUTION`.
+pub fn times_path(path: &Path) -> PathBuf {
+    let mut times_path = path.as_os_str().to_owned();
+    times_path.push(".times");
+    PathBuf::from(times_path)
+}
+
+/// The container commands run in for a component: its own container, or the
+/// first container of a pod.
+pub fn component_container(component: &Component) -> Result<String, Error> {
+    match component.component_type.as_str() {
+        "pod" => component
+            .containers
+            .first()
+            .map(|container| container.name.clone())
+            .ok_or_else(|| Error::Config(format!("Pod {} has no containers", component.name))),
+        "process" => Err(Error::Config(format!(
+            "{} is a process, not a container",
+            component.name
+        ))),
+        _ => Ok(component.name.clone()),
+    }
+}
+
+fn throttle_lines(lines: Vec<String>) -> Vec<Vec<u8>> {
+    lines
+        .into_iter()
+        .map(|line| (line + "\n").into_bytes())
+        .collect()
+}
+
+/// Command running a process, on the remote host if there is one.
+/// `long_running` processes are stopped by killing the local process.
+fn process_command(
+    remote: Option<&Remote>,
+    command: &[String],
+    env: &[(&str, &str)],
+    workdir: Option<&str>,
+    long_running: bool,
+) -> Command {
+    match (remote, workdir) {
+        (Some(remote), Some(workdir)) => {
+            // The directory is an argument of the shell, which needs no quoting
+            let mut in_workdir = vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                r#"cd "$0" && exec "$@""#.to_string(),
+                workdir.to_string(),
+            ];
+            in_workdir.extend_from_slice(command);
+            remote.command(&in_workdir, env, long_running)
+        }
+        (Some(remote), None) => remote.command(command, env, long_running),
+        (None, workdir) => {
+            let mut cmd = Command::new(&command[0]);
+            cmd.args(&command[1..]).envs(env.iter().copied());
+            if let Some(workdir) = workdir {
+                cmd.current_dir(workdir);
+            }
+            cmd
+        }
+    }
+}
+
+/// `cmd` as a line for a POSIX shell, printed instead of running it by
+/// `--dry-run`.
+pub fn command_line(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let mut words = vec![];
+    if let Some(dir) = cmd.get_current_dir() {
+        words.push(format!("cd {} &&", shell_quote(&dir.to_string_lossy())));
+    }
+    for (key, value) in cmd.get_envs() {
+        if let Some(value) = value {
+            let value = shell_quote(&value.to_string_lossy());
+            words.push(format!("{}={}", key.to_string_lossy(), value));
+        }
+    }
+    words.push(shell_quote(&cmd.get_program().to_string_lossy()));
+    words.extend(
+        cmd.get_args()
+            .map(|arg| shell_quote(&arg.to_string_lossy())),
+    );
+    words.join(" ")
+}
+
+/// The program of `cmd` and its subcommand if it has one, e.g. `podman run`,
+/// naming it in errors.
+pub fn command_name(cmd: &Command) -> String {
+    let cmd = cmd.as_std();
+    let program = Path::new(cmd.get_program()).file_name().unwrap_or_default();
+    let mut name = program.to_string_lossy().into_owned();
+    if let Some(arg) = cmd.get_args().next().map(|arg| arg.to_string_lossy()) {
+        if !arg.starts_with('-') {
+            name = format!("{} {}", name, arg);
+        }
+    }
+    name
+}
+
+/// Print `cmd` and pretend that it succeeded without output.
+fn dry_run(cmd: &Command) -> Output {
+    println!("{}", command_line(cmd));
+    Output {
+        status: std::os::unix::process::ExitStatusExt::from_raw(0),
+        stdout: vec![],
+        stderr: vec![],
+    }
+}
+
+// `KEY=value` environment variables as pairs
+fn env_pairs(environment: &[String]) -> Vec<(&str, &str)> {
+    environment
+        .iter()
+        .map(|env| env.split_once('=').unwrap_or((env, "")))
+        .collect()
+}
+
+/// The `StartedAt` of a container, `2024-05-01T10:00:00.123456789Z` from
+/// docker and `2024-05-01 12:00:00.123456789 +0200 CEST` from podman.
+fn parse_started_at(value: &str) -> Option<SystemTime> {
+    let mut parts = value.split_whitespace();
+    let date = parts.next()?;
+    let Some(time) = parts.next() else {
+        return humantime::parse_rfc3339_weak(date).ok();
+    };
+    let local = humantime::parse_rfc3339_weak(&format!("{}T{}", date, time)).ok()?;
+    let offset = parts.next().unwrap_or("+0000");
+    let (sign, digits) = offset.split_at_checked(1)?;
+    let hours = digits.get(..2)?.parse::<u64>().ok()?;
+    let minutes = digits.get(2..4)?.parse::<u64>().ok()?;
+    let offset = Duration::from_secs(hours * 3600 + minutes * 60);
+    match sign {
+        "+" => local.checked_sub(offset),
+        "-" => local.checked_add(offset),
+        _ => None,
+    }
+}
+
+/// Directories of sam, process output and PID files are kept in the local
+/// data directory.
+pub fn project_dirs() -> Result<ProjectDirs, Error> {
+    ProjectDirs::from("", "gevulot", "sam")
+        .ok_or_else(|| Error::Other("failed to find HOME directory".to_string()))
+}
+
+/// Reclaim the disk space listed in the `cleanup` section of `cfg`, once the
+/// environment was reset.
+pub async fn cleanup(cfg: &Config) -> Result<(), Error> {
+    if cfg.cleanup.volumes {
+        remove_volumes(cfg).await?;
+    }
+    if let Some(age) = cfg.cleanup.images_older_than()? {
+        log::info!(
+            "Pruning dangling images older than {}",
+            humantime::format_duration(age)
+        );
+        let until = format!("until={}s", age.as_secs());
+        let args = ["image", "prune", "-f", "--filter", &until].map(String::from);
+        reset_command(cfg, args.to_vec()).await?;
+    }
+    if let Some(keep) = &cfg.cleanup.build_cache {
+        log::info!("Pruning the build cache down to {}", keep);
+        let args = cfg.container_runtime().prune_build_cache_args(keep);
+        reset_command(cfg, args).await?;
+    }
+    Ok(())
+}
+
+/// Remove the named volumes SAM created for the components of `cfg`.
+async fn remove_volumes(cfg: &Config) -> Result<(), Error> {
+    let filter = format!("label=sam.config={}", cfg.name);
+    let list = ["volume", "ls", "-q", "--filter", &filter].map(String::from);
+    let names = reset_command(cfg, list.to_vec()).await?;
+    let names = names
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        return Ok(());
+    }
+    log::info!("Removing volumes {}", names.join(", "));
+    let mut remove = vec!["volume".to_string(), "rm".to_string(), "-f".to_string()];
+    remove.extend(names);
+    reset_command(cfg, remove).await?;
+    Ok(())
+}
+
+/// Run a container runtime command of the reset and return its stdout.
+async fn reset_command(cfg: &Config, args: Vec<String>) -> Result<String, Error> {
+    let mut cmd = cfg.container_runtime().command();
+    cmd.args(args);
+    if cfg.global.dry_run {
+        dry_run(&cmd);
+        return Ok(String::new());
+    }
+    let timeout = Some(cfg.global.timeouts.runtime()?);
+    let output = timeout::output(&mut cmd, timeout)
+        .await
+        .map_err(|e| Error::run(command_name(&cmd), timeout, e))?;
+    if !output.status.success() {
+        return Err(Error::exited(command_name(&cmd), &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
 }
 
 impl ConfigurableEnvironment {
-    pub fn new(cfg: &Config) -> Result<Self, Error> {
-        let dirs = ProjectDirs::from("", "gevulot", "sam")
-            .ok_or_else(|| Error::Other("failed to find HOME directory".to_string()))?;
+    pub fn new(cfg: &Config, run_id: &str) -> Result<Self, Error> {
+        if let Some(podman) = &cfg.podman {
+            podman.validate(cfg.runtime.unwrap_or_default(), cfg.remote.as_ref())?;
+        }
+        let dirs = project_dirs()?;
         let data_dir = dirs.data_local_dir();
         log::debug!("Creating {}", data_dir.display());
         std::fs::create_dir_all(data_dir).map_err(|err| {
@@ -56,29 +663,918 @@ impl ConfigurableEnvironment {
         })?;
         Ok(Self {
             cfg: cfg.clone(),
-            is_running: HashSet::new(),
-            stop_on_drop: true,
+            runtime: cfg.container_runtime(),
+            run_id: run_id.to_string(),
+            components: Arc::default(),
+            // Nothing was started in a dry run
+            stop_on_drop: !cfg.global.dry_run,
             dirs,
+            restarts: Arc::default(),
+            capture: Capture {
+                times: Arc::default(),
+                limits: cfg.global.log_limits.clone(),
+            },
+            runtime_timeout: cfg.global.timeouts.runtime()?,
+            dry_run: cfg.global.dry_run,
         })
     }
 
-    async fn make_sure_network_exists(&self) -> Result<(), Error> {
-        let output = Command::new("podman")
-            .arg("network")
-            .arg("exists")
-            .arg("samnet")
-            .output()
+    /// Labels attached to every container resource created for a component.
+    fn labels(&self, component_name: &str) -> Vec<String> {
+        vec![
+            format!("sam.run_id={}", self.run_id),
+            format!("sam.config={}", self.cfg.name),
+            format!("sam.component={}", component_name),
+        ]
+    }
+
+    /// Remove the containers (or pods, if `pods` is set) of this config that
+    /// carry all the given labels, returning how many were removed.
+    async fn remove_labeled(&self, pods: bool, labels: &[String]) -> Result<usize, Error> {
+        let mut cmd = self.runtime.command();
+        if pods {
+            cmd.arg("pod").arg("ps");
+        } else {
+            cmd.arg("ps").arg("-a");
+        }
+        cmd.arg("-q")
+            .arg("--filter")
+            .arg(format!("label=sam.config={}", self.cfg.name));
+        for label in labels {
+            cmd.arg("--filter").arg(format!("label={}", label));
+        }
+        let output = self.runtime_checked(&mut cmd).await?;
+
+        let ids = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let mut cmd = self.runtime.command();
+        if pods {
+            cmd.arg("pod");
+        }
+        cmd.arg("rm")
+            .args(self.runtime.force_remove_args())
+            .args(&ids);
+        self.runtime_checked(&mut cmd).await?;
+        Ok(ids.len())
+    }
+
+    /// Remove the containers (or pod) of a component by their labels, or by
+    /// the name of the component if none has its label, e.g. a container left
+    /// behind by a version of sam that did not label them.
+    async fn remove_component(&self, pods: bool, component_name: &str) -> Result<(), Error> {
+        let label = format!("sam.component={}", component_name);
+        if self.remove_labeled(pods, &[label]).await? > 0 {
+            return Ok(());
+        }
+        let mut cmd = self.runtime.command();
+        if pods {
+            cmd.arg("pod");
+        }
+        cmd.arg("rm")
+            .args(self.runtime.force_remove_args())
+            .arg(component_name);
+        // Fails if there is no such container, which is fine
+        let _ = self.runtime_run(&mut cmd).await;
+        Ok(())
+    }
+
+    /// `run` command for the container `name`, replacing an existing
+    /// container with the same name.
+    async fn run_command(&self, name: &str) -> Command {
+        let mut cmd = self.runtime.command();
+        cmd.arg("run");
+        if self.runtime.can_replace() {
+            cmd.arg("--replace");
+        } else {
+            // Fails if there is no such container, which is fine
+            let mut rm = self.runtime.command();
+            rm.arg("rm")
+                .args(self.runtime.force_remove_args())
+                .arg(name);
+            let _ = self.runtime_run(&mut rm).await;
+        }
+        cmd.arg("--name").arg(name);
+        cmd
+    }
+
+    /// Replace the `{{component.<name>.ip}}` and `{{port.<name>.<container
+    /// port>}}` placeholders in environment variables, with the address of a
+    /// running component and the host port mapped to a port of it.
+    async fn resolve_env(&self, environment: &[String]) -> Result<Vec<String>, Error> {
+        // The components referenced are not running in a dry run
+        if self.dry_run {
+            return Ok(environment.to_vec());
+        }
+        let mut values = HashMap::new();
+        for env in environment {
+            for caps in PLACEHOLDER.captures_iter(env) {
+                if values.contains_key(&caps[0]) {
+                    continue;
+                }
+                let value = self.placeholder_value(&caps[1], &caps[2], &caps[3]).await?;
+                values.insert(caps[0].to_string(), value);
+            }
+        }
+        Ok(environment
+            .iter()
+            .map(|env| {
+                PLACEHOLDER
+                    .replace_all(env, |caps: &regex::Captures| values[&caps[0]].clone())
+                    .into_owned()
+            })
+            .collect())
+    }
+
+    async fn placeholder_value(
+        &self,
+        kind: &str,
+        name: &str,
+        field: &str,
+    ) -> Result<String, Error> {
+        let component = self.cfg.get_component(name).ok_or_else(|| {
+            Error::Config(format!(
+                "Unknown component {} in {{{{{}.{}.{}}}}}",
+                name, kind, name, field
+            ))
+        })?;
+        if !self.is_running(name) {
+            return Err(Error::Config(format!(
+                "Component {} is referenced before it is running, add it to the dependencies",
+                name
+            )));
+        }
+        match (kind, component.component_type.as_str()) {
+            ("component", _) if field != "ip" => Err(Error::Config(format!(
+                "Unknown placeholder {{{{component.{}.{}}}}}, only ip is supported",
+                name, field
+            ))),
+            ("component", "process") => Ok("127.0.0.1".to_string()),
+            ("component", _) => self.container_ip(component).await,
+            (_, _) => {
+                let port: u16 = field.parse().map_err(|_| {
+                    Error::Config(format!(
+                        "Invalid port {} in {{{{port.{}.{}}}}}",
+                        field, name, field
+                    ))
+                })?;
+                self.host_port(component, port).await
+            }
+        }
+    }
+
+    /// Host port a port of a component is reachable on.
+    async fn host_port(&self, component: &Component, port: u16) -> Result<String, Error> {
+        match component.ports.iter().find(|p| p.container == port) {
+            Some(p) if p.host != 0 => Ok(p.host.to_string()),
+            Some(_) => self.published_port(&component.name, port).await,
+            // Processes listen on the host directly
+            None if component.component_type == "process" => Ok(port.to_string()),
+            None => Err(Error::Config(format!(
+                "Port {} of component {} is not published",
+                port, component.name
+            ))),
+        }
+    }
+
+    /// Address of a container, or of the infra container of a pod, in its
+    /// network.
+    async fn container_ip(&self, component: &Component) -> Result<String, Error> {
+        let mut container = component.name.clone();
+        if component.component_type == "pod" {
+            let format = "{{.InfraContainerID}}";
+            container = self
+                .runtime_output(&["pod", "inspect", "--format", format, &container])
+                .await?;
+        }
+        let ip = self
+            .runtime_output(&[
+                "inspect",
+                "--format",
+                "{{range .NetworkSettings.Networks}}{{.IPAddress}} {{end}}",
+                &container,
+            ])
+            .await?;
+        ip.split_whitespace()
+            .next()
+            .map(|ip| ip.to_string())
+            .ok_or_else(|| Error::Podman(format!("Component {} has no IP address", component.name)))
+    }
+
+    /// Host port a runtime assigned to `port` of a container.
+    async fn published_port(&self, container: &str, port: u16) -> Result<String, Error> {
+        let output = self
+            .runtime_output(&["port", container, &port.to_string()])
+            .await?;
+        output
+            .lines()
+            .next()
+            .and_then(|line| line.rsplit_once(':'))
+            .map(|(_, port)| port.trim().to_string())
+            .ok_or_else(|| {
+                Error::Podman(format!("Port {} of {} is not published", port, container))
+            })
+    }
+
+    async fn runtime_output(&self, args: &[&str]) -> Result<String, Error> {
+        self.runtime_output_within(args, Some(self.runtime_timeout))
             .await
-            .map_err(|e| Error::Podman(e.to_string()))?;
+    }
+
+    /// Stdout of a container runtime command, killed after `timeout`.
+    async fn runtime_output_within(
+        &self,
+        args: &[&str],
+        timeout: Option<Duration>,
+    ) -> Result<String, Error> {
+        let mut cmd = self.runtime.command();
+        cmd.args(args);
+        let output = self.execute(&mut cmd, timeout).await?;
         if !output.status.success() {
-            log::info!("Creating podman network samnet");
-            Command::new("podman")
-                .arg("network")
-                .arg("create")
-                .arg("samnet")
-                .output()
+            return Err(Error::exited(command_name(&cmd), &output));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Output of a container runtime command, killed after the runtime
+    /// timeout.
+    async fn runtime_run(&self, cmd: &mut Command) -> Result<Output, Error> {
+        self.execute(cmd, Some(self.runtime_timeout)).await
+    }
+
+    /// Like `runtime_run`, failing unless the command succeeds.
+    async fn runtime_checked(&self, cmd: &mut Command) -> Result<Output, Error> {
+        let output = self.runtime_run(cmd).await?;
+        if !output.status.success() {
+            return Err(Error::exited(command_name(cmd), &output));
+        }
+        Ok(output)
+    }
+
+    /// Output of `cmd`, killed after `timeout`. Only printed in a dry run.
+    async fn execute(&self, cmd: &mut Command, timeout: Option<Duration>) -> Result<Output, Error> {
+        if self.dry_run {
+            return Ok(dry_run(cmd));
+        }
+        timeout::output(cmd, timeout)
+            .await
+            .map_err(|e| Error::run(command_name(cmd), timeout, e))
+    }
+
+    fn process_command(
+        &self,
+        command: &[String],
+        env: &[(&str, &str)],
+        workdir: Option<&str>,
+        long_running: bool,
+    ) -> Command {
+        process_command(
+            self.cfg.remote.as_ref(),
+            command,
+            env,
+            workdir,
+            long_running,
+        )
+    }
+
+    /// The environment of a process component, with the placeholders and
+    /// `${VAR}` replaced.
+    async fn process_env(&self, component: &Component) -> Result<Vec<String>, Error> {
+        let env = self.resolve_env(&component.environment).await?;
+        Ok(env.iter().map(|env| config::expand_env_vars(env)).collect())
+    }
+
+    /// Record the exit code of a process, and restart it whenever it exits
+    /// as its restart policy says, until it is stopped.
+    fn monitor_process(
+        &self,
+        component_name: &str,
+        mut child: tokio::process::Child,
+        command: Vec<String>,
+        env: Vec<String>,
+        workdir: Option<String>,
+        policy: RestartPolicy,
+    ) {
+        let name = component_name.to_string();
+        let remote = self.cfg.remote.clone();
+        let data_dir = self.dirs.data_local_dir().to_path_buf();
+        let restarts = self.restarts.clone();
+        // Of the start that spawned `child`
+        let generation = restarts.lock().generations.get(&name).copied();
+        let generation = generation.unwrap_or_default();
+        let capture = self.capture.clone();
+        tokio::spawn(async move {
+            loop {
+                let status = match child.wait().await {
+                    Ok(status) => status,
+                    Err(e) => {
+                        log::error!("Failed to wait for process {}: {}", name, e);
+                        return;
+                    }
+                };
+                // Stopped, or stopped and started again
+                if !restarts.lock().monitors(&name, generation) {
+                    return;
+                }
+                if !policy.restarts(status.success()) {
+                    log::warn!("Process {} exited with {}", name, status);
+                    let exit_code = status.code().map_or(-1, |code| code as i64);
+                    let mut restarts = restarts.lock();
+                    if restarts.monitors(&name, generation) {
+                        restarts.exit_codes.insert(name, exit_code);
+                    }
+                    return;
+                }
+                log::warn!("Process {} exited with {}, restarting it", name, status);
+                tokio::time::sleep(RESTART_DELAY).await;
+                // Held while spawning, so that a stop does not miss the new
+                // process
+                let mut restarts = restarts.lock();
+                if !restarts.monitors(&name, generation) {
+                    return;
+                }
+                let cmd = process_command(
+                    remote.as_ref(),
+                    &command,
+                    &env_pairs(&env),
+                    workdir.as_deref(),
+                    true,
+                );
+                child = match spawn_process(cmd, &name, &data_dir, true, &capture) {
+                    Ok(child) => child,
+                    Err(e) => {
+                        log::error!("Failed to restart process {}: {}", name, e);
+                        return;
+                    }
+                };
+                *restarts.counts.entry(name.clone()).or_default() += 1;
+                restarts.started_at.insert(name.clone(), Instant::now());
+            }
+        });
+    }
+
+    /// Copy the logs of a container to files in the data directory, like the
+    /// output of processes, until the container is removed.
+    fn capture_container_logs(&self, container: &str) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut cmd = self.runtime.command();
+        cmd.args(["logs", "-f", container]);
+        let data_dir = self.dirs.data_local_dir();
+        let mut child = spawn_captured(cmd, container, data_dir, false, &self.capture)?;
+        tokio::spawn(async move { child.wait().await });
+        Ok(())
+    }
+
+    /// The container `container_exec` and `container_cp` use for `name`: the
+    /// container of a component, the first container of a pod, or a
+    /// container of a pod by its name.
+    fn container_name(&self, name: &str) -> Result<String, Error> {
+        match self.cfg.get_component(name) {
+            Some(component) => component_container(component),
+            None if self.cfg.components.iter().any(|component| {
+                component
+                    .containers
+                    .iter()
+                    .any(|container| container.name == name)
+            }) =>
+            {
+                Ok(name.to_string())
+            }
+            None => Err(Error::Config(format!(
+                "Component {} not found in config",
+                name
+            ))),
+        }
+    }
+
+    /// `container:/path` for a `name:/path` in a container, none for a host
+    /// path.
+    fn container_path(&self, path: &str) -> Result<Option<String>, Error> {
+        let Some((name, container_path)) = path.split_once(':') else {
+            return Ok(None);
+        };
+        let known = self.cfg.get_component(name).is_some()
+            || self.cfg.components.iter().any(|component| {
+                component
+                    .containers
+                    .iter()
+                    .any(|container| container.name == name)
+            });
+        if !known {
+            return Ok(None);
+        }
+        Ok(Some(format!(
+            "{}:{}",
+            self.container_name(name)?,
+            container_path
+        )))
+    }
+
+    /// Pause or resume the containers of a running component with the
+    /// runtime, or its process with SIGSTOP and SIGCONT.
+    async fn freeze(&self, component_name: &str, pause: bool) -> Result<(), Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        if !self.is_running(component_name) {
+            return Err(Error::Config(format!(
+                "Component {} is not running",
+                component_name
+            )));
+        }
+        let verb = if pause { "pause" } else { "unpause" };
+        match component.component_type.as_str() {
+            "container" => {
+                self.runtime_output(&[verb, component_name]).await?;
+            }
+            "pod" => {
+                self.runtime_output(&["pod", verb, component_name]).await?;
+            }
+            "process" => {
+                // Only the local ssh process is known, stopping it would not
+                // stop the process on the remote host
+                if let Some(remote) = &self.cfg.remote {
+                    return Err(Error::Config(format!(
+                        "Cannot {} process {}, it runs on {}",
+                        verb, component_name, remote.host
+                    )));
+                }
+                let pid_file_path = self
+                    .dirs
+                    .data_local_dir()
+                    .join(format!("{}.pid", component_name));
+                let pid = std::fs::read_to_string(&pid_file_path).map_err(|e| {
+                    Error::io(format!("Failed to read {}", pid_file_path.display()), e)
+                })?;
+                let signal = if pause { "-STOP" } else { "-CONT" };
+                let output = Command::new("kill")
+                    .args([signal, pid.trim()])
+                    .output()
+                    .await
+                    .map_err(|e| Error::run("kill", None, e))?;
+                if !output.status.success() {
+                    return Err(Error::exited("kill", &output));
+                }
+            }
+            other => {
+                return Err(Error::Config(format!(
+                    "Cannot {} {} component {}",
+                    verb, other, component_name
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Sum of the restart counts of the containers of a component.
+    async fn container_restart_count(&self, component: &Component) -> Result<u64, Error> {
+        let mut names = vec![component.name.clone()];
+        if component.component_type == "pod" {
+            names = component
+                .containers
+                .iter()
+                .map(|c| c.name.clone())
+                .collect();
+        }
+        let mut count = 0;
+        for name in names {
+            let output = self
+                .runtime_output(&["inspect", "--format", "{{.RestartCount}}", &name])
+                .await?;
+            count += output.trim().parse::<u64>().map_err(|_| {
+                Error::Podman(format!("Unexpected restart count of {}: {}", name, output))
+            })?;
+        }
+        Ok(count)
+    }
+
+    /// Status of the containers of a running container or pod component: a
+    /// pod runs while all its containers do, and was up since its latest start.
+    async fn container_status(&self, component: &Component) -> Result<ComponentStatus, Error> {
+        let mut names = vec![component.name.clone()];
+        if component.component_type == "pod" {
+            names = component
+                .containers
+                .iter()
+                .map(|c| c.name.clone())
+                .collect();
+        }
+        let format = "{{.State.Running}} {{.State.ExitCode}} {{.State.StartedAt}}";
+        let mut status = ComponentStatus {
+            running: true,
+            ..Default::default()
+        };
+        for name in names {
+            let output = self
+                .runtime_output(&["inspect", "--format", format, &name])
+                .await?;
+            let unexpected =
+                || Error::Podman(format!("Unexpected state of {}: {}", name, output.trim()));
+            let mut fields = output.trim().splitn(3, ' ');
+            let running = fields.next().ok_or_else(unexpected)? == "true";
+            let exit_code = fields.next().and_then(|code| code.parse::<i64>().ok());
+            let exit_code = exit_code.ok_or_else(unexpected)?;
+            if running {
+                let uptime = fields
+                    .next()
+                    .and_then(parse_started_at)
+                    .and_then(|started_at| SystemTime::now().duration_since(started_at).ok());
+                status.uptime = match (status.uptime, uptime) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            } else if status.running {
+                status.running = false;
+                status.exit_code = Some(exit_code);
+            }
+        }
+        if !status.running {
+            status.uptime = None;
+        }
+        status.restarts = self.container_restart_count(component).await?;
+        Ok(status)
+    }
+
+    /// Run the healthcheck of a component until it passes or times out.
+    async fn wait_healthy(&self, component_name: &str) -> Result<(), Error> {
+        if self.dry_run {
+            println!("# wait until {} is healthy", component_name);
+            return Ok(());
+        }
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        let healthcheck = component.healthcheck.as_ref().ok_or_else(|| {
+            Error::Config(format!(
+                "Component {} has no healthcheck to wait for",
+                component_name
+            ))
+        })?;
+        let duration = |value: &Option<String>, default| match value {
+            Some(value) => humantime::parse_duration(value).map_err(|e| {
+                Error::Config(format!(
+                    "Invalid healthcheck duration {} of {}: {}",
+                    value, component_name, e
+                ))
+            }),
+            None => Ok(default),
+        };
+        let interval = duration(&healthcheck.interval, Duration::from_secs(1))?;
+        let timeout = duration(&healthcheck.timeout, Duration::from_secs(60))?;
+
+        let client = reqwest::Client::builder()
+            .timeout(interval)
+            .build()
+            .map_err(|e| Error::Other(e.to_string()))?;
+
+        log::info!("Waiting for {} to be healthy", component_name);
+        let started_at = Instant::now();
+        loop {
+            // A check that hangs must not hold up the next one or the timeout
+            let attempt = interval.min(timeout.saturating_sub(started_at.elapsed()));
+            match self
+                .check_health(component, healthcheck, &client, attempt)
+                .await
+            {
+                Ok(()) => {
+                    log::debug!(
+                        "{} is healthy after {}",
+                        component_name,
+                        humantime::format_duration(started_at.elapsed())
+                    );
+                    return Ok(());
+                }
+                Err(e) if started_at.elapsed() >= timeout => {
+                    return Err(Error::Process(format!(
+                        "{} is not healthy after {}: {}",
+                        component_name,
+                        humantime::format_duration(timeout),
+                        e
+                    )));
+                }
+                Err(e) => log::debug!("{} is not healthy yet: {}", component_name, e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    async fn check_health(
+        &self,
+        component: &Component,
+        healthcheck: &Healthcheck,
+        client: &reqwest::Client,
+        attempt: Duration,
+    ) -> Result<(), String> {
+        let timed_out = || format!("no answer within {}", humantime::format_duration(attempt));
+        if !healthcheck.command.is_empty() {
+            let mut cmd = if component.component_type == "process" {
+                self.process_command(&healthcheck.command, &[], None, false)
+            } else {
+                let container = match component.containers.first() {
+                    Some(container) if component.component_type == "pod" => &container.name,
+                    _ => &component.name,
+                };
+                let mut cmd = self.runtime.command();
+                cmd.arg("exec").arg(container).args(&healthcheck.command);
+                cmd
+            };
+            let output =
+                self.execute(&mut cmd, Some(attempt))
+                    .await
+                    .map_err(|e| match e.timed_out() {
+                        true => format!("{}: {}", healthcheck.command.join(" "), timed_out()),
+                        false => e.to_string(),
+                    })?;
+            if !output.status.success() {
+                return Err(format!(
+                    "{} exited with {}",
+                    healthcheck.command.join(" "),
+                    output.status
+                ));
+            }
+        } else if let Some(url) = &healthcheck.http {
+            let response = tokio::time::timeout(attempt, client.get(url).send())
+                .await
+                .map_err(|_| timed_out())?
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("{} answered with {}", url, response.status()));
+            }
+        } else if let Some(port) = healthcheck.tcp {
+            let host = self
+                .cfg
+                .remote
+                .as_ref()
+                .map_or("127.0.0.1", |remote| remote.host.as_str());
+            tokio::time::timeout(attempt, tokio::net::TcpStream::connect((host, port)))
+                .await
+                .map_err(|_| format!("port {}: {}", port, timed_out()))?
+                .map_err(|e| format!("port {}: {}", port, e))?;
+        } else {
+            return Err("the healthcheck needs one of command, http or tcp".to_string());
+        }
+        Ok(())
+    }
+
+    /// Add the labels, options, image and command of a container component
+    /// to a `run` command.
+    async fn add_container_args(
+        &self,
+        cmd: &mut Command,
+        component: &Component,
+    ) -> Result<(), Error> {
+        for label in self.labels(&component.name) {
+            cmd.arg("--label").arg(label);
+        }
+
+        // Add volumes if specified
+        self.create_volumes(&component.name, &component.volumes)
+            .await?;
+        for volume in &component.volumes {
+            cmd.args(volume.args(true)?);
+        }
+
+        // Add environment variables if specified
+        for env in self.resolve_env(&component.environment).await? {
+            cmd.arg("-e").arg(env);
+        }
+
+        self.create_secrets(component).await?;
+        for secret in &component.secrets {
+            cmd.arg("--secret").arg(&secret.name);
+        }
+
+        // Add network mode if specified
+        if let Some(network) = &component.network {
+            cmd.arg(format!("--network={}", network));
+        }
+        cmd.args(self.network_args(&component.networks).await?);
+
+        cmd.args(component.resources.args());
+        cmd.args(component.security.args());
+
+        // Jobs run to completion and are removed afterwards
+        if component.restart != RestartPolicy::Never && component.component_type != "job" {
+            cmd.arg(format!("--restart={}", component.restart.container_arg()));
+        }
+
+        // Add ports if specified
+        for port in &component.ports {
+            cmd.arg("-p")
+                .arg(format!("{}:{}", port.host, port.container));
+        }
+
+        // Add entrypoint if specified
+        if let Some(entrypoint) = &component.entrypoint {
+            cmd.arg("--entrypoint").arg(entrypoint);
+        }
+
+        // Add image
+        cmd.arg(component.image().ok_or_else(|| {
+            Error::Config(format!("Image not specified for component {:?}", component))
+        })?);
+
+        // Add command if specified
+        if let Some(command) = &component.command {
+            cmd.args(command);
+        }
+        Ok(())
+    }
+
+    /// Create the named volumes that do not exist yet, labeled like the
+    /// containers so that `sam reset` finds them.
+    async fn create_volumes(&self, component_name: &str, volumes: &[Volume]) -> Result<(), Error> {
+        for name in volumes.iter().filter_map(|volume| volume.name.as_ref()) {
+            let mut cmd = self.runtime.command();
+            cmd.args(["volume", "inspect", name]);
+            let output = self.runtime_run(&mut cmd).await?;
+            // Created if missing, which a dry run cannot tell
+            if output.status.success() && !self.dry_run {
+                continue;
+            }
+            log::info!("Creating volume {} for {}", name, component_name);
+            let mut cmd = self.runtime.command();
+            cmd.args(["volume", "create"]);
+            for label in self.labels(component_name) {
+                cmd.arg("--label").arg(label);
+            }
+            self.runtime_checked(cmd.arg(name))
+                .await
+                .map_err(|e| e.context(format!("Failed to create volume {}", name)))?;
+        }
+        Ok(())
+    }
+
+    /// Create the secrets of a component, replacing those with the same name.
+    async fn create_secrets(&self, component: &Component) -> Result<(), Error> {
+        if component.secrets.is_empty() {
+            return Ok(());
+        }
+        if !self.runtime.supports_secrets() {
+            return Err(Error::Config(format!(
+                "Secrets of {} need podman, {} only supports them in a swarm",
+                component.name,
+                self.runtime.program()
+            )));
+        }
+        for secret in &component.secrets {
+            log::debug!("Creating secret {} for {}", secret.name, component.name);
+            let mut cmd = self.runtime.command();
+            cmd.args(secret.create_args());
+            for label in self.labels(&component.name) {
+                cmd.arg("--label").arg(label);
+            }
+            self.runtime_checked(&mut cmd)
                 .await
-                .map_err(|e| Error::Podman(e.to_string()))?;
+                .map_err(|e| e.context(format!("Failed to create secret {}", secret.name)))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the secrets of a stopped component, logging failures.
+    async fn remove_secrets(&self, component: &Component) {
+        for secret in &component.secrets {
+            let mut cmd = self.runtime.command();
+            cmd.args(["secret", "rm", &secret.name]);
+            match self.runtime_run(&mut cmd).await {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => log::warn!(
+                    "Failed to remove secret {}: {}",
+                    secret.name,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => log::warn!("Failed to remove secret {}: {}", secret.name, e),
+            }
+        }
+    }
+
+    /// Build the image of a component with a `build` section.
+    async fn build_image(&self, component: &Component) -> Result<(), Error> {
+        let (Some(build), Some(image)) = (&component.build, component.image()) else {
+            return Ok(());
+        };
+        log::info!("Building image {} for {}", image, component.name);
+        let mut cmd = self.runtime.command();
+        cmd.args(build.args(&image));
+        let context = || format!("Failed to build image {} for {}", image, component.name);
+        let output = self
+            .execute(&mut cmd, None)
+            .await
+            .map_err(|e| e.context(context()))?;
+        if !output.status.success() {
+            return Err(Error::exited(command_name(&cmd), &output).context(context()));
+        }
+        Ok(())
+    }
+
+    /// Run a job, in a container if it has an image and as a process
+    /// otherwise, and wait for it to exit. Its output is also written to the
+    /// data directory, like the output of processes.
+    async fn execute_job(&self, component: &Component) -> Result<JobResult, Error> {
+        log::info!("Running job {}", component.name);
+        let mut cmd = if component.image().is_some() {
+            self.build_image(component).await?;
+            let mut cmd = self.run_command(&component.name).await;
+            cmd.arg("--rm");
+            self.add_container_args(&mut cmd, component).await?;
+            cmd
+        } else {
+            let command = component.command.as_deref().unwrap_or_default();
+            if command.is_empty() {
+                return Err(Error::Config(format!(
+                    "Job {} needs an image or a command",
+                    component.name
+                )));
+            }
+            let env = self.process_env(component).await?;
+            let workdir = component.process_workdir();
+            self.process_command(
+                &component.process_command(),
+                &env_pairs(&env),
+                workdir.as_deref(),
+                false,
+            )
+        };
+
+        let started_at = Instant::now();
+        let output = self
+            .execute(&mut cmd, None)
+            .await
+            .map_err(|e| e.context(format!("Failed to run job {}", component.name)))?;
+        let result = JobResult {
+            exit_code: output.status.code().unwrap_or(-1) as i64,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            duration: started_at.elapsed(),
+        };
+        for (ext, content) in [("stdout", &result.stdout), ("stderr", &result.stderr)] {
+            let path = self
+                .dirs
+                .data_local_dir()
+                .join(format!("{}.{}", component.name, ext));
+            std::fs::write(&path, content)
+                .map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))?;
+        }
+        log::info!(
+            "Job {} exited with code {} after {}",
+            component.name,
+            result.exit_code,
+            humantime::format_duration(result.duration)
+        );
+        Ok(result)
+    }
+
+    async fn make_sure_network_exists(&self, network: &str) -> Result<(), Error> {
+        let mut cmd = self.runtime.command();
+        cmd.args(self.runtime.network_exists_args(network));
+        let output = self.runtime_run(&mut cmd).await?;
+        if !output.status.success() || self.dry_run {
+            log::info!("Creating {} network {}", self.runtime.program(), network);
+            let mut cmd = self.runtime.command();
+            cmd.arg("network")
+                .arg("create")
+                .arg("--label")
+                .arg(format!("sam.run_id={}", self.run_id))
+                .arg("--label")
+                .arg(format!("sam.config={}", self.cfg.name))
+                .arg(network);
+            self.runtime_run(&mut cmd).await?;
+        }
+        Ok(())
+    }
+
+    /// Create the named `networks` if missing, returning the options of
+    /// `run` attaching a container to them.
+    async fn network_args(&self, networks: &[String]) -> Result<Vec<String>, Error> {
+        for network in networks {
+            self.make_sure_network_exists(network).await?;
+        }
+        let attached = match self.runtime.multiple_networks() {
+            true => networks,
+            false => &networks[..networks.len().min(1)],
+        };
+        Ok(attached
+            .iter()
+            .map(|network| format!("--network={}", network))
+            .collect())
+    }
+
+    /// Connect a running container to the `networks` that `run` could not
+    /// attach it to.
+    async fn connect_networks(&self, container: &str, networks: &[String]) -> Result<(), Error> {
+        if self.runtime.multiple_networks() {
+            return Ok(());
+        }
+        for network in networks.iter().skip(1) {
+            self.runtime_output(&["network", "connect", network, container])
+                .await?;
         }
         Ok(())
     }
@@ -91,8 +1587,9 @@ impl ConfigurableEnvironment {
         while let Some(comp) = queue.pop() {
             if let Some(component) = self.cfg.get_component(&comp) {
                 for dep in &component.dependencies {
-                    if !self.is_running.contains(dep) && deps.insert(dep.clone()) {
-                        queue.push(dep.clone());
+                    let dep = dep.name();
+                    if !self.is_running(dep) && deps.insert(dep.to_string()) {
+                        queue.push(dep.to_string());
                     }
                 }
             }
@@ -100,6 +1597,7 @@ impl ConfigurableEnvironment {
 
         // Start dependencies in order
         let mut started = std::collections::HashSet::new();
+        let mut failed = HashMap::new();
         let mut remaining: Vec<_> = deps.into_iter().collect();
 
         while !remaining.is_empty() {
@@ -115,7 +1613,7 @@ impl ConfigurableEnvironment {
                 let deps_satisfied = component
                     .dependencies
                     .iter()
-                    .all(|dep| started.contains(dep));
+                    .all(|dep| started.contains(dep.name()));
 
                 if deps_satisfied {
                     // Start this component
@@ -123,6 +1621,7 @@ impl ConfigurableEnvironment {
                         tokio::runtime::Handle::current().block_on(self.start_component(dep_name))
                     }) {
                         log::error!("Failed to start component {}: {}", dep_name, e);
+                        failed.insert(dep_name.clone(), e.in_component(dep_name));
                         return true; // Keep in remaining list
                     }
 
@@ -137,6 +1636,9 @@ impl ConfigurableEnvironment {
             });
 
             if !made_progress && !remaining.is_empty() {
+                if let Some(e) = remaining.iter().find_map(|name| failed.remove(name)) {
+                    return Err(e);
+                }
                 return Err(Error::Config(format!(
                     "Circular dependency detected in components: {:?}",
                     remaining
@@ -145,13 +1647,15 @@ impl ConfigurableEnvironment {
         }
 
         // Finally start the requested component
-        ConfigurableEnvironment::start_component(self, component_name).await?;
+        ConfigurableEnvironment::start_component(self, component_name)
+            .await
+            .map_err(|e| e.in_component(component_name))?;
 
         Ok(())
     }
 
     async fn start_component(&mut self, component_name: &str) -> Result<(), Error> {
-        if self.is_running.contains(component_name) {
+        if self.is_running(component_name) {
             log::debug!("Component {} already running, skipping", component_name);
             return Ok(());
         }
@@ -161,103 +1665,102 @@ impl ConfigurableEnvironment {
         let component = self.cfg.get_component(component_name).ok_or_else(|| {
             Error::Config(format!("Component {} not found in config", component_name))
         })?;
+        if self.dry_run {
+            let position = self.components.lock().is_running.len() + 1;
+            println!(
+                "# {}. {} ({})",
+                position, component_name, component.component_type
+            );
+        }
 
-        match component.component_type.as_str() {
-            "container" => {
-                // Start container here
-                let mut cmd = Command::new("podman");
-                cmd.arg("run")
-                    .arg("-d")
-                    .arg("--replace")
-                    .arg("--name")
-                    .arg(&component.name);
-
-                // Add volumes if specified
-                for volume in &component.volumes {
-                    cmd.arg("-v")
-                        .arg(format!("{}:{}:z", volume.host, volume.container));
-                }
-
-                // Add environment variables if specified
-                for env in &component.environment {
-                    cmd.arg("-e").arg(env);
-                }
-
-                // Add network mode if specified
-                if let Some(network) = &component.network {
-                    cmd.arg(format!("--network={}", network));
-                }
-
-                // Add ports if specified
-                for port in &component.ports {
-                    cmd.arg("-p")
-                        .arg(format!("{}:{}", port.host, port.container));
-                }
+        for dep in &component.dependencies {
+            if dep.condition() == DependencyCondition::Healthy {
+                self.wait_healthy(dep.name()).await?;
+            }
+        }
 
-                // Add entrypoint if specified
-                if let Some(entrypoint) = &component.entrypoint {
-                    cmd.arg("--entrypoint").arg(entrypoint);
-                }
+        let hooks = component.hooks.clone();
+        let healthcheck = component.healthcheck.is_some();
+        self.run_component_hooks(component_name, "pre_start", &hooks.pre_start)
+            .await?;
 
-                // Add image
-                cmd.arg(component.image.as_ref().ok_or_else(|| {
-                    Error::Config(format!("Image not specified for component {:?}", component))
-                })?);
+        match component.component_type.as_str() {
+            "container" => {
+                self.build_image(component).await?;
 
-                // Add command if specified
-                if let Some(command) = &component.command {
-                    cmd.args(command);
-                }
+                // Start container here
+                let mut cmd = self.run_command(&component.name).await;
+                cmd.arg("-d");
 
-                let output = cmd
-                    .output()
-                    .await
-                    .map_err(|e| Error::Podman(e.to_string()))?;
+                self.add_container_args(&mut cmd, component).await?;
 
-                if !output.status.success() {
-                    return Err(Error::Podman(
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    ));
+                self.runtime_checked(&mut cmd).await?;
+                self.connect_networks(&component.name, &component.networks)
+                    .await?;
+                self.capture_container_logs(&component.name)?;
+            }
+            "job" => {
+                let component = component.clone();
+                let result = self.execute_job(&component).await?;
+                self.components
+                    .lock()
+                    .job_results
+                    .insert(component_name.to_string(), result.clone());
+                if !result.success() {
+                    return Err(Error::Exited {
+                        command: format!("Job {}", component_name),
+                        code: i32::try_from(result.exit_code)
+                            .ok()
+                            .filter(|code| *code >= 0),
+                        stderr: result.stderr.trim().to_string(),
+                    });
                 }
             }
             "pod" => {
-                self.make_sure_network_exists().await?;
+                if !self.runtime.supports_pods() {
+                    return Err(Error::Config(format!(
+                        "Pod {} needs podman, {} has no pods",
+                        component_name,
+                        self.runtime.program()
+                    )));
+                }
+                if component.network.is_none() && component.networks.is_empty() {
+                    self.make_sure_network_exists("samnet").await?;
+                }
 
                 let pod_name = &component.name;
+                self.create_secrets(component).await?;
 
                 // Create pod
-                let mut cmd = Command::new("podman");
+                let mut cmd = self.runtime.command();
                 cmd.arg("pod")
                     .arg("create")
                     .arg("--replace")
                     .arg("--name")
                     .arg(pod_name);
 
-                let network_arg = format!(
-                    "--network={}",
-                    &component.network.as_ref().unwrap_or(&"samnet".to_string())
-                );
-                cmd.arg(network_arg);
+                for label in self.labels(component_name) {
+                    cmd.arg("--label").arg(label);
+                }
+
+                if let Some(network) = &component.network {
+                    cmd.arg(format!("--network={}", network));
+                }
+                cmd.args(self.network_args(&component.networks).await?);
+                if component.network.is_none() && component.networks.is_empty() {
+                    cmd.arg("--network=samnet");
+                }
 
                 // Add port mappings if specified
                 for port in &component.ports {
                     cmd.arg(format!("-p={}:{}", port.host, port.container));
                 }
 
-                let output = cmd
-                    .output()
-                    .await
-                    .map_err(|e| Error::Podman(e.to_string()))?;
-
-                if !output.status.success() {
-                    return Err(Error::Podman(
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    ));
-                }
+                self.runtime_checked(&mut cmd).await?;
 
                 // Start all containers in the pod
                 for container in &component.containers {
-                    let mut cmd = Command::new("podman");
+                    let mut cmd = self.runtime.command();
                     cmd.arg("run")
                         .arg("-d")
                         .arg("--pod")
@@ -265,14 +1768,22 @@ impl ConfigurableEnvironment {
                         .arg("--name")
                         .arg(&container.name);
 
+                    for label in self.labels(component_name) {
+                        cmd.arg("--label").arg(label);
+                    }
+
                     // Add volumes if specified
+                    self.create_volumes(component_name, &container.volumes)
+                        .await?;
                     for volume in &container.volumes {
-                        cmd.arg("-v")
-                            .arg(format!("{}:{}", volume.host, volume.container));
+                        cmd.args(volume.args(false)?);
+                    }
+                    for secret in &component.secrets {
+                        cmd.arg("--secret").arg(&secret.name);
                     }
 
                     // Add environment variables if specified
-                    for env in &container.environment {
+                    for env in self.resolve_env(&container.environment).await? {
                         cmd.arg("-e").arg(env);
                     }
 
@@ -284,6 +1795,14 @@ impl ConfigurableEnvironment {
                     if let Some(network) = &container.network {
                         cmd.arg(format!("--network={}", network));
                     }
+                    cmd.args(self.network_args(&container.networks).await?);
+
+                    cmd.args(container.resources.args());
+                    cmd.args(container.security.args());
+
+                    if component.restart != RestartPolicy::Never {
+                        cmd.arg(format!("--restart={}", component.restart.container_arg()));
+                    }
 
                     cmd.arg(&container.image);
 
@@ -294,16 +1813,8 @@ impl ConfigurableEnvironment {
                         }
                     }
 
-                    let output = cmd
-                        .output()
-                        .await
-                        .map_err(|e| Error::Podman(e.to_string()))?;
-
-                    if !output.status.success() {
-                        return Err(Error::Podman(
-                            String::from_utf8_lossy(&output.stderr).to_string(),
-                        ));
-                    }
+                    self.runtime_checked(&mut cmd).await?;
+                    self.capture_container_logs(&container.name)?;
                 }
             }
             "process" => {
@@ -320,60 +1831,43 @@ impl ConfigurableEnvironment {
                     )));
                 }
 
-                let mut cmd = Command::new(&command[0]);
-
-                if command.len() > 1 {
-                    // Add arguments
-                    cmd.args(&command[1..]);
-                }
-
                 // Add environment variables if specified
-                for env in &component.environment {
-                    let mut parts = env.split('=');
-                    let key = parts.next().ok_or(Error::Config(format!(
-                        "Failed to find environment variable name in '{env}' for component {:?}",
-                        component
-                    )))?;
-                    let val = parts.collect::<Vec<_>>().join("");
-                    cmd.env(key, val);
-                }
-
-                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-
-                let child = cmd.spawn().map_err(|e| Error::Process(e.to_string()))?;
-
-                // Write PID to file
-                if let Some(pid) = child.id() {
-                    let pid_file_path = self
-                        .dirs
-                        .data_local_dir()
-                        .join(format!("{}.pid", component_name));
-                    std::fs::write(&pid_file_path, pid.to_string())
-                        .map_err(|e| Error::Process(e.to_string()))?;
-                }
-
-                // Handle stdout
-                if let Some(mut stdout) = child.stdout {
-                    let stdout_file = self
-                        .dirs
-                        .data_local_dir()
-                        .join(format!("{}.stdout", component_name));
-                    tokio::spawn(async move {
-                        let mut file = tokio::fs::File::create(&stdout_file).await.unwrap();
-                        tokio::io::copy(&mut stdout, &mut file).await.unwrap();
-                    });
-                }
-
-                // Handle stderr
-                if let Some(mut stderr) = child.stderr {
-                    let stderr_file = self
-                        .dirs
-                        .data_local_dir()
-                        .join(format!("{}.stderr", component_name));
-                    tokio::spawn(async move {
-                        let mut file = tokio::fs::File::create(&stderr_file).await.unwrap();
-                        tokio::io::copy(&mut stderr, &mut file).await.unwrap();
-                    });
+                let env = self.process_env(component).await?;
+                let command = component.process_command();
+                let workdir = component.process_workdir();
+                let cmd =
+                    self.process_command(&command, &env_pairs(&env), workdir.as_deref(), true);
+
+                if self.dry_run {
+                    dry_run(&cmd);
+                } else {
+                    {
+                        let mut restarts = self.restarts.lock();
+                        restarts.stopping.remove(component_name);
+                        restarts.exit_codes.remove(component_name);
+                        restarts
+                            .started_at
+                            .insert(component_name.to_string(), Instant::now());
+                        *restarts
+                            .generations
+                            .entry(component_name.to_string())
+                            .or_default() += 1;
+                    }
+                    let child = spawn_process(
+                        cmd,
+                        component_name,
+                        self.dirs.data_local_dir(),
+                        false,
+                        &self.capture,
+                    )?;
+                    self.monitor_process(
+                        component_name,
+                        child,
+                        command,
+                        env,
+                        workdir,
+                        component.restart,
+                    );
                 }
             }
             _ => {
@@ -384,7 +1878,16 @@ impl ConfigurableEnvironment {
             }
         }
 
-        self.is_running.insert(component_name.to_string());
+        self.components
+            .lock()
+            .is_running
+            .insert(component_name.to_string());
+
+        if healthcheck && !hooks.post_start.is_empty() {
+            self.wait_healthy(component_name).await?;
+        }
+        self.run_component_hooks(component_name, "post_start", &hooks.post_start)
+            .await?;
 
         Ok(())
     }
@@ -392,7 +1895,7 @@ impl ConfigurableEnvironment {
     async fn stop_component(&mut self, component_name: &str) -> Result<(), Error> {
         log::debug!("Stopping component {}", component_name);
 
-        if !self.is_running.contains(component_name) {
+        if !self.is_running(component_name) {
             log::debug!("Component {} not running, skipping", component_name);
             return Ok(());
         }
@@ -401,52 +1904,33 @@ impl ConfigurableEnvironment {
             Error::Config(format!("Component {} not found in config", component_name))
         })?;
 
-        match component.component_type.as_str() {
-            "pod" => {
-                let pod_name = &component.name;
-
-                let output = Command::new("podman")
-                    .arg("pod")
-                    .arg("rm")
-                    .arg("-f")
-                    .arg("-t=0")
-                    .arg(pod_name)
-                    .output()
-                    .await
-                    .map_err(|e| Error::Podman(e.to_string()))?;
-
-                if !output.status.success() {
-                    return Err(Error::Podman(
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    ));
-                }
-            }
-            "container" => {
-                let container_name = &component.name;
-
-                let output = Command::new("podman")
-                    .arg("rm")
-                    .arg("-f")
-                    .arg("-t=0")
-                    .arg(container_name)
-                    .output()
-                    .await
-                    .map_err(|e| Error::Podman(e.to_string()))?;
+        let hooks = component.hooks.clone();
+        if let Err(e) = self
+            .run_component_hooks(component_name, "pre_stop", &hooks.pre_stop)
+            .await
+        {
+            log::error!("{}, stopping {} anyway", e, component_name);
+        }
 
-                if !output.status.success() {
-                    return Err(Error::Podman(
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    ));
-                }
-            }
+        match component.component_type.as_str() {
+            // Jobs are done once they exited, there is nothing left to stop
+            "job" => {}
+            "pod" => self.remove_component(true, component_name).await?,
+            "container" => self.remove_component(false, component_name).await?,
             "process" => {
+                self.restarts
+                    .lock()
+                    .stopping
+                    .insert(component_name.to_string());
+
                 // Read PID from file
                 let pid_file_path = self
                     .dirs
                     .data_local_dir()
                     .join(format!("{}.pid", component_name));
-                let pid = std::fs::read_to_string(&pid_file_path)
-                    .map_err(|e| Error::Process(e.to_string()))?;
+                let pid = std::fs::read_to_string(&pid_file_path).map_err(|e| {
+                    Error::io(format!("Failed to read {}", pid_file_path.display()), e)
+                })?;
 
                 // Kill process
                 if let Err(e) = std::process::Command::new("kill")
@@ -455,7 +1939,7 @@ impl ConfigurableEnvironment {
                     .output()
                 {
                     log::error!("Failed to kill process {}: {}", pid, e);
-                    return Err(Error::Process(e.to_string()));
+                    return Err(Error::run("kill", None, e));
                 }
                 if let Err(e) = std::fs::remove_file(&pid_file_path) {
                     log::error!(
@@ -463,7 +1947,8 @@ impl ConfigurableEnvironment {
                         pid_file_path.display(),
                         e
                     );
-                    return Err(Error::Process(e.to_string()));
+                    let context = format!("Failed to remove {}", pid_file_path.display());
+                    return Err(Error::io(context, e));
                 }
             }
             _ => {
@@ -473,9 +1958,49 @@ impl ConfigurableEnvironment {
                 )))
             }
         }
+        self.remove_secrets(component).await;
+
+        self.components.lock().is_running.remove(component_name);
+
+        // The component is stopped either way, a failure must not keep it in
+        // the components left to stop
+        if let Err(e) = self
+            .run_component_hooks(component_name, "post_stop", &hooks.post_stop)
+            .await
+        {
+            log::error!("{}", e);
+        }
 
-        self.is_running.remove(component_name);
+        Ok(())
+    }
 
+    /// Run the `stage` hooks of a component, with the placeholders resolved
+    /// like in its environment.
+    async fn run_component_hooks(
+        &self,
+        component_name: &str,
+        stage: &str,
+        hooks: &[String],
+    ) -> Result<(), Error> {
+        if hooks.is_empty() {
+            return Ok(());
+        }
+        log::info!("Running {} hooks of {}", stage, component_name);
+        let env_vars = [
+            ("SAM_COMPONENT".to_string(), component_name.to_string()),
+            ("SAM_RUN_ID".to_string(), self.run_id.clone()),
+        ];
+        for hook in self.resolve_env(hooks).await? {
+            if self.dry_run {
+                let mut cmd = Command::new("sh");
+                cmd.args(["-c", &hook]).envs(env_vars.iter().cloned());
+                dry_run(&cmd);
+                continue;
+            }
+            crate::hooks::run_command(stage, &hook, &env_vars)
+                .await
+                .map_err(|e| e.in_component(component_name))?;
+        }
         Ok(())
     }
 }
@@ -487,12 +2012,12 @@ impl Environment for ConfigurableEnvironment {
 
         // Start all components in dependency order
         let mut started = std::collections::HashSet::new();
+        let mut failed = HashMap::new();
 
         let mut remaining: Vec<_> = self
             .cfg
-            .components
-            .iter()
-            .filter(|c| c.start_by_default)
+            .default_components()
+            .into_iter()
             .map(|c| c.name.clone())
             .collect();
 
@@ -506,7 +2031,7 @@ impl Environment for ConfigurableEnvironment {
                 let deps_satisfied = component
                     .dependencies
                     .iter()
-                    .all(|dep| started.contains(dep));
+                    .all(|dep| started.contains(dep.name()));
 
                 if deps_satisfied {
                     // Start this component
@@ -515,6 +2040,7 @@ impl Environment for ConfigurableEnvironment {
                             .block_on(self.start_component(component_name))
                     }) {
                         log::error!("Failed to start component {}: {}", component_name, e);
+                        failed.insert(component_name.clone(), e.in_component(component_name));
                         return true; // Keep in remaining list
                     }
 
@@ -529,6 +2055,9 @@ impl Environment for ConfigurableEnvironment {
             });
 
             if !made_progress && !remaining.is_empty() {
+                if let Some(e) = remaining.iter().find_map(|name| failed.remove(name)) {
+                    return Err(e);
+                }
                 return Err(Error::Config(format!(
                     "Something went wrong while starting components: {:?}",
                     remaining
@@ -555,9 +2084,9 @@ impl Environment for ConfigurableEnvironment {
             .components
             .iter()
             .map(|c| c.name.clone())
-            .filter(|name| !self.is_running.contains(name))
+            .filter(|name| !self.is_running(name))
             .collect();
-        let mut remaining: Vec<_> = self.is_running.iter().cloned().collect();
+        let mut remaining: Vec<_> = self.components.lock().is_running.iter().cloned().collect();
 
         while !remaining.is_empty() {
             let mut made_progress = false;
@@ -568,7 +2097,7 @@ impl Environment for ConfigurableEnvironment {
                     .cfg
                     .components
                     .iter()
-                    .filter(|c| c.dependencies.contains(component_name))
+                    .filter(|c| c.depends_on(component_name))
                     .all(|c| stopped.contains(&c.name));
 
                 if can_stop {
@@ -602,31 +2131,16 @@ impl Environment for ConfigurableEnvironment {
             }
         }
 
-        // Remove all pods
-        log::debug!("Removing pods");
-        for pod in self
-            .cfg
-            .components
-            .iter()
-            .filter(|c| c.component_type == "pod")
+        // Remove all pods, including the ones left behind by earlier runs
+        if self.runtime.supports_pods()
+            && self
+                .cfg
+                .components
+                .iter()
+                .any(|c| c.component_type == "pod")
         {
-            log::debug!("Removing pod {}", pod.name);
-            let output = Command::new("podman")
-                .arg("pod")
-                .arg("rm")
-                .arg("-f")
-                .arg("-t=0")
-                .arg(&pod.name)
-                .output()
-                .await
-                .map_err(|e| Error::Podman(e.to_string()))?;
-
-            if !output.status.success() {
-                return Err(Error::Podman(
-                    String::from_utf8_lossy(&output.stderr).to_string(),
-                ));
-            }
-            log::debug!("Removed pod {}", pod.name);
+            log::debug!("Removing pods");
+            self.remove_labeled(true, &[]).await?;
         }
 
         let duration = stop_time.elapsed(); // Calculate elapsed time
@@ -645,6 +2159,131 @@ impl Environment for ConfigurableEnvironment {
         ConfigurableEnvironment::stop_component(self, component_name).await
     }
 
+    async fn restart_component(&mut self, component_name: &str) -> Result<(), Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        let healthcheck = component.healthcheck.is_some();
+        log::info!("Restarting {}", component_name);
+        ConfigurableEnvironment::stop_component(self, component_name).await?;
+        self.start_component_with_deps(component_name).await?;
+        if healthcheck {
+            self.wait_healthy(component_name).await?;
+        }
+        Ok(())
+    }
+
+    fn create_component(&mut self, component: Component) -> Result<(), Error> {
+        if self.cfg.get_component(&component.name).is_some() {
+            return Err(Error::Config(format!(
+                "Component {} already exists",
+                component.name
+            )));
+        }
+
+        log::debug!("Creating component {}", component.name);
+        self.components
+            .lock()
+            .created
+            .insert(component.name.clone());
+        self.cfg.components.push(component);
+        Ok(())
+    }
+
+    fn destroy_component(&mut self, component_name: &str) -> Result<(), Error> {
+        if !self.components.lock().created.contains(component_name) {
+            return Err(Error::Config(format!(
+                "Component {} was not created by a script",
+                component_name
+            )));
+        }
+
+        log::debug!("Destroying component {}", component_name);
+        self.cfg.components.retain(|c| c.name != component_name);
+        self.components.lock().created.remove(component_name);
+        Ok(())
+    }
+
+    async fn run_job(&mut self, component_name: &str) -> Result<JobResult, Error> {
+        let component = self
+            .cfg
+            .get_component(component_name)
+            .filter(|c| c.component_type == "job")
+            .ok_or_else(|| Error::Config(format!("No job {} in config", component_name)))?
+            .clone();
+        let result = self.execute_job(&component).await?;
+        self.components
+            .lock()
+            .job_results
+            .insert(component_name.to_string(), result.clone());
+        if result.success() {
+            self.components
+                .lock()
+                .is_running
+                .insert(component_name.to_string());
+        } else {
+            self.components.lock().is_running.remove(component_name);
+        }
+        Ok(result)
+    }
+
+    fn job_result(&self, component_name: &str) -> Option<JobResult> {
+        self.components
+            .lock()
+            .job_results
+            .get(component_name)
+            .cloned()
+    }
+
+    async fn reload(&mut self, cfg: &Config) -> Result<(), Error> {
+        let mut restart = vec![];
+        for component in self.cfg.components.clone() {
+            if self.components.lock().created.contains(&component.name) {
+                continue;
+            }
+            match cfg.get_component(&component.name) {
+                None => {
+                    log::info!("Component {} was removed, stopping it", component.name);
+                    ConfigurableEnvironment::stop_component(self, &component.name).await?;
+                }
+                Some(changed) if *changed != component => {
+                    if self.is_running(&component.name) {
+                        log::info!("Component {} changed, restarting it", component.name);
+                        ConfigurableEnvironment::stop_component(self, &component.name).await?;
+                        restart.push(component.name.clone());
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        let added = cfg
+            .default_components()
+            .into_iter()
+            .filter(|c| self.cfg.get_component(&c.name).is_none())
+            .map(|c| c.name.clone())
+            .collect::<Vec<_>>();
+
+        // Components created by scripts are not part of the config file
+        let created = self
+            .cfg
+            .components
+            .iter()
+            .filter(|c| {
+                self.components.lock().created.contains(&c.name)
+                    && cfg.get_component(&c.name).is_none()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        self.cfg.components = cfg.components.clone();
+        self.cfg.components.extend(created);
+
+        for component_name in restart.iter().chain(&added) {
+            log::info!("Starting component {}", component_name);
+            self.start_component_with_deps(component_name).await?;
+        }
+        Ok(())
+    }
+
     fn stop_on_drop(&mut self, stop_on_drop: bool) {
         self.stop_on_drop = stop_on_drop;
     }
@@ -652,6 +2291,294 @@ impl Environment for ConfigurableEnvironment {
     fn data_dir(&self) -> &Path {
         self.dirs.data_local_dir()
     }
+
+    async fn restart_count(&self, component_name: &str) -> Result<u64, Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        match component.component_type.as_str() {
+            "container" | "pod" if self.is_running(component_name) => {
+                self.container_restart_count(component).await
+            }
+            "container" | "pod" | "job" => Ok(0),
+            _ => Ok(self
+                .restarts
+                .lock()
+                .counts
+                .get(component_name)
+                .copied()
+                .unwrap_or_default()),
+        }
+    }
+
+    async fn component_status(&self, component_name: &str) -> Result<ComponentStatus, Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        let running = self.is_running(component_name);
+        match component.component_type.as_str() {
+            "container" | "pod" if running => self.container_status(component).await,
+            "container" | "pod" => Ok(ComponentStatus::default()),
+            "job" => Ok(ComponentStatus {
+                exit_code: self
+                    .job_result(component_name)
+                    .map(|result| result.exit_code),
+                ..Default::default()
+            }),
+            _ => {
+                let restarts = self.restarts.lock();
+                let exit_code = restarts.exit_codes.get(component_name).copied();
+                let running = running && exit_code.is_none();
+                Ok(ComponentStatus {
+                    running,
+                    exit_code,
+                    uptime: restarts
+                        .started_at
+                        .get(component_name)
+                        .filter(|_| running)
+                        .map(Instant::elapsed),
+                    restarts: restarts
+                        .counts
+                        .get(component_name)
+                        .copied()
+                        .unwrap_or_default(),
+                })
+            }
+        }
+    }
+
+    fn component_logs(
+        &self,
+        component_name: &str,
+        since: Option<Duration>,
+    ) -> Result<String, Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        // The containers of pods have their own logs
+        let names = match component.component_type.as_str() {
+            "pod" => component
+                .containers
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect(),
+            _ => vec![component_name],
+        };
+        let since = since.map(|since| SystemTime::now() - since);
+
+        let mut logs = String::new();
+        for name in names {
+            for ext in ["stdout", "stderr"] {
+                let path = self.dirs.data_local_dir().join(format!("{}.{}", name, ext));
+                let Ok(content) = std::fs::read(&path) else {
+                    continue;
+                };
+                let start = match since {
+                    None => 0,
+                    Some(since) => self
+                        .capture
+                        .times
+                        .lock()
+                        .get(&path)
+                        .and_then(|times| {
+                            // The chunk started before `since` may have later lines
+                            times
+                                .iter()
+                                .find(|(time, _)| *time + LINE_TIME_RESOLUTION > since)
+                        })
+                        .map_or(content.len(), |(_, offset)| *offset as usize),
+                };
+                logs += &String::from_utf8_lossy(&content[start.min(content.len())..]);
+            }
+        }
+        Ok(logs)
+    }
+
+    async fn port_forward(&self, component_name: &str, port: u16) -> Result<PortForward, Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        if !self.is_running(component_name) {
+            return Err(Error::Config(format!(
+                "Component {} is not running",
+                component_name
+            )));
+        }
+        let host_port: u16 = self
+            .host_port(component, port)
+            .await?
+            .parse()
+            .map_err(|e| Error::Podman(format!("Invalid host port: {}", e)))?;
+        let Some(remote) = &self.cfg.remote else {
+            return Ok(PortForward {
+                port: host_port,
+                _tunnel: None,
+            });
+        };
+
+        let local_port = std::net::TcpListener::bind(("127.0.0.1", 0))
+            .and_then(|listener| listener.local_addr())
+            .map_err(|e| Error::io("Failed to find a free port", e))?
+            .port();
+        let mut tunnel = remote
+            .forward_command(local_port, host_port)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::run("ssh", None, e))?;
+        let command = format!(
+            "Tunnel to port {} of {} on {}",
+            port, component_name, remote.host
+        );
+        let started_at = Instant::now();
+        while tokio::net::TcpStream::connect(("127.0.0.1", local_port))
+            .await
+            .is_err()
+        {
+            if let Ok(Some(status)) = tunnel.try_wait() {
+                return Err(Error::Exited {
+                    command,
+                    code: status.code(),
+                    stderr: String::new(),
+                });
+            }
+            if started_at.elapsed() > PORT_FORWARD_TIMEOUT {
+                return Err(Error::TimedOut {
+                    command,
+                    timeout: PORT_FORWARD_TIMEOUT,
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        log::debug!(
+            "Forwarding port {} to port {} of {}",
+            local_port,
+            port,
+            component_name
+        );
+        Ok(PortForward {
+            port: local_port,
+            _tunnel: Some(tunnel),
+        })
+    }
+
+    async fn wait_exit(&self, component_name: &str, timeout: Duration) -> Result<JobResult, Error> {
+        let component = self.cfg.get_component(component_name).ok_or_else(|| {
+            Error::Config(format!("Component {} not found in config", component_name))
+        })?;
+        let timed_out = || Error::TimedOut {
+            command: format!("Waiting for {} to exit", component_name),
+            timeout,
+        };
+        let started_at = Instant::now();
+        match component.component_type.as_str() {
+            "container" => {
+                let wait = ["wait", component_name];
+                let exit_code =
+                    tokio::time::timeout(timeout, self.runtime_output_within(&wait, None))
+                        .await
+                        .map_err(|_| timed_out())??;
+                let exit_code = exit_code.lines().last().unwrap_or_default().trim();
+                let mut logs = self.runtime.command();
+                logs.args(["logs", component_name]);
+                let output = self.runtime_run(&mut logs).await?;
+                Ok(JobResult {
+                    exit_code: exit_code
+                        .parse()
+                        .map_err(|_| Error::Podman(format!("Invalid exit code {:?}", exit_code)))?,
+                    stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+                    stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+                    duration: started_at.elapsed(),
+                })
+            }
+            "process" => {
+                let exit_code = loop {
+                    if let Some(code) = self.restarts.lock().exit_codes.get(component_name) {
+                        break *code;
+                    }
+                    if started_at.elapsed() > timeout {
+                        return Err(timed_out());
+                    }
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                };
+                let read = |ext: &str| {
+                    let path = self
+                        .dirs
+                        .data_local_dir()
+                        .join(format!("{}.{}", component_name, ext));
+                    std::fs::read_to_string(path).unwrap_or_default()
+                };
+                Ok(JobResult {
+                    exit_code,
+                    stdout: read("stdout"),
+                    stderr: read("stderr"),
+                    duration: started_at.elapsed(),
+                })
+            }
+            other => Err(Error::Config(format!(
+                "Cannot wait for {} {} to exit, only containers and processes exit",
+                other, component_name
+            ))),
+        }
+    }
+
+    async fn container_exec(
+        &self,
+        name: &str,
+        command: &[String],
+        timeout: Option<Duration>,
+    ) -> Result<JobResult, Error> {
+        let container = self.container_name(name)?;
+        let started_at = Instant::now();
+        let mut cmd = self.runtime.command();
+        cmd.arg("exec").arg(&container).args(command);
+        let output = timeout::output(&mut cmd, timeout)
+            .await
+            .map_err(|e| Error::run(command_name(&cmd), timeout, e))?;
+        Ok(JobResult {
+            exit_code: output.status.code().unwrap_or(-1) as i64,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            duration: started_at.elapsed(),
+        })
+    }
+
+    async fn container_cp(&self, src: &str, dst: &str) -> Result<(), Error> {
+        let args = match (self.container_path(src)?, self.container_path(dst)?) {
+            (Some(_), Some(_)) => {
+                return Err(Error::Config(format!(
+                    "Cannot copy from {} to {}, copy between containers through the host",
+                    src, dst
+                )))
+            }
+            (None, None) => {
+                return Err(Error::Config(format!(
+                    "Neither {} nor {} is in a container, expected component:/path",
+                    src, dst
+                )))
+            }
+            (Some(src), None) => [src, dst.to_string()],
+            (None, Some(dst)) => [src.to_string(), dst],
+        };
+        let mut cmd = self.runtime.command();
+        cmd.arg("cp").args(&args);
+        self.runtime_checked(&mut cmd).await?;
+        Ok(())
+    }
+
+    async fn pause_component(&self, component_name: &str) -> Result<(), Error> {
+        self.freeze(component_name, true).await
+    }
+
+    async fn resume_component(&self, component_name: &str) -> Result<(), Error> {
+        self.freeze(component_name, false).await
+    }
+
+    fn is_running(&self, component_name: &str) -> bool {
+        self.components.lock().is_running.contains(component_name)
+    }
 }
 
 impl Drop for ConfigurableEnvironment {
@@ -663,3 +2590,390 @@ impl Drop for ConfigurableEnvironment {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+    use crate::runtime::Podman;
+
+    #[tokio::test]
+    async fn test_copy_output() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("api.stdout");
+        let capture = Capture::default();
+        copy_output(
+            &b"first\nsecond\n"[..],
+            path.clone(),
+            false,
+            capture.clone(),
+        )
+        .await;
+        copy_output(&b"third"[..], path.clone(), true, capture.clone()).await;
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "first\nsecond\nthird"
+        );
+        let offsets = capture.times.lock()[&path]
+            .iter()
+            .map(|(_, offset)| *offset)
+            .collect::<Vec<_>>();
+        // The lines written at once share a time
+        assert_eq!(offsets, [0, 13]);
+        let times = std::fs::read_to_string(times_path(&path)).unwrap();
+        let offsets = times
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, ["0", "13"]);
+
+        let capture = Capture {
+            limits: Some(LogLimits {
+                dedup: true,
+                max_per_second: None,
+            }),
+            ..Default::default()
+        };
+        copy_output(&b"a\na\na\nb"[..], path.clone(), false, capture).await;
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "a\nlast message repeated 2 times\nb\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_command() {
+        let component: Component = serde_yaml::from_str(
+            r#"
+name: api
+type: process
+command: ["echo $GREETING ${SAM_TEST_PROCESS_NAME} from", "$(pwd)"]
+shell: true
+workdir: ${SAM_TEST_PROCESS_DIR}
+environment:
+  GREETING: hello
+  PORT: 8080
+"#,
+        )
+        .unwrap();
+        assert_eq!(component.environment, ["GREETING=hello", "PORT=8080"]);
+
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        std::env::set_var("SAM_TEST_PROCESS_NAME", "api");
+        std::env::set_var("SAM_TEST_PROCESS_DIR", &dir);
+        let command = component.process_command();
+        assert_eq!(command[..2], ["sh", "-c"]);
+        let workdir = component.process_workdir();
+        let output = process_command(
+            None,
+            &command,
+            &env_pairs(&component.environment),
+            workdir.as_deref(),
+            false,
+        )
+        .output()
+        .await
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("hello api from {}\n", dir.display())
+        );
+    }
+
+    /// Podman, run by a script appending its arguments to a file.
+    struct LoggingRuntime(&'static str);
+
+    impl ContainerRuntime for LoggingRuntime {
+        fn program(&self) -> &'static str {
+            self.0
+        }
+        fn can_replace(&self) -> bool {
+            Podman.can_replace()
+        }
+        fn force_remove_args(&self) -> &'static [&'static str] {
+            Podman.force_remove_args()
+        }
+        fn supports_pods(&self) -> bool {
+            Podman.supports_pods()
+        }
+        fn supports_secrets(&self) -> bool {
+            Podman.supports_secrets()
+        }
+        fn network_exists_args(&self, network: &str) -> Vec<String> {
+            Podman.network_exists_args(network)
+        }
+        fn prune_build_cache_args(&self, keep: &str) -> Vec<String> {
+            Podman.prune_build_cache_args(keep)
+        }
+        fn image_digest_format(&self) -> &'static str {
+            Podman.image_digest_format()
+        }
+        fn logs_multiple(&self) -> bool {
+            Podman.logs_multiple()
+        }
+        fn multiple_networks(&self) -> bool {
+            Podman.multiple_networks()
+        }
+        fn remote_args(&self, remote: &Remote) -> Vec<String> {
+            Podman.remote_args(remote)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_unlabeled_container() {
+        let cfg: Config = serde_yaml::from_str(
+            r#"
+name: unlabeled
+components:
+  - name: api
+    type: container
+    image: alpine:latest
+"#,
+        )
+        .unwrap();
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let calls = dir.path().join("calls");
+        let script = dir.path().join("podman");
+        // `ps` finds no labeled container, like for one started without labels
+        std::fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$@\" >> {}\n", calls.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let program = Box::leak(script.display().to_string().into_boxed_str());
+
+        let mut env = ConfigurableEnvironment::new(&cfg, "run").unwrap();
+        env.stop_on_drop(false);
+        env.runtime = Arc::new(LoggingRuntime(program));
+        env.components.lock().is_running.insert("api".to_string());
+        ConfigurableEnvironment::stop_component(&mut env, "api")
+            .await
+            .unwrap();
+
+        let calls = std::fs::read_to_string(&calls).unwrap();
+        let calls: Vec<_> = calls.lines().collect();
+        assert_eq!(
+            calls,
+            [
+                "ps -a -q --filter label=sam.config=unlabeled --filter label=sam.component=api",
+                "rm -f -t=0 api",
+            ]
+        );
+        assert!(!env.is_running("api"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_resolve_env() {
+        let cfg: Config = serde_yaml::from_str(
+            r#"
+name: placeholders
+components:
+  - name: api
+    type: container
+    image: alpine:latest
+    ports:
+      - host: 0
+        container: 8080
+  - name: db
+    type: container
+    image: postgres:latest
+    ports:
+      - host: 5432
+        container: 5432
+  - name: worker
+    type: process
+    command: [sleep, "60"]
+"#,
+        )
+        .unwrap();
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let script = dir.path().join("podman");
+        std::fs::write(
+            &script,
+            "#!/bin/sh\ncase \"$1\" in\n  port) echo 0.0.0.0:49153 ;;\n  inspect) echo 10.88.0.5 ;;\nesac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let program = Box::leak(script.display().to_string().into_boxed_str());
+
+        let mut env = ConfigurableEnvironment::new(&cfg, "run").unwrap();
+        env.stop_on_drop(false);
+        env.runtime = Arc::new(LoggingRuntime(program));
+        for name in ["api", "db", "worker"] {
+            env.components.lock().is_running.insert(name.to_string());
+        }
+        let resolved = env
+            .resolve_env(&[
+                "API=http://{{ component.api.ip }}:{{port.api.8080}}".to_string(),
+                "DB_PORT={{port.db.5432}}".to_string(),
+                "WORKER={{component.worker.ip}}:{{port.worker.9000}}".to_string(),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            [
+                "API=http://10.88.0.5:49153",
+                "DB_PORT=5432",
+                "WORKER=127.0.0.1:9000",
+            ]
+        );
+
+        let err = env
+            .resolve_env(&["DB={{component.ghost.ip}}".to_string()])
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown component ghost"),
+            "{}",
+            err
+        );
+        let err = env
+            .resolve_env(&["DB={{component.db.host}}".to_string()])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("only ip is supported"), "{}", err);
+        env.components.lock().is_running.remove("db");
+        let err = env
+            .resolve_env(&["DB={{port.db.5432}}".to_string()])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("before it is running"), "{}", err);
+    }
+
+    // On one thread the monitor only sees the kill once the process started
+    // again
+    #[tokio::test]
+    async fn test_restart_process_once() {
+        let cfg: Config = serde_yaml::from_str(
+            r#"
+name: restart-generation
+components:
+  - name: sleeper
+    type: process
+    command: [sleep, "30"]
+    restart: always
+"#,
+        )
+        .unwrap();
+        let mut env = ConfigurableEnvironment::new(&cfg, "run").unwrap();
+        env.stop_on_drop(false);
+        env.start_component("sleeper").await.unwrap();
+        env.restart_component("sleeper").await.unwrap();
+        // The monitor of the killed process must not start another one
+        tokio::time::sleep(RESTART_DELAY * 2).await;
+        let status = env.component_status("sleeper").await.unwrap();
+        assert_eq!(status.restarts, 0);
+        assert!(status.running);
+        ConfigurableEnvironment::stop_component(&mut env, "sleeper")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_wait_healthy_hanging_check() {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        let cfg: Config = serde_yaml::from_str(&format!(
+            r#"
+name: healthcheck
+components:
+  - name: api
+    type: process
+    command: [sleep, "60"]
+    healthcheck:
+      http: http://127.0.0.1:{}/health
+      interval: 100ms
+      timeout: 500ms
+  - name: worker
+    type: process
+    command: [sleep, "60"]
+    healthcheck:
+      command: [sleep, "60"]
+      interval: 100ms
+      timeout: 500ms
+"#,
+            port
+        ))
+        .unwrap();
+        let mut env = ConfigurableEnvironment::new(&cfg, "run").unwrap();
+        env.stop_on_drop(false);
+
+        let started_at = Instant::now();
+        let err = env.wait_healthy("api").await.unwrap_err();
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.to_string().contains("not healthy after 500ms"),
+            "{}",
+            err
+        );
+
+        // The command is killed after each attempt
+        let started_at = Instant::now();
+        let err = env.wait_healthy("worker").await.unwrap_err();
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        assert!(
+            err.to_string().contains("sleep 60: no answer within"),
+            "{}",
+            err
+        );
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_pause_remote_process() {
+        let cfg: Config = serde_yaml::from_str(
+            r#"
+name: remote
+remote:
+  host: lab
+components:
+  - name: api
+    type: process
+    command: [sleep, "60"]
+"#,
+        )
+        .unwrap();
+        let mut env = ConfigurableEnvironment::new(&cfg, "run").unwrap();
+        env.stop_on_drop(false);
+        env.components.lock().is_running.insert("api".to_string());
+
+        let err = env.pause_component("api").await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Config error: Cannot pause process api, it runs on lab"
+        );
+    }
+
+    #[test]
+    fn test_parse_started_at() {
+        let docker = parse_started_at("2024-05-01T10:00:00.123456789Z").unwrap();
+        let podman = parse_started_at("2024-05-01 12:00:00.123456789 +0200 CEST").unwrap();
+        assert_eq!(docker, podman);
+        let utc = parse_started_at("2024-05-01 07:30:00.123456789 -0230 NDT").unwrap();
+        assert_eq!(docker, utc);
+        assert_eq!(parse_started_at("0001-01-01 00:00:00 +0000 UTC"), None);
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut cmd = Command::new("podman");
+        cmd.args(["run", "-e", "GREETING=it's me", "alpine"])
+            .env("SAM_COMPONENT", "api")
+            .current_dir("/tmp/my dir");
+        assert_eq!(
+            command_line(&cmd),
+            r#"cd '/tmp/my dir' && SAM_COMPONENT=api podman run -e 'GREETING=it'\''s me' alpine"#
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Errors of sam, with the component or script they happened in and the exit
+//! code of what failed. Failures of the environment are structured: an I/O
+//! error keeps its source, and a command that timed out is told apart from
+//! one that exited with an error. Invalid configs, failed tests and other
+//! failures are described by their message.
+
+use std::{fmt, io, process::Output, time::Duration};
+
+/// What failed, deciding the exit code of `sam`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorKind {
+    /// Assertions or scripts failed, exit code 1.
+    Test,
+    /// The config, the arguments or a file they name are invalid, exit code 2.
+    Config,
+    /// The container runtime or a process of the environment failed, exit
+    /// code 3.
+    Environment,
+    /// Anything else, e.g. writing the report, exit code 4.
+    Other,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Test => 1,
+            Self::Config => 2,
+            Self::Environment => 3,
+            Self::Other => 4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Test => "test",
+            Self::Config => "config",
+            Self::Environment => "environment",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Podman(String),
+    Other(String),
+    Config(String),
+    Process(String),
+    Test(String),
+    /// A file or a command of the environment could not be read, written or
+    /// started while doing `context`.
+    Io {
+        context: String,
+        source: io::Error,
+    },
+    /// A command of the environment, e.g. `podman run`, was killed after
+    /// running for `timeout`.
+    TimedOut {
+        command: String,
+        timeout: Duration,
+    },
+    /// A command of the environment exited with an error, with `code` unless
+    /// it was killed by a signal.
+    Exited {
+        command: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    /// `source` happened in a component, e.g. while starting it.
+    Component {
+        name: String,
+        source: Box<Error>,
+    },
+    /// A script failed to compile or threw, at `line` if known.
+    Script {
+        path: String,
+        line: Option<usize>,
+        message: String,
+    },
+    /// `source` happened while doing `context`, e.g. reading a file.
+    Context {
+        context: String,
+        source: Box<Error>,
+    },
+    /// Several failures, e.g. of the scripts of a run.
+    Multiple(Vec<Error>),
+}
+
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Test(_) | Self::Script { .. } => ErrorKind::Test,
+            Self::Config(_) => ErrorKind::Config,
+            Self::Podman(_)
+            | Self::Process(_)
+            | Self::Io { .. }
+            | Self::TimedOut { .. }
+            | Self::Exited { .. } => ErrorKind::Environment,
+            Self::Other(_) => ErrorKind::Other,
+            Self::Component { source, .. } | Self::Context { source, .. } => source.kind(),
+            // The worst of them, a broken environment over failed tests
+            Self::Multiple(errors) => errors
+                .iter()
+                .map(Error::kind)
+                .max()
+                .unwrap_or(ErrorKind::Other),
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        self.kind().exit_code()
+    }
+
+    /// The error of `context` failing with `source`, e.g. writing a file.
+    pub fn io(context: impl Into<String>, source: io::Error) -> Self {
+        Self::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// The error of running `command`, killed after `timeout` if the output
+    /// was collected by [`crate::timeout::output`].
+    pub fn run(command: impl Into<String>, timeout: Option<Duration>, source: io::Error) -> Self {
+        let command = command.into();
+        match timeout {
+            Some(timeout) if source.kind() == io::ErrorKind::TimedOut => {
+                Self::TimedOut { command, timeout }
+            }
+            _ => Self::io(format!("Failed to run {}", command), source),
+        }
+    }
+
+    /// The error of `command` exiting with the unsuccessful `output`.
+    pub fn exited(command: impl Into<String>, output: &Output) -> Self {
+        Self::Exited {
+            command: command.into(),
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        }
+    }
+
+    /// Whether a command was killed for running too long, which may pass
+    /// when retried.
+    pub fn timed_out(&self) -> bool {
+        match self {
+            Self::TimedOut { .. } => true,
+            Self::Component { source, .. } | Self::Context { source, .. } => source.timed_out(),
+            Self::Multiple(errors) => errors.iter().any(Error::timed_out),
+            _ => false,
+        }
+    }
+
+    /// The error as having happened in the component `name`.
+    pub fn in_component(self, name: &str) -> Self {
+        match self {
+            Self::Component {
+                name: ref inner, ..
+            } if inner == name => self,
+            source => Self::Component {
+                name: name.to_string(),
+                source: Box::new(source),
+            },
+        }
+    }
+
+    /// The error as having happened while doing `context`.
+    pub fn context(self, context: impl Into<String>) -> Self {
+        Self::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The innermost component the error happened in.
+    pub fn component(&self) -> Option<&str> {
+        match self {
+            Self::Component { name, source } => source.component().or(Some(name)),
+            Self::Context { source, .. } => source.component(),
+            _ => None,
+        }
+    }
+
+    /// The script the error happened in, and the line if known.
+    pub fn script(&self) -> Option<(&str, Option<usize>)> {
+        match self {
+            Self::Script { path, line, .. } => Some((path, *line)),
+            Self::Component { source, .. } | Self::Context { source, .. } => source.script(),
+            _ => None,
+        }
+    }
+
+    /// The message of this error, without its source.
+    fn message(&self) -> String {
+        match self {
+            Self::Podman(e) => format!("Podman error: {}", e),
+            Self::Other(e) => format!("Other error: {}", e),
+            Self::Config(e) => format!("Config error: {}", e),
+            Self::Process(e) => format!("Process error: {}", e),
+            Self::Test(e) => format!("Test error: {}", e),
+            Self::Io { context, .. } => context.clone(),
+            Self::TimedOut { command, timeout } => format!(
+                "{} timed out after {}",
+                command,
+                humantime::format_duration(*timeout)
+            ),
+            Self::Exited {
+                command,
+                code,
+                stderr,
+            } => {
+                let mut message = match code {
+                    Some(code) => format!("{} exited with code {}", command, code),
+                    None => format!("{} was killed by a signal", command),
+                };
+                if !stderr.is_empty() {
+                    message += &format!(": {}", stderr);
+                }
+                message
+            }
+            Self::Component { name, .. } => format!("Component {} failed", name),
+            Self::Script {
+                path,
+                line: Some(line),
+                message,
+            } => format!("{}:{}: {}", path, line, message),
+            Self::Script { path, message, .. } => format!("{}: {}", path, message),
+            Self::Context { context, .. } => context.clone(),
+            Self::Multiple(errors) => format!("{} errors", errors.len()),
+        }
+    }
+
+    /// The error printed for the user: one `error:` line for each failure,
+    /// followed by the errors causing it, in red and bold if `color`.
+    pub fn report(&self, color: bool) -> String {
+        if let Self::Multiple(errors) = self {
+            return errors
+                .iter()
+                .map(|error| error.report(color))
+                .collect::<Vec<_>>()
+                .join("\n");
+        }
+        let label = match color {
+            true => "\x1b[1;31merror:\x1b[0m",
+            false => "error:",
+        };
+        let mut lines = vec![format!("{} {}", label, self.message())];
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            let message = match error.downcast_ref::<Error>() {
+                Some(error) => error.message(),
+                None => error.to_string(),
+            };
+            lines.push(format!("  caused by: {}", message));
+            source = error.source();
+        }
+        lines.join("\n")
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Component { source, .. } | Self::Context { source, .. } => Some(source.as_ref()),
+            Self::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The message followed by the messages of the sources, as most errors end up
+/// in the messages of other errors.
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Component { source, .. } | Self::Context { source, .. } => {
+                write!(f, "{}: {}", self.message(), source)
+            }
+            Self::Io { source, .. } => write!(f, "{}: {}", self.message(), source),
+            Self::Multiple(errors) => {
+                let errors = errors.iter().map(Error::to_string).collect::<Vec<_>>();
+                write!(f, "{}", errors.join("\n"))
+            }
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_chain() {
+        let error = Error::Podman("no such image".to_string())
+            .context("Failed to pull docker.io/library/postgres")
+            .in_component("db");
+        assert_eq!(error.kind(), ErrorKind::Environment);
+        assert_eq!(error.exit_code(), 3);
+        assert_eq!(error.component(), Some("db"));
+        assert_eq!(
+            error.to_string(),
+            "Component db failed: Failed to pull docker.io/library/postgres: \
+             Podman error: no such image"
+        );
+        assert_eq!(
+            error.report(false),
+            "error: Component db failed\n  \
+             caused by: Failed to pull docker.io/library/postgres\n  \
+             caused by: Podman error: no such image"
+        );
+
+        let errors = Error::Multiple(vec![
+            Error::Script {
+                path: "tests/api.rhai".to_string(),
+                line: Some(3),
+                message: "boom".to_string(),
+            },
+            Error::Config("unknown component".to_string()),
+        ]);
+        assert_eq!(errors.kind(), ErrorKind::Config);
+        assert_eq!(
+            errors.report(false),
+            "error: tests/api.rhai:3: boom\nerror: Config error: unknown component"
+        );
+    }
+
+    #[test]
+    fn test_environment_errors() {
+        let source = io::Error::new(io::ErrorKind::TimedOut, "deadline passed");
+        let error =
+            Error::run("podman exec", Some(Duration::from_secs(5)), source).in_component("db");
+        assert!(error.timed_out());
+        assert_eq!(error.kind(), ErrorKind::Environment);
+        assert_eq!(
+            error.to_string(),
+            "Component db failed: podman exec timed out after 5s"
+        );
+
+        let source = io::Error::new(io::ErrorKind::NotFound, "No such file or directory");
+        let error = Error::run("podman", None, source).context("Failed to start db");
+        assert!(!error.timed_out());
+        assert_eq!(
+            error.report(false),
+            "error: Failed to start db\n  \
+             caused by: Failed to run podman\n  \
+             caused by: No such file or directory"
+        );
+
+        let error = Error::Exited {
+            command: "podman run".to_string(),
+            code: Some(125),
+            stderr: "no such image".to_string(),
+        };
+        assert_eq!(error.kind(), ErrorKind::Environment);
+        assert_eq!(
+            error.to_string(),
+            "podman run exited with code 125: no such image"
+        );
+    }
+}
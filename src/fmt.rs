@@ -0,0 +1,556 @@
+//! `sam fmt`, formatting the Rhai scripts and modules of a config.
+//!
+//! The formatter works on tokens so that comments are kept, and compares the
+//! AST of the formatted script with the original one, without positions, so
+//! that formatting never changes what a script does.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::ArgMatches;
+use regex::Regex;
+
+use crate::{config::Config, Error};
+
+const INDENT: &str = "    ";
+
+const KEYWORDS: &[&str] = &[
+    "if", "else", "while", "for", "in", "loop", "do", "until", "let", "const", "fn", "private",
+    "return", "throw", "try", "catch", "switch", "import", "export", "as", "break", "continue",
+];
+
+/// Statements terminated with `;` when it is missing at the end of a line.
+const STATEMENT_KEYWORDS: &[&str] = &[
+    "let", "const", "import", "export", "return", "throw", "break", "continue",
+];
+
+const BINARY_OPERATORS: &[&str] = &[
+    "=", "+=", "-=", "*=", "/=", "%=", "**=", "<<=", ">>=", "&=", "|=", "^=", "==", "!=", "<", ">",
+    "<=", ">=", "&&", "||", "&", "|", "^", "+", "-", "*", "/", "%", "**", "<<", ">>", "??", "=>",
+];
+
+/// Operators written without spaces around them.
+const TIGHT_OPERATORS: &[&str] = &[".", "?.", "::", "..", "..="];
+
+/// Tokens starting a line that continues the expression of the line before.
+const CONTINUATIONS: &[&str] = &[".", "?.", "&&", "||", "??", "+", "*", "/", "%"];
+
+const PUNCTUATION: &[&str] = &[
+    "**=", "<<=", ">>=", "..=", "#{", "?.", "?[", "??", "::", "=>", "==", "!=", "<=", ">=", "&&",
+    "||", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "**", "<<", ">>", "..",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Identifiers, keywords and numbers.
+    Word,
+    /// Strings and characters.
+    Literal,
+    Comment,
+    Punct,
+    Newline,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    text: &'a str,
+}
+
+impl Token<'_> {
+    fn is(&self, text: &str) -> bool {
+        self.kind == Kind::Punct && self.text == text
+    }
+
+    fn is_opener(&self) -> bool {
+        self.kind == Kind::Punct && ["(", "[", "{", "#{", "?["].contains(&self.text)
+    }
+
+    fn is_closer(&self) -> bool {
+        self.kind == Kind::Punct && [")", "]", "}"].contains(&self.text)
+    }
+
+    fn is_keyword(&self) -> bool {
+        self.kind == Kind::Word && KEYWORDS.contains(&self.text)
+    }
+
+    fn is_binary(&self) -> bool {
+        self.kind == Kind::Punct && BINARY_OPERATORS.contains(&self.text)
+    }
+
+    /// Whether an operator after this token is unary, or a `|` starts the
+    /// parameters of a closure.
+    fn expects_operand(&self) -> bool {
+        match self.kind {
+            Kind::Punct => !self.is_closer(),
+            Kind::Word => self.is_keyword(),
+            _ => false,
+        }
+    }
+}
+
+fn lex(src: &str) -> Result<Vec<Token<'_>>, String> {
+    let bytes = src.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    // A shebang line is kept as it is
+    if src.starts_with("#!") {
+        i = src.find('\n').unwrap_or(src.len());
+        tokens.push(Token {
+            kind: Kind::Comment,
+            text: &src[..i],
+        });
+    }
+    while i < bytes.len() {
+        let start = i;
+        let c = src[i..].chars().next().unwrap();
+        let kind = match c {
+            '\n' => {
+                i += 1;
+                Kind::Newline
+            }
+            c if c.is_whitespace() => {
+                i += c.len_utf8();
+                continue;
+            }
+            '/' if bytes.get(i + 1) == Some(&b'/') => {
+                i = src[i..].find('\n').map_or(src.len(), |end| i + end);
+                Kind::Comment
+            }
+            '/' if bytes.get(i + 1) == Some(&b'*') => {
+                // Block comments nest
+                let mut depth = 0;
+                loop {
+                    if bytes[i..].starts_with(b"/*") {
+                        depth += 1;
+                        i += 2;
+                    } else if bytes[i..].starts_with(b"*/") {
+                        depth -= 1;
+                        i += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if i < bytes.len() {
+                        i += 1;
+                    } else {
+                        return Err("unterminated block comment".to_string());
+                    }
+                }
+                Kind::Comment
+            }
+            '"' | '\'' => {
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        Some(b'\\') => i += 2,
+                        Some(&b) if b == c as u8 => break,
+                        Some(_) => i += 1,
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                i += 1;
+                Kind::Literal
+            }
+            '`' => {
+                i += 1;
+                loop {
+                    match bytes.get(i) {
+                        // A doubled back-tick is part of the string
+                        Some(b'`') if bytes.get(i + 1) == Some(&b'`') => i += 2,
+                        Some(b'`') => break,
+                        Some(_) => i += 1,
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                i += 1;
+                Kind::Literal
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let word = |c: char| c.is_alphanumeric() || c == '_';
+                i += src[i..].find(|c| !word(c)).unwrap_or(src.len() - i);
+                if c.is_ascii_digit() {
+                    // Fraction and exponent of floats
+                    let digit = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+                    if bytes.get(i) == Some(&b'.') && digit(i + 1) {
+                        i += 1;
+                        i += src[i..].find(|c| !word(c)).unwrap_or(src.len() - i);
+                    }
+                    if matches!(bytes[i - 1], b'e' | b'E')
+                        && matches!(bytes.get(i), Some(b'+' | b'-'))
+                        && digit(i + 1)
+                    {
+                        i += 1;
+                        i += src[i..].find(|c| !word(c)).unwrap_or(src.len() - i);
+                    }
+                }
+                Kind::Word
+            }
+            c => {
+                i += PUNCTUATION
+                    .iter()
+                    .find(|p| src[i..].starts_with(**p))
+                    .map_or(c.len_utf8(), |p| p.len());
+                Kind::Punct
+            }
+        };
+        let text = &src[start..i];
+        tokens.push(Token {
+            kind,
+            text: if kind == Kind::Comment {
+                text.trim_end()
+            } else {
+                text
+            },
+        });
+    }
+    Ok(tokens)
+}
+
+/// A bracket that is still open.
+struct Open {
+    /// Indentation of the line it was opened on.
+    base: usize,
+    /// Whether the lines inside are indented, only the last bracket left
+    /// open on a line indents.
+    indents: bool,
+    line: usize,
+}
+
+fn format_tokens(tokens: &[Token]) -> String {
+    let lines: Vec<Vec<Token>> = tokens
+        .split(|token| token.kind == Kind::Newline)
+        .map(|line| line.to_vec())
+        .collect();
+
+    let mut out = String::new();
+    let mut stack: Vec<Open> = vec![];
+    let mut blank = false;
+    let mut continuation = false;
+    // Stack depth of a statement that needs a `;` at its end
+    let mut statement: Option<usize> = None;
+    // Last token that is not a comment
+    let mut last: Option<Token> = None;
+
+    for (n, line) in lines.iter().enumerate() {
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+
+        let leading_closers = line.iter().take_while(|token| token.is_closer()).count();
+        let indent = if leading_closers > 0 {
+            let outermost = stack.len().saturating_sub(leading_closers);
+            stack.get(outermost).map_or(0, |open| open.base)
+        } else {
+            let inner = stack
+                .last()
+                .map_or(0, |open| open.base + open.indents as usize);
+            let continues = line[0].kind == Kind::Punct && CONTINUATIONS.contains(&line[0].text);
+            inner + (continuation || continues) as usize
+        };
+
+        // No blank lines at the start and end of blocks
+        let after_opener = last.is_some_and(|token| token.is_opener());
+        if blank && !after_opener && leading_closers == 0 {
+            out.push('\n');
+        }
+        blank = false;
+
+        if statement.is_none()
+            && line[0].kind == Kind::Word
+            && STATEMENT_KEYWORDS.contains(&line[0].text)
+        {
+            statement = Some(stack.len());
+        }
+
+        let mut text = INDENT.repeat(indent);
+        let mut prev: Option<Token> = None;
+        let mut prev_unary = false;
+        let mut closure_params = false;
+        let mut prev_closure_start = false;
+        // End of the code before trailing comments, where a `;` goes
+        let mut code_end = text.len();
+        for token in line {
+            // Empty statements
+            if token.is(";") && last.is_some_and(|last| last.is(";") || last.is("{")) {
+                continue;
+            }
+
+            let mut unary = false;
+            let mut closure_start = false;
+            let space = prev.is_some_and(|prev| {
+                let tight = |token: &Token| {
+                    token.kind == Kind::Punct && TIGHT_OPERATORS.contains(&token.text)
+                };
+                if prev.is_opener() {
+                    (prev.is("{") || prev.is("#{")) && !token.is("}")
+                } else if token.is("}") {
+                    true
+                } else if [")", "]", ",", ";", ":"].iter().any(|p| token.is(p)) {
+                    false
+                } else if [",", ";", ":"].iter().any(|p| prev.is(p))
+                    || token.kind == Kind::Comment
+                    || prev.kind == Kind::Comment
+                {
+                    true
+                } else if tight(&prev)
+                    || tight(token)
+                    || prev_unary
+                    || prev_closure_start
+                    || token.is("|") && closure_params
+                {
+                    false
+                } else if token.is("(") || token.is("[") {
+                    // Calls and indexing
+                    !(prev.kind == Kind::Word && !prev.is_keyword() || prev.is_closer())
+                } else {
+                    !token.is("?[")
+                }
+            });
+
+            if token.is("|") {
+                if closure_params {
+                    closure_params = false;
+                } else if prev.is_none_or(|prev| prev.expects_operand()) {
+                    closure_params = true;
+                    closure_start = true;
+                }
+            } else if ["-", "+", "!"].contains(&token.text) && token.kind == Kind::Punct {
+                unary = token.is("!") || prev.is_none_or(|prev| prev.expects_operand());
+            }
+
+            if space {
+                text.push(' ');
+            }
+            text += token.text;
+            if token.kind != Kind::Comment {
+                code_end = text.len();
+                last = Some(*token);
+            }
+
+            if token.is_opener() {
+                stack.push(Open {
+                    base: indent,
+                    indents: false,
+                    line: n,
+                });
+            } else if token.is_closer() {
+                stack.pop();
+            }
+            prev = Some(*token);
+            prev_unary = unary;
+            prev_closure_start = closure_start;
+        }
+
+        if let Some(open) = stack.iter_mut().rev().find(|open| open.line == n) {
+            open.indents = true;
+        }
+
+        let ends_open = last.is_some_and(|last| {
+            last.is(",") || last.is_opener() || last.is_binary() && !last.is("|")
+        });
+        continuation = last.is_some_and(|last| last.is_binary() && !last.is("|"));
+
+        if let Some(depth) = statement {
+            let next = lines[n + 1..].iter().find_map(|line| line.first());
+            let continued = next.is_some_and(|next| {
+                next.kind == Kind::Punct && (CONTINUATIONS.contains(&next.text) || next.is_binary())
+                    || next.kind == Kind::Word && ["else", "catch"].contains(&next.text)
+            });
+            if stack.len() < depth || last.is_some_and(|last| last.is(";")) {
+                statement = None;
+            } else if stack.len() == depth && !ends_open && !continued {
+                text.insert(code_end, ';');
+                last = Some(Token {
+                    kind: Kind::Punct,
+                    text: ";",
+                });
+                statement = None;
+            }
+        }
+
+        out += &text;
+        out.push('\n');
+    }
+    out
+}
+
+/// The AST of a script without positions, to compare scripts. Closures are
+/// named after a hash of their source, so their names are left out and the
+/// functions sorted.
+fn ast_outline(src: &str) -> Result<String, String> {
+    let mut engine = rhai::Engine::new_raw();
+    engine.set_max_expr_depths(0, 0);
+    let ast = engine.compile(src).map_err(|e| e.to_string())?;
+    let debug = format!("{:?}", ast);
+    let debug = Regex::new(r"\d+:\d+(-\d+(:\d+)?)?")
+        .unwrap()
+        .replace_all(&debug, "");
+    let debug = Regex::new(r"anon\$[0-9a-f]+")
+        .unwrap()
+        .replace_all(&debug, "anon");
+    let debug = debug.trim_end_matches(" }");
+    let mut starts = Regex::new(r", (private )?[\w$]+\([^()]*\): \[")
+        .unwrap()
+        .find_iter(debug)
+        .map(|m| m.start())
+        .collect::<Vec<_>>();
+    starts.push(debug.len());
+    let mut parts = vec![&debug[..starts[0]]];
+    parts.extend(starts.windows(2).map(|w| &debug[w[0]..w[1]]));
+    parts[1..].sort();
+    Ok(parts.join("\n"))
+}
+
+/// Format a script: indentation, spaces between tokens, at most one blank
+/// line, no empty statements and `;` after `let`, `const`, `return` and
+/// similar statements. Fails if the script does not compile.
+pub fn format_script(src: &str) -> Result<String, String> {
+    let outline = ast_outline(src)?;
+    let formatted = format_tokens(&lex(src)?);
+    if ast_outline(&formatted).ok().as_ref() != Some(&outline) {
+        return Err("formatting would change the script, it is left as it is".to_string());
+    }
+    Ok(formatted)
+}
+
+/// The `.rhai` files of `path`, recursively if it is a directory.
+fn rhai_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)
+        .map_err(|e| Error::Other(format!("Failed to read {}: {}", path.display(), e)))?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() || entry.extension().is_some_and(|ext| ext == "rhai") {
+            rhai_files(&entry, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Scripts, module directories and setup and teardown scripts of a config.
+fn config_paths(config_path: &str) -> Result<Vec<PathBuf>, Error> {
+    let cfg = Config::load(config_path)?;
+    let base = cfg.workdir(config_path).unwrap_or_default();
+    Ok(cfg
+        .global
+        .scripts
+        .iter()
+        .chain(&cfg.global.module_dirs)
+        .chain(&cfg.global.setup_script)
+        .chain(&cfg.global.teardown_script)
+        .map(|path| base.join(path))
+        .collect())
+}
+
+/// Format the given scripts and directories, or those of the config. With
+/// `--check` nothing is written and a diff is printed for every script that
+/// is not formatted.
+pub fn fmt(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let paths = match sub_matches.get_many::<String>("paths") {
+        Some(paths) => paths.map(PathBuf::from).collect(),
+        None => config_paths(sub_matches.get_one::<String>("config").unwrap())?,
+    };
+    let mut files = vec![];
+    for path in &paths {
+        rhai_files(path, &mut files)?;
+    }
+    files.dedup();
+
+    let check = sub_matches.get_flag("check");
+    let mut unformatted = 0;
+    let mut errors = vec![];
+    for file in &files {
+        let name = file.display().to_string();
+        let src = fs::read_to_string(file)
+            .map_err(|e| Error::Other(format!("Failed to read {}: {}", name, e)))?;
+        let formatted = match format_script(&src) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                errors.push(format!("{}: {}", name, e));
+                continue;
+            }
+        };
+        if formatted == src {
+            continue;
+        }
+        unformatted += 1;
+        if check {
+            let diff = similar::TextDiff::from_lines(&src, &formatted);
+            print!("{}", diff.unified_diff().header(&name, &name));
+        } else {
+            fs::write(file, formatted)
+                .map_err(|e| Error::Other(format!("Failed to write {}: {}", name, e)))?;
+            log::info!("Formatted {}", name);
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(Error::Other(format!(
+            "Failed to format {} scripts:\n{}",
+            errors.len(),
+            errors.join("\n")
+        )));
+    }
+    if check && unformatted > 0 {
+        return Err(Error::Test(format!(
+            "{} of {} scripts are not formatted, run sam fmt",
+            unformatted,
+            files.len()
+        )));
+    }
+    log::info!("{} scripts formatted", files.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_script() {
+        let src = r#"import "auth" as auth;
+
+// Login flow
+describe("login",||{
+  let user=#{ name : "ann", roles : [ "admin" ] };
+
+
+  it("works" , || {
+      let x=-1+ 2*3;
+      if(x>0){print(x);;}
+      let f=|a,b|a+b;
+      /* keep */ assert(f(1,2)==3 , `sum ${x}`);
+      return
+  });
+});
+"#;
+        let expected = r#"import "auth" as auth;
+
+// Login flow
+describe("login", || {
+    let user = #{ name: "ann", roles: ["admin"] };
+
+    it("works", || {
+        let x = -1 + 2 * 3;
+        if (x > 0) { print(x); }
+        let f = |a, b| a + b;
+        /* keep */ assert(f(1, 2) == 3, `sum ${x}`);
+        return;
+    });
+});
+"#;
+        let formatted = format_script(src).unwrap();
+        similar_asserts::assert_eq!(formatted, expected);
+        assert_eq!(format_script(&formatted).unwrap(), formatted);
+        assert!(format_script("let x = ").is_err());
+    }
+}
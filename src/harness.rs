@@ -0,0 +1,162 @@
+//! Run SAM suites from Rust integration tests, one `#[tokio::test]` per suite:
+//!
+//! ```no_run
+//! #[tokio::test(flavor = "multi_thread")]
+//! async fn api() -> Result<(), sam::Error> {
+//!     sam::harness::run("tests/api/config.yaml", &["tests/api/smoke.rhai"]).await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! The environment is stopped when the run finishes, which like the script
+//! functions blocks in place, so tests need the multi-threaded runtime.
+
+use std::path::PathBuf;
+
+use crate::{
+    chaos::ChaosScheduler,
+    config::Config,
+    hooks::{self, RunSummary},
+    rhai::{Engine, RunResult},
+    seed,
+    state::TestReport,
+    ConfigurableEnvironment, Environment, Error,
+};
+
+/// Runs the scripts of a config, or the ones given instead, in a fresh
+/// environment with its hooks and chaos. Report uploads are left to the `sam`
+/// binary.
+pub struct Harness {
+    cfg: Config,
+    scripts: Vec<String>,
+}
+
+impl Harness {
+    pub fn new(config_path: &str) -> Result<Self, Error> {
+        let mut cfg = Config::load(config_path)?;
+        cfg.check_profiles()?;
+        cfg.select_target()?;
+        Ok(Self {
+            cfg,
+            scripts: vec![],
+        })
+    }
+
+    /// Run `script` instead of the scripts of the config, in the order added.
+    pub fn add_script(&mut self, script: &str) {
+        self.scripts.push(script.to_string());
+    }
+
+    pub fn set_filter(&mut self, filter: &str) {
+        self.cfg.global.filter = Some(filter.to_string());
+    }
+
+    /// Run the scripts against the entry `name` of `targets`, like `--target`.
+    pub fn set_target(&mut self, name: &str) -> Result<(), Error> {
+        self.cfg.global.target = Some(name.to_string());
+        self.cfg.select_target()
+    }
+
+    /// Start the components of `profiles` besides those without profiles, like
+    /// `--profile`.
+    pub fn set_profiles(&mut self, profiles: &[&str]) -> Result<(), Error> {
+        self.cfg.global.profiles = profiles.iter().map(|p| p.to_string()).collect();
+        self.cfg.check_profiles()
+    }
+
+    /// Start the environment and run the scripts. The report is returned if
+    /// all of them passed, otherwise the failures are in the error.
+    pub async fn run(mut self) -> Result<TestReport, Error> {
+        if !self.scripts.is_empty() {
+            self.cfg.global.scripts = std::mem::take(&mut self.scripts);
+        }
+        let cfg = self.cfg;
+        let run_id = format!("{:016x}", rand::random::<u64>());
+
+        cfg.load_env_files()?;
+        let mut env = ConfigurableEnvironment::new(&cfg, &run_id)?;
+        env.start().await?;
+
+        let mut engine = Engine::new(env, &cfg.module_dirs()?);
+        engine.configure(&cfg, &run_id)?;
+        seed::seed_running(&mut engine).await?;
+        hooks::run_hooks("pre_run", &cfg.hooks.pre_run, &mut engine, &[]).await?;
+
+        let mut failures = vec![];
+        let mut results = RunResult::default();
+        if let Some(script) = &cfg.global.setup_script {
+            if let Err(e) = hooks::run_script("setup", script, &mut engine) {
+                failures.push(e.to_string());
+            }
+        }
+        if failures.is_empty() {
+            let chaos = ChaosScheduler::for_config(&cfg, engine.shared_state())?;
+            for script in &cfg.global.scripts {
+                let result = engine.run(PathBuf::from(script));
+                let failed = !result.success();
+                results.extend(result);
+                if failed && !engine.continue_on_error() {
+                    break;
+                }
+            }
+            if let Some(chaos) = chaos {
+                chaos.stop().await;
+            }
+        }
+        failures.extend(results.errors().map(|script| {
+            format!(
+                "{}: {}",
+                script.path.display(),
+                script.error.as_ref().unwrap()
+            )
+        }));
+        if let Some(script) = &cfg.global.teardown_script {
+            if let Err(e) = hooks::run_script("teardown", script, &mut engine) {
+                failures.push(e.to_string());
+            }
+        }
+
+        let report = engine.get_report();
+        let ran_ok = failures.is_empty();
+        failed_tests(&report, "", &mut failures);
+        let summary = RunSummary::new(&engine, &run_id, &cfg.name, report.test_count, ran_ok, None);
+        if let Err(e) = hooks::run_post_hooks(&cfg, &mut engine, &summary).await {
+            failures.push(e.to_string());
+        }
+        engine.flush_log();
+        if !failures.is_empty() || !summary.success {
+            return Err(Error::Test(format!(
+                "{} failed:\n{}",
+                cfg.name,
+                failures.join("\n")
+            )));
+        }
+        Ok(report)
+    }
+}
+
+/// Run `scripts` of the config at `config_path`, or all of its scripts if
+/// none are given.
+pub async fn run(config_path: &str, scripts: &[&str]) -> Result<TestReport, Error> {
+    let mut harness = Harness::new(config_path)?;
+    for script in scripts {
+        harness.add_script(script);
+    }
+    harness.run().await
+}
+
+// Names of the failed tests without failed children, as their path below
+// the root of the report
+fn failed_tests(report: &TestReport, prefix: &str, failures: &mut Vec<String>) {
+    for child in report.children.iter().filter(|child| !child.success) {
+        let name = match prefix {
+            "" => child.name.clone(),
+            prefix => format!("{} > {}", prefix, child.name),
+        };
+        if child.children.iter().any(|c| !c.success) {
+            failed_tests(child, &name, failures);
+        } else {
+            failures.push(name);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use crate::{config::Config, environment::Environment, rhai::Engine, Error};
+
+/// Outcome of a run, handed to post_run and post_failure hooks as
+/// environment variables, and as JSON in `SAM_SUMMARY`.
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub run_id: String,
+    pub name: String,
+    pub test_count: usize,
+    pub error_count: usize,
+    pub success: bool,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub duration_ms: u64,
+    /// Where the report is written at the end of the run, with `--output`.
+    pub report_path: Option<String>,
+}
+
+impl RunSummary {
+    /// Summary of the tests run by `engine`, a success if `ran_ok` and no
+    /// assertion failed.
+    pub fn new<E: Environment + Clone + 'static>(
+        engine: &Engine<E>,
+        run_id: &str,
+        name: &str,
+        test_count: usize,
+        ran_ok: bool,
+        report_path: Option<String>,
+    ) -> Self {
+        let error_count = engine.get_error_count();
+        let state = engine.shared_state();
+        let state = state.lock();
+        Self {
+            run_id: run_id.to_string(),
+            name: name.to_string(),
+            test_count,
+            error_count,
+            success: ran_ok && error_count == 0,
+            passed: state.tests_run - state.tests_failed,
+            failed: state.tests_failed,
+            skipped: state.tests_skipped,
+            duration_ms: state.started_at.elapsed().as_millis() as u64,
+            report_path,
+        }
+    }
+
+    pub fn env_vars(&self) -> Vec<(String, String)> {
+        vec![
+            ("SAM_RUN_ID".to_string(), self.run_id.clone()),
+            ("SAM_CONFIG_NAME".to_string(), self.name.clone()),
+            ("SAM_TEST_COUNT".to_string(), self.test_count.to_string()),
+            ("SAM_ERROR_COUNT".to_string(), self.error_count.to_string()),
+            ("SAM_SUCCESS".to_string(), self.success.to_string()),
+            ("SAM_PASSED".to_string(), self.passed.to_string()),
+            ("SAM_FAILED".to_string(), self.failed.to_string()),
+            ("SAM_SKIPPED".to_string(), self.skipped.to_string()),
+            ("SAM_DURATION_MS".to_string(), self.duration_ms.to_string()),
+            (
+                "SAM_REPORT_PATH".to_string(),
+                self.report_path.clone().unwrap_or_default(),
+            ),
+            ("SAM_SUMMARY".to_string(), self.to_json()),
+        ]
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("summary serializes")
+    }
+
+    /// One line for the log, e.g. `12 passed, 1 failed, 2 skipped in 3s`.
+    pub fn line(&self) -> String {
+        format!(
+            "{} passed, {} failed, {} skipped in {}",
+            self.passed,
+            self.failed,
+            self.skipped,
+            humantime::format_duration(std::time::Duration::from_millis(self.duration_ms))
+        )
+    }
+}
+
+/// Run the setup or teardown script, once for the whole run. Failed tests
+/// in the script count as a failure of the script.
+pub fn run_script<E: Environment + Clone + 'static>(
+    stage: &str,
+    script: &str,
+    engine: &mut Engine<E>,
+) -> Result<(), Error> {
+    log::info!("Running {} script {}", stage, script);
+    let result = engine.run(PathBuf::from(script));
+    if result.success() {
+        return Ok(());
+    }
+    let reason = match result.errors().next() {
+        Some(failed) => failed.error.as_ref().unwrap().to_string(),
+        None => {
+            let tests_failed: usize = result.scripts.iter().map(|s| s.tests_failed).sum();
+            format!("{} tests failed", tests_failed)
+        }
+    };
+    let e = Error::Test(format!("{} script {} failed: {}", stage, script, reason));
+    log::error!("{}", e);
+    Err(e)
+}
+
+/// Run the post_run hooks of `cfg`, then its post_failure hooks if the run
+/// failed.
+pub async fn run_post_hooks<E: Environment + Clone + 'static>(
+    cfg: &Config,
+    engine: &mut Engine<E>,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    let env_vars = summary.env_vars();
+    run_hooks("post_run", &cfg.hooks.post_run, engine, &env_vars).await?;
+    if !summary.success {
+        run_hooks("post_failure", &cfg.hooks.post_failure, engine, &env_vars).await?;
+    }
+    Ok(())
+}
+
+/// Run hook entries in order. Entries ending in `.rhai` are executed as
+/// scripts by the engine, with `env_vars` as constants, everything else as a
+/// shell command with `env_vars` in its environment.
+pub async fn run_hooks<E: Environment + Clone + 'static>(
+    stage: &str,
+    hooks: &[String],
+    engine: &mut Engine<E>,
+    env_vars: &[(String, String)],
+) -> Result<(), Error> {
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    log::info!("Running {} hooks", stage);
+    engine.push_constants(env_vars);
+    let result = run_entries(stage, hooks, engine, env_vars).await;
+    engine.remove_constants(env_vars);
+    result
+}
+
+async fn run_entries<E: Environment + Clone + 'static>(
+    stage: &str,
+    hooks: &[String],
+    engine: &mut Engine<E>,
+    env_vars: &[(String, String)],
+) -> Result<(), Error> {
+    for hook in hooks {
+        log::debug!("Running {} hook: {}", stage, hook);
+        if hook.ends_with(".rhai") {
+            engine
+                .run(PathBuf::from(hook))
+                .into_result()
+                .map_err(|e| Error::Other(format!("{} hook {} failed: {}", stage, hook, e)))?;
+        } else {
+            run_command(stage, hook, env_vars).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Run a shell command hook, failing if it exits with an error.
+pub async fn run_command(
+    stage: &str,
+    hook: &str,
+    env_vars: &[(String, String)],
+) -> Result<(), Error> {
+    let command = format!("{} hook '{}'", stage, hook);
+    let status = tokio::process::Command::new("sh")
+        .args(["-c", hook])
+        .envs(env_vars.iter().cloned())
+        .status()
+        .await
+        .map_err(|e| Error::run(&command, None, e))?;
+    if !status.success() {
+        // The output of the hook went to the terminal
+        return Err(Error::Exited {
+            command,
+            code: status.code(),
+            stderr: String::new(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,400 @@
+//! `sam import`, a `components:` section for the containers and pods running
+//! on the container runtime, as a starting point for environments that were
+//! set up by hand.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use clap::ArgMatches;
+use serde_json::Value;
+use serde_yaml::{Mapping, Value as Yaml};
+
+use crate::{
+    config::{Config, Timeouts},
+    environment::command_name,
+    runtime::ContainerRuntime,
+    runtime::Runtime,
+    timeout, Error,
+};
+
+/// Variables the runtime sets in every container, not worth importing.
+const RUNTIME_ENV: &[&str] = &["HOSTNAME", "HOME", "TERM", "container"];
+
+/// Print the components of the running containers matching the `--label`
+/// and `--name` filters as YAML, pods as a single component.
+pub async fn import(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let (runtime, timeout) = match sub_matches.get_one::<String>("runtime") {
+        Some(runtime) => (
+            serde_yaml::from_str::<Runtime>(runtime)
+                .map_err(|e| Error::Config(e.to_string()))?
+                .container_runtime(None, None),
+            Timeouts::default().runtime()?,
+        ),
+        // The runtime of the config if there is one
+        None => {
+            let config_path = sub_matches.get_one::<String>("config").unwrap();
+            match Config::load(config_path) {
+                Ok(cfg) => (cfg.container_runtime(), cfg.global.timeouts.runtime()?),
+                Err(e) => {
+                    // Usually there is no config yet when importing
+                    if std::path::Path::new(config_path).exists() {
+                        log::warn!("Not using the runtime of the config, using podman: {}", e);
+                    } else {
+                        log::info!("No config {}, using podman", config_path);
+                    }
+                    (
+                        Runtime::Podman.container_runtime(None, None),
+                        Timeouts::default().runtime()?,
+                    )
+                }
+            }
+        }
+    };
+    let runtime = runtime.as_ref();
+
+    let mut ps = vec!["ps".to_string(), "-q".to_string()];
+    for label in sub_matches
+        .get_many::<String>("label")
+        .into_iter()
+        .flatten()
+    {
+        ps.extend(["--filter".to_string(), format!("label={}", label)]);
+    }
+    if let Some(name) = sub_matches.get_one::<String>("name") {
+        ps.extend(["--filter".to_string(), format!("name={}", name)]);
+    }
+    let ids = output(runtime, &ps, timeout).await?;
+    let ids = ids.split_whitespace().collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Err(Error::Config(
+            "No running containers match the filters".to_string(),
+        ));
+    }
+    let containers = inspect(runtime, "inspect", &ids, timeout).await?;
+
+    let mut images = BTreeMap::new();
+    for container in &containers {
+        let image = container["Image"].as_str().unwrap_or_default();
+        if !image.is_empty() && !images.contains_key(image) {
+            let inspected = inspect(runtime, "image", &["inspect", image], timeout).await?;
+            images.insert(
+                image.to_string(),
+                inspected.into_iter().next().unwrap_or_default(),
+            );
+        }
+    }
+    let image_of = |container: &Value| {
+        container["Image"]
+            .as_str()
+            .and_then(|image| images.get(image))
+            .unwrap_or(&Value::Null)
+            .clone()
+    };
+
+    let mut components = vec![];
+    // The containers of a pod, by pod id in the order they were listed
+    let mut pods: Vec<(String, Vec<Value>)> = vec![];
+    for container in &containers {
+        match container["Pod"].as_str().filter(|pod| !pod.is_empty()) {
+            Some(pod) => match pods.iter_mut().find(|(id, _)| id == pod) {
+                Some((_, members)) => members.push(container.clone()),
+                None => pods.push((pod.to_string(), vec![container.clone()])),
+            },
+            None => components.push(container_component(container, &image_of(container))),
+        }
+    }
+    for (id, members) in pods {
+        let pod = inspect(runtime, "pod", &["inspect", &id], timeout).await?;
+        let name = pod
+            .first()
+            .and_then(|pod| pod["Name"].as_str())
+            .unwrap_or(&id)
+            .to_string();
+        let members = members
+            .iter()
+            .map(|container| (container.clone(), image_of(container)))
+            .collect::<Vec<_>>();
+        components.push(pod_component(&name, &members));
+    }
+
+    log::info!("Imported {} components", components.len());
+    let mut cfg = Mapping::new();
+    cfg.insert("components".into(), components.into());
+    let yaml = serde_yaml::to_string(&cfg).map_err(|e| Error::Other(e.to_string()))?;
+    print!("{}", yaml);
+    Ok(())
+}
+
+/// Stdout of a runtime command, killed after `timeout`.
+async fn output(
+    runtime: &dyn ContainerRuntime,
+    args: &[String],
+    timeout: Duration,
+) -> Result<String, Error> {
+    let mut cmd = runtime.command();
+    cmd.args(args);
+    let output = timeout::output(&mut cmd, Some(timeout))
+        .await
+        .map_err(|e| Error::run(command_name(&cmd), Some(timeout), e))?;
+    if !output.status.success() {
+        return Err(Error::exited(command_name(&cmd), &output));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// The JSON objects printed by `<command> <args>`, e.g. `inspect <ids>`.
+async fn inspect(
+    runtime: &dyn ContainerRuntime,
+    command: &str,
+    args: &[&str],
+    timeout: Duration,
+) -> Result<Vec<Value>, Error> {
+    let args = std::iter::once(command)
+        .chain(args.iter().copied())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let json = output(runtime, &args, timeout).await?;
+    match serde_json::from_str(&json).map_err(|e| Error::Podman(e.to_string()))? {
+        Value::Array(values) => Ok(values),
+        // `pod inspect` of a single pod on older podman versions
+        value => Ok(vec![value]),
+    }
+}
+
+/// A `container` component for the `inspect` output of a container, leaving
+/// out the command, entrypoint and environment it got from `image`.
+pub fn container_component(container: &Value, image: &Value) -> Yaml {
+    let mut component = Mapping::new();
+    component.insert("name".into(), name(container).into());
+    component.insert("type".into(), "container".into());
+    component.extend(container_fields(container, image));
+    if let Some(ports) = ports(container) {
+        component.insert("ports".into(), ports);
+    }
+    component.into()
+}
+
+/// A `pod` component for the `inspect` output of the containers of a pod
+/// and their images, the ports taken from its infra container.
+pub fn pod_component(name: &str, members: &[(Value, Value)]) -> Yaml {
+    let mut component = Mapping::new();
+    component.insert("name".into(), name.into());
+    component.insert("type".into(), "pod".into());
+    let ports = members.iter().find_map(|(container, _)| ports(container));
+    if let Some(ports) = ports {
+        component.insert("ports".into(), ports);
+    }
+    let containers = members
+        .iter()
+        .filter(|(container, _)| container["IsInfra"] != Value::Bool(true))
+        .map(|(container, image)| {
+            let mut fields = Mapping::new();
+            fields.insert("name".into(), container_name(container).into());
+            fields.extend(container_fields(container, image));
+            Yaml::from(fields)
+        })
+        .collect::<Vec<_>>();
+    component.insert("containers".into(), containers.into());
+    component.into()
+}
+
+/// The name of the component SAM started the container for, or the name of
+/// the container.
+fn name(container: &Value) -> String {
+    match container["Config"]["Labels"]["sam.component"].as_str() {
+        Some(component) => component.to_string(),
+        None => container_name(container),
+    }
+}
+
+/// The name of the container, which docker prefixes with `/`.
+fn container_name(container: &Value) -> String {
+    let name = container["Name"].as_str().unwrap_or_default();
+    name.trim_start_matches('/').to_string()
+}
+
+/// The fields shared by `container` components and the containers of pods.
+fn container_fields(container: &Value, image: &Value) -> Mapping {
+    let config = &container["Config"];
+    let host_config = &container["HostConfig"];
+    let mut fields = Mapping::new();
+    let image_name = container["ImageName"]
+        .as_str()
+        .or(config["Image"].as_str())
+        .unwrap_or_default();
+    fields.insert("image".into(), image_name.into());
+
+    let command = strings(&config["Cmd"]);
+    if !command.is_empty() && command != strings(&image["Config"]["Cmd"]) {
+        fields.insert("command".into(), command.into());
+    }
+    let entrypoint = strings(&config["Entrypoint"]);
+    if !entrypoint.is_empty() && entrypoint != strings(&image["Config"]["Entrypoint"]) {
+        fields.insert("entrypoint".into(), entrypoint.join(" ").into());
+    }
+
+    let image_env = strings(&image["Config"]["Env"]);
+    let environment = strings(&config["Env"])
+        .into_iter()
+        .filter(|entry| !image_env.contains(entry))
+        .filter_map(|entry| {
+            let (key, value) = entry.split_once('=')?;
+            let runtime_env = RUNTIME_ENV.contains(&key);
+            (!runtime_env).then(|| (Yaml::from(key), Yaml::from(value)))
+        })
+        .collect::<Mapping>();
+    if !environment.is_empty() {
+        fields.insert("environment".into(), environment.into());
+    }
+
+    let volumes = container["Mounts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|mount| {
+            let destination = mount["Destination"].as_str()?;
+            let mut volume = Mapping::new();
+            match mount["Type"].as_str()? {
+                "bind" => volume.insert("host".into(), mount["Source"].as_str()?.into()),
+                "volume" => volume.insert("name".into(), mount["Name"].as_str()?.into()),
+                "tmpfs" => volume.insert("tmpfs".into(), true.into()),
+                _ => return None,
+            };
+            volume.insert("container".into(), destination.into());
+            Some(Yaml::from(volume))
+        })
+        .collect::<Vec<_>>();
+    if !volumes.is_empty() {
+        fields.insert("volumes".into(), volumes.into());
+    }
+
+    if let Some(memory) = host_config["Memory"].as_u64().filter(|memory| *memory > 0) {
+        fields.insert("memory".into(), memory_size(memory).into());
+    }
+    if let Some(nano_cpus) = host_config["NanoCpus"].as_u64().filter(|cpus| *cpus > 0) {
+        fields.insert("cpus".into(), (nano_cpus as f64 / 1e9).into());
+    }
+    if let Some(user) = config["User"].as_str().filter(|user| !user.is_empty()) {
+        fields.insert("user".into(), user.into());
+    }
+    if host_config["Privileged"] == Value::Bool(true) {
+        fields.insert("privileged".into(), true.into());
+    }
+    let cap_add = strings(&host_config["CapAdd"]);
+    if !cap_add.is_empty() {
+        fields.insert("cap_add".into(), cap_add.into());
+    }
+    fields
+}
+
+/// The `ports` of the host ports published by a container.
+fn ports(container: &Value) -> Option<Yaml> {
+    let bindings = container["HostConfig"]["PortBindings"].as_object()?;
+    let ports = bindings
+        .iter()
+        .flat_map(|(port, hosts)| {
+            let container_port = port.split('/').next().and_then(|p| p.parse::<u16>().ok());
+            hosts
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(move |host| {
+                    let host = host["HostPort"].as_str()?.parse::<u16>().ok()?;
+                    let mut port = Mapping::new();
+                    port.insert("host".into(), host.into());
+                    port.insert("container".into(), container_port?.into());
+                    Some(Yaml::from(port))
+                })
+        })
+        .collect::<Vec<_>>();
+    (!ports.is_empty()).then_some(Yaml::from(ports))
+}
+
+/// The strings of a list, or a string split at spaces as older podman
+/// versions print the entrypoint.
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .filter_map(|value| value.as_str().map(String::from))
+            .collect(),
+        Value::String(value) => value.split_whitespace().map(String::from).collect(),
+        _ => vec![],
+    }
+}
+
+fn memory_size(bytes: u64) -> String {
+    const UNITS: [(u64, &str); 3] = [(1 << 30, "g"), (1 << 20, "m"), (1 << 10, "k")];
+    UNITS
+        .iter()
+        .find(|(size, _)| bytes.is_multiple_of(*size))
+        .map_or(bytes.to_string(), |(size, unit)| {
+            format!("{}{}", bytes / size, unit)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_container_component() {
+        let container: Value = serde_json::from_str(
+            r#"{
+                "Name": "/web",
+                "Image": "sha256:abc",
+                "Config": {
+                    "Image": "nginx:1.27",
+                    "Cmd": ["nginx", "-g", "daemon off;"],
+                    "Entrypoint": ["/docker-entrypoint.sh"],
+                    "Env": ["PATH=/usr/bin", "HOSTNAME=web", "MODE=prod"],
+                    "User": "",
+                    "Labels": {}
+                },
+                "HostConfig": {
+                    "PortBindings": {"80/tcp": [{"HostIp": "", "HostPort": "8080"}]},
+                    "Memory": 268435456,
+                    "NanoCpus": 500000000,
+                    "Privileged": false,
+                    "CapAdd": null
+                },
+                "Mounts": [
+                    {"Type": "bind", "Source": "/srv/html", "Destination": "/usr/share/nginx/html"},
+                    {"Type": "volume", "Name": "cache", "Destination": "/var/cache/nginx"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let image: Value = serde_json::from_str(
+            r#"{"Config": {
+                "Cmd": ["nginx", "-g", "daemon off;"],
+                "Entrypoint": ["/docker-entrypoint.sh"],
+                "Env": ["PATH=/usr/bin"]
+            }}"#,
+        )
+        .unwrap();
+
+        let component = container_component(&container, &image);
+        let yaml = serde_yaml::to_string(&component).unwrap();
+        assert_eq!(
+            yaml,
+            r#"name: web
+type: container
+image: nginx:1.27
+environment:
+  MODE: prod
+volumes:
+- host: /srv/html
+  container: /usr/share/nginx/html
+- name: cache
+  container: /var/cache/nginx
+memory: 256m
+cpus: 0.5
+ports:
+- host: 8080
+  container: 80
+"#
+        );
+        let component: crate::config::Component = serde_yaml::from_value(component).unwrap();
+        assert_eq!(component.environment, ["MODE=prod"]);
+    }
+}
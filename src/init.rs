@@ -1,8 +1,13 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 use clap::ArgMatches;
 
-use crate::Error;
+use crate::{config::Config, Error};
+
+const DEFAULT_TEST_DIR: &str = "tests/cases";
 
 pub async fn init(sub_matches: &ArgMatches) -> Result<(), Error> {
     // Create directory structure
@@ -102,3 +107,211 @@ describe("Example Test Suite", || {
     log::info!("Initialization complete!");
     Ok(())
 }
+
+/// `sam new test <name>`: create a test file with a describe/it skeleton
+/// importing the modules of the config, and add it to `global.scripts` with
+/// `--register`.
+pub fn new_test(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let config_path = sub_matches.get_one::<String>("config").unwrap();
+    let cfg = Config::load(config_path)?;
+    let name = sub_matches.get_one::<String>("name").unwrap();
+    let name = name.strip_suffix(".rhai").unwrap_or(name);
+    let base = cfg.workdir(config_path).unwrap_or_default();
+
+    // Next to the other tests: in the first script directory, or the
+    // directory of the first script
+    let dir = match sub_matches.get_one::<String>("dir") {
+        Some(dir) => dir.clone(),
+        None => cfg
+            .global
+            .scripts
+            .iter()
+            .map(|script| {
+                if base.join(script).is_dir() {
+                    script.clone()
+                } else {
+                    parent(script)
+                }
+            })
+            .next()
+            .unwrap_or(DEFAULT_TEST_DIR.to_string()),
+    };
+    let script = if dir.is_empty() {
+        format!("{}.rhai", name)
+    } else {
+        format!("{}/{}.rhai", dir.trim_end_matches('/'), name)
+    };
+    let path = base.join(&script);
+    if path.exists() && !sub_matches.get_flag("force") {
+        return Err(Error::Config(format!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        )));
+    }
+
+    let modules = module_names(&cfg.global.module_dirs, &base);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::Config(e.to_string()))?;
+    }
+    fs::write(&path, test_skeleton(name, &modules)).map_err(|e| Error::Config(e.to_string()))?;
+    log::info!("Created test {}", path.display());
+
+    if sub_matches.get_flag("register") {
+        let covered = cfg
+            .global
+            .scripts
+            .iter()
+            .any(|s| *s == script || (base.join(s).is_dir() && s.trim_end_matches('/') == dir));
+        if covered {
+            log::info!("{} already runs the scripts in {}", config_path, dir);
+        } else {
+            let yaml = fs::read_to_string(config_path).map_err(|e| Error::Config(e.to_string()))?;
+            let yaml = register_script(&yaml, &script).ok_or_else(|| {
+                Error::Config(format!(
+                    "No global.scripts list found in {}, add {} yourself",
+                    config_path, script
+                ))
+            })?;
+            fs::write(config_path, yaml).map_err(|e| Error::Config(e.to_string()))?;
+            log::info!("Added {} to the scripts of {}", script, config_path);
+        }
+    }
+    Ok(())
+}
+
+fn parent(path: &str) -> String {
+    Path::new(path)
+        .parent()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Names of the `.rhai` modules in the module directories, as imported.
+fn module_names(module_dirs: &[String], base: &Path) -> Vec<String> {
+    let mut names = module_dirs
+        .iter()
+        .filter_map(|dir| fs::read_dir(base.join(dir)).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| PathBuf::from(entry.file_name()))
+        .filter(|file| file.extension().is_some_and(|ext| ext == "rhai"))
+        .filter_map(|file| Some(file.file_stem()?.to_string_lossy().to_string()))
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+fn test_skeleton(name: &str, modules: &[String]) -> String {
+    let mut content = String::new();
+    for module in modules {
+        // Module names that are no identifiers need an alias
+        let alias = module.replace(|c: char| !c.is_ascii_alphanumeric() && c != '_', "_");
+        content += &format!("import \"{}\" as {};\n", escape(module), alias);
+    }
+    if !modules.is_empty() {
+        content += "\n";
+    }
+    let mut title = name.replace(['-', '_'], " ");
+    if let Some(first) = title.get(..1) {
+        title = first.to_uppercase() + &title[1..];
+    }
+    content += &format!(
+        r#"describe("{}", || {{
+    it("should work", || {{
+        // Arrange, act, then check the outcome, e.g.
+        // let response = http_get(#{{url: data.base_url}});
+        // assert::eq(response, "expected");
+        assert(true, "replace with a real assertion");
+    }});
+}});
+"#,
+        escape(&title)
+    );
+    content
+}
+
+/// `value` inside the quotes of a Rhai string.
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', r#"\""#)
+}
+
+/// Add `script` to the end of the `scripts` list of the `global` section,
+/// keeping the rest of the file as it is. `None` if there is no such list.
+fn register_script(yaml: &str, script: &str) -> Option<String> {
+    let lines = yaml.lines().collect::<Vec<_>>();
+    let global = lines.iter().position(|line| line.trim_end() == "global:")?;
+    let scripts = (global + 1..lines.len())
+        .take_while(|&i| lines[i].is_empty() || lines[i].starts_with([' ', '\t', '#']))
+        .find(|&i| lines[i].trim_start().starts_with("scripts:"))?;
+    let indent = lines[scripts].len() - lines[scripts].trim_start().len();
+    if lines[scripts].trim_start() != "scripts:"
+        && !lines[scripts].trim_start().starts_with("scripts: #")
+    {
+        // An inline list like `scripts: [a.rhai]`
+        return None;
+    }
+
+    // After the last item of the list
+    let mut end = scripts + 1;
+    let mut item_indent = None;
+    for (i, line) in lines.iter().enumerate().skip(scripts + 1) {
+        let trimmed = line.trim_start();
+        let line_indent = line.len() - trimmed.len();
+        if trimmed.starts_with("- ") && line_indent >= indent {
+            item_indent.get_or_insert(line_indent);
+            end = i + 1;
+        } else if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            break;
+        }
+    }
+    let item = format!(
+        "{}- {}",
+        " ".repeat(item_indent.unwrap_or(indent + 2)),
+        script
+    );
+
+    let mut result = lines[..end].to_vec();
+    result.push(&item);
+    result.extend(&lines[end..]);
+    let mut result = result.join("\n");
+    if yaml.ends_with('\n') {
+        result.push('\n');
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_script() {
+        let yaml = "name: x\nglobal:\n  scripts: # tests\n    - tests/a.rhai\n  repeat: 2\n";
+        assert_eq!(
+            register_script(yaml, "tests/b.rhai").unwrap(),
+            "name: x\nglobal:\n  scripts: # tests\n    - tests/a.rhai\n    - tests/b.rhai\n  repeat: 2\n"
+        );
+        let yaml = "global:\n  scripts:\n  - a.rhai\n";
+        assert_eq!(
+            register_script(yaml, "b.rhai").unwrap(),
+            "global:\n  scripts:\n  - a.rhai\n  - b.rhai\n"
+        );
+        assert!(register_script("global:\n  scripts: [a.rhai]\n", "b.rhai").is_none());
+        assert!(register_script("name: x\n", "b.rhai").is_none());
+    }
+
+    #[test]
+    fn test_skeleton_imports_modules() {
+        let content = test_skeleton("user-login", &["auth".to_string(), "my-api".to_string()]);
+        assert!(content.starts_with("import \"auth\" as auth;\nimport \"my-api\" as my_api;\n"));
+        assert!(content.contains("describe(\"User login\""));
+        rhai::Engine::new().compile(&content).unwrap();
+    }
+
+    #[test]
+    fn test_skeleton_escapes_title() {
+        let content = test_skeleton(r#"say-"hi"-c:\tmp"#, &[]);
+        assert!(content.contains(r#"describe("Say \"hi\" c:\\tmp""#));
+        rhai::Engine::new().compile(&content).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+//! SAM runs Rhai test scripts against an environment of containers, pods and
+//! processes described by a YAML config. Besides the `sam` binary, the crate
+//! can be used as a library, see [`harness`] for running suites under
+//! `cargo test`.
+
+pub mod chaos;
+pub mod commands;
+pub mod compose;
+pub mod config;
+pub mod environment;
+pub mod error;
+pub mod fmt;
+pub mod harness;
+pub mod hooks;
+pub mod import;
+pub mod init;
+pub mod lint;
+pub mod log_format;
+pub mod logs;
+pub mod provenance;
+pub mod remote;
+pub mod rhai;
+pub mod runtime;
+pub mod seed;
+pub mod state;
+pub mod suite;
+pub mod throttle;
+pub mod timeout;
+pub mod tui;
+pub mod upload;
+pub mod validate;
+pub mod writer;
+
+pub use environment::*;
+pub use error::{Error, ErrorKind};
@@ -0,0 +1,309 @@
+//! `sam config lint`, warnings for constructs of a config that load without
+//! an error but likely do not do what was meant: misspelled keys, components
+//! defined twice, keys replaced by a later document and unused anchors.
+
+use std::collections::HashMap;
+
+use clap::ArgMatches;
+use regex::Regex;
+use serde_json::Value as Schema;
+use serde_yaml::Value;
+
+use crate::{
+    config::{self, Config},
+    Error,
+};
+
+/// Keys accepted through serde aliases, which the schema does not list, and
+/// the field each stands for where the schema has that field.
+const ALIASES: &[(&str, &str)] = &[("depends_on", "dependencies"), ("name", "component")];
+
+/// Print the warnings of the config files, failing on any with `--strict`.
+pub fn lint(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let mut count = 0;
+    for path in sub_matches.get_many::<String>("config").unwrap() {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read {}: {}", path, e)))?;
+        let warnings =
+            lint_yaml(&yaml).map_err(|e| e.context(format!("Failed to lint config {}", path)))?;
+        for warning in &warnings {
+            println!("{}: warning: {}", path, warning);
+        }
+        count += warnings.len();
+    }
+    if count == 0 {
+        log::info!("No problems found");
+    } else if sub_matches.get_flag("strict") {
+        return Err(Error::Config(format!("{} lint warnings", count)));
+    }
+    Ok(())
+}
+
+/// The warnings of the config file `yaml`, an error if it does not load.
+pub fn lint_yaml(yaml: &str) -> Result<Vec<String>, Error> {
+    Config::from_yaml(yaml).map_err(|e| Error::Config(e.to_string()))?;
+    let documents = config::yaml_documents(yaml).map_err(|e| Error::Config(e.to_string()))?;
+    let schema = config_schema()?;
+
+    let mut warnings = vec![];
+    // The document each component and top-level key was first found in
+    let mut components = HashMap::new();
+    let mut keys = HashMap::new();
+    for (i, document) in documents.iter().enumerate() {
+        let number = i + 1;
+        for (key, value) in document.as_mapping().into_iter().flatten() {
+            let Some(key) = key.as_str() else {
+                continue;
+            };
+            if key != "components" {
+                if let Some(first) = keys.insert(key, number) {
+                    warnings.push(format!(
+                        "`{}` of document {} replaces the one of document {}",
+                        key, number, first
+                    ));
+                }
+                continue;
+            }
+            for component in value.as_sequence().into_iter().flatten() {
+                let Some(name) = component.get("name").and_then(Value::as_str) else {
+                    continue;
+                };
+                match components.insert(name, number) {
+                    Some(first) if first == number => {
+                        warnings.push(format!("component `{}` is defined twice", name))
+                    }
+                    Some(first) => warnings.push(format!(
+                        "component `{}` of document {} is also defined in document {}",
+                        name, number, first
+                    )),
+                    None => {}
+                }
+            }
+        }
+        check_keys(document, &schema, &schema, "", &mut warnings);
+    }
+    warnings.extend(unused_anchors(yaml));
+    Ok(warnings)
+}
+
+/// The keys of the config `documents` that the config format does not have,
+/// which serde ignores. Loading fails on them with `strict: true`.
+pub fn unknown_keys(documents: &[Value]) -> Result<Vec<String>, Error> {
+    let schema = config_schema()?;
+    let mut unknown = vec![];
+    for document in documents {
+        check_keys(document, &schema, &schema, "", &mut unknown);
+    }
+    Ok(unknown)
+}
+
+fn config_schema() -> Result<Schema, Error> {
+    serde_json::to_value(schemars::schema_for!(Config)).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Warn about the keys of `value` that `schema` does not know, which serde
+/// ignores.
+fn check_keys(
+    value: &Value,
+    schema: &Schema,
+    root: &Schema,
+    path: &str,
+    warnings: &mut Vec<String>,
+) {
+    let schemas = subschemas(schema, root);
+    match value {
+        Value::Mapping(mapping) => {
+            let properties = schemas
+                .iter()
+                .filter_map(|schema| schema.get("properties")?.as_object())
+                .collect::<Vec<_>>();
+            let additional = schemas
+                .iter()
+                .filter_map(|schema| schema.get("additionalProperties"))
+                .find(|additional| **additional != Schema::Bool(false));
+            for (key, value) in mapping {
+                let Some(key) = key.as_str() else {
+                    continue;
+                };
+                let key_path = match path {
+                    "" => key.to_string(),
+                    path => format!("{}.{}", path, key),
+                };
+                let field = ALIASES
+                    .iter()
+                    .find(|(alias, field)| {
+                        *alias == key && properties.iter().any(|p| p.contains_key(*field))
+                    })
+                    .map_or(key, |(_, field)| field);
+                if let Some(schema) = properties.iter().find_map(|p| p.get(field)).or(additional) {
+                    check_keys(value, schema, root, &key_path, warnings);
+                } else if !properties.is_empty() && !key.starts_with("x-") {
+                    let known = properties.iter().flat_map(|p| p.keys());
+                    warnings.push(unknown_key(&key_path, key, known));
+                }
+            }
+        }
+        Value::Sequence(items) => {
+            let Some(items_schema) = schemas.iter().find_map(|schema| schema.get("items")) else {
+                return;
+            };
+            for (i, item) in items.iter().enumerate() {
+                let item_path = match item.get("name").and_then(Value::as_str) {
+                    Some(name) => format!("{}[{}]", path, name),
+                    None => format!("{}[{}]", path, i),
+                };
+                check_keys(item, items_schema, root, &item_path, warnings);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `schema` with its reference resolved, and the alternatives it is made of.
+fn subschemas<'a>(schema: &'a Schema, root: &'a Schema) -> Vec<&'a Schema> {
+    let reference = schema
+        .get("$ref")
+        .and_then(Schema::as_str)
+        .and_then(|reference| reference.strip_prefix("#/$defs/"));
+    let schema = match reference {
+        Some(name) => &root["$defs"][name],
+        None => schema,
+    };
+    let mut schemas = vec![schema];
+    for key in ["allOf", "anyOf", "oneOf"] {
+        for alternative in schema
+            .get(key)
+            .and_then(Schema::as_array)
+            .into_iter()
+            .flatten()
+        {
+            schemas.extend(subschemas(alternative, root));
+        }
+    }
+    schemas
+}
+
+fn unknown_key<'a>(path: &str, key: &str, known: impl Iterator<Item = &'a String>) -> String {
+    let closest = known
+        .map(|known| (strsim::jaro_winkler(key, known), known))
+        .filter(|(similarity, _)| *similarity > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+    match closest {
+        Some((_, known)) => format!("unknown key `{}`, did you mean `{}`?", path, known),
+        // Anchors used elsewhere need a key to be defined under
+        None if !path.contains(['.', '[']) => format!(
+            "unknown key `{}`, prefix it with `x-` if it only holds anchors",
+            path
+        ),
+        None => format!("unknown key `{}`", path),
+    }
+}
+
+/// Anchors no alias refers to, e.g. after renaming one of them.
+fn unused_anchors(yaml: &str) -> Vec<String> {
+    let anchor = Regex::new(r"(?:^|[\s\[{,])&([^\s\[\]{},]+)").unwrap();
+    let alias = Regex::new(r"(?:^|[\s\[{,])\*([^\s\[\]{},]+)").unwrap();
+    let lines = yaml
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>();
+    let names = |re: &Regex| {
+        lines
+            .iter()
+            .flat_map(|line| re.captures_iter(line))
+            .map(|caps| caps[1].to_string())
+            .collect::<Vec<_>>()
+    };
+    let aliases = names(&alias);
+    let mut anchors = names(&anchor);
+    anchors.sort();
+    anchors.dedup();
+    anchors
+        .into_iter()
+        .filter(|name| !aliases.contains(name))
+        .map(|name| format!("anchor `&{}` is never used", name))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lint_yaml() {
+        let yaml = r#"
+name: lint
+x-defaults: &defaults
+  type: container
+  image: nginx
+x-unused: &unused
+  memory: 1g
+global:
+  scripts: [tests/]
+components:
+  - <<: *defaults
+    name: api
+    helthcheck:
+      command: [curl, localhost]
+  - <<: *defaults
+    name: web
+    volumes:
+      - name: data
+        container: /data
+        sise: 1g
+---
+components:
+  - name: api
+    type: process
+    command: [./api]
+global:
+  scripts: [more/]
+"#;
+        let cfg = Config::from_yaml(yaml).unwrap();
+        let names = cfg.components.iter().map(|c| &c.name).collect::<Vec<_>>();
+        assert_eq!(names, ["api", "web", "api"]);
+        assert_eq!(cfg.components[1].image.as_deref(), Some("nginx"));
+        assert_eq!(cfg.global.scripts, ["more/"]);
+
+        assert_eq!(
+            lint_yaml(yaml).unwrap(),
+            [
+                "unknown key `components[api].helthcheck`, did you mean `healthcheck`?",
+                "unknown key `components[web].volumes[data].sise`, did you mean `size`?",
+                "component `api` of document 2 is also defined in document 1",
+                "`global` of document 2 replaces the one of document 1",
+                "anchor `&unused` is never used",
+            ]
+        );
+        assert!(lint_yaml("name: ok\ncomponents: []\n").unwrap().is_empty());
+
+        // Aliases only where they stand for a field
+        let yaml = r#"
+name: aliases
+components:
+  - name: db
+    type: container
+    image: postgres
+    healthcheck:
+      depends_on: [api]
+      tcp: 5432
+  - name: api
+    type: container
+    image: api
+    depends_on:
+      - name: db
+        condition: healthy
+    ports:
+      - name: http
+        host: 8080
+        container: 80
+"#;
+        assert_eq!(
+            lint_yaml(yaml).unwrap(),
+            [
+                "unknown key `components[db].healthcheck.depends_on`",
+                "unknown key `components[api].ports[http].name`",
+            ]
+        );
+    }
+}
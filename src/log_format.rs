@@ -0,0 +1,95 @@
+//! Logs and test progress as logfmt or JSON lines, for log aggregation
+//! systems that parse fields instead of stripping colors.
+
+use std::io::Write;
+
+use serde_json::Value;
+
+use crate::config::LogFormat;
+
+/// Initialize the logger, configured with `RUST_LOG` like the pretty one.
+pub fn init_logger(format: LogFormat) {
+    if format == LogFormat::Pretty {
+        pretty_env_logger::init();
+        return;
+    }
+    let mut builder = pretty_env_logger::env_logger::Builder::new();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.format(move |buf, record| {
+        let fields = [
+            ("level", record.level().as_str().to_lowercase().into()),
+            ("target", record.target().into()),
+            ("msg", strip_ansi(&record.args().to_string()).into()),
+        ];
+        writeln!(buf, "{}", line(format, &fields))
+    });
+    builder.init();
+}
+
+/// A line with the current time and `fields`, in `format`.
+pub fn line(format: LogFormat, fields: &[(&str, Value)]) -> String {
+    let time = humantime::format_rfc3339_millis(std::time::SystemTime::now()).to_string();
+    let fields = std::iter::once(("time", Value::from(time))).chain(fields.iter().cloned());
+    match format {
+        LogFormat::Json => Value::Object(
+            fields
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+        .to_string(),
+        LogFormat::Logfmt | LogFormat::Pretty => fields
+            .map(|(key, value)| format!("{}={}", key, logfmt_value(&value)))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn logfmt_value(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("; "),
+        value => return value.to_string(),
+    };
+    let quote = text.is_empty() || text.contains(|c: char| c <= ' ' || c == '=' || c == '"');
+    if quote {
+        format!("{:?}", text)
+    } else {
+        text
+    }
+}
+
+pub fn strip_ansi(text: &str) -> String {
+    regex::Regex::new("\x1b\\[[0-9;]*m")
+        .unwrap()
+        .replace_all(text, "")
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let fields = [
+            ("event", "test".into()),
+            ("path", "api.login works".into()),
+            ("duration_ms", 12.into()),
+        ];
+        let logfmt = line(LogFormat::Logfmt, &fields);
+        assert!(logfmt.ends_with(r#" event=test path="api.login works" duration_ms=12"#));
+
+        let json: Value = serde_json::from_str(&line(LogFormat::Json, &fields)).unwrap();
+        assert_eq!(json["path"], "api.login works");
+        assert_eq!(json["duration_ms"], 12);
+    }
+}
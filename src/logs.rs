@@ -0,0 +1,250 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::ArgMatches;
+
+use crate::{config::Config, environment, runtime::ContainerRuntime, Error};
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Print the logs of a component of the config, following them with `-f` and
+/// only the lines of the last `--since` if set. Containers are found by their
+/// labels, so this also works for environments kept running by another sam
+/// process.
+pub async fn logs(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let config_path = sub_matches.get_one::<String>("config").unwrap();
+    let cfg = Config::load(config_path)?;
+    let name = sub_matches.get_one::<String>("component").unwrap();
+    let follow = sub_matches.get_flag("follow");
+    let since = sub_matches
+        .get_one::<String>("since")
+        .map(|since| {
+            humantime::parse_duration(since)
+                .map_err(|e| Error::Config(format!("Invalid --since {}: {}", since, e)))
+        })
+        .transpose()?;
+
+    let component = cfg.get_component(name).ok_or_else(|| {
+        let names = cfg
+            .components
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        Error::Config(format!(
+            "Component {} not found in config, available: {}",
+            name,
+            names.join(", ")
+        ))
+    })?;
+
+    match component.component_type.as_str() {
+        "container" | "pod" => {
+            let runtime = cfg.container_runtime();
+            container_logs(runtime.as_ref(), &cfg.name, name, follow, since).await
+        }
+        // Jobs write their output to files like processes, also in containers
+        "process" | "job" => process_logs(name, follow, since).await,
+        other => Err(Error::Config(format!("Unknown component type: {}", other))),
+    }
+}
+
+async fn container_logs(
+    runtime: &dyn ContainerRuntime,
+    config_name: &str,
+    component_name: &str,
+    follow: bool,
+    since: Option<Duration>,
+) -> Result<(), Error> {
+    // Go durations, understood by both podman and docker
+    let since_arg = since.map(|since| format!("--since={}s", since.as_secs()));
+    let output = runtime
+        .command()
+        .args(["ps", "-a", "-q"])
+        .arg("--filter")
+        .arg(format!("label=sam.config={}", config_name))
+        .arg("--filter")
+        .arg(format!("label=sam.component={}", component_name))
+        .output()
+        .await
+        .map_err(|e| Error::run(format!("{} ps", runtime.program()), None, e))?;
+    if !output.status.success() {
+        return Err(Error::exited(format!("{} ps", runtime.program()), &output));
+    }
+    let ids = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Err(Error::Podman(format!(
+            "No containers found for component {}, is the environment running?",
+            component_name
+        )));
+    }
+
+    let logs = format!("{} logs", runtime.program());
+    if runtime.logs_multiple() {
+        let mut cmd = runtime.command();
+        cmd.arg("logs").args(&since_arg);
+        if follow {
+            cmd.arg("-f");
+        }
+        if ids.len() > 1 {
+            cmd.arg("--names");
+        }
+        let status = cmd
+            .args(&ids)
+            .status()
+            .await
+            .map_err(|e| Error::run(&logs, None, e))?;
+        return check_logs_status(logs, status);
+    }
+
+    // Docker shows the logs of one container at a time, run one `logs` per
+    // container so that following shows them all
+    let mut children = Vec::new();
+    for id in &ids {
+        let mut cmd = runtime.command();
+        cmd.arg("logs").args(&since_arg);
+        if follow {
+            cmd.arg("-f");
+        }
+        children.push(
+            cmd.arg(id)
+                .spawn()
+                .map_err(|e| Error::run(&logs, None, e))?,
+        );
+    }
+    for mut child in children {
+        let status = child.wait().await.map_err(|e| Error::run(&logs, None, e))?;
+        check_logs_status(logs.clone(), status)?;
+    }
+    Ok(())
+}
+
+fn check_logs_status(command: String, status: std::process::ExitStatus) -> Result<(), Error> {
+    if !status.success() {
+        // The output of `logs` went to the terminal
+        return Err(Error::Exited {
+            command,
+            code: status.code(),
+            stderr: String::new(),
+        });
+    }
+    Ok(())
+}
+
+async fn process_logs(
+    component_name: &str,
+    follow: bool,
+    since: Option<Duration>,
+) -> Result<(), Error> {
+    let dirs = environment::project_dirs()?;
+    let files = ["stdout", "stderr"].map(|ext| {
+        dirs.data_local_dir()
+            .join(format!("{}.{}", component_name, ext))
+    });
+    if !files.iter().any(|file| file.exists()) {
+        return Err(Error::Process(format!(
+            "No output found for process {}, has it been started?",
+            component_name
+        )));
+    }
+
+    let mut positions = [0, 0];
+    if let Some(since) = since {
+        let cutoff = SystemTime::now() - since;
+        for (file, position) in files.iter().zip(positions.iter_mut()) {
+            *position = offset_since(file, cutoff);
+        }
+    }
+    loop {
+        for (file, position) in files.iter().zip(positions.iter_mut()) {
+            *position = print_from(file, *position)?;
+        }
+        if !follow {
+            return Ok(());
+        }
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                return result.map_err(|e| Error::Other(e.to_string()));
+            }
+            _ = tokio::time::sleep(FOLLOW_INTERVAL) => {}
+        }
+    }
+}
+
+/// Offset of the first line of a captured output file written after
+/// `cutoff`, from its line times, or up to `LINE_TIME_RESOLUTION` earlier.
+/// The whole file without them.
+fn offset_since(path: &Path, cutoff: SystemTime) -> u64 {
+    let Ok(times) = std::fs::read_to_string(environment::times_path(path)) else {
+        return 0;
+    };
+    let cutoff = cutoff
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let mut entries = times.lines().filter_map(|line| {
+        let (millis, offset) = line.split_once(' ')?;
+        Some((millis.parse::<u128>().ok()?, offset.parse::<u64>().ok()?))
+    });
+    let resolution = environment::LINE_TIME_RESOLUTION.as_millis();
+    match entries.find(|(millis, _)| *millis + resolution > cutoff) {
+        Some((_, offset)) => offset,
+        // Nothing was written since
+        None => std::fs::metadata(path).map_or(0, |metadata| metadata.len()),
+    }
+}
+
+// Print what was appended to the file since `position`, returning the new end
+fn print_from(path: &Path, position: u64) -> Result<u64, Error> {
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return Ok(position);
+    };
+    let failed = |e| Error::io(format!("Failed to read {}", path.display()), e);
+    let len = file.metadata().map_err(failed)?.len();
+    // The file is recreated when the process restarts
+    let position = if len < position { 0 } else { position };
+    file.seek(SeekFrom::Start(position)).map_err(failed)?;
+    let mut content = vec![];
+    file.read_to_end(&mut content).map_err(failed)?;
+    let mut stdout = std::io::stdout();
+    stdout
+        .write_all(&content)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| Error::Other(e.to_string()))?;
+    Ok(position + content.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offset_since() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let path = dir.path().join("api.stdout");
+        std::fs::write(&path, "first\nsecond\nthird\n").unwrap();
+        assert_eq!(offset_since(&path, SystemTime::now()), 0);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let times = format!(
+            "{} 0\n{} 6\n{} 13\n",
+            now - 600_000,
+            now - 60_000,
+            now - 1_000
+        );
+        std::fs::write(environment::times_path(&path), times).unwrap();
+        let ago = |secs| SystemTime::now() - Duration::from_secs(secs);
+        assert_eq!(offset_since(&path, ago(3600)), 0);
+        assert_eq!(offset_since(&path, ago(300)), 6);
+        assert_eq!(offset_since(&path, ago(10)), 13);
+        assert_eq!(offset_since(&path, ago(0)), 19);
+    }
+}
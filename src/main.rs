@@ -1,39 +1,18 @@
-mod commands;
-mod config;
-mod environment;
-mod init;
-mod rhai;
-mod state;
-
-use std::path::PathBuf;
+use std::{collections::HashMap, io::IsTerminal, path::PathBuf, process::ExitCode};
 
 use clap::{ArgMatches, Command};
-use config::Config;
-use environment::*;
-use rhai::Engine;
-
-#[derive(Debug)]
-enum Error {
-    Podman(String),
-    Other(String),
-    Config(String),
-    Process(String),
-    Test(String),
-}
+use sam::{
+    chaos::ChaosScheduler,
+    config::{self, Config, LogFormat},
+    environment, fmt, hooks, import, init, lint, log_format, logs,
+    provenance::{self, Provenance, ReportSigning},
+    rhai::{Engine, RunResult},
+    seed,
+    state::{TestInfo, TestReport},
+    tui, upload, validate, ConfigurableEnvironment, Environment, Error,
+};
 
-impl std::error::Error for Error {}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Podman(e) => write!(f, "Podman error: {}", e),
-            Self::Other(e) => write!(f, "Other error: {}", e),
-            Self::Config(e) => write!(f, "Config error: {}", e),
-            Self::Process(e) => write!(f, "Process error: {}", e),
-            Self::Test(e) => write!(f, "Test error: {}", e),
-        }
-    }
-}
+const CONFIG_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
 
 fn setup_command_line_args() -> Command {
     clap::command!()
@@ -58,8 +37,16 @@ fn setup_command_line_args() -> Command {
                 .short('c')
                 .long("config")
                 .global(true)
+                .action(clap::ArgAction::Append)
                 .default_value("sam.yaml")
-                .help("Use a custom config file"),
+                .help("Use a custom config file, repeat to run several environments"),
+        )
+        .arg(
+            clap::Arg::new("parallel-envs")
+                .long("parallel-envs")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Run the environments of multiple config files in parallel"),
         )
         .arg(
             clap::Arg::new("delay")
@@ -84,6 +71,60 @@ fn setup_command_line_args() -> Command {
                 .global(true)
                 .help("Run all tests regardless of failure"),
         )
+        .arg(
+            clap::Arg::new("script-order")
+                .long("script-order")
+                .value_parser(["lex", "random", "config"])
+                .global(true)
+                .help("Order of the scripts in script directories"),
+        )
+        .arg(
+            clap::Arg::new("continue-on-error")
+                .long("continue-on-error")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Keep running the remaining scripts after one fails"),
+        )
+        .arg(
+            clap::Arg::new("workdir")
+                .long("workdir")
+                .global(true)
+                .help("Resolve relative paths against this directory"),
+        )
+        .arg(
+            clap::Arg::new("tui")
+                .long("tui")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Show a live dashboard of the tests and components while running"),
+        )
+        .arg(
+            clap::Arg::new("ascii")
+                .long("ascii")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Print plain ASCII status markers instead of emoji"),
+        )
+        .arg(
+            clap::Arg::new("log-format")
+                .long("log-format")
+                .value_parser(["pretty", "logfmt", "json"])
+                .global(true)
+                .help("Print logs and test progress as logfmt or JSON lines"),
+        )
+        .arg(
+            clap::Arg::new("artifacts-dir")
+                .long("artifacts-dir")
+                .global(true)
+                .help("Directory for test artifacts [default: sam-artifacts]"),
+        )
+        .arg(
+            clap::Arg::new("keep-failed-artifacts")
+                .long("keep-failed-artifacts")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Keep the temporary directories of failed tests"),
+        )
         .arg(
             clap::Arg::new("filter")
                 .short('f')
@@ -98,6 +139,20 @@ fn setup_command_line_args() -> Command {
                 .global(true)
                 .help("Skip the tests"),
         )
+        .arg(
+            clap::Arg::new("dry-run")
+                .long("dry-run")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Print the startup order and the commands to run instead of running them"),
+        )
+        .arg(
+            clap::Arg::new("explain-skip")
+                .long("explain-skip")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("List each skipped test with the reason after the run"),
+        )
         .arg(
             clap::Arg::new("reset-once")
                 .long("reset-once")
@@ -120,6 +175,19 @@ fn setup_command_line_args() -> Command {
                 .global(true)
                 .help("The directory containing the Rhai modules"),
         )
+        .arg(
+            clap::Arg::new("env-file")
+                .long("env-file")
+                .global(true)
+                .help("File with environment variables loaded at startup [default: .env]"),
+        )
+        .arg(
+            clap::Arg::new("data-file")
+                .long("data-file")
+                .action(clap::ArgAction::Append)
+                .global(true)
+                .help("JSON, YAML or TOML file exposed to scripts as `data`"),
+        )
         .arg(
             clap::Arg::new("output")
                 .long("output")
@@ -127,32 +195,405 @@ fn setup_command_line_args() -> Command {
                 .global(true)
                 .help("The file to output the test report to"),
         )
+        .arg(
+            clap::Arg::new("list-json")
+                .long("list-json")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .help("Print the discovered tests as JSON without running them"),
+        )
+        .arg(
+            clap::Arg::new("run-exact")
+                .long("run-exact")
+                .action(clap::ArgAction::Append)
+                .global(true)
+                .help("Run only the test with this exact path, e.g. 'Suite.nested.test'"),
+        )
+        .arg(
+            clap::Arg::new("only-affecting")
+                .long("only-affecting")
+                .action(clap::ArgAction::Append)
+                .requires("impact-report")
+                .global(true)
+                .help("Run only the tests that touched this component in the impact report"),
+        )
+        .arg(
+            clap::Arg::new("impact-report")
+                .long("impact-report")
+                .global(true)
+                .help("Report of an earlier run recording the components each test touched"),
+        )
+        .arg(
+            clap::Arg::new("target")
+                .long("target")
+                .global(true)
+                .help("Entry of `targets` in the config the scripts run against"),
+        )
+        .arg(
+            clap::Arg::new("profile")
+                .long("profile")
+                .action(clap::ArgAction::Append)
+                .global(true)
+                .help("Also start the components of this profile"),
+        )
+        .arg(
+            clap::Arg::new("set")
+                .long("set")
+                .action(clap::ArgAction::Append)
+                .global(true)
+                .help("Override a config field as path=value, e.g. global.repeat=3"),
+        )
         .subcommand(Command::new("reset").about("Reset the e2e test environment"))
         .subcommand(Command::new("init").about("Initialize the e2e test environment"))
         .subcommand(Command::new("run").about("Run the tests"))
         .subcommand(
-            Command::new("generate-schema").about("Generate JSON schema for SAM config file"),
+            Command::new("new")
+                .about("Create files from templates")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("test")
+                        .about("Create a test file with a describe/it skeleton")
+                        .arg(
+                            clap::Arg::new("name")
+                                .required(true)
+                                .help("Name of the test file, without .rhai"),
+                        )
+                        .arg(
+                            clap::Arg::new("dir").long("dir").help(
+                                "Directory of the test, by default the first script directory",
+                            ),
+                        )
+                        .arg(
+                            clap::Arg::new("register")
+                                .long("register")
+                                .action(clap::ArgAction::SetTrue)
+                                .help("Add the test to global.scripts of the config"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("fmt")
+                .about("Format the Rhai scripts and modules")
+                .arg(
+                    clap::Arg::new("paths")
+                        .num_args(1..)
+                        .help("Scripts or directories, by default those of the config"),
+                )
+                .arg(
+                    clap::Arg::new("check")
+                        .long("check")
+                        .action(clap::ArgAction::SetTrue)
+                        .help(
+                            "Print a diff of unformatted scripts and fail instead of writing them",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Check the config files")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("lint")
+                        .about("Warn about unknown keys, duplicate components and unused anchors")
+                        .arg(
+                            clap::Arg::new("strict")
+                                .long("strict")
+                                .action(clap::ArgAction::SetTrue)
+                                .help("Fail if there are warnings"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Print the components of the running containers and pods as YAML")
+                .arg(
+                    clap::Arg::new("label")
+                        .long("label")
+                        .action(clap::ArgAction::Append)
+                        .help("Only containers with this label, key or key=value"),
+                )
+                .arg(
+                    clap::Arg::new("name")
+                        .long("name")
+                        .help("Only containers whose name matches this pattern"),
+                )
+                .arg(
+                    clap::Arg::new("runtime")
+                        .long("runtime")
+                        .value_parser(["podman", "docker"])
+                        .help("Container runtime, by default the one of the config or podman"),
+                ),
+        )
+        .subcommand(
+            Command::new("validate").about(
+                "Check component references, dependency cycles, types and ports of the config",
+            ),
+        )
+        .subcommand(
+            Command::new("logs")
+                .about("Print the logs of a component")
+                .arg(
+                    clap::Arg::new("component")
+                        .required(true)
+                        .help("Name of the component in the config"),
+                )
+                .arg(
+                    clap::Arg::new("follow")
+                        // -f is taken by the global --filter
+                        .short('F')
+                        .long("follow")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("Keep printing new output until Ctrl-C"),
+                )
+                .arg(
+                    clap::Arg::new("since")
+                        .long("since")
+                        .help("Only print the output of this long ago and later, e.g. 5m"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify-report")
+                .about("Verify the signature of a test report")
+                .arg(
+                    clap::Arg::new("report")
+                        .required(true)
+                        .help("JSON or YAML report written with --output"),
+                )
+                .arg(
+                    clap::Arg::new("public-key")
+                        .long("public-key")
+                        .help("Hex encoded key the report must be signed with"),
+                ),
+        )
+        .subcommand(
+            Command::new("generate-schema")
+                .visible_alias("schema")
+                .about("Generate JSON schema for SAM config file"),
         )
 }
 
 async fn run_environment(sub_matches: &ArgMatches) -> Result<(), Error> {
     log::debug!("Starting run_environment");
 
-    log::debug!("Loading config file");
-    let mut cfg = Config::load(sub_matches.get_one::<String>("config").unwrap())?;
+    // Absolute, as the working directory may change while running a config
+    let config_paths: Vec<String> = sub_matches
+        .get_many::<String>("config")
+        .unwrap()
+        .map(|path| absolute_path(path))
+        .collect::<Result<_, _>>()?;
+
+    let run_id = format!("{:016x}", rand::random::<u64>());
+    log::info!("Run id {}", run_id);
+
+    let mut results = vec![];
+    let parallel = sub_matches.get_flag("parallel-envs") && config_paths.len() > 1;
+    if parallel && sub_matches.get_flag("tui") {
+        return Err(Error::Config(
+            "--tui cannot be combined with --parallel-envs".to_string(),
+        ));
+    }
+    if parallel {
+        check_parallel_components(&config_paths, sub_matches)?;
+        log::info!("Running {} environments in parallel", config_paths.len());
+        let handles: Vec<_> = config_paths
+            .iter()
+            .map(|path| {
+                let path = path.clone();
+                let matches = sub_matches.clone();
+                let run_id = run_id.clone();
+                tokio::spawn(async move { run_config(&path, &matches, &run_id).await })
+            })
+            .collect();
+        // Every environment is finished and stopped before failing
+        let mut errors = vec![];
+        for handle in handles {
+            match handle.await.map_err(|e| Error::Other(e.to_string())) {
+                Ok(Ok(result)) => results.push(result),
+                Ok(Err(e)) | Err(e) => errors.push(e),
+            }
+        }
+        match errors.len() {
+            0 => {}
+            1 => return Err(errors.remove(0)),
+            _ => return Err(Error::Multiple(errors)),
+        }
+    } else {
+        for path in &config_paths {
+            results.push(run_config(path, sub_matches, &run_id).await?);
+        }
+    }
+
+    if sub_matches.get_flag("dry-run") {
+        return Ok(());
+    }
+
+    if sub_matches.get_flag("list-json") {
+        let tests: Vec<TestInfo> = results
+            .into_iter()
+            .flat_map(|result| result.tests)
+            .collect();
+        let listing = serde_json::json!({ "version": 1, "tests": tests });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&listing).map_err(|e| Error::Other(e.to_string()))?
+        );
+        return Ok(());
+    }
+
+    let error_count: usize = results.iter().map(|result| result.error_count).sum();
+
+    if let Some(output) = sub_matches.get_one::<String>("output") {
+        log::debug!("Writing test report to {}", output);
+        let report = if results.len() == 1 {
+            results.remove(0).report
+        } else {
+            // The signed children are signed once more as a whole, with the
+            // key of the first of their configs
+            let signing = results.iter().find_map(|result| result.signing.clone());
+            let mut report = TestReport::combine(
+                "root".to_string(),
+                results.into_iter().map(|result| result.report).collect(),
+            );
+            if let Some(signing) = signing {
+                provenance::sign(&mut report, &signing)?;
+            }
+            report
+        };
+        let is_yaml = output.ends_with(".yaml") || output.ends_with(".yml");
+        if is_yaml {
+            std::fs::write(output, serde_yaml::to_string(&report).unwrap())
+                .map_err(|e| Error::Other(e.to_string()))?;
+        } else {
+            std::fs::write(output, serde_json::to_string_pretty(&report).unwrap())
+                .map_err(|e| Error::Other(e.to_string()))?;
+        }
+    }
+    if error_count > 0 {
+        return Err(Error::Test(format!(
+            "Test run failed with {} failed assertions",
+            error_count
+        )));
+    }
+
+    log::debug!("run_environment completed successfully");
+    Ok(())
+}
+
+/// Paths of the tests that touched the `--only-affecting` components in the
+/// `--impact-report`, for the config named `name`.
+fn affected_paths(sub_matches: &ArgMatches, name: &str) -> Result<Option<Vec<String>>, Error> {
+    let Some(components) = sub_matches.get_many::<String>("only-affecting") else {
+        return Ok(None);
+    };
+    let components: Vec<String> = components.cloned().collect();
+    let path = sub_matches.get_one::<String>("impact-report").unwrap();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read impact report {}: {}", path, e)))?;
+    // YAML parses the JSON reports as well
+    let report: TestReport = serde_yaml::from_str(&content)
+        .map_err(|e| Error::Config(format!("Invalid impact report {}: {}", path, e)))?;
+    // Reports of several configs nest the tests below the config name
+    let prefix = format!("{}.", name);
+    let paths = report
+        .paths_touching(&components)
+        .into_iter()
+        .map(|path| {
+            path.strip_prefix(&prefix)
+                .map(str::to_string)
+                .unwrap_or(path)
+        })
+        .collect();
+    Ok(Some(paths))
+}
+
+struct ConfigResult {
+    /// Named after the config when several configs run.
+    report: TestReport,
+    signing: Option<ReportSigning>,
+    error_count: usize,
+    tests: Vec<TestInfo>,
+}
+
+/// Fail if configs run in parallel have components of the same name, which
+/// would share their containers and the pid and output files of processes.
+fn check_parallel_components(
+    config_paths: &[String],
+    sub_matches: &ArgMatches,
+) -> Result<(), Error> {
+    let mut seen: HashMap<String, &str> = HashMap::new();
+    for path in config_paths {
+        let mut cfg = Config::load(path)?;
+        cfg.read_flags(sub_matches)?;
+        for component in &cfg.components {
+            if let Some(other) = seen.insert(component.name.clone(), path) {
+                return Err(Error::Config(format!(
+                    "Component {} is in {} and {}, --parallel-envs needs distinct names",
+                    component.name, other, path
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_config(
+    config_path: &str,
+    sub_matches: &ArgMatches,
+    run_id: &str,
+) -> Result<ConfigResult, Error> {
+    log::debug!("Loading config file {}", config_path);
+    let mut cfg = Config::load(config_path)?;
     cfg.read_flags(sub_matches)?;
+    let list_only = sub_matches.get_flag("list-json");
+
+    let parallel = sub_matches.get_flag("parallel-envs")
+        && sub_matches.get_many::<String>("config").unwrap().count() > 1;
+    if parallel && cfg.global.workdir.is_some() {
+        return Err(Error::Config(
+            "workdir cannot be combined with --parallel-envs".to_string(),
+        ));
+    }
+    // Their variables are set for the whole process, and so for every config
+    if parallel && !cfg.global.env_files.is_empty() {
+        return Err(Error::Config(
+            "env_files cannot be combined with --parallel-envs".to_string(),
+        ));
+    }
+    let _workdir = enter_workdir(&cfg, config_path)?;
+    cfg.load_env_files()?;
 
-    if cfg.global.reset_once {
+    if cfg.global.reset_once && !list_only {
         log::debug!("Reset-once flag detected, resetting environment");
-        reset_environment(sub_matches).await?;
+        reset_environment(config_path, cfg.global.dry_run).await?;
     }
 
-    let global_cfg = cfg.global.clone();
+    let mut global_cfg = cfg.global.clone();
     log::debug!("Creating configurable environment");
-    let mut env = ConfigurableEnvironment::new(&cfg)?;
+    let mut env = ConfigurableEnvironment::new(&cfg, run_id)?;
+
+    if cfg.global.dry_run && !list_only {
+        log::info!(
+            "Dry run of {}, printing the commands instead of running them",
+            cfg.name
+        );
+        env.start().await?;
+        return Ok(ConfigResult {
+            report: TestReport::new(cfg.name.clone(), true),
+            signing: None,
+            error_count: 0,
+            tests: vec![],
+        });
+    }
 
-    log::debug!("Starting environment");
-    env.start().await?;
+    if list_only {
+        log::debug!("Listing tests, not starting environment");
+        env.stop_on_drop(false);
+        global_cfg.delay = None;
+        global_cfg.repeat = Some(1);
+    } else {
+        log::debug!("Starting environment");
+        env.start().await?;
+    }
 
     if let Some(delay) = global_cfg.delay {
         log::info!("Delaying start of the tests by {}", delay);
@@ -167,118 +608,331 @@ async fn run_environment(sub_matches: &ArgMatches) -> Result<(), Error> {
         log::info!("Repeating the tests {} times", repeat);
     }
 
-    log::debug!("Setting up module directories");
-    let mut module_dirs = global_cfg.module_dirs.clone();
-    if module_dirs.is_empty() {
-        log::debug!("No module directories specified, using script directory");
-        let first_script = global_cfg
-            .scripts
-            .first()
-            .ok_or(Error::Config("No scripts found in config".to_string()))?;
-        let path = PathBuf::from(first_script);
-        if path.is_file() {
-            log::debug!("Using parent directory of script file: {}", first_script);
-            module_dirs.push(
-                path.parent()
-                    .ok_or(Error::Other(format!(
-                        "No parent directory found for script {}",
-                        first_script
-                    )))?
-                    .to_string_lossy()
-                    .into_owned(),
-            );
-        } else if path.is_dir() {
-            log::debug!("Using script directory directly: {}", first_script);
-            module_dirs.push(path.to_string_lossy().into_owned());
-        } else {
-            return Err(Error::Other(format!(
-                "No script or directory found at {}",
-                first_script
-            )));
-        }
-    }
-
+    let module_dirs = cfg.module_dirs()?;
     log::debug!(
         "Creating Rhai engine with module directories: {:?}",
         module_dirs
     );
     let mut engine = Engine::new(env, &module_dirs);
+    engine.configure(&cfg, run_id)?;
 
-    if let Some(filter) = &global_cfg.filter {
-        log::debug!("Setting filter: {}", filter);
-        engine.set_filter(filter.to_string());
+    if let Some(exact) = sub_matches.get_many::<String>("run-exact") {
+        let tests: Vec<String> = exact.cloned().collect();
+        log::debug!("Running exact tests: {:?}", tests);
+        engine.set_exact_tests(tests);
     }
 
-    if let Some(skip) = &global_cfg.skip {
-        log::debug!("Setting skip: {}", skip);
-        engine.set_skip(skip.to_string());
+    if let Some(paths) = affected_paths(sub_matches, &cfg.name)? {
+        log::debug!(
+            "Running tests touching the selected components: {:?}",
+            paths
+        );
+        engine.set_affected_paths(paths);
     }
 
-    let fail_fast = !global_cfg.no_fail_fast;
-    log::debug!("Setting fail-fast: {}", fail_fast);
-    engine.set_fail_fast(fail_fast);
+    engine.set_list_only(list_only);
 
-    for i in 0..repeat {
-        log::debug!("Starting iteration {} of {}", i + 1, repeat);
-        for script in &global_cfg.scripts {
-            match engine
-                .run(PathBuf::from(script))
-                .map_err(|e| Error::Other(e.to_string()))
-            {
-                Ok(_) => log::debug!("Script {} completed successfully", script),
-                Err(e) => {
-                    log::error!("Script {} failed: {}", script, e);
-                    return Err(e);
+    if !list_only {
+        seed::seed_running(&mut engine).await?;
+        hooks::run_hooks("pre_run", &cfg.hooks.pre_run, &mut engine, &[]).await?;
+    }
+
+    let dashboard = if sub_matches.get_flag("tui") && !list_only {
+        if std::io::stdout().is_terminal() {
+            Some(tui::Dashboard::start(
+                cfg.name.clone(),
+                cfg.components.clone(),
+                cfg.container_runtime(),
+                engine.shared_state(),
+            ))
+        } else {
+            log::warn!("--tui needs a terminal, printing the plain output");
+            None
+        }
+    } else {
+        None
+    };
+
+    let setup_result = match &global_cfg.setup_script {
+        Some(script) if !list_only => hooks::run_script("setup", script, &mut engine),
+        _ => Ok(()),
+    };
+
+    let chaos = match list_only || setup_result.is_err() {
+        true => None,
+        false => ChaosScheduler::for_config(&cfg, engine.shared_state())?,
+    };
+
+    let mut script_results = RunResult::default();
+    if setup_result.is_ok() {
+        'repeat: for i in 0..repeat {
+            log::debug!("Starting iteration {} of {}", i + 1, repeat);
+            for script in &global_cfg.scripts {
+                let result = engine.run(PathBuf::from(script));
+                let failed = result.errors().count() > 0;
+                for script in result.errors() {
+                    log::error!(
+                        "Script {} failed: {}",
+                        script.path.display(),
+                        script.error.as_ref().unwrap()
+                    );
                 }
-            };
+                script_results.extend(result);
+                if !failed {
+                    log::debug!("Script {} completed successfully", script);
+                } else if !engine.continue_on_error() {
+                    break 'repeat;
+                }
+            }
+        }
+    } else {
+        log::error!("Not running the tests as the setup script failed");
+    }
+    if let Some(chaos) = chaos {
+        chaos.stop().await;
+    }
+    log::debug!(
+        "Ran {} script files in {}",
+        script_results.scripts.len(),
+        humantime::format_duration(script_results.duration())
+    );
+    let mut run_result = match script_results.error() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    };
+    run_result = setup_result.and(run_result);
+
+    if let Some(script) = global_cfg.teardown_script.as_ref().filter(|_| !list_only) {
+        if let Err(e) = hooks::run_script("teardown", script, &mut engine) {
+            run_result = run_result.and(Err(e));
         }
     }
 
-    if sub_matches.get_flag("keep-running") {
-        log::info!("Press Ctrl-C to stop");
+    if let Some(dashboard) = dashboard {
+        dashboard.stop();
+    }
+
+    let report = finish_report(&cfg, config_path, sub_matches, run_id, engine.get_report()).await?;
+    if !list_only {
+        let summary = hooks::RunSummary::new(
+            &engine,
+            run_id,
+            &cfg.name,
+            report.test_count,
+            run_result.is_ok() && script_results.success(),
+            sub_matches.get_one::<String>("output").cloned(),
+        );
+        log::info!("{}: {}", cfg.name, summary.line());
+        if sub_matches.get_flag("explain-skip") {
+            for line in engine.shared_state().lock().explain_skips() {
+                println!("{}", line);
+            }
+        }
+        if let Err(e) = hooks::run_post_hooks(&cfg, &mut engine, &summary).await {
+            log::error!("{}", e);
+            run_result = run_result.and(Err(e));
+        }
+        engine.flush_log();
+
+        if let Some(upload) = &cfg.report_upload {
+            if let Err(e) = upload::upload_report(upload, &report, &summary).await {
+                log::error!("{}", e);
+            }
+            if !summary.success {
+                let artifacts_dir = engine.shared_state().lock().artifacts_dir.clone();
+                if let Err(e) = upload::upload_artifacts(upload, &artifacts_dir, &summary).await {
+                    log::error!("{}", e);
+                }
+            }
+        }
+    }
+    run_result?;
+
+    if sub_matches.get_flag("keep-running") && !list_only {
+        log::info!(
+            "Press Ctrl-C to stop, changes to {} are applied to the running environment",
+            config_path
+        );
         log::debug!("Waiting for Ctrl-C signal");
-        tokio::signal::ctrl_c()
-            .await
-            .map_err(|e| Error::Other(e.to_string()))?;
+        watch_config(config_path, sub_matches, &engine).await?;
     }
 
-    if let Some(output) = sub_matches.get_one::<String>("output") {
-        log::debug!("Writing test report to {}", output);
-        let report = engine.get_report();
-        let is_yaml = output.ends_with(".yaml") || output.ends_with(".yml");
-        if is_yaml {
-            std::fs::write(output, serde_yaml::to_string(&report).unwrap())
-                .map_err(|e| Error::Other(e.to_string()))?;
-        } else {
-            std::fs::write(output, serde_json::to_string_pretty(&report).unwrap())
-                .map_err(|e| Error::Other(e.to_string()))?;
+    let mut tests = engine.take_discovered_tests();
+    for test in &mut tests {
+        test.environment = cfg.name.clone();
+    }
+
+    Ok(ConfigResult {
+        report,
+        signing: cfg.report_signing.clone(),
+        error_count: engine.get_error_count(),
+        tests,
+    })
+}
+
+/// Name the report of one of several configs after it, and add the
+/// provenance and signature if the config asks for them.
+async fn finish_report(
+    cfg: &Config,
+    config_path: &str,
+    sub_matches: &ArgMatches,
+    run_id: &str,
+    mut report: TestReport,
+) -> Result<TestReport, Error> {
+    if sub_matches.get_many::<String>("config").unwrap().count() > 1 {
+        report.name = cfg.name.clone();
+    }
+    if let Some(signing) = &cfg.report_signing {
+        report.provenance = Some(Provenance::collect(cfg, config_path, run_id).await?);
+        provenance::sign(&mut report, signing)?;
+    }
+    Ok(report)
+}
+
+// Latest modification of the config file or its bases
+fn config_modified(config_path: &str) -> Option<std::time::SystemTime> {
+    Config::source_files(config_path)
+        .ok()?
+        .iter()
+        .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Wait for Ctrl-C, applying changes to the config to the running
+/// environment in the meantime.
+async fn watch_config<E: Environment + Clone + 'static>(
+    config_path: &str,
+    sub_matches: &ArgMatches,
+    engine: &Engine<E>,
+) -> Result<(), Error> {
+    let mut modified = config_modified(config_path);
+    let mut interval = tokio::time::interval(CONFIG_POLL_INTERVAL);
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                return result.map_err(|e| Error::Other(e.to_string()));
+            }
+            _ = interval.tick() => {}
+        }
+
+        let current = config_modified(config_path);
+        if current == modified {
+            continue;
+        }
+        modified = current;
+
+        log::info!("Config {} changed, reloading", config_path);
+        let cfg = Config::load(config_path).and_then(|mut cfg| {
+            cfg.read_flags(sub_matches)?;
+            Ok(cfg)
+        });
+        let cfg = match cfg {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                log::error!("Not applying invalid config: {}", e);
+                continue;
+            }
+        };
+        let state = engine.shared_state();
+        let mut state = state.lock();
+        let result = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(state.env.reload(&cfg))
+        });
+        match result {
+            Ok(()) => log::info!("Applied the changes of {}", config_path),
+            Err(e) => log::error!("Failed to apply the changes of {}: {}", config_path, e),
         }
     }
-    if engine.get_error_count() > 0 {
-        return Err(Error::Test(format!(
-            "Test run failed with {} failed assertions",
-            engine.get_error_count()
-        )));
+}
+
+fn absolute_path(path: &str) -> Result<String, Error> {
+    std::path::absolute(path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .map_err(|e| Error::Config(format!("Invalid path {}: {}", path, e)))
+}
+
+/// Restores the previous working directory when dropped.
+struct WorkdirGuard {
+    previous: PathBuf,
+}
+
+impl Drop for WorkdirGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::env::set_current_dir(&self.previous) {
+            log::error!(
+                "Failed to restore working directory {}: {}",
+                self.previous.display(),
+                e
+            );
+        }
     }
+}
 
-    log::debug!("run_environment completed successfully");
-    Ok(())
+fn enter_workdir(cfg: &Config, config_path: &str) -> Result<Option<WorkdirGuard>, Error> {
+    let Some(workdir) = cfg.workdir(config_path) else {
+        return Ok(None);
+    };
+    let previous = std::env::current_dir().map_err(|e| Error::Other(e.to_string()))?;
+    log::info!("Using working directory {}", workdir.display());
+    std::env::set_current_dir(&workdir).map_err(|e| {
+        Error::Config(format!(
+            "Failed to enter working directory {}: {}",
+            workdir.display(),
+            e
+        ))
+    })?;
+    Ok(Some(WorkdirGuard { previous }))
 }
 
-async fn reset_environment(sub_matches: &ArgMatches) -> Result<(), Error> {
+async fn reset_environment(config_path: &str, dry_run: bool) -> Result<(), Error> {
     log::info!("Resetting environment");
 
-    let cfg = Config::load(sub_matches.get_one::<String>("config").unwrap())?;
+    let mut cfg = Config::load(config_path)?;
+    cfg.global.dry_run = dry_run;
+    let _workdir = enter_workdir(&cfg, config_path)?;
+    cfg.load_env_files()?;
     for command in cfg.reset.iter() {
-        tokio::process::Command::new("sh")
-            .args(["-c", command])
-            .spawn()
+        let mut cmd = tokio::process::Command::new("sh");
+        cmd.args(["-c", command]);
+        if dry_run {
+            println!("{}", environment::command_line(&cmd));
+            continue;
+        }
+        cmd.spawn()
             .map_err(|e| Error::Other(e.to_string()))?
             .wait()
             .await
             .map_err(|e| Error::Other(e.to_string()))?;
     }
+    environment::cleanup(&cfg).await?;
+
+    Ok(())
+}
 
+fn verify_report(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let path = sub_matches.get_one::<String>("report").unwrap();
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::Other(format!("Failed to read report {}: {}", path, e)))?;
+    // YAML parses the JSON reports as well
+    let report: TestReport = serde_yaml::from_str(&content)
+        .map_err(|e| Error::Other(format!("Invalid report {}: {}", path, e)))?;
+    let public_key = sub_matches.get_one::<String>("public-key");
+    // Reports of several configs carry the signatures of their children
+    let signed: Vec<&TestReport> = std::iter::once(&report)
+        .chain(&report.children)
+        .filter(|r| r.signature.is_some())
+        .collect();
+    if signed.is_empty() {
+        return Err(Error::Test(format!("Report {} is not signed", path)));
+    }
+    for report in signed {
+        provenance::verify(report, public_key.map(|key| key.as_str()))?;
+        log::info!(
+            "Report {} is signed by {}",
+            report.name,
+            report.signature.as_ref().unwrap().public_key
+        );
+    }
     Ok(())
 }
 
@@ -316,25 +970,96 @@ fn welcome() {
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> ExitCode {
     // will log the duration of the program
     let _time_logger = TimeLogger {
         start: std::time::Instant::now(),
     };
 
-    pretty_env_logger::init();
-
-    welcome();
-
     let cmd = setup_command_line_args();
     let matches = cmd.get_matches();
+    let args = matches.subcommand().map_or(&matches, |(_, m)| m);
+    let log_format = args
+        .get_one::<String>("log-format")
+        .map_or(LogFormat::Pretty, |format| LogFormat::parse(format));
+
+    match run(&matches, log_format).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            print_error(&e, log_format);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// Print the error that ended the run. The structured formats print it as a
+/// log line with what failed and where, for automation deciding what to do.
+fn print_error(e: &Error, format: LogFormat) {
+    if format == LogFormat::Pretty {
+        eprintln!("{}", e.report(std::io::stderr().is_terminal()));
+        return;
+    }
+    let mut fields = vec![
+        ("level", "error".into()),
+        ("event", "error".into()),
+        ("kind", e.kind().name().into()),
+        ("exit_code", e.exit_code().into()),
+    ];
+    if let Some(component) = e.component() {
+        fields.push(("component", component.into()));
+    }
+    if let Some((script, line)) = e.script() {
+        fields.push(("script", script.into()));
+        fields.extend(line.map(|line| ("line", line.into())));
+    }
+    if e.timed_out() {
+        fields.push(("timed_out", true.into()));
+    }
+    fields.push(("msg", e.to_string().into()));
+    eprintln!("{}", log_format::line(format, &fields));
+}
+
+async fn run(matches: &ArgMatches, log_format: LogFormat) -> Result<(), Error> {
+    // Load the environment file first, so it can also set RUST_LOG
+    let args = matches.subcommand().map_or(matches, |(_, m)| m);
+    let env_file = args.get_one::<String>("env-file");
+    let env_file_path = env_file.map_or(".env", |path| path.as_str());
+    let dotenv_result = config::load_dotenv(env_file_path, env_file.is_some());
+
+    log_format::init_logger(log_format);
+
+    if log_format == LogFormat::Pretty {
+        welcome();
+    }
+
+    if dotenv_result? {
+        log::info!("Loaded environment variables from {}", env_file_path);
+    }
 
     match matches.subcommand() {
-        Some(("reset", sub_matches)) => reset_environment(sub_matches).await?,
+        Some(("reset", sub_matches)) => {
+            for config_path in sub_matches.get_many::<String>("config").unwrap() {
+                let dry_run = sub_matches.get_flag("dry-run");
+                reset_environment(&absolute_path(config_path)?, dry_run).await?;
+            }
+        }
         Some(("init", sub_matches)) => init::init(sub_matches).await?,
+        Some(("new", sub_matches)) => match sub_matches.subcommand() {
+            Some(("test", sub_matches)) => init::new_test(sub_matches)?,
+            _ => unreachable!("Invalid new subcommand"),
+        },
         Some(("run", sub_matches)) => run_environment(sub_matches).await?,
+        Some(("fmt", sub_matches)) => fmt::fmt(sub_matches)?,
+        Some(("config", sub_matches)) => match sub_matches.subcommand() {
+            Some(("lint", sub_matches)) => lint::lint(sub_matches)?,
+            _ => unreachable!("Invalid config subcommand"),
+        },
+        Some(("validate", sub_matches)) => validate::validate(sub_matches)?,
+        Some(("import", sub_matches)) => import::import(sub_matches).await?,
+        Some(("logs", sub_matches)) => logs::logs(sub_matches).await?,
+        Some(("verify-report", sub_matches)) => verify_report(sub_matches)?,
         Some(("generate-schema", _)) => generate_json_schema()?,
-        None => run_environment(&matches).await?,
+        None => run_environment(matches).await?,
         _ => unreachable!("Invalid subcommand"),
     }
 
@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{expand_env_vars, Config},
+    runtime::ContainerRuntime,
+    state::TestReport,
+    Error,
+};
+
+/// Sign the report with an ed25519 key.
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct ReportSigning {
+    /// File with the hex encoded 32 byte secret key, e.g. created with
+    /// `openssl rand -hex 32`. `${VAR}` is replaced by the environment variable.
+    pub key_file: String,
+}
+
+/// Where and from what a report was produced.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Provenance {
+    pub sam_version: String,
+    pub run_id: String,
+    pub host: String,
+    pub user: String,
+    /// SHA-256 of the config file and the base files it is built on.
+    pub config_digest: String,
+    /// Digests of the images of the components, by image name. Images that
+    /// are not available locally are left out.
+    pub images: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReportSignature {
+    pub algorithm: String,
+    /// Hex encoded public key to verify the signature with.
+    pub public_key: String,
+    /// Hex encoded signature of the JSON report without this field.
+    pub signature: String,
+}
+
+impl Provenance {
+    pub async fn collect(cfg: &Config, config_path: &str, run_id: &str) -> Result<Self, Error> {
+        let mut hasher = Sha256::new();
+        for file in Config::source_files(config_path)? {
+            hasher.update(std::fs::read(&file).map_err(|e| Error::Config(e.to_string()))?);
+        }
+
+        let mut images = BTreeMap::new();
+        let mut names: Vec<String> = cfg
+            .components
+            .iter()
+            .flat_map(|component| {
+                component.image().into_iter().chain(
+                    component
+                        .containers
+                        .iter()
+                        .map(|container| container.image.clone()),
+                )
+            })
+            .collect();
+        names.sort();
+        names.dedup();
+        let runtime = cfg.container_runtime();
+        for name in names {
+            match image_digest(runtime.as_ref(), &name).await {
+                Some(digest) => {
+                    images.insert(name, digest);
+                }
+                None => log::warn!("No digest found for image {}", name),
+            }
+        }
+
+        Ok(Self {
+            sam_version: env!("CARGO_PKG_VERSION").to_string(),
+            run_id: run_id.to_string(),
+            host: whoami::fallible::hostname().unwrap_or_default(),
+            user: whoami::username(),
+            config_digest: hex::encode(hasher.finalize()),
+            images,
+        })
+    }
+}
+
+async fn image_digest(runtime: &dyn ContainerRuntime, image: &str) -> Option<String> {
+    let output = runtime
+        .command()
+        .args([
+            "image",
+            "inspect",
+            "--format",
+            runtime.image_digest_format(),
+            image,
+        ])
+        .output()
+        .await
+        .ok()?;
+    let digest = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !digest.is_empty()).then_some(digest)
+}
+
+/// The bytes covered by the signature of `report`.
+fn signed_bytes(report: &TestReport) -> Result<Vec<u8>, Error> {
+    let mut report = report.clone();
+    report.signature = None;
+    serde_json::to_vec(&report).map_err(|e| Error::Other(e.to_string()))
+}
+
+/// Sign `report` with the key of `signing`.
+pub fn sign(report: &mut TestReport, signing: &ReportSigning) -> Result<(), Error> {
+    let path = expand_env_vars(&signing.key_file);
+    let key = std::fs::read_to_string(&path)
+        .map_err(|e| Error::Config(format!("Failed to read signing key {}: {}", path, e)))?;
+    let key: [u8; 32] = hex::decode(key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| {
+            Error::Config(format!("Signing key {} must be 32 hex encoded bytes", path))
+        })?;
+    let key = SigningKey::from_bytes(&key);
+    let signature = key.sign(&signed_bytes(report)?);
+    report.signature = Some(ReportSignature {
+        algorithm: "ed25519".to_string(),
+        public_key: hex::encode(key.verifying_key().as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    });
+    Ok(())
+}
+
+/// Check the signature of `report`, made with `public_key` if given.
+pub fn verify(report: &TestReport, public_key: Option<&str>) -> Result<(), Error> {
+    let invalid = |msg: &str| Error::Test(format!("Invalid report signature: {}", msg));
+    let signature = report
+        .signature
+        .as_ref()
+        .ok_or_else(|| invalid("the report is not signed"))?;
+    if signature.algorithm != "ed25519" {
+        return Err(invalid(&format!(
+            "unsupported algorithm {}",
+            signature.algorithm
+        )));
+    }
+    if let Some(public_key) = public_key {
+        if !public_key.eq_ignore_ascii_case(&signature.public_key) {
+            return Err(invalid("signed with a different key"));
+        }
+    }
+    let key: [u8; 32] = hex::decode(&signature.public_key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| invalid("malformed public key"))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|e| invalid(&e.to_string()))?;
+    let bytes: [u8; 64] = hex::decode(&signature.signature)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid("malformed signature"))?;
+    key.verify(
+        &signed_bytes(report)?,
+        &ed25519_dalek::Signature::from_bytes(&bytes),
+    )
+    .map_err(|_| invalid("the report was modified after signing"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let key_file = dir.path().join("key");
+        std::fs::write(&key_file, hex::encode([7u8; 32])).unwrap();
+        let signing = ReportSigning {
+            key_file: key_file.to_string_lossy().to_string(),
+        };
+
+        let mut report = TestReport::new("root".to_string(), true);
+        sign(&mut report, &signing).unwrap();
+        verify(&report, None).unwrap();
+
+        // Survives writing and reading the report as YAML
+        let yaml = serde_yaml::to_string(&report).unwrap();
+        let mut report: TestReport = serde_yaml::from_str(&yaml).unwrap();
+        let public_key = report.signature.as_ref().unwrap().public_key.clone();
+        verify(&report, Some(&public_key)).unwrap();
+        assert!(verify(&report, Some(&hex::encode([1u8; 32]))).is_err());
+
+        report.success = false;
+        assert!(verify(&report, None).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Running the components, and optionally `exec` and file commands, on
+//! another host over SSH.
+
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::expand_env_vars, Error};
+
+/// Seconds `ssh` waits for the remote host to accept the connection.
+const CONNECT_TIMEOUT_SECS: u32 = 10;
+
+/// Host the containers and processes of the components run on. Containers
+/// are managed with the remote API of podman or docker, processes and
+/// healthcheck commands run through `ssh`. Volume paths are paths on the
+/// remote host.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Remote {
+    pub host: String,
+    pub user: Option<String>,
+    /// SSH port, 22 by default.
+    pub port: Option<u16>,
+    /// Private key to log in with, `${VAR}` is replaced by the environment
+    /// variable. Docker only uses the keys of the SSH agent and config.
+    pub key_file: Option<String>,
+    /// Podman API socket on the remote host.
+    #[serde(default = "default_socket")]
+    pub socket: String,
+    /// Run the commands of `exec` on the remote host.
+    #[serde(default)]
+    pub exec: bool,
+    /// Read and write files of `read_file`, `write_file`, `file_exists`,
+    /// `mkdir` and `remove` on the remote host.
+    #[serde(default)]
+    pub files: bool,
+}
+
+fn default_socket() -> String {
+    "/run/podman/podman.sock".to_string()
+}
+
+impl Remote {
+    /// `user@host` or `host`.
+    pub fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// `ssh://user@host:port`, followed by the path of the API socket if
+    /// `socket` is set.
+    pub fn url(&self, socket: bool) -> String {
+        let mut url = format!("ssh://{}", self.destination());
+        if let Some(port) = self.port {
+            url += &format!(":{}", port);
+        }
+        if socket {
+            url += &self.socket;
+        }
+        url
+    }
+
+    pub fn key_file(&self) -> Option<String> {
+        self.key_file.as_deref().map(expand_env_vars)
+    }
+
+    fn ssh_args(&self, tty: bool) -> Vec<String> {
+        let mut args = vec![
+            "-o".to_string(),
+            "BatchMode=yes".to_string(),
+            "-o".to_string(),
+            format!("ConnectTimeout={}", CONNECT_TIMEOUT_SECS),
+        ];
+        // Without a terminal the remote process is not stopped when the
+        // local ssh process is killed
+        if tty {
+            args.push("-tt".to_string());
+        }
+        if let Some(port) = self.port {
+            args.extend(["-p".to_string(), port.to_string()]);
+        }
+        if let Some(key_file) = self.key_file() {
+            args.extend(["-i".to_string(), key_file]);
+        }
+        args.push(self.destination());
+        args.push("--".to_string());
+        args
+    }
+
+    /// Command running `command` with `env` on the remote host. Long running
+    /// processes get a terminal, so that they stop with the local process.
+    pub fn command(
+        &self,
+        command: &[String],
+        env: &[(&str, &str)],
+        tty: bool,
+    ) -> tokio::process::Command {
+        let mut cmd = tokio::process::Command::new("ssh");
+        cmd.args(self.ssh_args(tty))
+            .arg(self.shell_line(command, env));
+        cmd
+    }
+
+    /// Command forwarding `local_port` on this host to `port` on the remote
+    /// host until it is killed.
+    pub fn forward_command(&self, local_port: u16, port: u16) -> tokio::process::Command {
+        let mut args = self.ssh_args(false);
+        // Nothing runs on the remote host
+        args.pop();
+        let mut cmd = tokio::process::Command::new("ssh");
+        cmd.args(["-N", "-o", "ExitOnForwardFailure=yes", "-L"])
+            .arg(format!("{}:127.0.0.1:{}", local_port, port))
+            .args(args);
+        cmd
+    }
+
+    fn shell_line(&self, command: &[String], env: &[(&str, &str)]) -> String {
+        let mut line = vec![];
+        if !env.is_empty() {
+            line.push("env".to_string());
+            line.extend(
+                env.iter()
+                    .map(|(key, value)| format!("{}={}", key, shell_quote(value))),
+            );
+        }
+        line.extend(command.iter().map(|arg| shell_quote(arg)));
+        line.join(" ")
+    }
+
+    /// Run a shell command line on the remote host, with `stdin` as input.
+    /// After `timeout` the local `ssh` process is killed with its group.
+    pub fn run(
+        &self,
+        command_line: &str,
+        stdin: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> Result<std::process::Output, Error> {
+        let mut cmd = std::process::Command::new("ssh");
+        cmd.args(self.ssh_args(false))
+            .arg(command_line)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if timeout.is_some() {
+            cmd.process_group(0);
+        }
+        let mut child = cmd.spawn().map_err(|e| Error::run("ssh", None, e))?;
+        let pid = child.id();
+        let input = stdin.zip(child.stdin.take());
+        let (done, finished) = mpsc::channel::<()>();
+        std::thread::scope(|scope| {
+            // Written while the output is read, as the remote command may
+            // not read all of its input before its output fills the pipes
+            let writer = input.map(|(input, mut pipe)| scope.spawn(move || pipe.write_all(input)));
+            let killer = timeout.map(|timeout| {
+                scope.spawn(move || {
+                    let expired =
+                        finished.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout);
+                    if expired {
+                        let _ = std::process::Command::new("kill")
+                            .args(["-KILL", "--", &format!("-{}", pid)])
+                            .output();
+                    }
+                    expired
+                })
+            });
+            let output = child.wait_with_output();
+            drop(done);
+            if let (Some(killer), Some(timeout)) = (killer, timeout) {
+                if killer.join().unwrap() {
+                    return Err(Error::TimedOut {
+                        command: format!("{} on {}", command_line, self.host),
+                        timeout,
+                    });
+                }
+            }
+            let output = output.map_err(|e| Error::io("Failed to read the output of ssh", e))?;
+            match writer.map(|writer| writer.join().unwrap()) {
+                // The command exited without reading all of its input
+                Some(Err(e)) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+                    Err(Error::io("Failed to write the input of ssh", e))
+                }
+                _ => Ok(output),
+            }
+        })
+    }
+
+    /// Like `run`, failing with stderr unless the command succeeds.
+    pub fn run_checked(
+        &self,
+        command_line: &str,
+        stdin: Option<&[u8]>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, Error> {
+        let output = self.run(command_line, stdin, timeout)?;
+        if !output.status.success() {
+            let command = format!("{} on {}", command_line, self.host);
+            return Err(Error::exited(command, &output));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Quote `value` for a POSIX shell, unless it only has safe characters.
+pub fn shell_quote(value: &str) -> String {
+    let safe = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if safe {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_line() {
+        let remote = Remote {
+            host: "lab".to_string(),
+            user: Some("ci".to_string()),
+            port: Some(2222),
+            key_file: None,
+            socket: default_socket(),
+            exec: false,
+            files: false,
+        };
+        assert_eq!(remote.url(true), "ssh://ci@lab:2222/run/podman/podman.sock");
+        assert_eq!(
+            remote.ssh_args(false)[2..],
+            ["-o", "ConnectTimeout=10", "-p", "2222", "ci@lab", "--"]
+        );
+        let command = ["echo".to_string(), "it's here".to_string()];
+        assert_eq!(remote.shell_line(&command, &[]), r"echo 'it'\''s here'");
+        assert_eq!(
+            remote.shell_line(&command[..1], &[("A", "1 2")]),
+            "env A='1 2' echo"
+        );
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        // A fake ssh running the command line locally
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let ssh = dir.path().join("ssh");
+        std::fs::write(
+            &ssh,
+            "#!/bin/sh\nfor arg; do line=$arg; done\nexec sh -c \"$line\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&ssh, std::os::unix::fs::PermissionsExt::from_mode(0o755))
+            .unwrap();
+        let path = std::env::var("PATH").unwrap_or_default();
+        std::env::set_var("PATH", format!("{}:{}", dir.path().display(), path));
+        let remote = Remote {
+            host: "lab".to_string(),
+            user: None,
+            port: None,
+            key_file: None,
+            socket: default_socket(),
+            exec: true,
+            files: false,
+        };
+
+        let output = remote
+            .run_checked("echo hi", None, Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(output, b"hi\n");
+
+        let started = std::time::Instant::now();
+        let error = remote
+            .run_checked(
+                "sleep 10 & sleep 10",
+                None,
+                Some(Duration::from_millis(100)),
+            )
+            .unwrap_err();
+        assert!(error.timed_out());
+        assert_eq!(
+            error.to_string(),
+            "sleep 10 & sleep 10 on lab timed out after 100ms"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
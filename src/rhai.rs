@@ -1,16 +1,163 @@
 use parking_lot::Mutex;
 use rhai::module_resolvers::{FileModuleResolver, ModuleResolversCollection};
 use rhai::{Dynamic, Engine as RhaiEngine, EvalAltResult, FnPtr, Position, Scope};
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::commands::register_commands;
+use crate::config::{
+    self, Config, EngineOptions, HttpRetry, OptimizationLevel, Output, ScriptOrder, Target,
+};
 use crate::environment::Environment;
-use crate::state::{SharedState, TestReport};
+use crate::remote::Remote;
+use crate::seed::ComponentSeeds;
+use crate::state::{SharedState, SkipReason, SkippedTest, TestInfo, TestReport};
+use crate::suite::{SkippedScript, Suite, SUITE_FILE};
+use crate::throttle::LogThrottle;
+use crate::writer::OutputWriter;
+use crate::Error;
+
+const DEFAULT_MAX_CALL_LEVELS: usize = 256;
+const DEFAULT_MAX_EXPR_DEPTH: usize = 256;
+/// Most idle engines kept per set of module directories.
+const MAX_IDLE_ENGINES: usize = 64;
+/// Engines set up in the background when a task first needs one, so that a
+/// fan-out of tasks does not set them up one at a time.
+const PREFILLED_ENGINES: usize = 8;
+
+/// What kind of failure stopped a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptErrorKind {
+    /// The script could not be read or parsed.
+    Compile,
+    /// The script raised an error outside of a failing test.
+    Runtime,
+    /// A test failed and fail-fast stopped the script.
+    Assertion,
+}
+
+impl fmt::Display for ScriptErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptErrorKind::Compile => write!(f, "compile error"),
+            ScriptErrorKind::Runtime => write!(f, "runtime error"),
+            ScriptErrorKind::Assertion => write!(f, "assertion failure"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ScriptError {
+    pub kind: ScriptErrorKind,
+    pub error: Box<EvalAltResult>,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.error)
+    }
+}
+
+/// Outcome of running a single script file.
+#[derive(Debug)]
+pub struct ScriptResult {
+    pub path: PathBuf,
+    pub duration: Duration,
+    /// Tests that failed while running the script.
+    pub tests_failed: usize,
+    pub error: Option<ScriptError>,
+}
+
+impl ScriptResult {
+    fn failed_to_load(path: PathBuf, msg: String) -> Self {
+        Self {
+            path,
+            duration: Duration::ZERO,
+            tests_failed: 0,
+            error: Some(ScriptError {
+                kind: ScriptErrorKind::Compile,
+                error: invalid(msg),
+            }),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.error.is_none() && self.tests_failed == 0
+    }
+
+    /// The error that stopped the script, with its path and line.
+    pub fn to_error(&self) -> Option<Error> {
+        let error = self.error.as_ref()?;
+        Some(Error::Script {
+            path: self.path.display().to_string(),
+            line: error.error.position().line(),
+            message: error.to_string(),
+        })
+    }
+}
+
+/// Outcome of running one or more script files, in the order they ran.
+#[derive(Debug, Default)]
+pub struct RunResult {
+    pub scripts: Vec<ScriptResult>,
+}
+
+impl RunResult {
+    pub fn success(&self) -> bool {
+        self.scripts.iter().all(|script| script.success())
+    }
+
+    /// Scripts that were stopped by an error.
+    pub fn errors(&self) -> impl Iterator<Item = &ScriptResult> {
+        self.scripts.iter().filter(|script| script.error.is_some())
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.scripts.iter().map(|script| script.duration).sum()
+    }
+
+    pub fn extend(&mut self, other: RunResult) {
+        self.scripts.extend(other.scripts);
+    }
+
+    /// The errors of the scripts that were stopped by one, if any.
+    pub fn error(&self) -> Option<Error> {
+        let mut errors = self
+            .scripts
+            .iter()
+            .filter_map(ScriptResult::to_error)
+            .collect::<Vec<_>>();
+        match errors.len() {
+            0 => None,
+            1 => errors.pop(),
+            _ => Some(Error::Multiple(errors)),
+        }
+    }
+
+    /// The first script error, for callers that only care whether all
+    /// scripts ran to completion.
+    pub fn into_result(self) -> Result<(), Box<EvalAltResult>> {
+        match self.scripts.into_iter().find_map(|script| script.error) {
+            Some(error) => Err(error.error),
+            None => Ok(()),
+        }
+    }
+}
 
 pub struct Engine<E: Environment> {
     engine: RhaiEngine,
     scope: Scope<'static>,
     shared_state: Arc<Mutex<SharedState<E>>>,
+    default_options: EngineOptions,
+    script_options: HashMap<PathBuf, EngineOptions>,
+    continue_on_error: bool,
+    script_order: ScriptOrder,
+    order: Vec<String>,
 }
 
 impl<E: Environment + Clone + 'static> Engine<E> {
@@ -19,12 +166,16 @@ impl<E: Environment + Clone + 'static> Engine<E> {
             engine: RhaiEngine::new(),
             scope: Scope::new(),
             shared_state: Arc::new(Mutex::new(SharedState::new(env))),
+            default_options: EngineOptions::default(),
+            script_options: HashMap::new(),
+            continue_on_error: false,
+            script_order: ScriptOrder::Lex,
+            order: vec![],
         };
 
         engine.shared_state.lock().module_dirs = module_dirs.into();
 
-        engine.engine.set_max_call_levels(256);
-        engine.engine.set_max_expr_depths(256, 256);
+        engine.apply_options(&EngineOptions::default());
 
         let mut resolvers = ModuleResolversCollection::new();
         for module_dir in module_dirs {
@@ -35,47 +186,280 @@ impl<E: Environment + Clone + 'static> Engine<E> {
         engine.engine.set_module_resolver(resolvers);
 
         register_commands(&mut engine.engine, engine.shared_state.clone());
+        engine.set_params(Default::default());
+
+        let state = engine.shared_state.clone();
+        engine.engine.on_print(move |text| {
+            let mut state = state.lock();
+            if state.capture_output {
+                state.console.push(text.to_string());
+            } else {
+                state.stdout.writeln(text);
+            }
+        });
 
         engine
     }
 
-    pub fn run_file(&mut self, path: PathBuf) -> Result<(), Box<EvalAltResult>> {
+    /// Apply the settings of `cfg` to the engine, the same for the `sam`
+    /// binary and the test harness.
+    pub fn configure(&mut self, cfg: &Config, run_id: &str) -> Result<(), Error> {
+        self.set_run_id(run_id.to_string());
+        self.set_options(cfg.global.engine.clone(), cfg.global.script_options.clone());
+
+        log::debug!("Loading script data");
+        self.set_data(cfg.script_data()?);
+        self.load_component_modules(&cfg.component_modules())?;
+
+        if let Some(filter) = &cfg.global.filter {
+            log::debug!("Setting filter: {}", filter);
+            self.set_filter(filter.to_string());
+        }
+        if let Some(skip) = &cfg.global.skip {
+            log::debug!("Setting skip: {}", skip);
+            self.set_skip(skip.to_string());
+        }
+
+        self.set_keep_failed_artifacts(cfg.global.keep_failed_artifacts);
+        self.set_output(cfg.global.output.clone());
+        self.set_http_retry(cfg.global.http_retry.clone());
+        self.set_exec_timeout(cfg.global.timeouts.exec()?);
+        self.set_log_throttle(LogThrottle::shared(cfg.global.log_limits.as_ref()));
+        self.set_remote(cfg.remote.clone());
+        self.set_targets(cfg.targets.clone(), cfg.global.target.clone());
+        self.set_artifacts_dir(
+            PathBuf::from(
+                cfg.global
+                    .artifacts_dir
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_ARTIFACTS_DIR),
+            )
+            .join(run_id)
+            .join(&cfg.name),
+        );
+
+        let fail_fast = !cfg.global.no_fail_fast;
+        log::debug!("Setting fail-fast: {}", fail_fast);
+        self.set_fail_fast(fail_fast);
+        self.set_continue_on_error(cfg.global.continue_on_error);
+        self.set_script_order(cfg.script_order(), cfg.global.order.clone());
+        self.set_seeds(cfg.component_seeds()?);
+        Ok(())
+    }
+
+    pub fn set_options(
+        &mut self,
+        default_options: EngineOptions,
+        script_options: HashMap<PathBuf, EngineOptions>,
+    ) {
+        self.default_options = default_options;
+        self.script_options = script_options;
+    }
+
+    // Options for a script file, taking the ones configured for the file
+    // itself over the ones configured for its directories, and the ones of a
+    // directory over the ones of its parents.
+    fn options_for(&self, path: &Path) -> EngineOptions {
+        let ancestors: Vec<_> = path.ancestors().collect();
+        ancestors
+            .iter()
+            .rev()
+            .filter_map(|ancestor| self.script_options.get(*ancestor))
+            .fold(self.default_options.clone(), |options, overrides| {
+                options.merge(overrides)
+            })
+    }
+
+    fn apply_options(&mut self, options: &EngineOptions) {
+        let optimization_level = match options.optimization_level {
+            Some(OptimizationLevel::None) => rhai::OptimizationLevel::None,
+            Some(OptimizationLevel::Simple) | None => rhai::OptimizationLevel::Simple,
+        };
+        let max_expr_depth = options.max_expr_depth.unwrap_or(DEFAULT_MAX_EXPR_DEPTH);
+        self.engine.set_optimization_level(optimization_level);
+        self.engine
+            .set_max_call_levels(options.max_call_levels.unwrap_or(DEFAULT_MAX_CALL_LEVELS));
+        self.engine
+            .set_max_expr_depths(max_expr_depth, max_expr_depth);
+        self.engine
+            .set_strict_variables(options.strict_variables.unwrap_or(false));
+    }
+
+    pub fn run_file(&mut self, path: PathBuf) -> ScriptResult {
         log::info!("Running script file {}", path.display());
-        {
+        let options = self.options_for(&path);
+        log::debug!("Using engine options {:?}", options);
+        self.apply_options(&options);
+        let tests_failed_before = {
             let mut state = self.shared_state.lock();
             state.current_file = Some(path.display().to_string());
-        }
-        self.engine.run_file_with_scope(&mut self.scope, path)?;
-        {
+            state.tests_failed
+        };
+        let started_at = Instant::now();
+        let result = self.engine.compile_file(path.clone()).and_then(|ast| {
+            let ast = rhai::Shared::new(ast);
+            self.shared_state
+                .lock()
+                .script_asts
+                .insert(path.display().to_string(), ast.clone());
+            self.engine.run_ast_with_scope(&mut self.scope, &ast)
+        });
+        let duration = started_at.elapsed();
+        let tests_failed = {
             let mut state = self.shared_state.lock();
             state.current_file = None;
+            state.port_forwards.clear();
+            for component in std::mem::take(&mut state.paused_components) {
+                log::warn!("Resuming {}, paused by {}", component, path.display());
+                let resumed = tokio::task::block_in_place(|| {
+                    tokio::runtime::Handle::current()
+                        .block_on(state.env.resume_component(&component))
+                });
+                if let Err(e) = resumed {
+                    log::error!("Failed to resume {}: {}", component, e);
+                }
+            }
+            state.stdout.flush();
+            state.tests_failed - tests_failed_before
+        };
+        let error = result.err().map(|error| {
+            let kind = match *error {
+                EvalAltResult::ErrorParsing(..) | EvalAltResult::ErrorSystem(..) => {
+                    ScriptErrorKind::Compile
+                }
+                _ if tests_failed > 0 => ScriptErrorKind::Assertion,
+                _ => ScriptErrorKind::Runtime,
+            };
+            ScriptError { kind, error }
+        });
+        ScriptResult {
+            path,
+            duration,
+            tests_failed,
+            error,
         }
-        Ok(())
     }
 
-    pub fn run_directory(&mut self, path: PathBuf) -> Result<(), Box<EvalAltResult>> {
-        for entry in std::fs::read_dir(path).map_err(|e| {
-            let msg = format!("Failed to read directory: {}", e);
-            Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
-        })? {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if path.is_file() && path.extension().unwrap_or_default() == "rhai" {
-                    self.run_file(path)?;
+    pub fn run_directory(&mut self, path: PathBuf) -> RunResult {
+        let mut result = RunResult::default();
+        let suite = match Suite::load(&path) {
+            Ok(suite) => suite,
+            Err(e) => {
+                result.scripts.push(ScriptResult::failed_to_load(
+                    path.join(SUITE_FILE),
+                    e.to_string(),
+                ));
+                return result;
+            }
+        };
+        let paths = match &suite {
+            Some(suite) if !suite.scripts.is_empty() => suite.script_paths(&path),
+            _ => match self.directory_scripts(&path) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    let msg = format!("Failed to read directory: {}", e);
+                    result.scripts.push(ScriptResult::failed_to_load(path, msg));
+                    return result;
+                }
+            },
+        };
+
+        let mut suite_report = suite.as_ref().map(|suite| suite.report(&path));
+        for script_path in paths {
+            let settings = suite
+                .as_ref()
+                .map(|suite| suite.settings_for(&path, &script_path))
+                .unwrap_or_default();
+            let missing_components = {
+                let state = self.shared_state.lock();
+                settings
+                    .requires
+                    .iter()
+                    .filter(|component| !state.env.is_running(component))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            if let Some(suite_report) = &mut suite_report {
+                let script = script_path.display().to_string();
+                if !missing_components.is_empty() {
+                    log::warn!(
+                        "Skipping script {}, components not running: {}",
+                        script,
+                        missing_components.join(", ")
+                    );
+                    self.shared_state.lock().skipped_tests.push(SkippedTest {
+                        test: script.clone(),
+                        reason: SkipReason::MissingComponents(missing_components.clone()),
+                    });
+                    suite_report.skipped.push(SkippedScript {
+                        script,
+                        missing_components,
+                    });
+                    continue;
                 }
+                suite_report.scripts.push(script);
+            }
+
+            self.set_params(settings.params);
+            self.shared_state
+                .lock()
+                .current_tags_stack
+                .push(settings.tags);
+            let script = self.run_file(script_path);
+            self.shared_state.lock().current_tags_stack.pop();
+
+            let stop = script.error.is_some() && !self.continue_on_error;
+            result.scripts.push(script);
+            if stop {
+                break;
             }
         }
-        Ok(())
+        self.set_params(Default::default());
+        if let Some(suite_report) = suite_report {
+            let mut state = self.shared_state.lock();
+            state.suites.retain(|suite| suite.path != suite_report.path);
+            state.suites.push(suite_report);
+        }
+        result
+    }
+
+    // The `.rhai` files of a directory, in the configured order
+    fn directory_scripts(&self, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut paths = std::fs::read_dir(path)?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && path.extension().unwrap_or_default() == "rhai")
+            .collect::<Vec<_>>();
+        sort_scripts(&mut paths, self.script_order, &self.order);
+        Ok(paths)
     }
 
-    pub fn run(&mut self, path: PathBuf) -> Result<(), Box<EvalAltResult>> {
+    pub fn run(&mut self, path: PathBuf) -> RunResult {
         if path.is_file() {
-            self.run_file(path)
+            RunResult {
+                scripts: vec![self.run_file(path)],
+            }
         } else {
             self.run_directory(path)
         }
     }
 
+    /// Keep running the remaining scripts after one of them fails.
+    pub fn set_continue_on_error(&mut self, continue_on_error: bool) {
+        self.continue_on_error = continue_on_error;
+    }
+
+    /// How to order the scripts of a directory, `order` lists the scripts
+    /// that run first with `ScriptOrder::Config`.
+    pub fn set_script_order(&mut self, script_order: ScriptOrder, order: Vec<String>) {
+        self.script_order = script_order;
+        self.order = order;
+    }
+
+    pub fn continue_on_error(&self) -> bool {
+        self.continue_on_error
+    }
+
     pub fn set_filter(&mut self, filter: String) {
         let mut state = self.shared_state.lock();
         state.filter_expression = Some(filter);
@@ -86,17 +470,156 @@ impl<E: Environment + Clone + 'static> Engine<E> {
         state.skip_expression = Some(skip);
     }
 
+    pub fn set_data(&mut self, data: rhai::Map) {
+        self.scope.push_constant("data", data);
+    }
+
+    /// Add string constants to the scope of the scripts, e.g. the variables
+    /// of the hooks.
+    pub fn push_constants(&mut self, constants: &[(String, String)]) {
+        for (name, value) in constants {
+            self.scope.push_constant(name.as_str(), value.clone());
+        }
+    }
+
+    /// Remove the constants added by `push_constants`.
+    pub fn remove_constants(&mut self, constants: &[(String, String)]) {
+        for (name, _) in constants {
+            let _ = self.scope.remove::<Dynamic>(name);
+        }
+    }
+
+    /// Load the `module` files of the components, by namespace and path.
+    pub fn load_component_modules(
+        &mut self,
+        modules: &[(String, String)],
+    ) -> Result<(), crate::Error> {
+        let mut loaded = vec![];
+        for (namespace, path) in modules {
+            log::debug!("Loading module {} as {}", path, namespace);
+            let module = self
+                .engine
+                .compile_file(path.into())
+                .and_then(|ast| rhai::Module::eval_ast_as_new(Scope::new(), &ast, &self.engine))
+                .map_err(|e| {
+                    crate::Error::Config(format!("Failed to load module {}: {}", path, e))
+                })?;
+            loaded.push((namespace.clone(), module.into()));
+        }
+        self.set_component_modules(loaded);
+        Ok(())
+    }
+
+    /// Register loaded component modules, e.g. the ones of the engine
+    /// spawning a task.
+    pub fn set_component_modules(&mut self, modules: Vec<(String, rhai::Shared<rhai::Module>)>) {
+        for (namespace, module) in &modules {
+            self.engine
+                .register_static_module(namespace, module.clone());
+        }
+        self.shared_state.lock().component_modules = modules;
+    }
+
+    // Replace the `params` constant, set from suite.yaml for each script
+    fn set_params(&mut self, params: serde_json::Map<String, serde_json::Value>) {
+        let params = rhai::serde::to_dynamic(params).unwrap_or_default();
+        let _ = self.scope.remove::<Dynamic>("params");
+        self.scope.push_constant_dynamic("params", params);
+    }
+
+    /// Share the output of another engine, e.g. the one spawning a task.
+    pub fn set_stdout(&mut self, stdout: OutputWriter) {
+        self.shared_state.lock().stdout = stdout;
+    }
+
+    pub fn set_run_id(&mut self, run_id: String) {
+        self.shared_state.lock().run_id = run_id;
+    }
+
+    pub fn set_exact_tests(&mut self, tests: Vec<String>) {
+        let mut state = self.shared_state.lock();
+        state.exact_tests = tests;
+    }
+
+    /// Run only the tests at or below `paths`.
+    pub fn set_affected_paths(&mut self, paths: Vec<String>) {
+        self.shared_state.lock().affected_paths = Some(paths);
+    }
+
+    pub fn set_list_only(&mut self, list_only: bool) {
+        let mut state = self.shared_state.lock();
+        state.list_only = list_only;
+        state.silent = list_only;
+    }
+
+    pub fn take_discovered_tests(&mut self) -> Vec<TestInfo> {
+        std::mem::take(&mut self.shared_state.lock().discovered_tests)
+    }
+
+    pub fn set_artifacts_dir(&mut self, dir: PathBuf) {
+        self.shared_state.lock().artifacts_dir = dir;
+    }
+
+    pub fn set_output(&mut self, output: Output) {
+        self.shared_state.lock().output = output;
+    }
+
+    pub fn set_http_retry(&mut self, retry: HttpRetry) {
+        self.shared_state.lock().http_retry = retry;
+    }
+
+    pub fn set_exec_timeout(&mut self, timeout: Option<std::time::Duration>) {
+        self.shared_state.lock().exec_timeout = timeout;
+    }
+
+    pub fn set_log_throttle(&mut self, throttle: Option<Arc<Mutex<LogThrottle>>>) {
+        self.shared_state.lock().log_throttle = throttle;
+    }
+
+    /// Log the counts of repeated and dropped `log` lines the throttle still
+    /// holds back, once the scripts are done.
+    pub fn flush_log(&self) {
+        let throttle = self.shared_state.lock().log_throttle.clone();
+        if let Some(throttle) = throttle {
+            for line in throttle.lock().flush() {
+                log::info!("{}", line);
+            }
+        }
+    }
+
+    pub fn set_remote(&mut self, remote: Option<Remote>) {
+        self.shared_state.lock().remote = remote;
+    }
+
+    pub fn set_targets(&mut self, targets: BTreeMap<String, Target>, selected: Option<String>) {
+        let mut state = self.shared_state.lock();
+        state.targets = targets;
+        state.target = selected;
+    }
+
+    pub fn set_seeds(&mut self, seeds: Vec<ComponentSeeds>) {
+        self.shared_state.lock().seeds = seeds;
+    }
+
+    pub fn set_keep_failed_artifacts(&mut self, keep: bool) {
+        self.shared_state.lock().keep_failed_artifacts = keep;
+    }
+
     pub fn set_fail_fast(&mut self, fail_fast: bool) {
         let mut state = self.shared_state.lock();
         state.fail_fast = fail_fast;
     }
 
+    pub fn shared_state(&self) -> Arc<Mutex<SharedState<E>>> {
+        self.shared_state.clone()
+    }
+
     pub fn get_error_count(&self) -> usize {
         let state = self.shared_state.lock();
         let error_count = state
             .assertions
-            .iter()
-            .flat_map(|(_, assertions)| assertions.iter().filter(|a| !a.success))
+            .values()
+            .flat_map(|assertions| assertions.iter().filter(|a| !a.success))
             .count();
         error_count
     }
@@ -106,12 +629,483 @@ impl<E: Environment + Clone + 'static> Engine<E> {
         TestReport::from(&*state)
     }
 
+    /// Call a function pointer of the script `source_file`, compiled as
+    /// `ast`. Closures and function names of the script carry its imports and
+    /// constants, the AST resolves pointers created with `Fn("name")`.
     pub fn run_fn_ptr(
         &mut self,
         fn_ptr: FnPtr,
         source_file: &str,
+        ast: rhai::Shared<rhai::AST>,
     ) -> Result<Dynamic, Box<EvalAltResult>> {
-        let ast = self.engine.compile(source_file)?;
-        fn_ptr.call(&mut self.engine, &ast, ())
+        {
+            let mut state = self.shared_state.lock();
+            state.current_file = Some(source_file.to_string());
+            // Tasks spawned by the task call functions of the same script
+            state
+                .script_asts
+                .insert(source_file.to_string(), ast.clone());
+        }
+        fn_ptr.call(&self.engine, &ast, ())
+    }
+
+    /// Start over with a fresh state in `env`, keeping the registered
+    /// functions and the modules resolved so far.
+    fn reset(&mut self, env: E) {
+        let mut state = self.shared_state.lock();
+        let module_dirs = std::mem::take(&mut state.module_dirs);
+        let component_modules = std::mem::take(&mut state.component_modules);
+        let stdout = state.stdout.clone();
+        *state = SharedState::with_stdout(env, stdout);
+        state.module_dirs = module_dirs;
+        state.component_modules = component_modules;
+    }
+}
+
+// Idle engines by their module directories
+type IdleEngines<E> = HashMap<Vec<String>, Vec<Engine<E>>>;
+
+/// Engines of finished spawned tasks, reused by later tasks with the same
+/// module directories so that spawning skips setting up a new engine. Clones
+/// share the idle engines.
+pub struct EnginePool<E: Environment> {
+    idle: Arc<Mutex<IdleEngines<E>>>,
+    /// Engines set up for a set of module directories the first time.
+    prefill: usize,
+}
+
+impl<E: Environment> Clone for EnginePool<E> {
+    fn clone(&self) -> Self {
+        Self {
+            idle: self.idle.clone(),
+            prefill: self.prefill,
+        }
+    }
+}
+
+impl<E: Environment> Default for EnginePool<E> {
+    fn default() -> Self {
+        Self {
+            idle: Arc::new(Mutex::new(HashMap::new())),
+            prefill: PREFILLED_ENGINES,
+        }
+    }
+}
+
+impl<E: Environment + Clone + 'static> EnginePool<E> {
+    /// An idle engine for `module_dirs` running in `env`, or a new one. The
+    /// engine spawns its own tasks from this pool as well.
+    pub fn take(&self, env: E, module_dirs: &[String]) -> Engine<E> {
+        let (idle, first) = {
+            let mut idle = self.idle.lock();
+            match idle.get_mut(module_dirs) {
+                Some(engines) => (engines.pop(), false),
+                None => {
+                    idle.insert(module_dirs.to_vec(), vec![]);
+                    (None, true)
+                }
+            }
+        };
+        if first {
+            self.prefill(env.clone(), module_dirs);
+        }
+        let engine = match idle {
+            Some(mut engine) => {
+                log::debug!("Reusing pooled engine");
+                engine.reset(env);
+                engine
+            }
+            None => Engine::new(env, module_dirs),
+        };
+        engine.shared_state.lock().engine_pool = self.clone();
+        engine
+    }
+
+    // Set up engines for `module_dirs` on another thread, for the tasks
+    // spawned after the first one
+    fn prefill(&self, env: E, module_dirs: &[String]) {
+        let pool = self.clone();
+        let module_dirs = module_dirs.to_vec();
+        let count = self.prefill;
+        std::thread::spawn(move || {
+            for _ in 0..count {
+                pool.put(Engine::new(env.clone(), &module_dirs));
+            }
+        });
+    }
+
+    /// Return the engine of a finished task.
+    pub fn put(&self, engine: Engine<E>) {
+        let module_dirs = {
+            let mut state = engine.shared_state.lock();
+            // Idle engines must not keep the pool alive, nor the scripts of
+            // their last task
+            state.engine_pool = EnginePool::default();
+            state.script_asts.clear();
+            state.module_dirs.clone()
+        };
+        let mut idle = self.idle.lock();
+        let engines = idle.entry(module_dirs).or_default();
+        if engines.len() < MAX_IDLE_ENGINES {
+            engines.push(engine);
+        }
+    }
+}
+
+/// A runtime error of a script, for invalid arguments of a command.
+pub fn invalid(msg: String) -> Box<EvalAltResult> {
+    Box::new(EvalAltResult::ErrorRuntime(msg.into(), Position::NONE))
+}
+
+fn sort_scripts(paths: &mut [PathBuf], script_order: ScriptOrder, order: &[String]) {
+    // Numeric prefixes sort by value, so `2-b.rhai` runs before `10-a.rhai`
+    let lex_key = |path: &PathBuf| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let digits = name.chars().take_while(|c| c.is_ascii_digit()).count();
+        let prefix = name[..digits].parse::<u64>().unwrap_or(u64::MAX);
+        (prefix, name.into_owned())
+    };
+    match script_order {
+        ScriptOrder::Lex => paths.sort_by_key(lex_key),
+        ScriptOrder::Config => paths.sort_by_key(|path| {
+            let position = order
+                .iter()
+                .position(|entry| path.ends_with(entry))
+                .unwrap_or(order.len());
+            (position, lex_key(path))
+        }),
+        ScriptOrder::Random => {
+            use rand::seq::SliceRandom;
+            paths.shuffle(&mut rand::thread_rng());
+            log::info!("Running scripts in random order: {:?}", paths);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockEnvironment;
+
+    #[test]
+    fn test_run_directory_continues_after_failing_script() {
+        let dir = tempdir::TempDir::new("sam-run").unwrap();
+        std::fs::write(dir.path().join("broken.rhai"), "let x = ;").unwrap();
+        std::fs::write(dir.path().join("throws.rhai"), "throw \"boom\";").unwrap();
+        std::fs::write(dir.path().join("ok.rhai"), "let x = 1;").unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        engine.set_continue_on_error(true);
+        let result = engine.run(dir.path().to_path_buf());
+
+        assert_eq!(result.scripts.len(), 3);
+        assert!(!result.success());
+        let kind = |name: &str| {
+            let script = result
+                .scripts
+                .iter()
+                .find(|script| script.path.ends_with(name))
+                .unwrap();
+            script.error.as_ref().map(|error| error.kind)
+        };
+        assert_eq!(kind("broken.rhai"), Some(ScriptErrorKind::Compile));
+        assert_eq!(kind("throws.rhai"), Some(ScriptErrorKind::Runtime));
+        assert_eq!(kind("ok.rhai"), None);
+    }
+
+    #[test]
+    fn test_constants() {
+        let dir = tempdir::TempDir::new("sam").unwrap();
+        let script = dir.path().join("hook.rhai");
+        std::fs::write(
+            &script,
+            "if SAM_ERROR_COUNT != \"2\" { throw \"wrong count\"; }",
+        )
+        .unwrap();
+        let constants = vec![("SAM_ERROR_COUNT".to_string(), "2".to_string())];
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        engine.push_constants(&constants);
+        assert!(engine.run(script.clone()).success());
+        engine.remove_constants(&constants);
+        assert!(!engine.run(script).success());
+        assert!(std::env::var("SAM_ERROR_COUNT").is_err());
+    }
+
+    #[test]
+    fn test_options_for_ancestor_directories() {
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        let options = |max_call_levels| EngineOptions {
+            max_call_levels: Some(max_call_levels),
+            ..Default::default()
+        };
+        engine.set_options(
+            EngineOptions {
+                strict_variables: Some(true),
+                ..Default::default()
+            },
+            HashMap::from([
+                (PathBuf::from("tests"), options(100)),
+                (PathBuf::from("tests/cases"), options(200)),
+                (
+                    PathBuf::from("tests/cases/generated/big.rhai"),
+                    options(300),
+                ),
+            ]),
+        );
+
+        let levels = |path: &str| engine.options_for(Path::new(path)).max_call_levels;
+        assert_eq!(levels("tests/cases/generated/big.rhai"), Some(300));
+        assert_eq!(levels("tests/cases/generated/small.rhai"), Some(200));
+        assert_eq!(levels("tests/other/small.rhai"), Some(100));
+        assert_eq!(levels("other/small.rhai"), None);
+        let options = engine.options_for(Path::new("tests/cases/generated/small.rhai"));
+        assert_eq!(options.strict_variables, Some(true));
+    }
+
+    #[test]
+    fn test_engine_pool_reuses_engines() {
+        let pool = EnginePool {
+            prefill: 2,
+            ..Default::default()
+        };
+        let module_dirs = vec!["modules".to_string()];
+        let idle = || pool.idle.lock()[&module_dirs].len();
+        let mut engine = pool.take(MockEnvironment {}, &module_dirs);
+        let started = Instant::now();
+        while idle() < 2 && started.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(idle(), 2);
+        engine.set_run_id("first".to_string());
+        pool.put(engine);
+        assert_eq!(idle(), 3);
+
+        let engine = pool.take(MockEnvironment {}, &module_dirs);
+        assert_eq!(idle(), 2);
+        let state = engine.shared_state.lock();
+        assert!(state.run_id.is_empty());
+        assert_eq!(state.module_dirs, module_dirs);
+    }
+
+    #[test]
+    fn test_component_modules() {
+        let dir = tempdir::TempDir::new("sam-modules").unwrap();
+        let module = dir.path().join("db.rhai");
+        std::fs::write(&module, "fn query(sql) { `ran ${sql}` }").unwrap();
+        let script = dir.path().join("test.rhai");
+        std::fs::write(
+            &script,
+            r#"if db_main::query("q") != "ran q" { throw "query"; }"#,
+        )
+        .unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        let modules = [("db_main".to_string(), module.to_string_lossy().into_owned())];
+        engine.load_component_modules(&modules).unwrap();
+        assert!(engine.run(script).success());
+    }
+
+    #[test]
+    fn test_sections() {
+        let dir = tempdir::TempDir::new("sam-sections").unwrap();
+        let script = dir.path().join("test.rhai");
+        let content = r#"
+            it("steps", || {
+                section("prepare", || {
+                    section("users", || assert(false, "created"));
+                });
+                assert(true, "done");
+            });
+        "#;
+        std::fs::write(&script, content).unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        engine.run(script);
+        let state = engine.shared_state.lock();
+        let assertions = state.assertions.values().next().unwrap();
+        assert_eq!(assertions[0].name, "steps/prepare/users/created");
+        assert_eq!(assertions[0].label(), "prepare > users > created");
+        assert_eq!(assertions[1].label(), "done");
+    }
+
+    #[test]
+    fn test_artifacts() {
+        let dir = tempdir::TempDir::new("sam-artifacts").unwrap();
+        let script = dir.path().join("test.rhai");
+        let content = r#"
+            it("saves", || {
+                save_artifact("logs/out.txt", "done");
+                artifact_path("never-written.txt");
+                for name in ["../escaped.txt", "/tmp/escaped.txt"] {
+                    let failed = false;
+                    try { artifact_path(name); } catch { failed = true; }
+                    assert(failed, name);
+                }
+            });
+        "#;
+        std::fs::write(&script, content).unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        engine.set_artifacts_dir(dir.path().join("artifacts"));
+        assert!(engine.run(script).success());
+        let report = engine.get_report();
+        let saved = dir.path().join("artifacts/saves/logs/out.txt");
+        assert_eq!(std::fs::read_to_string(&saved).unwrap(), "done");
+        assert_eq!(
+            report.children[0].annotations["artifacts"],
+            serde_json::json!([saved.to_string_lossy()])
+        );
+    }
+
+    #[test]
+    fn test_assertion_history() {
+        let dir = tempdir::TempDir::new("sam-history").unwrap();
+        let script = dir.path().join("test.rhai");
+        let content = r#"
+            task("validate", || {
+                step("schema", || assert(true, "valid"));
+                step("data", || section("rows", || assert(false, "counted")));
+            });
+            task("deploy", || {
+                let failed = failed_assertions();
+                require(assertion_count() == 2 && failed.len() == 1, "history");
+                require(failed[0].test == "validate.data" && failed[0].line == 4, "test");
+                require(failed[0].message == "counted" && failed[0].sections == ["rows"], "info");
+            });
+        "#;
+        std::fs::write(&script, content).unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        engine.run(script);
+        let state = engine.shared_state.lock();
+        let failed = state.assertions.values().flatten().filter(|a| !a.success);
+        assert_eq!(
+            failed.map(|a| a.label()).collect::<Vec<_>>(),
+            ["rows > counted"]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_spawned_function_pointers() {
+        let dir = tempdir::TempDir::new("sam-spawn").unwrap();
+        std::fs::write(dir.path().join("util.rhai"), "fn answer() { 42 }").unwrap();
+        let script = dir.path().join("test.rhai");
+        let content = r#"
+            import "util" as util;
+            const FACTOR = 2;
+            fn half() { 21 }
+            fn worker() { half() * global::FACTOR }
+            let base = 40;
+            it("spawns", || {
+                let tasks = [
+                    spawn_task(Fn("half")),
+                    spawn_task(worker),
+                    spawn_task(|| base + FACTOR),
+                    spawn_task(|| util::answer()),
+                ];
+                assert(wait_for_tasks(tasks) == [21, 42, 42, 42], "results");
+            });
+            it("waits for failed tasks", || {
+                let tasks = [spawn_task(|| throw "boom"), spawn_task(worker)];
+                let results = wait_for_tasks_result(tasks);
+                assert(results.map(|r| r.status) == ["failed", "ok"], "statuses");
+                assert(results[1].value == 42, "value");
+                let tasks = [spawn_task(worker), spawn_task(|| { sleep("1s"); 1 })];
+                let results = wait_for_tasks_result(tasks, "100ms");
+                assert(results.map(|r| r.status) == ["ok", "timeout"], "timeout");
+            });
+            it("waits for a task once", || {
+                let task = spawn_task(worker);
+                assert(wait_for_tasks([task, task]) == [42], "duplicates");
+                assert_throws(|| wait_for_tasks([task]), "No such task");
+            });
+        "#;
+        std::fs::write(&script, content).unwrap();
+
+        let module_dirs = [dir.path().display().to_string()];
+        let mut engine = Engine::new(MockEnvironment {}, &module_dirs);
+        assert!(engine.run(script).success());
+        let state = engine.shared_state.lock();
+        assert!(state.assertions.values().flatten().all(|a| a.success));
+    }
+
+    #[test]
+    fn test_sort_scripts() {
+        let paths = [
+            "tests/b.rhai",
+            "tests/10-c.rhai",
+            "tests/a.rhai",
+            "tests/2-d.rhai",
+        ];
+        let mut paths = paths.map(PathBuf::from).to_vec();
+
+        sort_scripts(&mut paths, ScriptOrder::Lex, &[]);
+        let names = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&paths), ["2-d.rhai", "10-c.rhai", "a.rhai", "b.rhai"]);
+
+        let order = ["b.rhai".to_string(), "tests/a.rhai".to_string()];
+        sort_scripts(&mut paths, ScriptOrder::Config, &order);
+        assert_eq!(names(&paths), ["b.rhai", "a.rhai", "2-d.rhai", "10-c.rhai"]);
+    }
+
+    #[test]
+    fn test_run_directory_with_suite() {
+        let dir = tempdir::TempDir::new("sam-suite").unwrap();
+        let suite = r#"
+name: api
+tags: [smoke]
+params:
+  user: alice
+scripts:
+  - b.rhai
+  - path: a.rhai
+    params:
+      user: bob
+  - path: c.rhai
+    requires: [db]
+"#;
+        std::fs::write(dir.path().join("suite.yaml"), suite).unwrap();
+        std::fs::write(
+            dir.path().join("a.rhai"),
+            r#"if params.user != "bob" { throw "a"; }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("b.rhai"),
+            r#"if params.user != "alice" { throw "b"; }"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("c.rhai"), r#"throw "c";"#).unwrap();
+        std::fs::write(dir.path().join("unlisted.rhai"), r#"throw "unlisted";"#).unwrap();
+
+        let mut engine = Engine::new(MockEnvironment {}, &[]);
+        let result = engine.run(dir.path().to_path_buf());
+
+        assert!(result.success(), "{:?}", result);
+        let names = result
+            .scripts
+            .iter()
+            .map(|script| {
+                script
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["b.rhai", "a.rhai"]);
+
+        let report = serde_json::to_value(engine.get_report()).unwrap();
+        let suite = &report["annotations"]["suites"][0];
+        assert_eq!(suite["name"], "api");
+        assert_eq!(suite["tags"][0], "smoke");
+        assert_eq!(suite["skipped"][0]["missing_components"][0], "db");
     }
 }
@@ -0,0 +1,341 @@
+//! The CLIs that run the containers and pods of the components.
+
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::{config::expand_env_vars, remote::Remote, Error};
+
+/// Container runtime selected with `runtime` in the config.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Runtime {
+    #[default]
+    Podman,
+    Docker,
+}
+
+impl Runtime {
+    /// The runtime, managing the containers of `remote` if set, or those of
+    /// the `podman` connection.
+    pub fn container_runtime(
+        self,
+        remote: Option<&Remote>,
+        podman: Option<&PodmanConnection>,
+    ) -> Arc<dyn ContainerRuntime> {
+        let runtime: Arc<dyn ContainerRuntime> = match self {
+            Self::Podman => Arc::new(Podman),
+            Self::Docker => Arc::new(Docker),
+        };
+        let args = match (remote, podman) {
+            (Some(remote), _) => runtime.remote_args(remote),
+            (None, Some(podman)) => podman.args(),
+            (None, None) => return runtime,
+        };
+        Arc::new(RemoteRuntime {
+            args,
+            inner: runtime,
+        })
+    }
+}
+
+/// Podman service managing the containers instead of the local one, e.g. a
+/// podman machine or a rootful socket. Unlike with `remote`, processes and
+/// healthcheck commands still run on this host.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct PodmanConnection {
+    /// Name of a connection of `podman system connection list`.
+    pub connection: Option<String>,
+    /// e.g. `unix:///run/podman/podman.sock` or
+    /// `ssh://user@host:22/run/podman/podman.sock`.
+    pub url: Option<String>,
+    /// SSH key of an `ssh://` url, `${VAR}` is replaced by the environment
+    /// variable.
+    pub identity: Option<String>,
+}
+
+impl PodmanConnection {
+    /// Check that it can be used with `runtime` and `remote`.
+    pub fn validate(&self, runtime: Runtime, remote: Option<&Remote>) -> Result<(), Error> {
+        if runtime != Runtime::Podman {
+            return Err(Error::Config(
+                "The podman connection needs the podman runtime".to_string(),
+            ));
+        }
+        if remote.is_some() {
+            return Err(Error::Config(
+                "The podman connection cannot be combined with remote".to_string(),
+            ));
+        }
+        if self.connection.is_some() && self.url.is_some() {
+            return Err(Error::Config(
+                "Set either connection or url of the podman connection".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(connection) = &self.connection {
+            args.extend(["--connection".to_string(), connection.clone()]);
+        }
+        if let Some(url) = &self.url {
+            args.extend(["--url".to_string(), expand_env_vars(url)]);
+        }
+        if let Some(identity) = &self.identity {
+            args.extend(["--identity".to_string(), expand_env_vars(identity)]);
+        }
+        args
+    }
+}
+
+/// Differences between the podman and docker CLIs. Everything else is called
+/// the same way on both.
+pub trait ContainerRuntime: Send + Sync {
+    fn program(&self) -> &'static str;
+
+    fn command(&self) -> Command {
+        Command::new(self.program())
+    }
+
+    fn std_command(&self) -> std::process::Command {
+        std::process::Command::new(self.program())
+    }
+
+    /// Whether `run --replace` replaces an existing container with the same
+    /// name. Otherwise it has to be removed first.
+    fn can_replace(&self) -> bool;
+
+    /// Arguments of `rm` removing running containers without waiting.
+    fn force_remove_args(&self) -> &'static [&'static str];
+
+    fn supports_pods(&self) -> bool;
+
+    /// Whether `secret create` works without a swarm.
+    fn supports_secrets(&self) -> bool;
+
+    /// Arguments of a command that succeeds if `network` exists.
+    fn network_exists_args(&self, network: &str) -> Vec<String>;
+
+    /// Arguments of a command shrinking the build cache to `keep`, e.g.
+    /// `10GB`.
+    fn prune_build_cache_args(&self, keep: &str) -> Vec<String>;
+
+    /// Go template printing the digest of an image with `image inspect`.
+    fn image_digest_format(&self) -> &'static str;
+
+    /// Whether `logs` accepts several containers and prefixes their lines
+    /// with `--names`.
+    fn logs_multiple(&self) -> bool;
+
+    /// Whether `run` attaches a container to several `--network`s. Otherwise
+    /// the others are connected once it runs.
+    fn multiple_networks(&self) -> bool;
+
+    /// Global options managing the containers of `remote` over SSH.
+    fn remote_args(&self, remote: &Remote) -> Vec<String>;
+}
+
+pub struct Podman;
+
+impl ContainerRuntime for Podman {
+    fn program(&self) -> &'static str {
+        "podman"
+    }
+
+    fn can_replace(&self) -> bool {
+        true
+    }
+
+    fn force_remove_args(&self) -> &'static [&'static str] {
+        &["-f", "-t=0"]
+    }
+
+    fn supports_pods(&self) -> bool {
+        true
+    }
+
+    fn supports_secrets(&self) -> bool {
+        true
+    }
+
+    fn network_exists_args(&self, network: &str) -> Vec<String> {
+        vec!["network".into(), "exists".into(), network.into()]
+    }
+
+    // The build cache of podman has no size limit, it is removed entirely
+    fn prune_build_cache_args(&self, _keep: &str) -> Vec<String> {
+        ["image", "prune", "-f", "--build-cache"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn image_digest_format(&self) -> &'static str {
+        "{{.Digest}}"
+    }
+
+    fn logs_multiple(&self) -> bool {
+        true
+    }
+
+    fn multiple_networks(&self) -> bool {
+        true
+    }
+
+    fn remote_args(&self, remote: &Remote) -> Vec<String> {
+        let mut args = vec!["--url".to_string(), remote.url(true)];
+        if let Some(key_file) = remote.key_file() {
+            args.extend(["--identity".to_string(), key_file]);
+        }
+        args
+    }
+}
+
+pub struct Docker;
+
+impl ContainerRuntime for Docker {
+    fn program(&self) -> &'static str {
+        "docker"
+    }
+
+    fn can_replace(&self) -> bool {
+        false
+    }
+
+    fn force_remove_args(&self) -> &'static [&'static str] {
+        &["-f"]
+    }
+
+    fn supports_pods(&self) -> bool {
+        false
+    }
+
+    fn supports_secrets(&self) -> bool {
+        false
+    }
+
+    fn network_exists_args(&self, network: &str) -> Vec<String> {
+        vec!["network".into(), "inspect".into(), network.into()]
+    }
+
+    fn prune_build_cache_args(&self, keep: &str) -> Vec<String> {
+        ["builder", "prune", "-f", "--keep-storage", keep]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn image_digest_format(&self) -> &'static str {
+        "{{index .RepoDigests 0}}"
+    }
+
+    fn logs_multiple(&self) -> bool {
+        false
+    }
+
+    fn multiple_networks(&self) -> bool {
+        false
+    }
+
+    fn remote_args(&self, remote: &Remote) -> Vec<String> {
+        vec!["--host".to_string(), remote.url(false)]
+    }
+}
+
+/// A runtime managing the containers of another host or podman service.
+pub struct RemoteRuntime {
+    inner: Arc<dyn ContainerRuntime>,
+    args: Vec<String>,
+}
+
+impl ContainerRuntime for RemoteRuntime {
+    fn program(&self) -> &'static str {
+        self.inner.program()
+    }
+
+    fn command(&self) -> Command {
+        let mut cmd = self.inner.command();
+        cmd.args(&self.args);
+        cmd
+    }
+
+    fn std_command(&self) -> std::process::Command {
+        let mut cmd = self.inner.std_command();
+        cmd.args(&self.args);
+        cmd
+    }
+
+    fn can_replace(&self) -> bool {
+        self.inner.can_replace()
+    }
+
+    fn force_remove_args(&self) -> &'static [&'static str] {
+        self.inner.force_remove_args()
+    }
+
+    fn supports_pods(&self) -> bool {
+        self.inner.supports_pods()
+    }
+
+    fn supports_secrets(&self) -> bool {
+        self.inner.supports_secrets()
+    }
+
+    fn network_exists_args(&self, network: &str) -> Vec<String> {
+        self.inner.network_exists_args(network)
+    }
+
+    fn prune_build_cache_args(&self, keep: &str) -> Vec<String> {
+        self.inner.prune_build_cache_args(keep)
+    }
+
+    fn image_digest_format(&self) -> &'static str {
+        self.inner.image_digest_format()
+    }
+
+    fn logs_multiple(&self) -> bool {
+        self.inner.logs_multiple()
+    }
+
+    fn multiple_networks(&self) -> bool {
+        self.inner.multiple_networks()
+    }
+
+    fn remote_args(&self, remote: &Remote) -> Vec<String> {
+        self.inner.remote_args(remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_podman_connection() {
+        let podman = PodmanConnection {
+            connection: None,
+            url: Some("ssh://ci@lab/run/podman/podman.sock".to_string()),
+            identity: Some("${SAM_TEST_PODMAN_HOME}/.ssh/id_ed25519".to_string()),
+        };
+        std::env::set_var("SAM_TEST_PODMAN_HOME", "/home/ci");
+        assert_eq!(
+            podman.args(),
+            [
+                "--url",
+                "ssh://ci@lab/run/podman/podman.sock",
+                "--identity",
+                "/home/ci/.ssh/id_ed25519"
+            ]
+        );
+        assert!(podman.validate(Runtime::Podman, None).is_ok());
+        assert!(podman.validate(Runtime::Docker, None).is_err());
+
+        let both = PodmanConnection {
+            connection: Some("machine".to_string()),
+            ..podman
+        };
+        assert!(both.validate(Runtime::Podman, None).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::{collections::HashMap, path::PathBuf, process::Stdio, sync::Arc};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{
+    config::Component,
+    environment::{component_container, Environment},
+    remote::Remote,
+    rhai::Engine,
+    runtime::ContainerRuntime,
+    Error,
+};
+
+/// One source of test data loaded into a component once it is running. Set
+/// exactly one of `sql`, `http`, `script` or `command`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, JsonSchema)]
+pub struct Seed {
+    /// SQL file passed as stdin to `exec`.
+    pub sql: Option<String>,
+    /// Command receiving the SQL file, e.g. `["psql", "-U", "postgres"]`. It
+    /// runs inside the container of the component, or locally for processes.
+    #[serde(default)]
+    pub exec: Vec<String>,
+    /// Container of a pod component to run `exec` in.
+    pub container: Option<String>,
+    /// Fixture sent to an HTTP endpoint of the component.
+    pub http: Option<HttpSeed>,
+    /// Rhai script run by the engine.
+    pub script: Option<String>,
+    /// Shell command.
+    pub command: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+pub struct HttpSeed {
+    pub url: String,
+    /// Defaults to POST.
+    pub method: Option<String>,
+    /// File sent as the request body.
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+/// The seeds of a component, with the component they are loaded into.
+#[derive(Clone)]
+pub struct ComponentSeeds {
+    pub component: Component,
+    pub seeds: Vec<Seed>,
+    /// Runtime of the containers to `exec` SQL seeds in.
+    pub runtime: Arc<dyn ContainerRuntime>,
+    /// Host process components run on.
+    pub remote: Option<Remote>,
+}
+
+impl Seed {
+    fn describe(&self) -> String {
+        if let Some(sql) = &self.sql {
+            format!("sql {}", sql)
+        } else if let Some(http) = &self.http {
+            format!("http {}", http.url)
+        } else if let Some(script) = &self.script {
+            format!("script {}", script)
+        } else if let Some(command) = &self.command {
+            format!("command '{}'", command)
+        } else {
+            "empty seed".to_string()
+        }
+    }
+}
+
+/// Load all seeds of a component in order. Scripts are handed to
+/// `run_script`, as only the caller has an engine to run them with.
+pub async fn run_seeds(
+    seeds: &ComponentSeeds,
+    run_script: &mut impl FnMut(&str) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let component = &seeds.component;
+    log::info!("Seeding component {}", component.name);
+    for seed in &seeds.seeds {
+        log::debug!("Seeding {} with {}", component.name, seed.describe());
+        let result = if let Some(sql) = &seed.sql {
+            run_sql(seeds, seed, sql).await
+        } else if let Some(http) = &seed.http {
+            send_fixture(http).await
+        } else if let Some(script) = &seed.script {
+            run_script(script)
+        } else if let Some(command) = &seed.command {
+            run_command(command).await
+        } else {
+            Err(Error::Config(
+                "Seed needs one of sql, http, script or command".to_string(),
+            ))
+        };
+        result.map_err(|e| {
+            e.context(format!(
+                "Failed to seed {} with {}",
+                component.name,
+                seed.describe()
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Seed the components that are running after the environment started,
+/// others are seeded by calling `reseed` once a script started them.
+pub async fn seed_running<E: Environment + Clone + 'static>(
+    engine: &mut Engine<E>,
+) -> Result<(), Error> {
+    let state = engine.shared_state();
+    let seeds = state.lock().seeds.clone();
+    for seeds in seeds {
+        if !state.lock().env.is_running(&seeds.component.name) {
+            log::debug!("Not seeding {}, it is not running", seeds.component.name);
+            continue;
+        }
+        run_seeds(&seeds, &mut |script| {
+            engine
+                .run(PathBuf::from(script))
+                .into_result()
+                .map_err(|e| Error::Other(e.to_string()))
+        })
+        .await?;
+    }
+    Ok(())
+}
+
+async fn run_sql(seeds: &ComponentSeeds, seed: &Seed, sql: &str) -> Result<(), Error> {
+    let component = &seeds.component;
+    let Some((program, args)) = seed.exec.split_first() else {
+        return Err(Error::Config("SQL seeds need an exec command".to_string()));
+    };
+    let content = std::fs::read(sql).map_err(|e| Error::Config(format!("{}: {}", sql, e)))?;
+
+    let mut cmd = match (component.component_type.as_str(), &seed.container) {
+        ("process", _) => match &seeds.remote {
+            Some(remote) => remote.command(&seed.exec, &[], false),
+            None => {
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                cmd
+            }
+        },
+        (_, container) => {
+            let container = match container {
+                Some(container) => container.clone(),
+                None => component_container(component)?,
+            };
+            let mut cmd = seeds.runtime.command();
+            cmd.arg("exec").arg("-i").arg(container).args(&seed.exec);
+            cmd
+        }
+    };
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::run(program, None, e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Written while the output is read, as the command may not read all of
+    // its input before its output fills the pipes
+    let write = async move {
+        let written = stdin.write_all(&content).await;
+        drop(stdin);
+        written
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    let output =
+        output.map_err(|e| Error::io(format!("Failed to read the output of {}", program), e))?;
+    match written {
+        // The command exited without reading all of its input
+        Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => {
+            return Err(Error::io(
+                format!("Failed to write {} to {}", sql, program),
+                e,
+            ))
+        }
+        _ => {}
+    }
+    if !output.status.success() {
+        return Err(Error::exited(program, &output));
+    }
+    Ok(())
+}
+
+async fn send_fixture(http: &HttpSeed) -> Result<(), Error> {
+    let method = http.method.as_deref().unwrap_or("POST");
+    let method = reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+        .map_err(|e| Error::Config(format!("Invalid method {}: {}", method, e)))?;
+    let mut request = reqwest::Client::new().request(method, &http.url);
+    for (key, value) in &http.headers {
+        request = request.header(key, value);
+    }
+    if let Some(body) = &http.body {
+        let body = std::fs::read(body).map_err(|e| Error::Config(format!("{}: {}", body, e)))?;
+        request = request.body(body);
+    }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::Other(format!(
+            "{} responded with {}",
+            http.url,
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+async fn run_command(command: &str) -> Result<(), Error> {
+    let status = Command::new("sh")
+        .args(["-c", command])
+        .status()
+        .await
+        .map_err(|e| Error::run("sh", None, e))?;
+    if !status.success() {
+        return Err(Error::Exited {
+            command: "sh".to_string(),
+            code: status.code(),
+            stderr: String::new(),
+        });
+    }
+    Ok(())
+}
@@ -1,22 +1,151 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    path::Path,
+    sync::Arc,
+};
 
 use rhai::{Dynamic, EvalAltResult};
 use serde::{Deserialize, Serialize};
 use tokio::task::JoinHandle;
 
-use crate::environment::Environment;
+use crate::{
+    chaos::ChaosEvent,
+    commands::{self, Latency, SseStream},
+    environment::{Environment, PortForward},
+    provenance::{Provenance, ReportSignature},
+    rhai::EnginePool,
+    seed::ComponentSeeds,
+    suite::SuiteReport,
+    throttle::LogThrottle,
+    writer::OutputWriter,
+};
 
+#[allow(dead_code)]
 pub struct Assertion {
     pub name: String,
     pub success: bool,
     pub message: String,
     pub file: String,
     pub line: usize,
+    /// Sections of the test the assertion is in, outermost first.
+    pub sections: Vec<String>,
+}
+
+impl Assertion {
+    /// The message, prefixed with the sections of the test.
+    pub fn label(&self) -> String {
+        let mut label = self.sections.join(" > ");
+        if !label.is_empty() {
+            label += " > ";
+        }
+        label + self.message.as_str()
+    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
 pub struct TestId(Vec<String>);
 
+/// A test found while running scripts in discovery mode.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TestInfo {
+    pub id: String,
+    pub environment: String,
+    pub path: Vec<String>,
+    pub file: String,
+    pub line: usize,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    Running,
+    Passed,
+    Failed,
+    Skipped,
+}
+
+/// Status of a test that was started or skipped, in execution order.
+#[derive(Debug, Clone)]
+pub struct TestProgress {
+    pub path: Vec<String>,
+    pub status: TestStatus,
+    pub duration: Option<std::time::Duration>,
+}
+
+impl TestProgress {
+    pub fn new(path: Vec<String>, status: TestStatus) -> Self {
+        Self {
+            path,
+            status,
+            duration: None,
+        }
+    }
+
+    pub fn finish(&mut self, status: TestStatus, duration: std::time::Duration) {
+        self.status = status;
+        self.duration = Some(duration);
+    }
+}
+
+/// Why a test was not run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Not one of the tests given with `--run-exact`.
+    NotExact,
+    /// Does not touch the components given with `--only-affecting`.
+    NotAffected,
+    /// Matches the `--skip` expression.
+    Skip(String),
+    /// Does not match the `--filter` expression.
+    Filter(String),
+    /// Its script requires components that are not running.
+    MissingComponents(Vec<String>),
+}
+
+impl SkipReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NotExact => "run_exact",
+            Self::NotAffected => "only_affecting",
+            Self::Skip(_) => "skip",
+            Self::Filter(_) => "filter",
+            Self::MissingComponents(_) => "missing_components",
+        }
+    }
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotExact => write!(f, "not selected with --run-exact"),
+            Self::NotAffected => write!(f, "does not touch the --only-affecting components"),
+            Self::Skip(expression) => write!(f, "matches --skip '{}'", expression),
+            Self::Filter(expression) => write!(f, "does not match --filter '{}'", expression),
+            Self::MissingComponents(components) => {
+                write!(f, "components not running: {}", components.join(", "))
+            }
+        }
+    }
+}
+
+/// A skipped test, or a skipped script for reasons applying to all its tests.
+#[derive(Debug, Clone)]
+pub struct SkippedTest {
+    pub test: String,
+    pub reason: SkipReason,
+}
+
+impl SkippedTest {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "test": self.test,
+            "reason": self.reason.name(),
+            "cause": self.reason.to_string(),
+        })
+    }
+}
+
 impl Display for TestId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0.join("."))
@@ -24,49 +153,220 @@ impl Display for TestId {
 }
 
 pub struct SharedState<E: Environment> {
+    /// Identifies this invocation of sam, shared by all spawned engines.
+    pub run_id: String,
+    pub started_at: std::time::Instant,
+    /// Totals over the whole run, unlike test_count and error_count which
+    /// are scoped to the current describe block.
+    pub tests_run: usize,
+    pub tests_failed: usize,
+    pub tests_skipped: usize,
+    /// The skipped tests with the reason, in execution order.
+    pub skipped_tests: Vec<SkippedTest>,
     pub indention_level: usize,
     pub test_count: usize,
     pub error_count: usize,
     pub nested_test_counts: Vec<(usize, usize)>, // (test_count, error_count) stack for nested describes
     pub filter_expression: Option<String>,
     pub skip_expression: Option<String>,
+    /// Full test paths to run, all other tests are skipped.
+    pub exact_tests: Vec<String>,
+    /// Paths of the tests and describe blocks to run when selecting tests by
+    /// the components they touch, all other tests are skipped.
+    pub affected_paths: Option<Vec<String>>,
     pub current_test_stack: Vec<String>,
+    /// Sections entered with `section` in the running test.
+    pub current_sections: Vec<String>,
+    /// Sections an error of the running test was thrown in.
+    pub failed_sections: Option<Vec<String>>,
+    /// Tags of the enclosing describe blocks.
+    pub current_tags_stack: Vec<Vec<String>>,
     pub current_file: Option<String>,
     pub assertions: HashMap<TestId, Vec<Assertion>>,
+    /// Extra values attached to tests in the report, e.g. the time spent waiting.
+    pub annotations: HashMap<TestId, BTreeMap<String, serde_json::Value>>,
     pub current_test_failed: bool,
     pub silent: bool,
+    /// Script `print` output is collected in `console` instead of printed,
+    /// while the dashboard is shown.
+    pub capture_output: bool,
+    pub console: Vec<String>,
+    /// All test output goes through this writer, shared with spawned tasks.
+    pub stdout: OutputWriter,
+    pub progress: Vec<TestProgress>,
+    pub output: crate::config::Output,
+    /// Default retry policy of the http commands.
+    pub http_retry: crate::config::HttpRetry,
+    /// Default timeout of `exec` and `container_exec`.
+    pub exec_timeout: Option<std::time::Duration>,
+    /// Deduplicates and rate limits `log` output, shared with spawned tasks.
+    pub log_throttle: Option<Arc<parking_lot::Mutex<LogThrottle>>>,
+    /// Host `exec` and the file commands may run on.
+    pub remote: Option<crate::remote::Remote>,
+    /// Deployments of the config returned by `target`, by name.
+    pub targets: BTreeMap<String, crate::config::Target>,
+    /// The target selected with `--target`.
+    pub target: Option<String>,
     /// Terminate the execution immediately when a test fails.
     pub fail_fast: bool,
     pub kv_store: HashMap<String, Dynamic>,
+    /// Temporary directories removed at the end of the run.
     pub temp_dirs: Vec<tempdir::TempDir>,
+    /// Temporary directories removed at the end of the current test.
+    pub test_temp_dirs: Vec<tempdir::TempDir>,
+    /// Whether a test body is currently running.
+    pub in_test: bool,
+    /// Keep the per-test temporary directories of failed tests.
+    pub keep_failed_artifacts: bool,
+    /// Artifacts of this run are saved below this directory.
+    pub artifacts_dir: std::path::PathBuf,
     pub env: E,
     pub module_dirs: Vec<String>,
-    pub spawn_handles: HashMap<i64,JoinHandle<Result<Dynamic, Box<EvalAltResult>>>>,
+    /// Modules of the components by namespace, registered in the engines of
+    /// spawned tasks as well.
+    pub component_modules: Vec<(String, rhai::Shared<rhai::Module>)>,
+    /// Compiled scripts by path, for the function pointers of spawned tasks.
+    pub script_asts: HashMap<String, rhai::Shared<rhai::AST>>,
+    pub spawn_handles: HashMap<i64, JoinHandle<Result<Dynamic, Box<EvalAltResult>>>>,
+    /// Event streams opened by `sse_connect`, by handle.
+    pub sse_streams: HashMap<i64, SseStream>,
+    pub next_sse_handle: i64,
+    /// Ports opened by `port_forward`, by handle, closed at the end of the
+    /// script.
+    pub port_forwards: HashMap<i64, PortForward>,
+    pub next_port_forward_handle: i64,
+    /// Components paused by `pause_component`, resumed at the end of the
+    /// script.
+    pub paused_components: Vec<String>,
+    /// Protobuf descriptors loaded by `proto_load`, by handle.
+    pub proto_pools: HashMap<i64, prost_reflect::DescriptorPool>,
+    pub next_proto_handle: i64,
+    /// Engines for spawned tasks, shared with the engines of the tasks.
+    pub engine_pool: EnginePool<E>,
+    /// Record tests instead of running them.
+    pub list_only: bool,
+    pub discovered_tests: Vec<TestInfo>,
+    /// Suites of the script directories that ran.
+    pub suites: Vec<SuiteReport>,
+    /// Seeds of the components, loaded again by `reseed`.
+    pub seeds: Vec<ComponentSeeds>,
+    /// Failures injected by the chaos scheduler, in the order they happened.
+    pub chaos_events: Vec<ChaosEvent>,
+    /// Durations of the http requests, by method and URL without the query.
+    pub latencies: BTreeMap<String, Vec<std::time::Duration>>,
 }
 
 impl<E: Environment> SharedState<E> {
     pub fn new(env: E) -> Self {
+        Self::with_stdout(env, OutputWriter::new())
+    }
+
+    /// A fresh state printing through an existing writer, which saves
+    /// starting the thread of a new one.
+    pub fn with_stdout(env: E, stdout: OutputWriter) -> Self {
         Self {
+            run_id: String::new(),
+            started_at: std::time::Instant::now(),
+            tests_run: 0,
+            tests_failed: 0,
+            tests_skipped: 0,
+            skipped_tests: vec![],
             indention_level: 1,
             test_count: 0,
             error_count: 0,
             nested_test_counts: vec![],
             filter_expression: None,
             skip_expression: None,
+            exact_tests: vec![],
+            affected_paths: None,
             current_test_stack: vec![],
+            current_sections: vec![],
+            failed_sections: None,
+            current_tags_stack: vec![],
             current_file: None,
             assertions: HashMap::new(),
+            annotations: HashMap::new(),
             current_test_failed: false,
             silent: false,
+            capture_output: false,
+            console: vec![],
+            stdout,
+            progress: vec![],
+            output: crate::config::Output::default(),
+            http_retry: crate::config::HttpRetry::default(),
+            exec_timeout: None,
+            log_throttle: None,
+            remote: None,
+            targets: BTreeMap::new(),
+            target: None,
             fail_fast: true,
             kv_store: HashMap::new(),
             temp_dirs: vec![],
+            test_temp_dirs: vec![],
+            in_test: false,
+            keep_failed_artifacts: false,
+            artifacts_dir: std::path::PathBuf::from(crate::config::DEFAULT_ARTIFACTS_DIR),
             module_dirs: vec![],
+            component_modules: vec![],
+            script_asts: HashMap::new(),
             spawn_handles: HashMap::new(),
+            sse_streams: HashMap::new(),
+            next_sse_handle: 0,
+            port_forwards: HashMap::new(),
+            paused_components: vec![],
+            next_port_forward_handle: 0,
+            proto_pools: HashMap::new(),
+            next_proto_handle: 0,
+            engine_pool: EnginePool::default(),
+            list_only: false,
+            discovered_tests: vec![],
+            suites: vec![],
+            chaos_events: vec![],
+            latencies: BTreeMap::new(),
+            seeds: vec![],
             env,
         }
     }
 
+    /// Whether test progress is printed as indented lines.
+    pub fn pretty(&self) -> bool {
+        !self.silent && !self.output.structured()
+    }
+
+    /// Write a test progress event with `fields` as a logfmt or JSON line,
+    /// unless the output is pretty.
+    pub fn write_event(&self, event: &str, fields: &[(&str, serde_json::Value)]) {
+        if self.silent || !self.output.structured() {
+            return;
+        }
+        let mut all_fields = vec![
+            ("level", "info".into()),
+            ("event", event.into()),
+            ("path", self.current_test_stack.join(".").into()),
+        ];
+        all_fields.extend(fields.iter().cloned());
+        let format = self.output.format.unwrap_or_default();
+        self.stdout
+            .writeln(crate::log_format::line(format, &all_fields));
+    }
+
+    /// Write `msg` with the TEST prefix, indented by `indention_level`.
+    pub fn print_indented(&self, msg: &str, indention_level: usize) {
+        if !self.pretty() {
+            return;
+        }
+        let prefix = format!(" \x1b[32mTEST\x1b[0m{}", "  ".repeat(indention_level));
+        if msg.contains('\n') {
+            let lines = msg
+                .lines()
+                .map(|line| format!("{}{}\n", prefix, line))
+                .collect::<String>();
+            self.stdout.write(lines);
+        } else {
+            self.stdout.write(format!("{}{}", prefix, msg));
+        }
+    }
+
     pub fn get_current_test_id(&self) -> TestId {
         TestId(self.current_test_stack.clone())
     }
@@ -75,20 +375,138 @@ impl<E: Environment> SharedState<E> {
         let test_id = self.get_current_test_id();
         self.assertions.entry(test_id).or_default().push(assertion);
     }
+
+    /// Append `value` to the list annotation `key` of the current test, unless
+    /// it is already listed.
+    pub fn push_annotation(&mut self, key: &str, value: serde_json::Value) {
+        let test_id = self.get_current_test_id();
+        let annotations = self.annotations.entry(test_id).or_default();
+        match annotations.get_mut(key) {
+            Some(serde_json::Value::Array(values)) => {
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+            _ => {
+                annotations.insert(key.to_string(), vec![value].into());
+            }
+        }
+    }
+
+    pub fn record_latency(&mut self, latency: Latency) {
+        self.latencies
+            .entry(latency.endpoint)
+            .or_default()
+            .push(latency.elapsed);
+    }
+
+    /// Percentiles of the request durations of each endpoint, in ms.
+    pub fn latency_metrics(&self) -> serde_json::Map<String, serde_json::Value> {
+        let round = |ms: f64| (ms * 100.0).round() / 100.0;
+        self.latencies
+            .iter()
+            .map(|(endpoint, durations)| {
+                let millis: rhai::Array = durations
+                    .iter()
+                    .map(|d| Dynamic::from_float(d.as_secs_f64() * 1000.0))
+                    .collect();
+                let percentile = |p| round(commands::percentile(&millis, p).unwrap_or_default());
+                let metrics = serde_json::json!({
+                    "count": durations.len(),
+                    "p50_ms": percentile(50.0),
+                    "p90_ms": percentile(90.0),
+                    "p95_ms": percentile(95.0),
+                    "p99_ms": percentile(99.0),
+                    "max_ms": percentile(100.0),
+                });
+                (endpoint.clone(), metrics)
+            })
+            .collect()
+    }
+
+    /// The number of skipped tests by reason and the tests with their cause.
+    pub fn skip_summary(&self) -> serde_json::Value {
+        let mut by_reason = BTreeMap::<&str, usize>::new();
+        for skipped in &self.skipped_tests {
+            *by_reason.entry(skipped.reason.name()).or_default() += 1;
+        }
+        serde_json::json!({
+            "count": self.skipped_tests.len(),
+            "by_reason": by_reason,
+            "tests": self.skipped_tests.iter().map(SkippedTest::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// Lines listing each skipped test with its cause, for `--explain-skip`.
+    pub fn explain_skips(&self) -> Vec<String> {
+        if let Some(format) = self.output.format.filter(|_| self.output.structured()) {
+            return self
+                .skipped_tests
+                .iter()
+                .map(|skipped| {
+                    let fields = [
+                        ("level", "info".into()),
+                        ("event", "skip".into()),
+                        ("path", skipped.test.as_str().into()),
+                        ("reason", skipped.reason.name().into()),
+                        ("cause", skipped.reason.to_string().into()),
+                    ];
+                    crate::log_format::line(format, &fields)
+                })
+                .collect();
+        }
+        let summary = self.skip_summary();
+        let counts = summary["by_reason"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(reason, count)| format!("{} {}", count, reason))
+            .collect::<Vec<_>>();
+        let mut lines = vec![format!(
+            "Skipped {} tests{}",
+            self.skipped_tests.len(),
+            match counts.is_empty() {
+                true => String::new(),
+                false => format!(" ({})", counts.join(", ")),
+            }
+        )];
+        for skipped in &self.skipped_tests {
+            lines.push(format!("  {}: {}", skipped.test, skipped.reason));
+        }
+        lines
+    }
+
+    /// Add `millis` to the numeric annotation `key` of the current test.
+    pub fn add_duration_annotation(&mut self, key: &str, millis: u64) {
+        let test_id = self.get_current_test_id();
+        let annotations = self.annotations.entry(test_id).or_default();
+        let total = annotations.get(key).and_then(|v| v.as_u64()).unwrap_or(0) + millis;
+        annotations.insert(key.to_string(), total.into());
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TestReport {
     pub name: String,
     pub success: bool,
     pub error_count: usize,
     pub test_count: usize,
     pub children: Vec<TestReport>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ReportSignature>,
 }
 
 impl From<&Assertion> for TestReport {
     fn from(assertion: &Assertion) -> Self {
-        Self::new(assertion.message.clone(), assertion.success)
+        // Colored diffs in messages are only meant for the terminal
+        Self::new(
+            crate::log_format::strip_ansi(&assertion.label()),
+            assertion.success,
+        )
     }
 }
 
@@ -100,6 +518,22 @@ impl TestReport {
             error_count: if success { 0 } else { 1 },
             test_count: 1,
             children: vec![],
+            annotations: BTreeMap::new(),
+            provenance: None,
+            signature: None,
+        }
+    }
+
+    pub fn combine(name: String, children: Vec<TestReport>) -> Self {
+        Self {
+            name,
+            success: children.iter().all(|c| c.success),
+            error_count: children.iter().map(|c| c.error_count).sum(),
+            test_count: children.iter().map(|c| c.test_count).sum(),
+            children,
+            annotations: BTreeMap::new(),
+            provenance: None,
+            signature: None,
         }
     }
 
@@ -122,6 +556,68 @@ impl TestReport {
         self.error_count = self.children.iter().map(|c| c.error_count).sum();
         self.test_count = self.children.iter().map(|c| c.test_count).sum();
     }
+
+    pub fn annotate(&mut self, path: &TestId, annotations: &BTreeMap<String, serde_json::Value>) {
+        self.insert(path, &vec![]);
+        let mut report = self;
+        for name in &path.0 {
+            report = report
+                .children
+                .iter_mut()
+                .find(|c| c.name == *name)
+                .expect("inserted above");
+        }
+        report.annotations.extend(annotations.clone());
+    }
+
+    /// Dotted paths of the tests and describe blocks below this report that
+    /// touched any of `components`.
+    pub fn paths_touching(&self, components: &[String]) -> Vec<String> {
+        let mut paths = vec![];
+        for child in &self.children {
+            child.collect_paths_touching(components, "", &mut paths);
+        }
+        paths
+    }
+
+    fn collect_paths_touching(&self, components: &[String], parent: &str, paths: &mut Vec<String>) {
+        let path = if parent.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}.{}", parent, self.name)
+        };
+        let touched = self
+            .annotations
+            .get("components")
+            .and_then(|touched| touched.as_array())
+            .is_some_and(|touched| {
+                touched.iter().any(|c| {
+                    components
+                        .iter()
+                        .any(|name| c.as_str() == Some(name.as_str()))
+                })
+            });
+        if touched {
+            paths.push(path.clone());
+        }
+        for child in &self.children {
+            child.collect_paths_touching(components, &path, paths);
+        }
+    }
+}
+
+/// `annotations` without the paths of `artifact_path` no file was written to.
+fn written_artifacts(
+    annotations: &BTreeMap<String, serde_json::Value>,
+) -> BTreeMap<String, serde_json::Value> {
+    let mut annotations = annotations.clone();
+    if let Some(serde_json::Value::Array(paths)) = annotations.get_mut("artifacts") {
+        paths.retain(|path| path.as_str().is_some_and(|path| Path::new(path).exists()));
+        if paths.is_empty() {
+            annotations.remove("artifacts");
+        }
+    }
+    annotations
 }
 
 impl<E: Environment> From<&SharedState<E>> for TestReport {
@@ -133,6 +629,26 @@ impl<E: Environment> From<&SharedState<E>> for TestReport {
         for (test_id, assertions) in &state.assertions {
             report.insert(test_id, assertions);
         }
+        for (test_id, annotations) in &state.annotations {
+            report.annotate(test_id, &written_artifacts(annotations));
+        }
+        if !state.suites.is_empty() {
+            let suites = serde_json::to_value(&state.suites).unwrap_or_default();
+            report.annotations.insert("suites".to_string(), suites);
+        }
+        if !state.chaos_events.is_empty() {
+            let events = serde_json::to_value(&state.chaos_events).unwrap_or_default();
+            report.annotations.insert("chaos".to_string(), events);
+        }
+        if !state.latencies.is_empty() {
+            let metrics = serde_json::json!({ "http_latency": state.latency_metrics() });
+            report.annotations.insert("metrics".to_string(), metrics);
+        }
+        if !state.skipped_tests.is_empty() {
+            report
+                .annotations
+                .insert("skipped".to_string(), state.skip_summary());
+        }
         report
     }
 }
@@ -153,6 +669,7 @@ mod tests {
             message: "test".to_string(),
             file: "test".to_string(),
             line: 1,
+            sections: vec![],
         });
         state.current_test_stack.pop();
         state.current_test_stack.push("grandchild_2".to_string());
@@ -162,8 +679,107 @@ mod tests {
             message: "test".to_string(),
             file: "test".to_string(),
             line: 1,
+            sections: vec![],
         });
         let report = TestReport::from(&state);
         println!("{:#?}", report);
     }
+
+    #[tokio::test]
+    async fn test_paths_touching() {
+        use crate::{
+            state::{SharedState, TestReport},
+            MockEnvironment,
+        };
+
+        let mut state = SharedState::new(MockEnvironment {});
+        state.current_test_stack.push("api".to_string());
+        state.push_annotation("components", "db".into());
+        state.current_test_stack.push("lists items".to_string());
+        state.push_annotation("components", "cache".into());
+        state.current_test_stack.pop();
+        state.current_test_stack.pop();
+        state.current_test_stack.push("worker".to_string());
+        state.push_annotation("components", "queue".into());
+        let report = TestReport::from(&state);
+        assert_eq!(report.paths_touching(&["db".to_string()]), vec!["api"]);
+        assert_eq!(
+            report
+                .paths_touching(&["cache".to_string(), "queue".to_string()])
+                .len(),
+            2
+        );
+        assert!(report.paths_touching(&["web".to_string()]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_latency_metrics() {
+        use crate::{
+            commands::Latency,
+            state::{SharedState, TestReport},
+            MockEnvironment,
+        };
+        use std::time::Duration;
+
+        let mut state = SharedState::new(MockEnvironment {});
+        for ms in 1..=100 {
+            state.record_latency(Latency {
+                endpoint: "GET http://localhost/users".to_string(),
+                elapsed: Duration::from_millis(ms),
+            });
+        }
+        let report = TestReport::from(&state);
+        let users = &report.annotations["metrics"]["http_latency"]["GET http://localhost/users"];
+        assert_eq!(users["count"], 100);
+        assert_eq!(users["p50_ms"], 50.5);
+        assert_eq!(users["p99_ms"], 99.01);
+        assert_eq!(users["max_ms"], 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_skip_summary() {
+        use crate::{
+            state::{SharedState, SkipReason, SkippedTest, TestReport},
+            MockEnvironment,
+        };
+
+        let mut state = SharedState::new(MockEnvironment {});
+        state.skipped_tests = vec![
+            SkippedTest {
+                test: "api.lists items".to_string(),
+                reason: SkipReason::Filter("^web".to_string()),
+            },
+            SkippedTest {
+                test: "api.deletes items".to_string(),
+                reason: SkipReason::Filter("^web".to_string()),
+            },
+            SkippedTest {
+                test: "tests/worker.rhai".to_string(),
+                reason: SkipReason::MissingComponents(vec!["queue".to_string()]),
+            },
+        ];
+        let report = TestReport::from(&state);
+        let skipped = &report.annotations["skipped"];
+        assert_eq!(skipped["count"], 3);
+        assert_eq!(skipped["by_reason"]["filter"], 2);
+        assert_eq!(skipped["tests"][2]["reason"], "missing_components");
+        assert_eq!(
+            skipped["tests"][2]["cause"],
+            "components not running: queue"
+        );
+        assert_eq!(
+            state.explain_skips(),
+            [
+                "Skipped 3 tests (2 filter, 1 missing_components)",
+                "  api.lists items: does not match --filter '^web'",
+                "  api.deletes items: does not match --filter '^web'",
+                "  tests/worker.rhai: components not running: queue",
+            ]
+        );
+
+        state.output.format = Some(crate::config::LogFormat::Logfmt);
+        let lines = state.explain_skips();
+        assert!(lines[0].contains(r#" event=skip path="api.lists items" reason=filter "#));
+        assert!(lines[0].ends_with(r#" cause="does not match --filter '^web'""#));
+    }
 }
@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+
+pub const SUITE_FILE: &str = "suite.yaml";
+
+/// Optional `suite.yaml` of a script directory, declaring its scripts and
+/// what they need.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct Suite {
+    /// Defaults to the name of the directory.
+    pub name: Option<String>,
+    /// Scripts to run, in this order, instead of all `.rhai` files.
+    #[serde(default)]
+    pub scripts: Vec<SuiteScript>,
+    /// Tags added to all tests of the suite.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Components that must be running, scripts are skipped otherwise.
+    #[serde(default)]
+    pub requires: Vec<String>,
+    /// Exposed to scripts as the `params` constant.
+    #[serde(default)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A script of a suite, either just its path or with its own settings,
+/// which are added to the ones of the suite.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SuiteScript {
+    Path(String),
+    Detailed {
+        path: String,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        requires: Vec<String>,
+        #[serde(default)]
+        params: serde_json::Map<String, serde_json::Value>,
+    },
+}
+
+/// Settings a single script runs with.
+#[derive(Debug, Default)]
+pub struct ScriptSettings {
+    pub tags: Vec<String>,
+    pub requires: Vec<String>,
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+/// What ran of a suite, attached to the report.
+#[derive(Debug, Serialize)]
+pub struct SuiteReport {
+    pub name: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub scripts: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub skipped: Vec<SkippedScript>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SkippedScript {
+    pub script: String,
+    pub missing_components: Vec<String>,
+}
+
+impl SuiteScript {
+    pub fn path(&self) -> &str {
+        match self {
+            SuiteScript::Path(path) | SuiteScript::Detailed { path, .. } => path,
+        }
+    }
+}
+
+impl Suite {
+    /// Load the suite file of `dir`, if there is one.
+    pub fn load(dir: &Path) -> Result<Option<Self>, Error> {
+        let path = dir.join(SUITE_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let suite = std::fs::read_to_string(&path)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        let suite = serde_yaml::from_str(&suite)
+            .map_err(|e| Error::Config(format!("{}: {}", path.display(), e)))?;
+        Ok(Some(suite))
+    }
+
+    /// The declared scripts below `dir`, empty when the suite does not list
+    /// them.
+    pub fn script_paths(&self, dir: &Path) -> Vec<PathBuf> {
+        self.scripts
+            .iter()
+            .map(|script| dir.join(script.path()))
+            .collect()
+    }
+
+    pub fn settings_for(&self, dir: &Path, path: &Path) -> ScriptSettings {
+        let mut settings = ScriptSettings {
+            tags: self.tags.clone(),
+            requires: self.requires.clone(),
+            params: self.params.clone(),
+        };
+        let script = self
+            .scripts
+            .iter()
+            .find(|script| dir.join(script.path()) == path);
+        if let Some(SuiteScript::Detailed {
+            tags,
+            requires,
+            params,
+            ..
+        }) = script
+        {
+            settings.tags.extend(tags.iter().cloned());
+            settings.requires.extend(requires.iter().cloned());
+            settings.params.extend(params.clone());
+        }
+        settings
+    }
+
+    pub fn report(&self, dir: &Path) -> SuiteReport {
+        let name = self.name.clone().unwrap_or_else(|| {
+            dir.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        });
+        SuiteReport {
+            name,
+            path: dir.display().to_string(),
+            tags: self.tags.clone(),
+            scripts: vec![],
+            skipped: vec![],
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Deduplication and rate limiting of log lines, keeping the output of long
+//! runs small.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::config::LogLimits;
+
+/// Filters a stream of lines: repeats of the previous line are counted and
+/// reported as `last message repeated N times` once another line comes, and
+/// lines beyond `max_per_second` are dropped and counted.
+#[derive(Debug)]
+pub struct LogThrottle {
+    limits: LogLimits,
+    last: Option<String>,
+    repeated: usize,
+    window_start: Option<Instant>,
+    in_window: u32,
+    dropped: usize,
+}
+
+impl LogThrottle {
+    pub fn new(limits: LogLimits) -> Self {
+        Self {
+            limits,
+            last: None,
+            repeated: 0,
+            window_start: None,
+            in_window: 0,
+            dropped: 0,
+        }
+    }
+
+    /// A throttle shared by the engines of a run, if there are limits.
+    pub fn shared(limits: Option<&LogLimits>) -> Option<Arc<parking_lot::Mutex<Self>>> {
+        limits.map(|limits| Arc::new(parking_lot::Mutex::new(Self::new(limits.clone()))))
+    }
+
+    /// The lines to write for `line`, none if it is dropped.
+    pub fn lines(&mut self, line: &str) -> Vec<String> {
+        self.lines_at(line, Instant::now())
+    }
+
+    fn lines_at(&mut self, line: &str, now: Instant) -> Vec<String> {
+        if self.limits.dedup && self.last.as_deref() == Some(line) {
+            self.repeated += 1;
+            return vec![];
+        }
+        let mut lines = self.pending();
+
+        let window_start = *self.window_start.get_or_insert(now);
+        if now.duration_since(window_start) >= Duration::from_secs(1) {
+            lines.extend(self.dropped_line());
+            self.window_start = Some(now);
+            self.in_window = 0;
+        }
+        self.last = Some(line.to_string());
+        if self
+            .limits
+            .max_per_second
+            .is_some_and(|max| self.in_window >= max)
+        {
+            self.dropped += 1;
+        } else {
+            self.in_window += 1;
+            lines.push(line.to_string());
+        }
+        lines
+    }
+
+    /// The counts not reported yet, at the end of the output.
+    pub fn flush(&mut self) -> Vec<String> {
+        let mut lines = self.pending();
+        lines.extend(self.dropped_line());
+        lines
+    }
+
+    fn pending(&mut self) -> Vec<String> {
+        match std::mem::take(&mut self.repeated) {
+            0 => vec![],
+            1 => vec!["last message repeated 1 time".to_string()],
+            n => vec![format!("last message repeated {} times", n)],
+        }
+    }
+
+    fn dropped_line(&mut self) -> Option<String> {
+        match std::mem::take(&mut self.dropped) {
+            0 => None,
+            n => Some(format!("{} messages dropped by the rate limit", n)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let mut throttle = LogThrottle::new(LogLimits {
+            dedup: true,
+            max_per_second: Some(2),
+        });
+        let start = Instant::now();
+        let mut lines = vec![];
+        let input = [
+            ("a", 0),
+            ("a", 1),
+            ("a", 2),
+            ("b", 3),
+            ("c", 4),
+            ("d", 5),
+            ("e", 1000),
+        ];
+        for (line, ms) in input {
+            lines.extend(throttle.lines_at(line, start + Duration::from_millis(ms)));
+        }
+        lines.extend(throttle.flush());
+        assert_eq!(
+            lines,
+            [
+                "a",
+                "last message repeated 2 times",
+                "b",
+                "2 messages dropped by the rate limit",
+                "e"
+            ]
+        );
+    }
+}
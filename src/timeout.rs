@@ -0,0 +1,61 @@
+//! Commands killed along with their children once they run too long, so a
+//! wedged container runtime or shell command fails instead of hanging the run.
+
+use std::{io, process::Output, process::Stdio, time::Duration};
+
+use tokio::process::Command;
+
+/// Run `cmd` and collect its output like [`Command::output`], killing its
+/// process group after `timeout`.
+pub async fn output(cmd: &mut Command, timeout: Option<Duration>) -> io::Result<Output> {
+    let Some(timeout) = timeout else {
+        return cmd.output().await;
+    };
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+    let child = cmd.spawn()?;
+    let pid = child.id();
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output,
+        Err(_) => {
+            // The child is killed on drop, its own children with the group
+            if let Some(pid) = pid {
+                let _ = Command::new("kill")
+                    .args(["-KILL", "--", &format!("-{}", pid)])
+                    .output()
+                    .await;
+            }
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {}", humantime::format_duration(timeout)),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_output() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo hi"]);
+        let result = output(&mut cmd, Some(Duration::from_secs(5)))
+            .await
+            .unwrap();
+        assert_eq!(result.stdout, b"hi\n");
+
+        let started = std::time::Instant::now();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 10 & sleep 10"]);
+        let error = output(&mut cmd, Some(Duration::from_millis(100)))
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "timed out after 100ms");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    config::Component,
+    environment::Environment,
+    runtime::ContainerRuntime,
+    state::{SharedState, TestProgress, TestStatus},
+};
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+const LOG_TAIL_LINES: usize = 3;
+const CONSOLE_LINES: usize = 200;
+
+/// Live view of a test run, drawn on a separate thread until stopped or left
+/// with `q`.
+pub struct Dashboard {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+struct View {
+    title: String,
+    components: Vec<Component>,
+    runtime: Arc<dyn ContainerRuntime>,
+    started_at: Instant,
+    /// Lines scrolled up from the end of the test list, 0 follows the run.
+    scroll: usize,
+    logs: HashMap<String, Vec<String>>,
+    logs_refreshed_at: Option<Instant>,
+    data_dir: PathBuf,
+    snapshot: Snapshot,
+}
+
+/// What is drawn of the shared state, copied so that drawing does not hold
+/// its lock.
+#[derive(Default)]
+struct Snapshot {
+    progress: Vec<TestProgress>,
+    console: Vec<String>,
+    running: HashSet<String>,
+}
+
+impl Snapshot {
+    fn take<E: Environment>(state: &SharedState<E>, components: &[Component]) -> Self {
+        let first = state.console.len().saturating_sub(CONSOLE_LINES);
+        Self {
+            progress: state.progress.clone(),
+            console: state.console[first..].to_vec(),
+            running: components
+                .iter()
+                .filter(|component| state.env.is_running(&component.name))
+                .map(|component| component.name.clone())
+                .collect(),
+        }
+    }
+}
+
+impl Dashboard {
+    pub fn start<E: Environment + 'static>(
+        title: String,
+        components: Vec<Component>,
+        runtime: Arc<dyn ContainerRuntime>,
+        state: Arc<Mutex<SharedState<E>>>,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let data_dir = {
+            let mut state = state.lock();
+            state.silent = true;
+            state.capture_output = true;
+            state.env.data_dir().to_path_buf()
+        };
+        let log_level = log::max_level();
+        log::set_max_level(log::LevelFilter::Off);
+
+        let stop_clone = stop.clone();
+        let handle = std::thread::spawn(move || {
+            let mut terminal = ratatui::init();
+            let mut view = View {
+                title,
+                components,
+                runtime,
+                started_at: Instant::now(),
+                scroll: 0,
+                logs: HashMap::new(),
+                logs_refreshed_at: None,
+                data_dir,
+                snapshot: Snapshot::default(),
+            };
+            let result = view.run(&mut terminal, &state, &stop_clone);
+            ratatui::restore();
+
+            log::set_max_level(log_level);
+            let mut state = state.lock();
+            state.silent = false;
+            state.capture_output = false;
+            for line in std::mem::take(&mut state.console) {
+                state.stdout.writeln(line);
+            }
+            if let Err(e) = result {
+                log::error!("Dashboard failed: {}", e);
+            }
+        });
+
+        Self { stop, handle }
+    }
+
+    /// Draw the final state and give the terminal back.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.join();
+    }
+}
+
+impl View {
+    fn run<E: Environment>(
+        &mut self,
+        terminal: &mut DefaultTerminal,
+        state: &Arc<Mutex<SharedState<E>>>,
+        stop: &AtomicBool,
+    ) -> std::io::Result<()> {
+        loop {
+            // Keep drawing the last snapshot while a script holds the state
+            if let Some(state) = state.try_lock_for(FRAME_INTERVAL) {
+                self.snapshot = Snapshot::take(&state, &self.components);
+            }
+            self.refresh_logs();
+            terminal.draw(|frame| self.draw(frame))?;
+            if stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            if !event::poll(FRAME_INTERVAL)? {
+                continue;
+            }
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Up => self.scroll += 1,
+                    KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+                    KeyCode::PageUp => self.scroll += 10,
+                    KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+                    KeyCode::End => self.scroll = 0,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // Tail the logs of processes from their output files and of containers
+    // from the container runtime, which is too slow to do on every frame
+    fn refresh_logs(&mut self) {
+        if self
+            .logs_refreshed_at
+            .is_some_and(|at| at.elapsed() < LOG_REFRESH_INTERVAL)
+        {
+            return;
+        }
+        self.logs_refreshed_at = Some(Instant::now());
+        for component in &self.components {
+            let output = match component.component_type.as_str() {
+                "process" => {
+                    let mut output = String::new();
+                    for ext in ["stdout", "stderr"] {
+                        let path: PathBuf =
+                            self.data_dir.join(format!("{}.{}", component.name, ext));
+                        output += &std::fs::read_to_string(path).unwrap_or_default();
+                    }
+                    output
+                }
+                "container" => self
+                    .runtime
+                    .std_command()
+                    .args(["logs", "--tail", &LOG_TAIL_LINES.to_string()])
+                    .arg(&component.name)
+                    .output()
+                    .map(|o| {
+                        format!(
+                            "{}{}",
+                            String::from_utf8_lossy(&o.stdout),
+                            String::from_utf8_lossy(&o.stderr)
+                        )
+                    })
+                    .unwrap_or_default(),
+                _ => String::new(),
+            };
+            let lines = output.lines().map(|l| l.to_string()).collect::<Vec<_>>();
+            let tail = lines[lines.len().saturating_sub(LOG_TAIL_LINES)..].to_vec();
+            self.logs.insert(component.name.clone(), tail);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(3)]).areas(frame.area());
+        let [tests, side] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(main);
+        let [components, console] =
+            Layout::vertical([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(side);
+
+        self.draw_tests(frame, tests, &self.snapshot.progress);
+        self.draw_components(frame, components);
+        draw_console(frame, console, &self.snapshot.console);
+        self.draw_footer(frame, footer);
+    }
+
+    fn draw_tests(&self, frame: &mut Frame, area: Rect, progress: &[TestProgress]) {
+        let mut lines = vec![];
+        let mut previous: &[String] = &[];
+        for test in progress {
+            let (describes, name) = test.path.split_at(test.path.len().saturating_sub(1));
+            // Print the describe blocks that differ from the previous test
+            let common = describes
+                .iter()
+                .zip(previous.iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            for (depth, describe) in describes.iter().enumerate().skip(common) {
+                lines.push(Line::from(Span::styled(
+                    format!("{}{}", "  ".repeat(depth), describe),
+                    Style::default().add_modifier(Modifier::BOLD),
+                )));
+            }
+            previous = describes;
+
+            let (marker, color) = match test.status {
+                TestStatus::Running => ("[..]  ", Color::Yellow),
+                TestStatus::Passed => ("[ok]  ", Color::Green),
+                TestStatus::Failed => ("[FAIL]", Color::Red),
+                TestStatus::Skipped => ("[skip]", Color::DarkGray),
+            };
+            let duration = test
+                .duration
+                .map(|d| format!(" ({})", humantime::format_duration(truncate(d))))
+                .unwrap_or_default();
+            lines.push(Line::from(vec![
+                Span::raw("  ".repeat(describes.len())),
+                Span::styled(marker, Style::default().fg(color)),
+                Span::raw(format!(" {}{}", name.join(""), duration)),
+            ]));
+        }
+
+        let height = area.height.saturating_sub(2) as usize;
+        let scroll = self.scroll.min(lines.len().saturating_sub(height));
+        let first = lines.len().saturating_sub(height + scroll);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" {} ", self.title));
+        frame.render_widget(Paragraph::new(lines[first..].to_vec()).block(block), area);
+    }
+
+    fn draw_components(&self, frame: &mut Frame, area: Rect) {
+        let mut lines = vec![];
+        for component in &self.components {
+            let (status, color) = if self.snapshot.running.contains(&component.name) {
+                ("running", Color::Green)
+            } else {
+                ("stopped", Color::DarkGray)
+            };
+            lines.push(Line::from(vec![
+                Span::styled(
+                    component.name.clone(),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(format!(" ({}) ", component.component_type)),
+                Span::styled(status, Style::default().fg(color)),
+            ]));
+            for log in self.logs.get(&component.name).into_iter().flatten() {
+                lines.push(Line::from(Span::styled(
+                    format!("  {}", log),
+                    Style::default().fg(Color::DarkGray),
+                )));
+            }
+        }
+        let block = Block::default().borders(Borders::ALL).title(" Components ");
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_footer(&self, frame: &mut Frame, area: Rect) {
+        let progress = &self.snapshot.progress;
+        let count = |status| progress.iter().filter(|t| t.status == status).count();
+        let running = progress
+            .iter()
+            .rev()
+            .find(|t| t.status == TestStatus::Running)
+            .map(|t| t.path.join(" > "))
+            .unwrap_or_default();
+        let line = Line::from(vec![
+            Span::styled(
+                format!("passed {} ", count(TestStatus::Passed)),
+                Style::default().fg(Color::Green),
+            ),
+            Span::styled(
+                format!("failed {} ", count(TestStatus::Failed)),
+                Style::default().fg(Color::Red),
+            ),
+            Span::styled(
+                format!("skipped {} ", count(TestStatus::Skipped)),
+                Style::default().fg(Color::DarkGray),
+            ),
+            Span::raw(format!(
+                "| {} | {}",
+                humantime::format_duration(truncate(self.started_at.elapsed())),
+                running
+            )),
+        ]);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(" Up/Down/PgUp/PgDn scroll, End follow, q leave dashboard ");
+        frame.render_widget(Paragraph::new(line).block(block), area);
+    }
+}
+
+fn draw_console(frame: &mut Frame, area: Rect, console: &[String]) {
+    let height = area.height.saturating_sub(2) as usize;
+    let first = console.len().saturating_sub(height.min(CONSOLE_LINES));
+    let lines = console[first..]
+        .iter()
+        .map(|line| Line::from(line.as_str()))
+        .collect::<Vec<_>>();
+    let block = Block::default().borders(Borders::ALL).title(" Output ");
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+// Whole milliseconds, so durations stay readable
+fn truncate(duration: Duration) -> Duration {
+    Duration::from_millis(duration.as_millis() as u64)
+}
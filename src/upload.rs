@@ -0,0 +1,109 @@
+use std::path::Path;
+
+use crate::{
+    config::{expand_env_vars, ReportFormat, ReportUpload},
+    hooks::RunSummary,
+    state::TestReport,
+    Error,
+};
+
+/// POST the report, with the summary of the run as JSON in the
+/// `X-SAM-Summary` header.
+pub async fn upload_report(
+    upload: &ReportUpload,
+    report: &TestReport,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    log::info!("Uploading test report to {}", upload.url);
+    let (body, content_type) = match upload.format {
+        ReportFormat::Json => (
+            serde_json::to_string_pretty(report).map_err(|e| Error::Other(e.to_string()))?,
+            "application/json",
+        ),
+        ReportFormat::Yaml => (
+            serde_yaml::to_string(report).map_err(|e| Error::Other(e.to_string()))?,
+            "application/yaml",
+        ),
+    };
+
+    post(
+        upload,
+        &upload.url,
+        body.into_bytes(),
+        content_type,
+        summary,
+    )
+    .await
+    .map_err(|e| Error::Other(format!("Failed to upload report: {}", e)))?;
+    log::debug!("Report uploaded to {}", upload.url);
+    Ok(())
+}
+
+/// POST the artifacts saved by a failed run as a gzipped tarball to the
+/// `artifacts_url`, with the summary of the run in the `X-SAM-Summary` header.
+pub async fn upload_artifacts(
+    upload: &ReportUpload,
+    artifacts_dir: &Path,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    let Some(url) = &upload.artifacts_url else {
+        return Ok(());
+    };
+    if !artifacts_dir.is_dir() {
+        log::debug!("No artifacts in {} to upload", artifacts_dir.display());
+        return Ok(());
+    }
+    log::info!(
+        "Uploading artifacts of {} to {}",
+        artifacts_dir.display(),
+        url
+    );
+    let output = tokio::process::Command::new("tar")
+        .args(["-czf", "-", "-C"])
+        .arg(artifacts_dir)
+        .arg(".")
+        .output()
+        .await
+        .map_err(|e| Error::run("tar", None, e).context("Failed to bundle artifacts"))?;
+    if !output.status.success() {
+        return Err(Error::exited("tar", &output).context("Failed to bundle artifacts"));
+    }
+    post(upload, url, output.stdout, "application/gzip", summary)
+        .await
+        .map_err(|e| Error::Other(format!("Failed to upload artifacts: {}", e)))?;
+    log::debug!("Artifacts uploaded to {}", url);
+    Ok(())
+}
+
+async fn post(
+    upload: &ReportUpload,
+    url: &str,
+    body: Vec<u8>,
+    content_type: &str,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(upload.timeout()?)
+        .build()
+        .map_err(|e| Error::Other(e.to_string()))?;
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .header("X-SAM-Summary", summary.to_json())
+        .body(body);
+    for (key, value) in &upload.headers {
+        request = request.header(key, expand_env_vars(value));
+    }
+
+    let response = request
+        .send()
+        .await
+        .map_err(|e| Error::Other(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::Other(format!(
+            "server responded with {}",
+            response.status()
+        )));
+    }
+    Ok(())
+}
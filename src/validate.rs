@@ -0,0 +1,436 @@
+//! `sam validate`, the problems of a config that would otherwise only show
+//! once the environment is half started: references to unknown components,
+//! dependency cycles, duplicate names, unknown types and port conflicts.
+
+use std::collections::HashMap;
+
+use clap::ArgMatches;
+use regex::Regex;
+
+use crate::{
+    config::{Config, LogFormat},
+    environment::PLACEHOLDER,
+    log_format, Error,
+};
+
+const COMPONENT_TYPES: &[&str] = &["container", "pod", "process", "job"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+        }
+    }
+}
+
+/// A problem of a config, at the line defining the component it is about if
+/// that is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What was checked: `parse`, `load`, `type`, `duplicate`, `reference`,
+    /// `cycle` or `port`.
+    pub check: &'static str,
+    pub path: String,
+    pub line: Option<usize>,
+    pub component: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// The diagnostic as `path:line: error: message`, or as a logfmt or JSON
+    /// line with the fields of the diagnostic.
+    pub fn format(&self, format: LogFormat) -> String {
+        if format == LogFormat::Pretty {
+            let location = match self.line {
+                Some(line) => format!("{}:{}", self.path, line),
+                None => self.path.clone(),
+            };
+            return format!("{}: {}: {}", location, self.severity.name(), self.message);
+        }
+        let mut fields = vec![
+            ("level", self.severity.name().into()),
+            ("event", "diagnostic".into()),
+            ("check", self.check.into()),
+            ("path", self.path.as_str().into()),
+        ];
+        if let Some(line) = self.line {
+            fields.push(("line", line.into()));
+        }
+        if let Some(component) = &self.component {
+            fields.push(("component", component.as_str().into()));
+        }
+        fields.push(("msg", self.message.as_str().into()));
+        log_format::line(format, &fields)
+    }
+}
+
+/// Print the diagnostics of the config files, failing if there are errors.
+pub fn validate(sub_matches: &ArgMatches) -> Result<(), Error> {
+    let format = sub_matches
+        .get_one::<String>("log-format")
+        .map_or(LogFormat::Pretty, |format| LogFormat::parse(format));
+    let mut errors = 0;
+    let mut warnings = 0;
+    for path in sub_matches.get_many::<String>("config").unwrap() {
+        for diagnostic in validate_file(path)? {
+            println!("{}", diagnostic.format(format));
+            match diagnostic.severity {
+                Severity::Error => errors += 1,
+                Severity::Warning => warnings += 1,
+            }
+        }
+    }
+    if errors > 0 {
+        return Err(Error::Config(format!(
+            "{} errors and {} warnings in the config",
+            errors, warnings
+        )));
+    }
+    if warnings == 0 {
+        log::info!("No problems found");
+    }
+    Ok(())
+}
+
+/// The diagnostics of the config file at `path`, merged with its base and
+/// compose files.
+pub fn validate_file(path: &str) -> Result<Vec<Diagnostic>, Error> {
+    let yaml = std::fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("Failed to read {}: {}", path, e)))?;
+    let diagnostic = |check, line, message: String| Diagnostic {
+        severity: Severity::Error,
+        check,
+        path: path.to_string(),
+        line,
+        component: None,
+        message,
+    };
+    if let Err(e) = Config::from_yaml(&yaml) {
+        let line = e.location().map(|location| location.line());
+        return Ok(vec![diagnostic("parse", line, e.to_string())]);
+    }
+    let cfg = match Config::load(path) {
+        Ok(cfg) => cfg,
+        Err(e) => return Ok(vec![diagnostic("load", None, e.to_string())]),
+    };
+    let mut sources = vec![];
+    for file in Config::source_files(path)? {
+        let text = std::fs::read_to_string(&file)
+            .map_err(|e| Error::Config(format!("Failed to read {}: {}", file, e)))?;
+        sources.push((file, text));
+    }
+    Ok(validate_config(&cfg, &sources))
+}
+
+/// The diagnostics of the loaded `cfg`, located in `sources`, the paths and
+/// contents of the files it was loaded from, the config file first.
+pub fn validate_config(cfg: &Config, sources: &[(String, String)]) -> Vec<Diagnostic> {
+    let locations = Locations::new(sources);
+    let mut diagnostics = vec![];
+    let mut add = |severity, check, component: &str, occurrence, message: String| {
+        let (path, line) = locations.find(component, occurrence);
+        diagnostics.push(Diagnostic {
+            severity,
+            check,
+            path,
+            line,
+            component: Some(component.to_string()),
+            message,
+        });
+    };
+
+    let mut occurrences = HashMap::new();
+    let mut host_ports = HashMap::new();
+    for component in &cfg.components {
+        let name = component.name.as_str();
+        let occurrence = occurrences.entry(name).or_insert(0);
+        *occurrence += 1;
+        let occurrence = *occurrence;
+        if occurrence > 1 {
+            let message = format!("component `{}` is defined {} times", name, occurrence);
+            add(Severity::Error, "duplicate", name, occurrence, message);
+        }
+
+        if !COMPONENT_TYPES.contains(&component.component_type.as_str()) {
+            let message = format!(
+                "component `{}` has unknown type `{}`, expected one of {}",
+                name,
+                component.component_type,
+                COMPONENT_TYPES.join(", ")
+            );
+            add(Severity::Error, "type", name, occurrence, message);
+        }
+
+        for dependency in &component.dependencies {
+            let dependency = dependency.name();
+            if cfg.get_component(dependency).is_none() {
+                let message = format!(
+                    "component `{}` depends on unknown component `{}`",
+                    name, dependency
+                );
+                add(Severity::Error, "reference", name, occurrence, message);
+            }
+        }
+
+        let environment = component
+            .environment
+            .iter()
+            .chain(component.containers.iter().flat_map(|c| &c.environment));
+        let dependencies = dependency_closure(cfg, name);
+        for caps in environment.flat_map(|env| PLACEHOLDER.captures_iter(env)) {
+            let referenced = &caps[2];
+            if cfg.get_component(referenced).is_none() {
+                let message = format!(
+                    "component `{}` refers to unknown component `{}` in `{}`",
+                    name, referenced, &caps[0]
+                );
+                add(Severity::Error, "reference", name, occurrence, message);
+            } else if !dependencies
+                .iter()
+                .any(|dependency| dependency == referenced)
+            {
+                let message = format!(
+                    "component `{}` refers to `{}` in `{}`, which may not be running yet, \
+                     add it to the dependencies",
+                    name, referenced, &caps[0]
+                );
+                add(Severity::Warning, "reference", name, occurrence, message);
+            }
+        }
+
+        for port in &component.ports {
+            match host_ports.insert(port.host, name) {
+                Some(other) if other == name => {
+                    let message = format!(
+                        "component `{}` maps host port {} more than once",
+                        name, port.host
+                    );
+                    add(Severity::Error, "port", name, occurrence, message);
+                }
+                Some(other) => {
+                    let message = format!(
+                        "component `{}` maps host port {}, which component `{}` already maps",
+                        name, port.host, other
+                    );
+                    host_ports.insert(port.host, other);
+                    add(Severity::Error, "port", name, occurrence, message);
+                }
+                None => {}
+            }
+        }
+    }
+
+    for cycle in dependency_cycles(cfg) {
+        let message = format!("dependency cycle {}", cycle.join(" -> "));
+        add(Severity::Error, "cycle", &cycle[0], 1, message);
+    }
+
+    let mut seeds = cfg.seeds.keys().collect::<Vec<_>>();
+    seeds.sort();
+    for name in seeds {
+        if cfg.get_component(name).is_none() {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Error,
+                check: "reference",
+                path: locations.config_path(),
+                line: locations.find_key("seeds"),
+                component: Some(name.clone()),
+                message: format!("seeds for unknown component `{}`", name),
+            });
+        }
+    }
+    diagnostics
+}
+
+/// The names of the components `name` depends on, directly or not.
+fn dependency_closure(cfg: &Config, name: &str) -> Vec<String> {
+    let mut closure: Vec<String> = vec![];
+    let mut pending = vec![name.to_string()];
+    while let Some(name) = pending.pop() {
+        let Some(component) = cfg.get_component(&name) else {
+            continue;
+        };
+        for dependency in &component.dependencies {
+            let dependency = dependency.name().to_string();
+            if !closure.contains(&dependency) {
+                closure.push(dependency.clone());
+                pending.push(dependency);
+            }
+        }
+    }
+    closure
+}
+
+/// The cycles of the dependencies, each as the components along it ending
+/// with the first one again, found once.
+fn dependency_cycles(cfg: &Config) -> Vec<Vec<String>> {
+    fn visit<'a>(
+        cfg: &'a Config,
+        name: &'a str,
+        stack: &mut Vec<&'a str>,
+        done: &mut Vec<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        if let Some(start) = stack.iter().position(|n| *n == name) {
+            let mut cycle = stack[start..]
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>();
+            cycle.push(name.to_string());
+            cycles.push(cycle);
+            return;
+        }
+        if done.contains(&name) {
+            return;
+        }
+        let Some(component) = cfg.get_component(name) else {
+            return;
+        };
+        stack.push(name);
+        for dependency in &component.dependencies {
+            visit(cfg, dependency.name(), stack, done, cycles);
+        }
+        stack.pop();
+        done.push(name);
+    }
+
+    let mut cycles = vec![];
+    let mut done = vec![];
+    for component in &cfg.components {
+        visit(cfg, &component.name, &mut vec![], &mut done, &mut cycles);
+    }
+    cycles
+}
+
+/// The lines the components are defined at, found by their `name:` key as
+/// the config is no longer tied to its YAML once loaded.
+struct Locations<'a> {
+    sources: &'a [(String, String)],
+}
+
+impl<'a> Locations<'a> {
+    fn new(sources: &'a [(String, String)]) -> Self {
+        Self { sources }
+    }
+
+    /// The file and line of the `occurrence`th definition of the component
+    /// `name`, or of its last one if there are fewer.
+    fn find(&self, name: &str, occurrence: usize) -> (String, Option<usize>) {
+        let re = Regex::new(&format!(
+            r#"^\s*(?:-\s+)?name:\s*["']?{}["']?\s*(?:#.*)?$"#,
+            regex::escape(name)
+        ))
+        .unwrap();
+        let found = self
+            .sources
+            .iter()
+            .flat_map(|(path, text)| {
+                text.lines()
+                    .enumerate()
+                    .filter(|(_, line)| re.is_match(line))
+                    .map(move |(i, _)| (path.clone(), Some(i + 1)))
+            })
+            .collect::<Vec<_>>();
+        match found.get(occurrence - 1).or(found.last()) {
+            Some(location) => location.clone(),
+            // Added from a compose file
+            None => (self.config_path(), None),
+        }
+    }
+
+    /// The line of the top-level `key` of the config file.
+    fn find_key(&self, key: &str) -> Option<usize> {
+        let (_, text) = self.sources.first()?;
+        let prefix = format!("{}:", key);
+        text.lines()
+            .position(|line| line.starts_with(&prefix))
+            .map(|i| i + 1)
+    }
+
+    fn config_path(&self) -> String {
+        self.sources
+            .first()
+            .map_or_else(String::new, |(path, _)| path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_config() {
+        let yaml = r#"
+name: validate
+components:
+  - name: db
+    type: container
+    image: postgres
+    ports:
+      - host: 5432
+        container: 5432
+  - name: api
+    type: container
+    image: api
+    dependencies: [db, cache]
+    environment:
+      DATABASE_URL: "postgres://{{component.db.ip}}:5432"
+      WEB_URL: "http://localhost:{{port.web.80}}"
+    ports:
+      - host: 5432
+        container: 8080
+  - name: web
+    type: contianer
+    dependencies: [worker]
+  - name: worker
+    type: process
+    command: [./worker]
+    dependencies: [web]
+  - name: web
+    type: process
+    command: [./web]
+seeds:
+  search: []
+"#;
+        let cfg = Config::from_yaml(yaml).unwrap();
+        let sources = [("sam.yaml".to_string(), yaml.to_string())];
+        let diagnostics = validate_config(&cfg, &sources);
+        let lines = diagnostics
+            .iter()
+            .map(|d| d.format(LogFormat::Pretty))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "sam.yaml:10: error: component `api` depends on unknown component `cache`",
+                "sam.yaml:10: warning: component `api` refers to `web` in `{{port.web.80}}`, \
+                 which may not be running yet, add it to the dependencies",
+                "sam.yaml:10: error: component `api` maps host port 5432, which component \
+                 `db` already maps",
+                "sam.yaml:20: error: component `web` has unknown type `contianer`, expected \
+                 one of container, pod, process, job",
+                "sam.yaml:27: error: component `web` is defined 2 times",
+                "sam.yaml:20: error: dependency cycle web -> worker -> web",
+                "sam.yaml:30: error: seeds for unknown component `search`",
+            ]
+        );
+
+        let json: Value = serde_json::from_str(&diagnostics[0].format(LogFormat::Json)).unwrap();
+        assert_eq!(json["check"], "reference");
+        assert_eq!(json["line"], 10);
+        assert_eq!(json["component"], "api");
+
+        let cfg = Config::from_yaml("name: ok\ncomponents: []\n").unwrap();
+        assert!(validate_config(&cfg, &[]).is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    io::Write,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+enum Message {
+    Text(String),
+    Flush(Sender<()>),
+}
+
+/// Buffered stdout of an engine. Writes are sent to a single thread that
+/// owns stdout, so concurrent tasks never interleave within a write. Clones
+/// share the thread, which exits once the last clone is dropped.
+#[derive(Clone)]
+pub struct OutputWriter {
+    sender: Sender<Message>,
+}
+
+impl OutputWriter {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || consume(receiver));
+        Self { sender }
+    }
+
+    pub fn write(&self, text: impl Into<String>) {
+        let _ = self.sender.send(Message::Text(text.into()));
+    }
+
+    pub fn writeln(&self, text: impl Into<String>) {
+        let mut text = text.into();
+        text.push('\n');
+        self.write(text);
+    }
+
+    /// Wait until everything written so far is on stdout, e.g. before
+    /// logging to stderr.
+    pub fn flush(&self) {
+        let (sender, receiver) = channel();
+        if self.sender.send(Message::Flush(sender)).is_ok() {
+            let _ = receiver.recv();
+        }
+    }
+}
+
+impl Default for OutputWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Write all pending messages, then flush stdout once the channel is drained
+fn consume(receiver: Receiver<Message>) {
+    let mut stdout = std::io::BufWriter::new(std::io::stdout());
+    while let Ok(message) = receiver.recv() {
+        let mut flushed = vec![];
+        let mut message = Some(message);
+        while let Some(next) = message {
+            match next {
+                Message::Text(text) => {
+                    let _ = stdout.write_all(text.as_bytes());
+                }
+                Message::Flush(done) => flushed.push(done),
+            }
+            message = receiver.try_recv().ok();
+        }
+        let _ = stdout.flush();
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
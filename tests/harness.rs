@@ -0,0 +1,21 @@
+#[tokio::test(flavor = "multi_thread")]
+async fn test_self_test_data() -> Result<(), sam::Error> {
+    let report = sam::harness::run(
+        "examples/self-test/config.yaml",
+        &["examples/self-test/tests/data.rhai"],
+    )
+    .await?;
+    assert!(report.success);
+    assert!(report.test_count > 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failing_script() {
+    let result = sam::harness::run(
+        "examples/self-test/config.yaml",
+        &["examples/self-test/tests/missing.rhai"],
+    )
+    .await;
+    assert!(matches!(result, Err(sam::Error::Test(_))));
+}